@@ -0,0 +1,181 @@
+//! Benchmarks of ray intersections and of a small full render, to validate performance work such
+//! as changes to the BVH or to threading. Run with `cargo bench`.
+#[macro_use]
+extern crate criterion;
+extern crate image;
+extern crate nalgebra;
+extern crate tinyraytracer_rs;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use criterion::{black_box, Criterion};
+use image::Rgba;
+use nalgebra::{Point3, Vector3};
+use tinyraytracer_rs::bvh::Bvh;
+use tinyraytracer_rs::overlays::Overlays;
+use tinyraytracer_rs::{
+    render_scene, BackgroundFallback, Camera, Light, Material, MeshImportOptions, PlainMaterial,
+    Projection, Ray, RayKind, RayVisibility, Rectangle, RenderSettings, Scene, Sphere, TraceObj,
+    Triangle, TriangleMesh,
+};
+
+/// Number of rays per side of the grid of rays cast at the duck.
+const DUCK_RAYS: u32 = 16;
+
+fn material(
+    color: [u8; 4],
+    albedo: [f32; 4],
+    spec_exponent: f32,
+    refr_ratio: f32,
+) -> Arc<dyn Material> {
+    Arc::new(PlainMaterial {
+        color: Rgba(color),
+        albedo,
+        spec_exponent,
+        refr_ratio,
+        absorption: [0.; 3],
+        abbe_number: 0.,
+        visibility: RayVisibility::default(),
+    })
+}
+
+fn ivory() -> Arc<dyn Material> {
+    material([102, 102, 76, 255], [0.6, 0.3, 0.1, 0.], 50., 1.)
+}
+
+fn glass() -> Arc<dyn Material> {
+    material([255, 255, 255, 255], [0., 0.5, 0.1, 0.8], 125., 1.5)
+}
+
+fn duck() -> TriangleMesh {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/duck.obj");
+    TriangleMesh::open_obj(&path, &MeshImportOptions::default(), glass())
+        .expect("Could not load the duck model")
+}
+
+/// Rays from the origin through a grid covering the bounding box of an object, as seen from the
+/// front.
+fn rays_at(object: &dyn TraceObj) -> Vec<Ray> {
+    let bbox = object.bounding_box().expect("Unbounded object");
+    let origin = Point3::new(0., 0., 0.);
+    let mut rays = Vec::new();
+    for i in 0..DUCK_RAYS {
+        for j in 0..DUCK_RAYS {
+            let (u, v) = (
+                (i as f32 + 0.5) / DUCK_RAYS as f32,
+                (j as f32 + 0.5) / DUCK_RAYS as f32,
+            );
+            let target = Point3::new(
+                bbox.min.x + (bbox.max.x - bbox.min.x) * u,
+                bbox.min.y + (bbox.max.y - bbox.min.y) * v,
+                bbox.max.z,
+            );
+            rays.push(Ray::new(origin, (target - origin).normalize()));
+        }
+    }
+    rays
+}
+
+fn intersections(c: &mut Criterion) {
+    let sphere = Sphere {
+        center: Point3::new(0., 0., -16.),
+        radius: 2.,
+        material: ivory(),
+    };
+    let triangle = Triangle {
+        a: Point3::new(-2., -2., -16.),
+        b: Point3::new(2., -2., -16.),
+        c: Point3::new(0., 2., -16.),
+        two_sided: false,
+        material: ivory(),
+    };
+    let hit_ray = Ray::new(Point3::new(0., 0., 0.), Vector3::new(0., 0., -1.));
+    let miss_ray = Ray::new(Point3::new(0., 0., 0.), Vector3::new(0., 1., 0.));
+
+    c.bench_function("sphere hit", |b| {
+        b.iter(|| sphere.ray_intersect(black_box(&hit_ray)))
+    });
+    c.bench_function("sphere miss", |b| {
+        b.iter(|| sphere.ray_intersect(black_box(&miss_ray)))
+    });
+    c.bench_function("triangle hit", |b| {
+        b.iter(|| triangle.ray_intersect(black_box(&hit_ray)))
+    });
+    c.bench_function("triangle miss", |b| {
+        b.iter(|| triangle.ray_intersect(black_box(&miss_ray)))
+    });
+
+    // Nearest hits in a scene holding the duck, as found for every ray of a render
+    let objs: Vec<Box<dyn TraceObj>> = vec![Box::new(duck())];
+    let rays = rays_at(&*objs[0]);
+    let bvh = Bvh::build(&objs);
+    c.bench_function("scene intersect duck", |b| {
+        b.iter(|| {
+            for ray in &rays {
+                black_box(bvh.intersect(ray, RayKind::Camera));
+            }
+        })
+    });
+}
+
+/// The spheres, floor and duck of the default scene, without its environment map.
+fn scene() -> Scene {
+    let objs: Vec<Box<dyn TraceObj>> = vec![
+        Box::new(Sphere {
+            center: Point3::new(-3., 0., -16.),
+            radius: 2.,
+            material: ivory(),
+        }),
+        Box::new(Sphere {
+            center: Point3::new(-1., -1.5, -12.),
+            radius: 2.,
+            material: glass(),
+        }),
+        Box::new(Rectangle {
+            low_left: Point3::new(-10., -4., -10.),
+            up_right: Point3::new(10., -4., -30.),
+            material: ivory(),
+        }),
+        Box::new(duck()),
+    ];
+    Scene {
+        objs,
+        lights: vec![
+            Light::new(Point3::new(-20., 20., 20.), 1.5),
+            Light::new(Point3::new(30., 50., -25.), 1.8),
+        ],
+        camera: Camera {
+            fov: 1.,
+            position: Point3::new(0., 0., 0.),
+            projection: Projection::Perspective,
+            view_height: 10.,
+            aperture: 0.,
+            focus_dist: 1.,
+            near: 0.,
+            far: f32::INFINITY,
+        },
+        background: BackgroundFallback::default().to_image(),
+        section: None,
+        overlays: Overlays::default(),
+    }
+}
+
+fn full_frame(c: &mut Criterion) {
+    let scene = scene();
+    let settings = RenderSettings {
+        width: 128,
+        height: 96,
+        ..RenderSettings::default()
+    };
+    let mut group = c.benchmark_group("render");
+    // Whole renders take far longer than single intersections
+    group.sample_size(10);
+    group.bench_function("default scene 128x96", |b| {
+        b.iter(|| render_scene(&scene, &settings))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, intersections, full_frame);
+criterion_main!(benches);
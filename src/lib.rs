@@ -0,0 +1,21 @@
+//! Rust implementation of Tiny Raytracer. Build a `Scene` out of objects, lights and a camera,
+//! then render it into an image with `render_scene`.
+extern crate core_affinity;
+extern crate exr;
+extern crate image;
+extern crate libc;
+extern crate nalgebra;
+extern crate obj;
+extern crate png;
+extern crate rand;
+#[cfg(feature = "rapier")]
+extern crate rapier3d;
+extern crate rayon;
+extern crate ron;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+
+pub mod tinyraytracer;
+
+pub use tinyraytracer::*;
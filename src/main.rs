@@ -1,95 +1,380 @@
+extern crate clap;
 extern crate image;
 extern crate nalgebra;
-extern crate obj;
 extern crate piston_window;
+extern crate tinyraytracer_rs;
 
-mod tinyraytracer;
-
-use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
-use std::rc::Rc;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use image::{Rgba, RgbaImage};
-use nalgebra::Point3;
-use obj::{load_obj, Obj, Position};
-use piston_window::EventLoop;
+use clap::{Args, Parser, Subcommand};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+use nalgebra::{Point3, Vector3};
+use piston_window::{Button, EventLoop, Key, PressEvent, ReleaseEvent};
 
-use tinyraytracer::materials::{CheckerFloorMaterial, PlainMaterial};
-use tinyraytracer::render;
-use tinyraytracer::{Camera, Light, Rectangle, Sphere, TraceObj};
+use tinyraytracer_rs::aov;
+use tinyraytracer_rs::color::{load_image, ColorEncoding, ColorSpace, Dithering, ToneMapping};
+use tinyraytracer_rs::compare;
+use tinyraytracer_rs::integrators::Integrator;
+use tinyraytracer_rs::layout::{Layout, Selection};
+use tinyraytracer_rs::materials::{CheckerFloorMaterial, PlainMaterial, RayVisibility};
+use tinyraytracer_rs::output::{
+    frame_path, is_exr, is_pfm, save_image, save_image_16, supports_16_bit, write_exr, write_pfm,
+    write_raw_f32, write_snapshot, ExrLayer,
+};
+use tinyraytracer_rs::overlays::{draw_overlays, Grid, Overlays};
+use tinyraytracer_rs::post::Sharpen;
+use tinyraytracer_rs::scene::loader::load_scene;
+use tinyraytracer_rs::threads::CoreSelection;
+use tinyraytracer_rs::thumbnail;
+use tinyraytracer_rs::{
+    render_scene, render_scene_timed, scene_stats, tone_map_16, validate_scene, Preset,
+    RenderSettings, RenderTimings, Rgba16Image, SamplingPattern, Scene,
+};
+use tinyraytracer_rs::{
+    Attenuation, BackgroundFallback, Camera, Light, MeshImportOptions, Projection, Rectangle,
+    Sphere, TraceObj, TriangleMesh,
+};
 
-use crate::tinyraytracer::push_obj_faces;
+/// Minimum time between refreshes of the preview window while rendering.
+const PREVIEW_INTERVAL: Duration = Duration::from_millis(100);
+const PREVIEW_FPS: u64 = 30;
+/// Minimum factor the resolution is divided by in layout mode, so that the scene is re-rendered
+/// right away after every move.
+const LAYOUT_PREVIEW_SCALE: u32 = 4;
+/// Distance the selected element is moved by a key press in layout mode, without modifiers.
+const LAYOUT_STEP: f32 = 0.25;
 
-const WIDTH: u32 = 1024;
-const HEIGHT: u32 = 768;
+/// `compare` subcommand. Print the PSNR and SSIM between two images and optionally write a
+/// heatmap of their differences.
+fn compare_images(
+    img_path: &Path,
+    reference_path: &Path,
+    heatmap_path: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let img = image::open(img_path)?.into_rgba8();
+    let reference = image::open(reference_path)?.into_rgba8();
+    if img.dimensions() != reference.dimensions() {
+        return Err(format!(
+            "Compared images must have the same dimensions, got {}x{} and {}x{}",
+            img.width(),
+            img.height(),
+            reference.width(),
+            reference.height()
+        )
+        .into());
+    }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut img = RgbaImage::from_pixel(WIDTH, HEIGHT, Rgba([0, 0, 0, 255]));
+    println!("PSNR: {:.2} dB", compare::psnr(&img, &reference));
+    println!("SSIM: {:.4}", compare::ssim(&img, &reference));
+    if let Some(heatmap_path) = heatmap_path {
+        compare::difference_heatmap(&img, &reference).save(heatmap_path)?;
+    }
+    Ok(())
+}
+
+/// `thumbnail` subcommand. Render a thumbnail of every scene file of `dir` into `output_dir`.
+/// Scenes which fail to load are reported and skipped.
+fn thumbnails(dir: &Path, output_dir: &Path, size: u32) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(output_dir)?;
+    let mut failed = 0;
+    for scene_path in thumbnail::scene_files(dir)? {
+        let img = match thumbnail::render_thumbnail(&scene_path, size) {
+            Ok(img) => img,
+            Err(e) => {
+                eprintln!("Skipped {}: {}", scene_path.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        let mut output_path = output_dir.join(scene_path.file_stem().unwrap_or_default());
+        output_path.set_extension("png");
+        img.save(&output_path)?;
+        println!("Saved: {}", output_path.display());
+    }
+    if failed > 0 {
+        return Err(format!("{} scene files could not be rendered", failed).into());
+    }
+    Ok(())
+}
+
+/// `render --dry-run` option. Print statistics about the scene, and fail if it can't be rendered.
+fn dry_run_report(scene: &Scene, settings: &RenderSettings) -> Result<(), Box<dyn Error>> {
+    let stats = scene_stats(scene, settings);
+    println!(
+        "Objects: {} ({} unbounded)",
+        stats.objects, stats.unbounded_objects
+    );
+    println!("BVH nodes: {}", stats.bvh_nodes);
+    println!("Lights: {}", stats.lights);
+    if let Some(bbox) = stats.bounding_box {
+        println!(
+            "Bounds: [{:.2}, {:.2}, {:.2}] to [{:.2}, {:.2}, {:.2}]",
+            bbox.min.x, bbox.min.y, bbox.min.z, bbox.max.x, bbox.max.y, bbox.max.z
+        );
+    }
+    println!("Image: {}x{}", settings.width, settings.height);
+    println!(
+        "Estimated memory: {:.1} MiB",
+        stats.estimated_memory as f64 / (1024. * 1024.)
+    );
 
-    // Assets dir
-    let args: Vec<String> = env::args().collect();
-    if args.len() <= 1 {
-        panic!("No assets directory provided!");
+    let problems = validate_scene(scene, settings);
+    for problem in &problems {
+        eprintln!("Error: {}", problem);
     }
-    let assets_dir = Path::new(&args[1])
-        .canonicalize()
-        .unwrap_or_else(|_| panic!("Wrong path for assets directory!"));
+    if !problems.is_empty() {
+        return Err(format!("Scene has {} problem(s)", problems.len()).into());
+    }
+    println!("Scene is valid");
+    Ok(())
+}
+
+/// Print the selected element of the scene and where it is, in layout mode.
+fn print_selection(layout: &Layout, scene: &Scene) {
+    let name = match layout.selection(scene) {
+        Some(Selection::Light(idx)) => format!("Light {}", idx),
+        Some(Selection::Object(idx)) => format!("Object {}", idx),
+        None => return,
+    };
+    match layout.position(scene) {
+        Some(pos) => println!("{} at [{:.3}, {:.3}, {:.3}]", name, pos.x, pos.y, pos.z),
+        None => println!("{} (unbounded)", name),
+    }
+}
+
+/// `preview --layout` mode. Show the scene in a window where lights and objects are selected with Tab
+/// (Shift+Tab goes backwards) and moved with the arrow keys along the X and Z axes and with Page
+/// Up / Page Down along the Y axis. Holding Shift moves 10 times farther, Ctrl 10 times less.
+/// The scene is re-rendered at a low resolution after every move, and the new position of the
+/// selected element is printed so that it can be copied into the scene file.
+fn layout_viewer(
+    mut scene: Scene,
+    settings: RenderSettings,
+    preview_scale: u32,
+) -> Result<(), Box<dyn Error>> {
+    let (width, height) = (settings.width, settings.height);
+    let scale = u32::max(preview_scale, LAYOUT_PREVIEW_SCALE);
+    let preview_settings = RenderSettings {
+        width: u32::max(width / scale, 1),
+        height: u32::max(height / scale, 1),
+        passes: 1,
+        samples: 1,
+        adaptive_threshold: 0.,
+        ..settings
+    };
+    let render_preview = |scene: &Scene| {
+        let img = with_overlays(&render_scene(scene, &preview_settings), scene);
+        imageops::resize(&img, width, height, FilterType::Triangle)
+    };
+    let mut layout = Layout::new(&mut scene);
+    println!("Tab: select, arrows / Page Up / Page Down: move, Shift: coarse, Ctrl: fine");
+
+    let mut window: piston_window::PistonWindow =
+        piston_window::WindowSettings::new("tinyraytracer_rs", [width, height])
+            .exit_on_esc(true)
+            .build()
+            .unwrap_or_else(|_e| panic!("Could not create window!"));
+    let mut texture_context = window.create_texture_context();
+    let mut texture = piston_window::Texture::from_image(
+        &mut texture_context,
+        &render_preview(&scene),
+        &piston_window::TextureSettings::new(),
+    )
+    .unwrap();
 
-    // Load model
-    let obj_path = assets_dir.join("duck.obj");
-    let input = BufReader::new(File::open(obj_path)?);
-    let model: Obj<Position> = load_obj(input)?;
+    let (mut shift, mut ctrl) = (false, false);
+    while let Some(event) = window.next() {
+        if let Some(Button::Keyboard(key)) = event.release_args() {
+            match key {
+                Key::LShift | Key::RShift => shift = false,
+                Key::LCtrl | Key::RCtrl => ctrl = false,
+                _ => (),
+            }
+        }
+        if let Some(Button::Keyboard(key)) = event.press_args() {
+            let direction = match key {
+                Key::LShift | Key::RShift => {
+                    shift = true;
+                    None
+                }
+                Key::LCtrl | Key::RCtrl => {
+                    ctrl = true;
+                    None
+                }
+                Key::Tab => {
+                    if shift {
+                        layout.select_previous(&scene);
+                    } else {
+                        layout.select_next(&scene);
+                    }
+                    print_selection(&layout, &scene);
+                    None
+                }
+                Key::Left => Some(-Vector3::x()),
+                Key::Right => Some(Vector3::x()),
+                Key::Up => Some(-Vector3::z()),
+                Key::Down => Some(Vector3::z()),
+                Key::PageUp => Some(Vector3::y()),
+                Key::PageDown => Some(-Vector3::y()),
+                _ => None,
+            };
+            if let Some(direction) = direction {
+                let step = if shift {
+                    10. * LAYOUT_STEP
+                } else if ctrl {
+                    0.1 * LAYOUT_STEP
+                } else {
+                    LAYOUT_STEP
+                };
+                if layout.nudge(&mut scene, direction * step) {
+                    texture
+                        .update(&mut texture_context, &render_preview(&scene))
+                        .unwrap();
+                    print_selection(&layout, &scene);
+                }
+            }
+        }
+        window.draw_2d(&event, |c, g, device| {
+            texture_context.encoder.flush(device);
+            piston_window::clear([0.0, 0.0, 0.0, 1.0], g);
+            piston_window::image(&texture, c.transform, g);
+        });
+    }
+    Ok(())
+}
 
+/// Build the default scene: spheres over a checkered floor and the duck model, using the
+/// assets found in the given directory.
+fn default_scene(
+    assets_dir: &Path,
+    projection: Projection,
+    subdivisions: u32,
+) -> Result<Scene, Box<dyn Error>> {
     // Load environment map
     let background_path = assets_dir.join("envmap.jpg");
-    let mut background = image::open(background_path)
-        .expect("Opening image failed")
-        .into_rgba8();
-    image::imageops::flip_vertical_in_place(&mut background);
+    let background = match load_image(&background_path, ColorEncoding::Srgb, ColorSpace::Srgb) {
+        Ok(mut background) => {
+            image::imageops::flip_vertical_in_place(&mut background);
+            background
+        }
+        Err(e) => {
+            eprintln!(
+                "Could not load environment map {}, using the fallback background: {}",
+                background_path.display(),
+                e
+            );
+            BackgroundFallback::default().to_image()
+        }
+    };
 
-    let camera = Camera {
-        fov: 1., // Radians
-        position: Point3::new(0., 0., 0.),
+    let camera = match projection {
+        Projection::Perspective => Camera {
+            fov: 1., // Radians
+            position: Point3::new(0., 0., 0.),
+            projection,
+            view_height: 10.,
+            aperture: 0.,
+            focus_dist: 1.,
+            near: 0.,
+            far: f32::INFINITY,
+        },
+        Projection::Stereographic => Camera {
+            fov: 5., // Radians. Covers most of the sphere around the camera
+            position: Point3::new(0., 0., -14.),
+            projection,
+            view_height: 10.,
+            aperture: 0.,
+            focus_dist: 1.,
+            near: 0.,
+            far: f32::INFINITY,
+        },
+        Projection::Orthographic => Camera {
+            fov: 1., // Unused
+            position: Point3::new(0., 0., 0.),
+            projection,
+            view_height: 16.,
+            aperture: 0.,
+            focus_dist: 1.,
+            near: 0.,
+            far: f32::INFINITY,
+        },
+        Projection::Equidistant | Projection::Equisolid => Camera {
+            fov: 3.3, // Radians. Slightly wider than a half sphere
+            position: Point3::new(0., 0., 0.),
+            projection,
+            view_height: 10.,
+            aperture: 0.,
+            focus_dist: 1.,
+            near: 0.,
+            far: f32::INFINITY,
+        },
+        Projection::Equirectangular => Camera {
+            fov: 1., // Unused
+            position: Point3::new(0., 0., -12.),
+            projection,
+            view_height: 10.,
+            aperture: 0.,
+            focus_dist: 1.,
+            near: 0.,
+            far: f32::INFINITY,
+        },
     };
 
     // Materials
-    let ivory = Rc::new(PlainMaterial {
+    let ivory = Arc::new(PlainMaterial {
         color: Rgba([102, 102, 76, 255]),
         albedo: [0.6, 0.3, 0.1, 0.],
         spec_exponent: 50.,
         refr_ratio: 1.,
+        absorption: [0.; 3],
+        abbe_number: 0.,
+        visibility: RayVisibility::default(),
     });
 
-    let red_rubber = Rc::new(PlainMaterial {
+    let red_rubber = Arc::new(PlainMaterial {
         color: Rgba([76, 25, 25, 255]),
         albedo: [0.9, 0.1, 0., 0.],
         spec_exponent: 10.,
         refr_ratio: 1.,
+        absorption: [0.; 3],
+        abbe_number: 0.,
+        visibility: RayVisibility::default(),
     });
 
-    let mirror = Rc::new(PlainMaterial {
+    let mirror = Arc::new(PlainMaterial {
         color: Rgba([255, 255, 255, 255]),
         albedo: [0.0, 10., 0.8, 0.],
         spec_exponent: 1425.,
         refr_ratio: 1.,
+        absorption: [0.; 3],
+        abbe_number: 0.,
+        visibility: RayVisibility::default(),
     });
 
-    let glass = Rc::new(PlainMaterial {
+    let glass = Arc::new(PlainMaterial {
         color: Rgba([255, 255, 255, 255]),
         albedo: [0.0, 0.5, 0.1, 0.8],
         spec_exponent: 125.,
         refr_ratio: 1.5,
+        absorption: [0.; 3],
+        abbe_number: 0.,
+        visibility: RayVisibility::default(),
     });
 
-    let checkered_floor = Rc::new(CheckerFloorMaterial {
+    let checkered_floor = Arc::new(CheckerFloorMaterial {
         color0: Rgba([76, 76, 76, 255]),
         color1: Rgba([76, 53, 22, 255]),
         albedo: [0.9, 0.1, 0., 0.],
         spec_exponent: 10.,
         refr_ratio: 1.,
+        visibility: RayVisibility::default(),
     });
 
     // Objects
@@ -126,52 +411,959 @@ fn main() -> Result<(), Box<dyn Error>> {
         Box::new(sphere3),
         Box::new(plane),
     ];
-    push_obj_faces(&model, &mut objs, glass);
+    objs.push(Box::new(TriangleMesh::open_obj(
+        &assets_dir.join("duck.obj"),
+        &MeshImportOptions {
+            subdivisions,
+            ..MeshImportOptions::default()
+        },
+        glass,
+    )?));
 
     // Light sources
     let light0 = Light {
         position: Point3::new(-20., 20., 20.),
         intensity: 1.5,
+        color: [1., 1., 1.],
+        attenuation: Attenuation::None,
+        area: None,
     };
     let light1 = Light {
         position: Point3::new(30., 50., -25.),
         intensity: 1.8,
+        color: [1., 1., 1.],
+        attenuation: Attenuation::None,
+        area: None,
     };
     let light2 = Light {
         position: Point3::new(30., 20., 30.),
         intensity: 1.7,
+        color: [1., 1., 1.],
+        attenuation: Attenuation::None,
+        area: None,
     };
 
     let lights: Vec<Light> = vec![light0, light1, light2];
 
-    // Render scene
-    use std::time::Instant;
-    let now = Instant::now();
-    render(&objs, &lights, &camera, &background, &mut img);
-    let elapsed = now.elapsed();
-    println!("Elapsed: {:.2?}", elapsed);
+    Ok(Scene {
+        objs,
+        lights,
+        camera,
+        background,
+        section: None,
+        overlays: Overlays::default(),
+    })
+}
+
+/// Parse a linear RGB color given as comma separated components, e.g. `0.2,0.25,0.3`.
+fn parse_rgb(rgb: &str) -> Result<[f32; 3], String> {
+    let components: Vec<f32> = rgb
+        .split(',')
+        .map(|component| component.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    match components[..] {
+        [r, g, b] => Ok([r, g, b]),
+        _ => Err("expected an r,g,b color".to_string()),
+    }
+}
+
+/// Copy of a render with the scene's overlays drawn on top.
+fn with_overlays(img: &RgbaImage, scene: &Scene) -> RgbaImage {
+    let mut img = img.clone();
+    draw_overlays(&mut img, &scene.camera, &scene.overlays);
+    img
+}
+
+/// Copy the overlays drawn on the 8-bit version of a render into its 16-bit version `img_16`: the
+/// pixels where `with_overlays` differs from `without_overlays` are overwritten.
+fn copy_overlays_16(
+    img_16: &mut Rgba16Image,
+    without_overlays: &RgbaImage,
+    with_overlays: &RgbaImage,
+) {
+    let pixels = without_overlays.pixels().zip(with_overlays.pixels());
+    for (pixel_16, (before, after)) in img_16.pixels_mut().zip(pixels) {
+        if before != after {
+            // 257 maps 255 to 65535
+            *pixel_16 = Rgba(after.0.map(|channel| channel as u16 * 257));
+        }
+    }
+}
+
+/// Parser of options taking one of a fixed set of names, e.g. `--projection <perspective|...>`.
+fn one_of<T: Copy + Send + Sync + 'static>(
+    choices: &'static [(&'static str, T)],
+) -> impl Fn(&str) -> Result<T, String> + Clone + Send + Sync + 'static {
+    move |name: &str| {
+        choices
+            .iter()
+            .find(|(choice, _)| *choice == name)
+            .map(|&(_, value)| value)
+            .ok_or_else(|| {
+                let names: Vec<&str> = choices.iter().map(|(choice, _)| *choice).collect();
+                format!("must be one of: {}", names.join(", "))
+            })
+    }
+}
+
+const PROJECTIONS: [(&str, Projection); 6] = [
+    ("perspective", Projection::Perspective),
+    ("stereographic", Projection::Stereographic),
+    ("orthographic", Projection::Orthographic),
+    ("equirectangular", Projection::Equirectangular),
+    ("equidistant", Projection::Equidistant),
+    ("equisolid", Projection::Equisolid),
+];
+const PRESETS: [(&str, Preset); 3] = [
+    ("draft", Preset::Draft),
+    ("medium", Preset::Medium),
+    ("final", Preset::Final),
+];
+const SAMPLING_PATTERNS: [(&str, SamplingPattern); 3] = [
+    ("jittered", SamplingPattern::Jittered),
+    ("stratified", SamplingPattern::Stratified),
+    ("halton", SamplingPattern::Halton),
+];
+const TONE_MAPPINGS: [(&str, ToneMapping); 3] = [
+    ("none", ToneMapping::None),
+    ("reinhard", ToneMapping::Reinhard),
+    ("aces", ToneMapping::Aces),
+];
+const COLOR_SPACES: [(&str, ColorSpace); 4] = [
+    ("srgb", ColorSpace::Srgb),
+    ("rec709", ColorSpace::Rec709),
+    ("displayp3", ColorSpace::DisplayP3),
+    ("acescg", ColorSpace::AcesCg),
+];
+const DITHERINGS: [(&str, Dithering); 2] =
+    [("none", Dithering::None), ("ordered", Dithering::Ordered)];
+const INTEGRATORS: [(&str, Integrator); 2] =
+    [("whitted", Integrator::Whitted), ("path", Integrator::Path)];
+const CORE_SELECTIONS: [(&str, CoreSelection); 3] = [
+    ("all", CoreSelection::All),
+    ("performance", CoreSelection::Performance),
+    ("efficiency", CoreSelection::Efficiency),
+];
+const BIT_DEPTHS: [(&str, u32); 2] = [("8", 8), ("16", 16)];
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Rust implementation of Tiny Raytracer")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Render a scene to an image file
+    Render {
+        #[command(flatten)]
+        scene: SceneOptions,
+        /// Path of the rendered image. The format is inferred from the extension, and a frame
+        /// number placeholder such as %04d is filled in with --frame
+        #[arg(short, long)]
+        output: String,
+        #[command(flatten)]
+        progress: ProgressOptions,
+        #[command(flatten)]
+        aovs: AovOptions,
+        /// Bits per channel of the rendered image. 16 bits, for renders graded afterwards, are only
+        /// supported by PNG and PPM files. EXR and PFM files always hold floating point values
+        #[arg(long, value_parser = one_of(&BIT_DEPTHS), default_value = "8")]
+        bit_depth: u32,
+        /// Show the render in a window as tiles complete
+        #[arg(long)]
+        window: bool,
+        /// Print the time spent loading the scene, building its BVH, rendering, post processing
+        /// and saving the render
+        #[arg(long)]
+        timings: bool,
+        /// Check that the scene loads and is valid and print statistics about it, without
+        /// rendering it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Render a scene in a window, refreshed as tiles complete
+    Preview {
+        #[command(flatten)]
+        scene: SceneOptions,
+        /// Also save the render to this path
+        #[arg(short, long)]
+        output: Option<String>,
+        #[command(flatten)]
+        progress: ProgressOptions,
+        /// Render at a fraction of the resolution, upscaled to fill the window. Renders can't be
+        /// saved in this mode
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        preview_scale: u32,
+        /// Move lights and objects around with the keyboard instead of rendering the scene once
+        #[arg(long)]
+        layout: bool,
+        /// Print the time spent loading the scene, building its BVH, rendering, post processing
+        /// and saving the render
+        #[arg(long)]
+        timings: bool,
+    },
+    /// Render a scene several times without saving it and print how long renders take
+    Bench {
+        #[command(flatten)]
+        scene: SceneOptions,
+        /// Number of timed renders
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+    },
+    /// Print the PSNR and SSIM between an image and a reference
+    Compare {
+        image: PathBuf,
+        reference: PathBuf,
+        /// Write a heatmap of the differences between both images to this path
+        #[arg(long)]
+        heatmap: Option<PathBuf>,
+    },
+    /// Render a small preview of every scene file (.json or .ron) of a directory, seen from the
+    /// same angle, e.g. to build a gallery of saved scenes
+    Thumbnail {
+        /// Directory containing the scene files
+        dir: PathBuf,
+        /// Directory the thumbnails are saved to, as PNG files named after the scene files
+        #[arg(short, long, default_value = "thumbnails")]
+        output: PathBuf,
+        /// Width and height of the thumbnails in pixels
+        #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(u32).range(1..))]
+        size: u32,
+    },
+}
+
+/// Scene to render and overrides of its render settings. Options which aren't given keep the
+/// values of the scene file (or the defaults for the default scene).
+#[derive(Debug, Args)]
+struct SceneOptions {
+    /// Directory containing the assets of the default scene
+    #[arg(required_unless_present = "scene")]
+    assets: Option<PathBuf>,
+    /// Scene file (JSON or RON) to render instead of the default scene
+    #[arg(long)]
+    scene: Option<PathBuf>,
+    /// Projection of the default scene's camera
+    #[arg(long, value_parser = one_of(&PROJECTIONS), default_value = "perspective")]
+    projection: Projection,
+    /// Loop subdivision levels applied to the default scene's duck
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=3))]
+    subdivide: u32,
+    /// Image width in pixels
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    width: Option<u32>,
+    /// Image height in pixels
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    height: Option<u32>,
+    /// Camera field of view, in degrees
+    #[arg(long)]
+    fov: Option<f32>,
+    /// Height of the area seen by an orthographic camera, in scene units
+    #[arg(long)]
+    view_height: Option<f32>,
+    /// Environment map replacing the scene's
+    #[arg(long)]
+    background: Option<PathBuf>,
+    /// Quality preset, which the other options override
+    #[arg(long, value_parser = one_of(&PRESETS))]
+    preset: Option<Preset>,
+    /// Number of progressive passes
+    #[arg(long)]
+    passes: Option<u32>,
+    /// Samples per pixel taken in each pass
+    #[arg(long)]
+    samples: Option<u32>,
+    #[arg(long, value_parser = one_of(&SAMPLING_PATTERNS))]
+    sampling: Option<SamplingPattern>,
+    /// Noise level under which tiles stop receiving samples
+    #[arg(long = "adaptive", value_name = "THRESHOLD")]
+    adaptive_threshold: Option<f32>,
+    /// Ambient occlusion rays casted from every shaded point
+    #[arg(long = "ao", value_name = "SAMPLES")]
+    ao_samples: Option<u32>,
+    /// Distance beyond which objects don't occlude
+    #[arg(long)]
+    ao_distance: Option<f32>,
+    /// Shadow rays casted towards each area light
+    #[arg(long)]
+    light_samples: Option<u32>,
+    #[arg(long)]
+    reflection_depth: Option<u32>,
+    #[arg(long)]
+    refraction_depth: Option<u32>,
+    /// Don't add environment reflections to non-mirror materials
+    #[arg(long)]
+    no_env_reflections: bool,
+    /// Start shadow rays from the flat faces of smooth shaded meshes, as the geometry is
+    #[arg(long)]
+    no_terminator_offset: bool,
+    /// Refractive index of the medium the scene is immersed in
+    #[arg(long)]
+    env_refr_idx: Option<f32>,
+    /// Radius of the glow lights are drawn with
+    #[arg(long)]
+    light_glow: Option<f32>,
+    #[arg(long, value_parser = one_of(&TONE_MAPPINGS))]
+    tone_mapping: Option<ToneMapping>,
+    /// Multiplier applied to linear colors before tone mapping
+    #[arg(long)]
+    exposure: Option<f32>,
+    /// Lens diameter, zero for a pinhole camera
+    #[arg(long)]
+    aperture: Option<f32>,
+    /// Distance of the plane in focus
+    #[arg(long)]
+    focus_dist: Option<f32>,
+    #[arg(long, value_parser = one_of(&INTEGRATORS))]
+    integrator: Option<Integrator>,
+    /// Maximum bounces of the path tracer
+    #[arg(long)]
+    max_bounces: Option<u32>,
+    /// Distance from the camera under which objects are clipped
+    #[arg(long)]
+    near: Option<f32>,
+    /// Distance from the camera beyond which objects are clipped
+    #[arg(long)]
+    far: Option<f32>,
+    /// Radius of the disk shadow tests are jittered over
+    #[arg(long)]
+    shadow_jitter: Option<f32>,
+    #[arg(long)]
+    shadow_jitter_samples: Option<u32>,
+    /// Strength of the sharpening applied to the render, e.g. 0.5
+    #[arg(long)]
+    sharpen: Option<f32>,
+    /// Color space of the output image
+    #[arg(long, value_parser = one_of(&COLOR_SPACES))]
+    color_space: Option<ColorSpace>,
+    #[arg(long = "dither", value_parser = one_of(&DITHERINGS))]
+    dithering: Option<Dithering>,
+    /// Linear r,g,b ambient light received by surfaces facing up
+    #[arg(long, value_parser = parse_rgb)]
+    ambient_sky: Option<[f32; 3]>,
+    /// Linear r,g,b ambient light received by surfaces facing down
+    #[arg(long, value_parser = parse_rgb)]
+    ambient_ground: Option<[f32; 3]>,
+    /// Rays casted towards the environment map from every shaded point
+    #[arg(long = "env-light", value_name = "SAMPLES")]
+    env_light_samples: Option<u32>,
+    /// Refractive surfaces environment light rays are bent through, zero to let glass block it
+    #[arg(long, value_name = "SURFACES")]
+    env_light_refractions: Option<u32>,
+    /// Length of the world axes drawn over the render
+    #[arg(long)]
+    axes: Option<f32>,
+    /// Spacing of a grid drawn over the render
+    #[arg(long = "grid", value_name = "SPACING")]
+    grid_spacing: Option<f32>,
+    /// Height of the grid's plane
+    #[arg(long, requires = "grid_spacing")]
+    grid_height: Option<f32>,
+    /// Number of render threads, zero for one per selected core
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Lower the priority of render threads
+    #[arg(long)]
+    low_priority: bool,
+    /// Cores render threads may run on
+    #[arg(long, value_parser = one_of(&CORE_SELECTIONS))]
+    cores: Option<CoreSelection>,
+}
+
+impl SceneOptions {
+    /// Load the scene, and its render settings overridden by the options.
+    fn load(&self) -> Result<(Scene, RenderSettings), Box<dyn Error>> {
+        let (mut scene, mut settings) = match self.scene {
+            Some(ref scene_path) => load_scene(scene_path)?,
+            None => {
+                let assets_dir = self.assets.as_ref().ok_or("No assets directory provided")?;
+                let assets_dir = assets_dir.canonicalize().map_err(|e| {
+                    format!(
+                        "Wrong path for assets directory {}: {}",
+                        assets_dir.display(),
+                        e
+                    )
+                })?;
+                (
+                    default_scene(&assets_dir, self.projection, self.subdivide)?,
+                    RenderSettings::default(),
+                )
+            }
+        };
+
+        if let Some(fov) = self.fov {
+            scene.camera.fov = fov.to_radians();
+        }
+        if let Some(view_height) = self.view_height {
+            scene.camera.view_height = view_height;
+        }
+        if let Some(ref background_path) = self.background {
+            let mut background =
+                load_image(background_path, ColorEncoding::Srgb, ColorSpace::Srgb)?;
+            image::imageops::flip_vertical_in_place(&mut background);
+            scene.background = background;
+        }
+        if let Some(aperture) = self.aperture {
+            scene.camera.aperture = aperture;
+        }
+        if let Some(focus_dist) = self.focus_dist {
+            scene.camera.focus_dist = focus_dist;
+        }
+        if let Some(near) = self.near {
+            scene.camera.near = near;
+        }
+        if let Some(far) = self.far {
+            scene.camera.far = far;
+        }
+        // Presets only provide defaults, which individual options override
+        if let Some(preset) = self.preset {
+            settings.apply_preset(preset);
+        }
+        if let Some(axes) = self.axes {
+            scene.overlays.axes = axes;
+        }
+        if let Some(spacing) = self.grid_spacing {
+            scene.overlays.grid = Some(Grid {
+                height: self.grid_height.unwrap_or(0.),
+                spacing,
+                extent: 10. * spacing,
+            });
+        }
+        if let Some(width) = self.width {
+            settings.width = width;
+        }
+        if let Some(height) = self.height {
+            settings.height = height;
+        }
+        if let Some(passes) = self.passes {
+            settings.passes = passes;
+        }
+        if let Some(samples) = self.samples {
+            settings.samples = samples;
+        }
+        if let Some(sampling) = self.sampling {
+            settings.sampling = sampling;
+        }
+        if let Some(adaptive_threshold) = self.adaptive_threshold {
+            settings.adaptive_threshold = adaptive_threshold;
+        }
+        if let Some(ao_samples) = self.ao_samples {
+            settings.ao_samples = ao_samples;
+        }
+        if let Some(ao_distance) = self.ao_distance {
+            settings.ao_distance = ao_distance;
+        }
+        if let Some(ambient_sky) = self.ambient_sky {
+            settings.ambient.sky = ambient_sky;
+        }
+        if let Some(ambient_ground) = self.ambient_ground {
+            settings.ambient.ground = ambient_ground;
+        }
+        if let Some(env_light_samples) = self.env_light_samples {
+            settings.env_light_samples = env_light_samples;
+        }
+        if let Some(env_light_refractions) = self.env_light_refractions {
+            settings.env_light_refractions = env_light_refractions;
+        }
+        if let Some(light_samples) = self.light_samples {
+            settings.light_samples = light_samples;
+        }
+        if let Some(shadow_jitter) = self.shadow_jitter {
+            settings.shadow_jitter = shadow_jitter;
+        }
+        if let Some(shadow_jitter_samples) = self.shadow_jitter_samples {
+            settings.shadow_jitter_samples = shadow_jitter_samples;
+        }
+        if let Some(reflection_depth) = self.reflection_depth {
+            settings.reflection_depth = reflection_depth;
+        }
+        if let Some(refraction_depth) = self.refraction_depth {
+            settings.refraction_depth = refraction_depth;
+        }
+        if let Some(threads) = self.threads {
+            settings.threads.threads = threads;
+        }
+        if let Some(tone_mapping) = self.tone_mapping {
+            settings.tone_mapping = tone_mapping;
+        }
+        if let Some(exposure) = self.exposure {
+            settings.exposure = exposure;
+        }
+        if let Some(amount) = self.sharpen {
+            settings.post.push(Arc::new(Sharpen { amount }));
+        }
+        if let Some(color_space) = self.color_space {
+            settings.color_space = color_space;
+        }
+        if let Some(dithering) = self.dithering {
+            settings.dithering = dithering;
+        }
+        if let Some(integrator) = self.integrator {
+            settings.integrator = integrator;
+        }
+        if let Some(max_bounces) = self.max_bounces {
+            settings.max_bounces = max_bounces;
+        }
+        if self.no_env_reflections {
+            settings.env_reflections = false;
+        }
+        if self.no_terminator_offset {
+            settings.terminator_offset = false;
+        }
+        if let Some(env_refr_idx) = self.env_refr_idx {
+            settings.env_refr_idx = env_refr_idx;
+        }
+        if let Some(light_glow) = self.light_glow {
+            settings.light_glow = light_glow;
+        }
+        if self.low_priority {
+            settings.threads.low_priority = true;
+        }
+        if let Some(cores) = self.cores {
+            settings.threads.cores = cores;
+        }
+        Ok((scene, settings))
+    }
+}
+
+/// What is written to disk while a render progresses.
+#[derive(Debug, Args)]
+struct ProgressOptions {
+    /// Frame index filling the placeholder of the output path
+    #[arg(long, default_value_t = 0)]
+    frame: u32,
+    /// Write the image rendered so far to this path as the render progresses
+    #[arg(long)]
+    snapshot: Option<String>,
+    /// Seconds between snapshots, zero to disable timed snapshots
+    #[arg(long, default_value_t = 10)]
+    snapshot_secs: u64,
+    /// Passes between snapshots, zero to disable
+    #[arg(long, default_value_t = 0)]
+    snapshot_passes: u32,
+    /// Write the pass, position and hash of every completed tile to this path
+    #[arg(long)]
+    tile_hashes: Option<PathBuf>,
+}
+
+/// Auxiliary images written alongside the render. Their paths can hold a frame number
+/// placeholder, like the output path. Images requested without a path are saved as layers of the
+/// render, which must then be an EXR file.
+#[derive(Debug, Args)]
+struct AovOptions {
+    /// Write the distance to the nearest surface seen through each pixel to this path, as raw
+    /// little-endian f32 values for .raw files and as a grayscale image (white when near) otherwise
+    #[arg(long, num_args = 0..=1, value_name = "PATH")]
+    depth: Option<Option<String>>,
+    /// Write the world space normals of the surfaces seen through each pixel to this path, as
+    /// raw f32 values for .raw files and encoded as colors otherwise
+    #[arg(long, num_args = 0..=1, value_name = "PATH")]
+    normals: Option<Option<String>>,
+    /// Write the unlit color of the surfaces seen through each pixel to this path, as raw linear
+    /// f32 values for .raw files and as sRGB colors otherwise
+    #[arg(long, num_args = 0..=1, value_name = "PATH")]
+    albedo: Option<Option<String>>,
+    /// Write the index of the object seen through each pixel to this path, as raw f32 values
+    /// (-1 without object) for .raw files and as one color per object otherwise
+    #[arg(long, num_args = 0..=1, value_name = "PATH")]
+    object_ids: Option<Option<String>>,
+    /// Write the number of intersection tests performed by the camera ray through each pixel to
+    /// this path, as raw f32 values for .raw files and as a heatmap otherwise
+    #[arg(long, num_args = 0..=1, value_name = "PATH")]
+    intersections: Option<Option<String>>,
+}
+
+impl AovOptions {
+    /// Check if any image is requested as a layer of the render.
+    fn has_layers(&self) -> bool {
+        [
+            &self.depth,
+            &self.normals,
+            &self.albedo,
+            &self.object_ids,
+            &self.intersections,
+        ]
+        .iter()
+        .any(|aov| aov.as_ref().map_or(false, Option::is_none))
+    }
+}
+
+/// Render and save the auxiliary images requested by `aovs`. Return those requested as layers of
+/// the render.
+fn write_aovs(
+    scene: &Scene,
+    settings: &RenderSettings,
+    aovs: &AovOptions,
+    frame: u32,
+) -> Result<Vec<ExrLayer>, Box<dyn Error>> {
+    let size = (settings.width, settings.height);
+    let mut layers = Vec::new();
+    if let Some(ref template) = aovs.depth {
+        let depth = aov::render_depth(scene, settings);
+        let layer = ExrLayer::new("depth", &["Z"], depth.as_raw());
+        save_aov(template, frame, size, layer, &mut layers, || {
+            DynamicImage::ImageLuma8(aov::depth_to_grayscale(&depth))
+        })?;
+    }
+    if let Some(ref template) = aovs.normals {
+        let normals = aov::render_normals(scene, settings);
+        let layer = ExrLayer::new("normals", &["X", "Y", "Z"], normals.as_raw());
+        save_aov(template, frame, size, layer, &mut layers, || {
+            DynamicImage::ImageRgb8(aov::normals_to_rgb(&normals))
+        })?;
+    }
+    if let Some(ref template) = aovs.albedo {
+        let albedo = aov::render_albedo(scene, settings);
+        let layer = ExrLayer::new("albedo", &["R", "G", "B"], albedo.as_raw());
+        save_aov(template, frame, size, layer, &mut layers, || {
+            DynamicImage::ImageRgb8(aov::albedo_to_srgb(&albedo))
+        })?;
+    }
+    if let Some(ref template) = aovs.object_ids {
+        let ids = aov::render_object_ids(scene, settings);
+        let layer = ExrLayer::new("object", &["id"], ids.as_raw());
+        save_aov(template, frame, size, layer, &mut layers, || {
+            DynamicImage::ImageRgb8(aov::object_ids_to_rgb(&ids, scene))
+        })?;
+    }
+    if let Some(ref template) = aovs.intersections {
+        let tests = aov::render_intersection_tests(scene, settings);
+        let layer = ExrLayer::new("intersections", &["count"], tests.as_raw());
+        save_aov(template, frame, size, layer, &mut layers, || {
+            DynamicImage::ImageRgb8(aov::intersection_tests_to_heatmap(&tests))
+        })?;
+    }
+    Ok(layers)
+}
+
+/// Save an auxiliary image to the path made from `template`: its raw values for .raw files, a
+/// single layer EXR file for .exr files, a float map for .pfm files and the picture made by
+/// `picture` otherwise. Without a template, `layer` is added to the layers of the render instead.
+fn save_aov<F>(
+    template: &Option<String>,
+    frame: u32,
+    (width, height): (u32, u32),
+    layer: ExrLayer,
+    layers: &mut Vec<ExrLayer>,
+    picture: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce() -> DynamicImage,
+{
+    let template = match *template {
+        Some(ref template) => template,
+        None => {
+            layers.push(layer);
+            return Ok(());
+        }
+    };
+    let path = frame_path(template, frame);
+    if path.extension().map_or(false, |ext| ext == "raw") {
+        write_raw_f32(&layer.values, &path)?;
+    } else if is_exr(&path) {
+        write_exr(width, height, &[layer], &path)?;
+    } else if is_pfm(&path) {
+        write_pfm(width, height, layer.channels.len(), &layer.values, &path)?;
+    } else {
+        picture().save(&path)?;
+    }
+    println!("Saved: {}", path.display());
+    Ok(())
+}
+
+/// Where `render` saves the render.
+struct RenderOutput {
+    /// Path template, which can hold a frame number placeholder
+    template: String,
+    /// Bits per channel of PNG files, 8 or 16
+    bit_depth: u32,
+    /// Auxiliary images saved along with the render in EXR files
+    layers: Vec<ExrLayer>,
+}
+
+/// `bench` subcommand. Render the scene `runs` times and print the time taken by each render and
+/// their average.
+fn bench(scene: &Scene, settings: &RenderSettings, runs: u32) {
+    let mut total = Duration::from_secs(0);
+    let mut fastest = Duration::from_secs(u64::MAX);
+    for run in 1..=runs {
+        let now = Instant::now();
+        render_scene(scene, settings);
+        let elapsed = now.elapsed();
+        println!("Run {}: {:.2?}", run, elapsed);
+        total += elapsed;
+        fastest = fastest.min(elapsed);
+    }
+    println!("Mean: {:.2?}, fastest: {:.2?}", total / runs, fastest);
+}
+
+/// Render a scene, optionally showing it in a window as tiles complete, saving it and writing the
+/// files requested by `progress` along the way. Renders saved to disk are completed even if the
+/// window is closed. If `timings` are given, with the time taken by the stages before the render,
+/// the timings of every stage are printed once the render is saved.
+fn render(
+    scene: Scene,
+    mut settings: RenderSettings,
+    output: Option<RenderOutput>,
+    progress: ProgressOptions,
+    show_window: bool,
+    preview_scale: u32,
+    timings: Option<RenderTimings>,
+) -> Result<(), Box<dyn Error>> {
+    let ProgressOptions {
+        frame,
+        snapshot: snapshot_path,
+        snapshot_secs,
+        snapshot_passes,
+        tile_hashes: tile_hashes_path,
+    } = progress;
+
+    // Render scene on a worker thread, so that the preview window shows the image as tiles
+    // complete. If requested, write intermediate snapshots every few seconds and/or passes
+    // Preview renders are computed at a fraction of the resolution and upscaled for display
+    let (width, height) = (settings.width, settings.height);
+    settings.width = u32::max(width / preview_scale, 1);
+    settings.height = u32::max(height / preview_scale, 1);
+    let wait_for_render = output.is_some() || snapshot_path.is_some();
+    let preview: Arc<Mutex<Option<RgbaImage>>> = Arc::new(Mutex::new(None));
+    let render_preview = if show_window {
+        Some(preview.clone())
+    } else {
+        None
+    };
+    // One line per completed tile: pass, x, y, width, height and hash of the tile
+    let mut tile_hashes = match tile_hashes_path {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let render_job = move || -> Result<(), String> {
+        let snapshot_interval = Duration::from_secs(snapshot_secs);
+        let mut last_snapshot = Instant::now();
+        let mut last_preview = Instant::now();
+        let (mut img, linear, mut render_timings) =
+            render_scene_timed(&scene, &settings, |partial, progress| {
+                if let (Some(file), Some(tile)) = (tile_hashes.as_mut(), progress.tile) {
+                    let line = writeln!(
+                        file,
+                        "{} {} {} {} {} {:016x}",
+                        tile.pass, tile.x, tile.y, tile.width, tile.height, tile.hash
+                    );
+                    if let Err(e) = line {
+                        eprintln!("Could not write tile hash: {}", e);
+                    }
+                }
+
+                if let Some(ref preview) = render_preview {
+                    if progress.pass_complete() || last_preview.elapsed() >= PREVIEW_INTERVAL {
+                        *preview.lock().unwrap() = Some(with_overlays(partial, &scene));
+                        last_preview = Instant::now();
+                    }
+                }
+
+                let snapshot_path = match snapshot_path {
+                    Some(ref path) if progress.pass_complete() => Path::new(path),
+                    _ => return,
+                };
+                let (pass, total_passes) = (progress.pass, progress.total_passes);
+                let pass_due = snapshot_passes > 0 && pass % snapshot_passes == 0;
+                let time_due = snapshot_secs > 0 && last_snapshot.elapsed() >= snapshot_interval;
+                if pass_due || time_due || pass == total_passes {
+                    let snapshot = with_overlays(partial, &scene);
+                    if let Err(e) = write_snapshot(&snapshot, snapshot_path, pass, total_passes) {
+                        eprintln!("Could not write snapshot: {}", e);
+                    }
+                    last_snapshot = Instant::now();
+                }
+            });
+        if let Some(mut file) = tile_hashes {
+            file.flush().map_err(|e| e.to_string())?;
+        }
+        let now = Instant::now();
+        let rendered = img.clone();
+        draw_overlays(&mut img, &scene.camera, &scene.overlays);
+        render_timings.post += now.elapsed();
+
+        // Save render. Output path templates like render_%04d.png are expanded with the frame
+        // index
+        let now = Instant::now();
+        if let Some(output) = output {
+            let output_path = frame_path(&output.template, frame);
+            if is_exr(&output_path) || is_pfm(&output_path) {
+                // Linear light values, which overlays don't apply to
+                let exposure = settings.exposure;
+                let values: Vec<f32> = linear.as_raw().iter().map(|v| v * exposure).collect();
+                let (width, height) = linear.dimensions();
+                if is_pfm(&output_path) {
+                    write_pfm(width, height, 3, &values, &output_path)
+                        .map_err(|e| e.to_string())?;
+                } else {
+                    let mut layers = vec![ExrLayer {
+                        name: String::new(),
+                        channels: &["R", "G", "B"],
+                        values,
+                    }];
+                    layers.extend(output.layers);
+                    write_exr(width, height, &layers, &output_path).map_err(|e| e.to_string())?;
+                }
+            } else if output.bit_depth == 16 {
+                let mut img_16 = tone_map_16(&linear, &settings);
+                copy_overlays_16(&mut img_16, &rendered, &img);
+                save_image_16(&img_16, &output_path).map_err(|e| e.to_string())?;
+            } else {
+                save_image(&img, &output_path).map_err(|e| e.to_string())?;
+            }
+            println!("Saved: {}", output_path.display());
+        }
+        render_timings.encode = now.elapsed();
+
+        if let Some(timings) = timings {
+            render_timings.load = timings.load;
+            println!("{}", render_timings);
+        }
+        Ok(())
+    };
+
+    if !show_window {
+        return render_job().map_err(Into::into);
+    }
+    let render_thread = thread::spawn(render_job);
 
     // Rendering window
     let mut window: piston_window::PistonWindow =
-        piston_window::WindowSettings::new("tinyraytracer_rs", [WIDTH, HEIGHT])
+        piston_window::WindowSettings::new("tinyraytracer_rs", [width, height])
             .exit_on_esc(true)
             .build()
             .unwrap_or_else(|_e| panic!("Could not create window!"));
 
-    window.set_lazy(true);
+    // Keep polling so the texture is refreshed while the render progresses
+    window.set_max_fps(PREVIEW_FPS);
 
-    let texture = piston_window::Texture::from_image(
-        &mut window.create_texture_context(),
-        &img,
+    let mut texture_context = window.create_texture_context();
+    let mut texture = piston_window::Texture::from_image(
+        &mut texture_context,
+        &RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255])),
         &piston_window::TextureSettings::new(),
     )
     .unwrap();
 
     while let Some(event) = window.next() {
-        window.draw_2d(&event, |c, g, _| {
+        if let Some(mut partial) = preview.lock().unwrap().take() {
+            if partial.dimensions() != (width, height) {
+                partial = imageops::resize(&partial, width, height, FilterType::Lanczos3);
+            }
+            texture.update(&mut texture_context, &partial).unwrap();
+        }
+        window.draw_2d(&event, |c, g, device| {
+            texture_context.encoder.flush(device);
             piston_window::clear([0.0, 0.0, 0.0, 1.0], g);
             piston_window::image(&texture, c.transform, g);
         });
     }
+
+    // Closing the window doesn't interrupt renders whose result is written to disk
+    if wait_for_render {
+        render_thread.join().expect("Render thread panicked!")?;
+    }
     Ok(())
 }
+
+fn main() -> Result<(), Box<dyn Error>> {
+    match Cli::parse().command {
+        Command::Render {
+            scene: options,
+            output,
+            progress,
+            aovs,
+            bit_depth,
+            window,
+            timings,
+            dry_run,
+        } => {
+            let now = Instant::now();
+            let (scene, settings) = options.load()?;
+            let load = now.elapsed();
+            if dry_run {
+                return dry_run_report(&scene, &settings);
+            }
+            // Fail before a long render rather than when saving it
+            if bit_depth == 16 && !supports_16_bit(Path::new(&output)) {
+                return Err("16-bit output is only supported for PNG and PPM files".into());
+            }
+            if aovs.has_layers() && !is_exr(Path::new(&output)) {
+                return Err("Auxiliary images without a path need an EXR output".into());
+            }
+            let layers = write_aovs(&scene, &settings, &aovs, progress.frame)?;
+            let output = RenderOutput {
+                template: output,
+                bit_depth,
+                layers,
+            };
+            let timings = if timings {
+                Some(RenderTimings {
+                    load,
+                    ..RenderTimings::default()
+                })
+            } else {
+                None
+            };
+            render(scene, settings, Some(output), progress, window, 1, timings)
+        }
+        Command::Preview {
+            scene: options,
+            output,
+            progress,
+            preview_scale,
+            layout,
+            timings,
+        } => {
+            let now = Instant::now();
+            let (scene, settings) = options.load()?;
+            let load = now.elapsed();
+            if layout {
+                return layout_viewer(scene, settings, preview_scale);
+            }
+            // Reduced resolution renders are only meant for the window
+            if preview_scale > 1 && (output.is_some() || progress.snapshot.is_some()) {
+                return Err("--preview-scale can't be combined with --output or --snapshot".into());
+            }
+            let output = output.map(|template| RenderOutput {
+                template,
+                bit_depth: 8,
+                layers: Vec::new(),
+            });
+            let timings = if timings {
+                Some(RenderTimings {
+                    load,
+                    ..RenderTimings::default()
+                })
+            } else {
+                None
+            };
+            render(
+                scene,
+                settings,
+                output,
+                progress,
+                true,
+                preview_scale,
+                timings,
+            )
+        }
+        Command::Bench {
+            scene: options,
+            runs,
+        } => {
+            let (scene, settings) = options.load()?;
+            bench(&scene, &settings, runs);
+            Ok(())
+        }
+        Command::Compare {
+            image,
+            reference,
+            heatmap,
+        } => compare_images(&image, &reference, heatmap.as_ref().map(PathBuf::as_path)),
+        Command::Thumbnail { dir, output, size } => thumbnails(&dir, &output, size),
+    }
+}
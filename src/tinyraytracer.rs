@@ -1,107 +1,350 @@
+pub mod aov;
+pub mod bvh;
+pub mod color;
+pub mod compare;
+pub mod integrators;
+pub mod layout;
+pub mod math;
+pub mod noise;
+pub mod output;
+pub mod overlays;
+#[cfg(feature = "rapier")]
+pub mod physics;
+pub mod post;
+pub mod sampling;
+pub mod scene;
 pub mod scene_elems;
+pub mod texture_cache;
+pub mod threads;
+pub mod thumbnail;
 
-use std::rc::Rc;
-
+use self::bvh::{Bvh, Tlas};
+use self::color::{ColorSpace, Dithering, ToneMapping};
+use self::integrators::{Integrator, PathTracer};
+use self::math::{dot, length, length_squared, normalize, Point3, Vec3};
+use self::post::PostChain;
+use self::sampling::{cosine_hemisphere, halton, orthonormal_basis, uniform_disk, Onb};
+pub use self::scene::{BackgroundFallback, Scene};
 pub use self::scene_elems::materials;
+use self::scene_elems::materials::{
+    kajiya_kay, schlick_fresnel, texture_lookup, Dirt, DirtSource, ShadingModel, DIELECTRIC_F0,
+};
 pub use self::scene_elems::{
-    Camera, Light, Material, PlainMaterial, Ray, Rectangle, Sphere, TraceObj, Triangle,
+    text_texture, Aabb, AreaLight, Attenuation, Billboard, BoxObj, Camera, Disk, Hit, Light,
+    Material, MeshImportOptions, PlainMaterial, Projection, Ray, RayKind, RayVisibility, Rectangle,
+    Sdf, SdfObject, SectionPlane, Sphere, Torus, TraceObj, Transformed, Triangle, TriangleMesh,
+    UpAxis,
 };
-use image::{Pixel, Rgba, RgbaImage};
-use nalgebra::{Point3, Vector3};
-use obj::{Obj, Position};
-
-const INTERSECT_LIMIT: f32 = 1000.;
-const RAY_DEPTH: u8 = 4;
-const ENV_REFR_IDX: f32 = 1.;
-
-/// Check if a given ray intersects any object. Return the nearest intersection distance as well
-/// as the nearest object.
-fn scene_intersect<'a>(
-    ray: &'a Ray,
-    objs: &'a Vec<Box<dyn TraceObj>>,
-) -> Option<(f32, &'a Box<dyn TraceObj>)> {
-    // Placeholders
-    let mut intersect_dist = INTERSECT_LIMIT;
-    let mut object = None;
-
-    for obj in objs.iter() {
-        if let Some(intersection) = obj.ray_intersect(&ray) {
-            if intersection < intersect_dist {
-                intersect_dist = intersection;
-                object = Some(obj);
-            }
+use self::threads::{build_thread_pool, ThreadSettings};
+use image::{ImageBuffer, Rgb, Rgb32FImage, Rgba, Rgba32FImage, RgbaImage};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Distance by which shadow rays stop short of their target, so they don't hit the surface being
+/// shaded.
+const SHADOW_BIAS: f32 = 1e-3;
+/// Number of transparent surfaces a shadow ray goes through before the light is considered blocked.
+const MAX_SHADOW_LAYERS: u32 = 16;
+/// Refractive index assumed for the environment reflections of opaque materials.
+const DIELECTRIC_REFR_IDX: f32 = 1.5;
+const TILE_SIZE: u32 = 32;
+/// Angle between consecutive points of a Fibonacci spiral, pi * (3 - sqrt(5)).
+const GOLDEN_ANGLE: f32 = 2.399_963;
+
+/// Number of bounces a ray went through since leaving the camera.
+#[derive(Debug, Clone, Copy, Default)]
+struct RayDepth {
+    reflections: u32,
+    refractions: u32,
+    /// Color channel carried alone by rays split by dispersion
+    channel: Option<usize>,
+}
+
+/// Check if a given ray intersects any object. Return the nearest intersection as well as the
+/// nearest object.
+fn scene_intersect<'a>(ray: &Ray, kind: RayKind, bvh: &Bvh<'a>) -> Option<(Hit, &'a dyn TraceObj)> {
+    bvh.intersect(ray, kind)
+}
+
+/// Fraction of the light of each color channel going from one point to another through the
+/// objects in between. Opaque objects block all of it, while every surface of a refractive object
+/// lets through its refraction albedo, tinted by its color. Used to render shadows, colored ones
+/// behind glass.
+fn shadow_transmittance(src_point: Point3, dst_point: Point3, bvh: &Bvh) -> [f32; 3] {
+    let mut ray = Ray {
+        origin: dst_point,
+        direction: -normalize(&(dst_point - src_point)),
+        t_min: 0.,
+        t_max: length(&(dst_point - src_point)) - SHADOW_BIAS,
+    };
+
+    let mut transmittance = [1.; 3];
+    for _ in 0..MAX_SHADOW_LAYERS {
+        let (hit, object) = match bvh.intersect(&ray, RayKind::Shadow) {
+            Some(intersection) => intersection,
+            None => return transmittance,
+        };
+        let material = object.material();
+        let transmission = f32::min(material.albedo()[3], 1.);
+        if transmission <= 0. {
+            return [0.; 3];
+        }
+        let color = material.linear_color(&hit);
+        for ch in 0..3 {
+            transmittance[ch] *= transmission * color[ch];
         }
+        if transmittance == [0.; 3] {
+            return transmittance;
+        }
+        // Continue past the surface towards the shaded point
+        ray.t_min = hit.t + SHADOW_BIAS;
     }
-    if intersect_dist < INTERSECT_LIMIT {
-        Some((intersect_dist, object.unwrap()))
+    [0.; 3]
+}
+
+/// Point the shadow rays of a hit start from. With `settings.terminator_offset`, points of smooth
+/// shaded meshes are moved by the hit's shadow offset, unless their surface is seen from behind,
+/// so that low-poly meshes don't show faceted shadow terminators.
+fn shadow_origin(hit: &Hit, normal: Vec3, settings: &RenderSettings) -> Point3 {
+    if settings.terminator_offset && dot(&hit.shadow_offset, &normal) > 0. {
+        hit.point + hit.shadow_offset
     } else {
-        None
+        hit.point
     }
 }
 
-/// Determine if there is any object between two points. Used to render shadows.
-fn single_intersect(
-    src_point: Point3<f32>,
-    dst_point: Point3<f32>,
-    objs: &Vec<Box<dyn TraceObj>>,
-) -> bool {
-    let ray_dir = -(dst_point - src_point).normalize();
-    let ray_dist = (dst_point - src_point).norm() - 1e-3;
+/// Average transmittance of shadow tests towards a light position, per color channel. With
+/// `settings.shadow_jitter` above zero, the tested point is jittered over a disk of that radius
+/// around the shaded point, in its tangent plane, which softens aliased shadow edges.
+fn shadow_visibility<R: Rng>(
+    point: Point3,
+    normal: Vec3,
+    light_position: Point3,
+    bvh: &Bvh,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> [f32; 3] {
+    if settings.shadow_jitter <= 0. || settings.shadow_jitter_samples <= 1 {
+        return shadow_transmittance(point, light_position, bvh);
+    }
 
-    let ray = Ray {
-        origin: dst_point,
-        direction: ray_dir,
-    };
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    let mut visibility = [0.; 3];
+    for _ in 0..settings.shadow_jitter_samples {
+        let (x, y) = uniform_disk(rng.gen(), rng.gen());
+        let test_point = point + (tangent * x + bitangent * y) * settings.shadow_jitter;
+        let transmittance = shadow_transmittance(test_point, light_position, bvh);
+        for ch in 0..3 {
+            visibility[ch] += transmittance[ch] / settings.shadow_jitter_samples as f32;
+        }
+    }
+    visibility
+}
+
+/// Fraction of the hemisphere around a point which is not blocked by objects within
+/// `settings.ao_distance`, estimated with `settings.ao_samples` rays. Occluders closer to the
+/// point block more, which darkens contact areas such as the ground under an object.
+fn ambient_visibility(point: Point3, normal: Vec3, bvh: &Bvh, settings: &RenderSettings) -> f32 {
+    hemisphere_visibility(
+        point,
+        normal,
+        bvh,
+        settings.ao_samples,
+        settings.ao_distance,
+    )
+}
+
+/// Fraction of the hemisphere around a point which is not blocked by objects within `distance`,
+/// estimated with `samples` rays.
+fn hemisphere_visibility(
+    point: Point3,
+    normal: Vec3,
+    bvh: &Bvh,
+    samples: u32,
+    distance: f32,
+) -> f32 {
+    if samples == 0 || distance <= 0. {
+        return 1.;
+    }
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    let origin = point + normal * 1e-3;
 
-    for obj in objs.iter() {
-        if let Some(intersection) = obj.ray_intersect(&ray) {
-            if intersection < ray_dist {
-                return true;
+    let mut occlusion = 0.;
+    for sample_idx in 0..samples {
+        // Cosine-weighted directions spread evenly over the hemisphere along a Fibonacci spiral
+        let radius = f32::sqrt((sample_idx as f32 + 0.5) / samples as f32);
+        let angle = sample_idx as f32 * GOLDEN_ANGLE;
+        let direction = tangent * (radius * angle.cos())
+            + bitangent * (radius * angle.sin())
+            + normal * f32::sqrt(1. - radius * radius);
+        let ray = Ray {
+            t_max: distance,
+            ..Ray::new(origin, direction)
+        };
+        if let Some((hit, _)) = bvh.intersect(&ray, RayKind::Shadow) {
+            occlusion += 1. - hit.t / distance;
+        }
+    }
+
+    1. - occlusion / samples as f32
+}
+
+/// Environment light arriving along a ray. The ray is followed through up to
+/// `settings.env_light_refractions` refractive surfaces, bent at each one and tinted by the
+/// refraction albedo and color of the object as in `shadow_transmittance`, so that points under a
+/// glass object receive the light it focuses on them instead of being shadowed by it. Opaque
+/// objects and total internal reflection block the light.
+fn env_light_along(
+    mut ray: Ray,
+    bvh: &Bvh,
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+) -> [f32; 3] {
+    let mut transmittance = [1.; 3];
+    for refraction in 0..=settings.env_light_refractions {
+        let (hit, object) = match scene_intersect(&ray, RayKind::Shadow, bvh) {
+            Some(intersection) => intersection,
+            None => {
+                let env = get_background(background, &ray.direction);
+                return [
+                    env[0] * transmittance[0],
+                    env[1] * transmittance[1],
+                    env[2] * transmittance[2],
+                ];
             }
+        };
+        let material = object.material();
+        let transmission = f32::min(material.albedo()[3], 1.);
+        if transmission <= 0. || refraction == settings.env_light_refractions {
+            break;
+        }
+        let color = material.linear_color(&hit);
+        for ch in 0..3 {
+            transmittance[ch] *= transmission * color[ch];
+        }
+        let direction = match refract_dir(
+            ray.direction,
+            hit.normal,
+            settings.env_refr_idx,
+            material.refr_ratio(),
+        ) {
+            Some(direction) => direction,
+            None => break,
+        };
+        // Continue from the side of the surface the refracted ray leaves through
+        let offset = if dot(&direction, &hit.normal) > 0. {
+            1e-3
+        } else {
+            -1e-3
+        };
+        ray = Ray::new(hit.point + hit.normal * offset, direction);
+    }
+    [0.; 3]
+}
+
+/// Environment light arriving at a point, averaged over `samples` cosine-weighted directions
+/// around the normal. Directions hidden by objects contribute nothing, while those through glass
+/// are bent by it as `env_light_along` describes. This is the irradiance divided by pi, on the
+/// same scale as the ambient light.
+fn env_irradiance<R: Rng>(
+    point: Point3,
+    normal: Vec3,
+    bvh: &Bvh,
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> [f32; 3] {
+    let onb = Onb::from_normal(normal);
+    let origin = point + normal * 1e-3;
+    let samples = settings.env_light_samples;
+    let mut total = [0.; 3];
+    for _ in 0..samples {
+        let direction = onb.to_world(cosine_hemisphere(rng.gen(), rng.gen()));
+        let env = env_light_along(Ray::new(origin, direction), bvh, background, settings);
+        for ch in 0..3 {
+            total[ch] += env[ch];
         }
     }
-    false
+    let samples = samples as f32;
+    [total[0] / samples, total[1] / samples, total[2] / samples]
+}
+
+/// Amount of dirt, between 0 (clean) and 1, covering a point of a material with dirt.
+fn dirt_amount(dirt: &Dirt, hit: &Hit, bvh: &Bvh) -> f32 {
+    let amount = match dirt.source {
+        // Baked occlusion maps are white where the surface is clean
+        DirtSource::Baked(ref texture) => {
+            let texel = texture_lookup(texture, hit.uv);
+            1. - luma(Rgb([texel[0], texel[1], texel[2]]))
+        }
+        // Crevices are the parts of the surface hidden by nearby geometry
+        DirtSource::Procedural { distance, samples } => {
+            1. - hemisphere_visibility(hit.point, hit.normal, bvh, samples, distance)
+        }
+    };
+    f32::max(0., f32::min(1., amount * dirt.strength))
+}
+
+/// Seed for the random number generator used when shading a point. Derived from the point itself
+/// so that renders stay reproducible regardless of scheduling, while neighboring points get
+/// uncorrelated samples.
+fn point_seed(point: Point3) -> u64 {
+    (point.x.to_bits() as u64)
+        ^ (point.y.to_bits() as u64).rotate_left(21)
+        ^ (point.z.to_bits() as u64).rotate_left(42)
 }
 
-fn reflect_dir(light_dir: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
-    light_dir - normal * 2. * normal.dot(&light_dir)
+fn reflect_dir(light_dir: Vec3, normal: Vec3) -> Vec3 {
+    light_dir - normal * 2. * dot(&normal, &light_dir)
 }
 
 /// Recursively reflect a ray until no intersection is met or until ray depth is reached.
 /// Return the resulting reflection color.
 fn get_reflection_color(
     ray: &Ray,
-    point: Point3<f32>,
-    normal: Vector3<f32>,
-    objs: &Vec<Box<dyn TraceObj>>,
+    point: Point3,
+    normal: Vec3,
+    bvh: &Bvh,
     lights: &Vec<Light>,
-    background: &RgbaImage,
-    depth: u8,
-) -> Rgba<u8> {
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    depth: RayDepth,
+) -> Rgb<f32> {
     let ray_dir = reflect_dir(ray.direction, normal);
     // Perturb origin point so ray doesn't intersect with originating object.
     let ray_origin = point
         + normal
-            * (if ray_dir.dot(&normal) > 0. {
+            * (if dot(&ray_dir, &normal) > 0. {
                 1e-3
             } else {
                 -1e-3
             });
 
-    let ray = Ray {
-        origin: ray_origin,
-        direction: ray_dir,
-    };
-    cast_ray(ray, objs, lights, background, depth + 1)
+    let ray = Ray::new(ray_origin, ray_dir);
+    cast_ray(
+        ray,
+        RayKind::Reflection,
+        bvh,
+        lights,
+        background,
+        settings,
+        RayDepth {
+            reflections: depth.reflections + 1,
+            ..depth
+        },
+    )
 }
 
-fn refract_dir(
-    light_dir: Vector3<f32>,
-    normal: Vector3<f32>,
-    n1: f32,
-    n2: f32,
-) -> Option<Vector3<f32>> {
-    let cos = -f32::max(-1., f32::min(1., normal.dot(&light_dir)));
+fn refract_dir(light_dir: Vec3, normal: Vec3, n1: f32, n2: f32) -> Option<Vec3> {
+    let cos = -f32::max(-1., f32::min(1., dot(&normal, &light_dir)));
     // If ray inside object
     if cos < 0. {
         return refract_dir(light_dir, -normal, n2, n1);
@@ -113,40 +356,157 @@ fn refract_dir(
     if k > 0. {
         let refracted = eta * light_dir + (eta * cos - f32::sqrt(k)) * normal;
         // The ray refracts.
-        Some(refracted.normalize())
+        Some(normalize(&refracted))
     } else {
         // Total internal reflection. No refraction occurs.
         None
     }
 }
 
+/// Fraction of the light reflected by the surface between the environment, of refractive index
+/// `env_refr_idx`, and a material of the given refractive index, using Schlick's approximation.
+/// The rest is refracted.
+fn fresnel_reflectance(light_dir: Vec3, normal: Vec3, env_refr_idx: f32, refr_ratio: f32) -> f32 {
+    let cos = dot(&normal, &light_dir);
+    // If ray inside object, it goes from the material to the environment
+    let (n1, n2, cos) = if cos > 0. {
+        (refr_ratio, env_refr_idx, cos)
+    } else {
+        (env_refr_idx, refr_ratio, -cos)
+    };
+
+    // When leaving the denser medium, the approximation uses the angle of the refracted ray
+    let cos = if n1 > n2 {
+        let sin_sq_refracted = (n1 / n2) * (n1 / n2) * (1. - cos * cos);
+        if sin_sq_refracted >= 1. {
+            // Total internal reflection
+            return 1.;
+        }
+        f32::sqrt(1. - sin_sq_refracted)
+    } else {
+        f32::min(cos, 1.)
+    };
+
+    let r0 = ((n1 - n2) / (n1 + n2)) * ((n1 - n2) / (n1 + n2));
+    r0 + (1. - r0) * f32::powi(1. - cos, 5)
+}
+
+/// Weight of the environment reflection of a non-mirror material. It follows the Fresnel term of
+/// a typical dielectric, fading out on rough materials.
+fn env_reflection_weight(
+    ray: &Ray,
+    normal: Vec3,
+    material: &dyn Material,
+    settings: &RenderSettings,
+) -> f32 {
+    // Materials which don't refract have no meaningful refractive index
+    let refr_ratio = if material.refr_ratio() > 1. {
+        material.refr_ratio()
+    } else {
+        DIELECTRIC_REFR_IDX
+    };
+    // Roughness matching the width of the Phong specular lobe
+    let roughness = f32::min(1., f32::sqrt(2. / (material.spec_exponent() + 2.)));
+
+    fresnel_reflectance(ray.direction, normal, settings.env_refr_idx, refr_ratio) * (1. - roughness)
+}
+
 /// Recursively refract a ray until no intersection is met or until ray depth is reached.
-/// Return the resulting refr_color color.
+/// Return the resulting refr_color color. Materials with a different refractive index per
+/// channel (dispersion) split the ray into one ray per channel, each refracted with its own
+/// index, which fans white light out into rainbows.
 fn get_refraction_color(
     ray: &Ray,
-    point: Point3<f32>,
-    normal: Vector3<f32>,
+    point: Point3,
+    normal: Vec3,
+    refr_ratios: [f32; 3],
+    absorption: [f32; 3],
+    bvh: &Bvh,
+    lights: &Vec<Light>,
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    depth: RayDepth,
+) -> Option<Rgb<f32>> {
+    let refract = |refr_ratio: f32, depth: RayDepth| {
+        refract_ray(
+            ray, point, normal, refr_ratio, absorption, bvh, lights, background, settings, depth,
+        )
+    };
+    let dispersive = refr_ratios[0] != refr_ratios[1] || refr_ratios[1] != refr_ratios[2];
+    match depth.channel {
+        // Split rays keep following their own channel, so they don't split again
+        Some(ch) => refract(refr_ratios[ch], depth),
+        None if dispersive => {
+            let mut color = Rgb([0., 0., 0.]);
+            let mut refracted = false;
+            for ch in 0..3 {
+                let channel_depth = RayDepth {
+                    channel: Some(ch),
+                    ..depth
+                };
+                // Channels undergoing total internal reflection stay black
+                if let Some(channel_color) = refract(refr_ratios[ch], channel_depth) {
+                    color[ch] = channel_color[ch];
+                    refracted = true;
+                }
+            }
+            if refracted {
+                Some(color)
+            } else {
+                None
+            }
+        }
+        None => refract(refr_ratios[1], depth),
+    }
+}
+
+/// Refract a ray with a single refractive index. None in case of total internal reflection.
+fn refract_ray(
+    ray: &Ray,
+    point: Point3,
+    normal: Vec3,
     refr_ratio: f32,
-    objs: &Vec<Box<dyn TraceObj>>,
+    absorption: [f32; 3],
+    bvh: &Bvh,
     lights: &Vec<Light>,
-    background: &RgbaImage,
-    depth: u8,
-) -> Option<Rgba<u8>> {
-    if let Some(ray_dir) = refract_dir(ray.direction, normal, ENV_REFR_IDX, refr_ratio) {
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    depth: RayDepth,
+) -> Option<Rgb<f32>> {
+    if let Some(ray_dir) = refract_dir(ray.direction, normal, settings.env_refr_idx, refr_ratio) {
         // Perturb origin point so ray doesn't intersect with originating object.
         let ray_origin = point
             + normal
-                * (if ray_dir.dot(&normal) > 0. {
+                * (if dot(&ray_dir, &normal) > 0. {
                     1e-3
                 } else {
                     -1e-3
                 });
 
-        let ray = Ray {
-            origin: ray_origin,
-            direction: ray_dir,
-        };
-        Some(cast_ray(ray, objs, lights, background, depth + 1))
+        let ray = Ray::new(ray_origin, ray_dir);
+        let mut color = cast_ray(
+            ray,
+            RayKind::Refraction,
+            bvh,
+            lights,
+            background,
+            settings,
+            RayDepth {
+                refractions: depth.refractions + 1,
+                ..depth
+            },
+        );
+        // Light entering an absorbing object is attenuated along the distance it travels inside
+        // until the next surface (Beer-Lambert law), so thick parts are more tinted
+        let entering = dot(&ray_dir, &normal) < 0.;
+        if entering && absorption.iter().any(|&coef| coef > 0.) {
+            if let Some((hit, _)) = scene_intersect(&ray, RayKind::Refraction, bvh) {
+                for ch in 0..3 {
+                    color[ch] *= f32::exp(-absorption[ch] * hit.t);
+                }
+            }
+        }
+        Some(color)
     } else {
         // Total internal reflection. No refraction
         None
@@ -156,73 +516,250 @@ fn get_refraction_color(
 /// Get pixel color according to the computed Phong model of the object closest to the camera.
 fn get_point_color(
     ray: &Ray,
-    point: Point3<f32>,
-    normal: Vector3<f32>,
-    objs: &Vec<Box<dyn TraceObj>>,
+    hit: &Hit,
+    bvh: &Bvh,
     lights: &Vec<Light>,
     material: &dyn Material,
-    background: &RgbaImage,
-    depth: u8,
-) -> Rgba<u8> {
-    let mut diff_light_intensity = 0.;
-    let mut spec_light_intensity = 0.;
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    depth: RayDepth,
+) -> Rgb<f32> {
+    // Normal and bump maps only change the normal lighting is computed with, not the geometry
+    let (point, normal) = (hit.point, material.shading_normal(hit));
+    let shaded_hit = Hit { normal, ..*hit };
+    let model = material.shading_model();
+    let mut diff_light_intensity = [0.; 3];
+    let mut spec_light_intensity = [0.; 3];
+    // Light of the light sources reflected through the BRDF of microfacet materials
+    let mut brdf_light = [0.; 3];
 
+    let mut rng = SmallRng::seed_from_u64(point_seed(point));
+    let shadow_point = shadow_origin(hit, normal, settings);
     for light in lights {
-        // Determine if there is any object between the current point and the light source
-        if single_intersect(point, light.position, &objs) {
-            continue;
-        };
+        // Area lights are split into several samples, each one contributing an equal share of
+        // the light's intensity
+        let light_positions = light.sample_positions(settings.light_samples, &mut rng);
+        let light_intensity = light.rgb_intensity();
+        let share = 1. / light_positions.len() as f32;
+        for light_position in light_positions {
+            // Determine how much of the light source is hidden by objects between it and the
+            // current point
+            let visibility = shadow_visibility(
+                shadow_point,
+                normal,
+                light_position,
+                bvh,
+                settings,
+                &mut rng,
+            );
+            if visibility == [0.; 3] {
+                continue;
+            };
+            let light_vec = light_position - point;
+            let attenuation = light.attenuation.factor(length(&light_vec));
+            let light_dir = normalize(&light_vec);
+            let diffuse = f32::max(0., dot(&light_dir, &normal));
+            if let ShadingModel::Microfacet { .. } = model {
+                // Scaled by pi so that white diffuse surfaces are lit as brightly as with Phong
+                let brdf = material.eval_brdf(&light_dir, &-ray.direction, &shaded_hit);
+                for ch in 0..3 {
+                    let intensity = light_intensity[ch] * share * visibility[ch] * attenuation;
+                    brdf_light[ch] += PI * brdf[ch] * diffuse * intensity;
+                }
+                continue;
+            }
+            let (diffuse, specular) = if model == ShadingModel::Hair {
+                kajiya_kay(
+                    &hit.tangent,
+                    &light_dir,
+                    &-ray.direction,
+                    material.spec_exponent(),
+                )
+            } else {
+                let reflected = dot(&reflect_dir(light_dir, normal), &ray.direction);
+                let specular = f32::powf(f32::max(0., reflected), material.spec_exponent());
+                (diffuse, specular)
+            };
+            for ch in 0..3 {
+                let intensity = light_intensity[ch] * share * visibility[ch] * attenuation;
+                diff_light_intensity[ch] += intensity * diffuse;
+                spec_light_intensity[ch] += intensity * specular;
+            }
+        }
+    }
+
+    // Ambient fill light, which contact shadows darken too
+    let ambient = settings.ambient.irradiance(normal);
+    for ch in 0..3 {
+        diff_light_intensity[ch] += ambient[ch];
+    }
 
-        let light_dir = (light.position - point).normalize();
-        // Diffuse
-        diff_light_intensity += light.intensity * f32::max(0., light_dir.dot(&normal));
-        // Specular
-        let reflected = reflect_dir(light_dir, normal).dot(&ray.direction);
-        spec_light_intensity +=
-            f32::powf(f32::max(0., reflected), material.spec_exponent()) * light.intensity;
+    // Contact shadows
+    if material.albedo()[0] > 0. {
+        let visibility = ambient_visibility(point, normal, bvh, settings);
+        for channel in diff_light_intensity.iter_mut() {
+            *channel *= visibility;
+        }
+    }
+
+    // Environment light, already shadowed by the objects hiding it
+    if material.albedo()[0] > 0. && settings.env_light_samples > 0 {
+        let env_light = env_irradiance(point, normal, bvh, background, settings, &mut rng);
+        for ch in 0..3 {
+            diff_light_intensity[ch] += env_light[ch];
+        }
     }
 
+    // Light hitting a refractive surface is partly reflected, more so at grazing angles. Split the
+    // refraction albedo between reflection and refraction according to the Fresnel term
+    let fresnel = if material.albedo()[3] > 0. {
+        fresnel_reflectance(
+            ray.direction,
+            normal,
+            settings.env_refr_idx,
+            material.refr_ratio(),
+        )
+    } else {
+        0.
+    };
+    let weights = ShadingWeights::new(
+        material.albedo()[0],
+        material.albedo()[1],
+        material.albedo()[2] + material.albedo()[3] * fresnel,
+        material.albedo()[3] * (1. - fresnel),
+    );
+
     // Get reflection image
-    let mut reflection = Rgba([0, 0, 0, 0]);
-    if material.albedo()[2] > 0. {
-        reflection = get_reflection_color(&ray, point, normal, objs, lights, &background, depth);
-        reflection.apply_without_alpha(|ch| ((ch as f32) * material.albedo()[2]) as u8);
+    let mut reflection = Rgb([0., 0., 0.]);
+    if weights.reflection > 0. {
+        reflection = get_reflection_color(
+            &ray,
+            point,
+            normal,
+            bvh,
+            lights,
+            &background,
+            settings,
+            depth,
+        );
     }
 
     // Get refraction image
-    let mut refr_color = Rgba([0, 0, 0, 0]);
-    if material.albedo()[3] > 0. {
-        if let Some(mut refraction) = get_refraction_color(
+    let mut refraction = Rgb([0., 0., 0.]);
+    if weights.refraction > 0. {
+        if let Some(refr_color) = get_refraction_color(
             &ray,
             point,
             normal,
-            material.refr_ratio(),
-            objs,
+            material.refr_ratios(),
+            material.absorption(),
+            bvh,
             lights,
             &background,
+            settings,
             depth,
         ) {
-            refraction.apply_without_alpha(|ch| ((ch as f32) * material.albedo()[3]) as u8);
-            refr_color = refraction;
+            refraction = refr_color;
+        }
+    }
+
+    // Subtle environment reflection on dielectrics which don't reflect already, e.g. plastics
+    // picking up the sky's tint at grazing angles
+    let mut env_reflection = Rgb([0., 0., 0.]);
+    let mut env_weight = 0.;
+    if settings.env_reflections && weights.reflection == 0. && model == ShadingModel::Phong {
+        env_weight =
+            env_reflection_weight(ray, normal, material, settings) * (1. - weights.diffuse);
+        let direction = reflect_dir(ray.direction, normal);
+        let env_ray = Ray::new(point + normal * 1e-3, direction);
+        // Only the unoccluded environment is reflected
+        if env_weight > 0. && scene_intersect(&env_ray, RayKind::Reflection, bvh).is_none() {
+            env_reflection = get_background(&background, &direction);
         }
     }
 
     // Apply Phong reflection model according to material properties. Also add reflections.
-    let mut color_channels = material.color(point).0;
-    color_channels[..=2] // Only process R, G, and B channels
-        .iter_mut()
-        .enumerate()
-        .for_each(|(i, ch)| {
-            *ch = (*ch as f32 * (diff_light_intensity * material.albedo()[0])
-                + 255. * spec_light_intensity * material.albedo()[1]
-                + reflection[i] as f32
-                + refr_color[i] as f32) as u8;
-        });
+    // Contributions are added in linear space. Values above 1 are kept for tone mapping
+    let mut surface_color = material.linear_color(hit);
+    if let Some(dirt) = material.dirt() {
+        let amount = dirt_amount(dirt, hit, bvh);
+        let dirt_color = color::to_linear(dirt.color);
+        for ch in 0..3 {
+            surface_color[ch] += (dirt_color[ch] - surface_color[ch]) * amount;
+        }
+    }
+
+    // Microfacet surfaces mirror their surroundings as much as the Fresnel term allows, less so
+    // the rougher they are
+    let mut glossy_reflection = [0.; 3];
+    if let ShadingModel::Microfacet {
+        metallic,
+        roughness,
+    } = model
+    {
+        let smoothness = f32::powi(f32::max(0., 1. - roughness), 2);
+        if smoothness > 0. {
+            let reflected = get_reflection_color(
+                &ray,
+                point,
+                normal,
+                bvh,
+                lights,
+                &background,
+                settings,
+                depth,
+            );
+            let cos = f32::max(0., -dot(&ray.direction, &normal));
+            for ch in 0..3 {
+                let f0 = DIELECTRIC_F0 + (surface_color[ch] - DIELECTRIC_F0) * metallic;
+                glossy_reflection[ch] = reflected[ch] * schlick_fresnel(f0, cos) * smoothness;
+            }
+        }
+    }
+    let mut color_channels = [0.; 3];
+    for ch in 0..3 {
+        color_channels[ch] = surface_color[ch] * diff_light_intensity[ch] * weights.diffuse
+            + spec_light_intensity[ch] * weights.specular
+            + brdf_light[ch]
+            + glossy_reflection[ch]
+            + reflection[ch] * weights.reflection
+            + env_reflection[ch] * env_weight
+            + refraction[ch] * weights.refraction;
+    }
+
+    Rgb(color_channels)
+}
+
+/// Weights of the terms making up the color of a surface point.
+struct ShadingWeights {
+    diffuse: f32,
+    specular: f32,
+    reflection: f32,
+    refraction: f32,
+}
 
-    Rgba(color_channels)
+impl ShadingWeights {
+    /// Weights from the given albedo terms, rescaled so that diffuse, reflection and refraction
+    /// don't add up to more than 1. These three share the light reaching the surface, so
+    /// together they can't return more light than they receive. Specular highlights stand in
+    /// for reflections of the (infinitely small) light sources, so their weight is kept.
+    fn new(diffuse: f32, specular: f32, reflection: f32, refraction: f32) -> Self {
+        let (diffuse, reflection, refraction) =
+            (diffuse.max(0.), reflection.max(0.), refraction.max(0.));
+        let total = diffuse + reflection + refraction;
+        let scale = if total > 1. { 1. / total } else { 1. };
+        ShadingWeights {
+            diffuse: diffuse * scale,
+            specular: specular.max(0.),
+            reflection: reflection * scale,
+            refraction: refraction * scale,
+        }
+    }
 }
 
-fn get_background(background: &RgbaImage, direction: &Vector3<f32>) -> Rgba<u8> {
+/// Linear color of the environment map in the given direction. Values of high dynamic range
+/// environment maps are kept as they are, even above 1.
+fn get_background(background: &Rgba32FImage, direction: &Vec3) -> Rgb<f32> {
     // Calculate spherical coordinates of direction vector
     let (x, y, z) = (direction.x, direction.y, direction.z);
 
@@ -235,38 +772,805 @@ fn get_background(background: &RgbaImage, direction: &Vector3<f32>) -> Rgba<u8>
     let height_pos = (((cos_theta + 1.) / 2.) * (background.height() - 1) as f32) as u32;
     let width_pos = (((cos_phi + 1.) / 2.) * (background.width() - 1) as f32) as u32;
 
-    *background.get_pixel(width_pos, height_pos)
+    let pixel = background.get_pixel(width_pos, height_pos);
+    Rgb([pixel[0], pixel[1], pixel[2]])
 }
 
 /// Cast a ray. Compute a color according to the elements of the scene the ray intersects.
 fn cast_ray(
     ray: Ray,
-    objs: &Vec<Box<dyn TraceObj>>,
+    kind: RayKind,
+    bvh: &Bvh,
     lights: &Vec<Light>,
-    background: &RgbaImage,
-    depth: u8,
-) -> Rgba<u8> {
-    if depth >= RAY_DEPTH {
-        return get_background(&background, &ray.direction);
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    depth: RayDepth,
+) -> Rgb<f32> {
+    if depth.reflections > settings.reflection_depth
+        || depth.refractions > settings.refraction_depth
+    {
+        // Lights behind the last surface traced stay visible, unless an object hides them
+        let max_dist = if settings.light_glow > 0. {
+            scene_intersect(&ray, kind, bvh).map_or(f32::INFINITY, |(hit, _)| hit.t)
+        } else {
+            f32::INFINITY
+        };
+        return background_color(&ray, max_dist, background, lights, settings);
     }
 
-    if let Some((intersect_dist, object)) = scene_intersect(&ray, &objs) {
+    if let Some((hit, object)) = scene_intersect(&ray, kind, bvh) {
         let material = object.material();
-        let intersect_point = ray.origin + ray.direction * intersect_dist;
-        let normal = object.get_normal(intersect_point);
         let color = get_point_color(
             &ray,
-            intersect_point,
-            normal,
-            objs,
+            &hit,
+            bvh,
             lights,
             material,
             &background,
+            settings,
             depth,
         );
         color
     } else {
-        get_background(&background, &ray.direction)
+        background_color(&ray, f32::INFINITY, background, lights, settings)
+    }
+}
+
+/// Color seen along a ray which hits no object closer than `max_dist`: the environment, plus
+/// the glow of the lights the ray passes close to if `settings.light_glow` is enabled.
+fn background_color(
+    ray: &Ray,
+    max_dist: f32,
+    background: &Rgba32FImage,
+    lights: &Vec<Light>,
+    settings: &RenderSettings,
+) -> Rgb<f32> {
+    let mut color = get_background(background, &ray.direction);
+    if settings.light_glow <= 0. {
+        return color;
+    }
+    let radius_sq = settings.light_glow * settings.light_glow;
+    for light in lights {
+        let to_light = light.position - ray.origin;
+        let t = dot(&to_light, &ray.direction);
+        if t <= 0. || t > max_dist {
+            continue;
+        }
+        let dist_sq = length_squared(&(to_light - ray.direction * t));
+        if dist_sq < radius_sq {
+            // Brightest at the light's position, fading out towards the edge of its glow
+            let falloff = 1. - dist_sq / radius_sq;
+            let intensity = light.rgb_intensity();
+            for ch in 0..3 {
+                color[ch] += intensity[ch] * falloff;
+            }
+        }
+    }
+    color
+}
+
+/// Distribution of the samples taken inside a pixel when supersampling.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplingPattern {
+    /// Uniformly random positions inside the pixel
+    Jittered,
+    /// Pixel split into a grid of cells, with one random position inside each cell
+    Stratified,
+    /// Points of the Halton sequence, continued from one pass to the next. Each pass fills the
+    /// largest gaps left by the previous ones, so progressive renders refine evenly instead of
+    /// sampling the same cells again
+    Halton,
+}
+
+/// Fill light coming from every direction, blending from the ground color for surfaces facing
+/// down to the sky color for surfaces facing up. Keeps regions no light reaches from going black.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct HemisphereLight {
+    /// Linear RGB intensity of the light coming from above
+    pub sky: [f32; 3],
+    /// Linear RGB intensity of the light coming from below
+    pub ground: [f32; 3],
+}
+
+impl HemisphereLight {
+    /// Intensity received by a surface with the given normal.
+    pub fn irradiance(&self, normal: Vec3) -> [f32; 3] {
+        let sky_weight = 0.5 * (normal.y + 1.);
+        [
+            self.ground[0] + (self.sky[0] - self.ground[0]) * sky_weight,
+            self.ground[1] + (self.sky[1] - self.ground[1]) * sky_weight,
+            self.ground[2] + (self.sky[2] - self.ground[2]) * sky_weight,
+        ]
+    }
+}
+
+/// Settings controlling how a scene is rendered.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    /// Output image width in pixels
+    pub width: u32,
+    /// Output image height in pixels
+    pub height: u32,
+    /// Number of progressive passes. Each pass adds `samples` samples per pixel
+    pub passes: u32,
+    /// Samples per pixel taken in each pass, averaged to anti-alias edges
+    pub samples: u32,
+    pub sampling: SamplingPattern,
+    /// Noise level under which a tile is considered converged and receives no further samples.
+    /// Zero disables adaptive sampling
+    pub adaptive_threshold: f32,
+    /// Operator mapping the linear, high dynamic range render to displayable colors
+    pub tone_mapping: ToneMapping,
+    /// Multiplier applied to linear colors before tone mapping
+    pub exposure: f32,
+    /// Color space of the output image, matching the display it is shown on
+    pub color_space: ColorSpace,
+    /// Dithering applied when quantizing the output to 8 bits, which hides banding
+    pub dithering: Dithering,
+    /// Number of rays used to estimate short-range ambient occlusion at each shaded point, which
+    /// adds contact shadows. Zero disables it
+    pub ao_samples: u32,
+    /// Distance beyond which objects don't occlude
+    pub ao_distance: f32,
+    /// Ambient light added to the diffuse lighting of the Whitted integrator. Black (none) by
+    /// default
+    pub ambient: HemisphereLight,
+    /// Rays casted towards the environment map from every point shaded in Whitted mode, adding
+    /// the light of the parts of the environment no object hides to the diffuse lighting. Zero
+    /// disables it
+    pub env_light_samples: u32,
+    /// Refractive surfaces the environment light rays are followed through, so that objects under
+    /// glass receive the light it focuses. Zero makes glass block environment light like opaque
+    /// objects
+    pub env_light_refractions: u32,
+    /// Shadow rays casted towards each area light from every shaded point
+    pub light_samples: u32,
+    /// Radius of the disk shadow tests are jittered over, which anti-aliases shadow edges in
+    /// Whitted mode. Zero disables it
+    pub shadow_jitter: f32,
+    /// Shadow tests averaged per light sample when jittering shadows
+    pub shadow_jitter_samples: u32,
+    /// Start the shadow rays of smooth shaded meshes from the curved surface their normals
+    /// describe rather than from their flat faces, which hides the faceted shadow line low-poly
+    /// meshes show where they turn away from a light
+    pub terminator_offset: bool,
+    /// Add view-dependent reflections of the environment to non-mirror materials
+    pub env_reflections: bool,
+    /// Refractive index of the medium the scene is immersed in: 1 for air, 1.33 for underwater
+    /// scenes
+    pub env_refr_idx: f32,
+    /// Radius of the glow lights are drawn with in the background layer of the Whitted
+    /// integrator. Rays which miss every object, or which reach the depth limits, show the lights
+    /// they pass near, so lamps remain visible directly, in mirrors and through glass. Zero (the
+    /// default) keeps lights invisible
+    pub light_glow: f32,
+    /// Maximum number of reflections traced along a ray path. Mirrors rarely need more than 2
+    /// or 3
+    pub reflection_depth: u32,
+    /// Maximum number of refractions traced along a ray path. Each glass object takes two
+    /// (entering and leaving), so scenes with nested glass may need 8 or more
+    pub refraction_depth: u32,
+    /// Algorithm used to compute the color of camera rays
+    pub integrator: Integrator,
+    /// Maximum number of bounces of the paths traced by the path tracing integrator
+    pub max_bounces: u32,
+    /// Effects applied to the linear render before tone mapping. Scene files can't set them
+    #[serde(skip)]
+    pub post: PostChain,
+    pub threads: ThreadSettings,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 1024,
+            height: 768,
+            passes: 1,
+            samples: 1,
+            sampling: SamplingPattern::Stratified,
+            adaptive_threshold: 0.,
+            tone_mapping: ToneMapping::Aces,
+            exposure: 1.,
+            color_space: ColorSpace::Srgb,
+            dithering: Dithering::None,
+            ao_samples: 0,
+            ao_distance: 1.,
+            ambient: HemisphereLight::default(),
+            env_light_samples: 0,
+            env_light_refractions: 4,
+            light_samples: 16,
+            shadow_jitter: 0.,
+            shadow_jitter_samples: 4,
+            terminator_offset: true,
+            env_reflections: true,
+            env_refr_idx: 1.,
+            light_glow: 0.,
+            reflection_depth: 3,
+            refraction_depth: 3,
+            integrator: Integrator::Whitted,
+            max_bounces: 8,
+            post: PostChain::new(),
+            threads: ThreadSettings::default(),
+        }
+    }
+}
+
+/// Named trade-off between render time and quality, bundling the settings that matter most.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    /// Half resolution, single sample and shallow ray paths, for quick checks of a scene
+    Draft,
+    /// Anti-aliased full resolution render with moderately soft shadows
+    Medium,
+    /// Many samples, smooth soft shadows and deep ray paths
+    Final,
+}
+
+impl RenderSettings {
+    /// Override the settings bundled by a preset. The resolution is scaled, so draft renders of
+    /// a scene keep its aspect ratio. Ambient occlusion and environment lighting are only
+    /// adjusted if they are enabled.
+    pub fn apply_preset(&mut self, preset: Preset) {
+        let (resolution_scale, samples, light_samples, jitter_samples, depth, max_bounces) =
+            match preset {
+                Preset::Draft => (0.5, 1, 4, 1, 2, 3),
+                Preset::Medium => (1., 4, 16, 4, 3, 6),
+                Preset::Final => (1., 16, 64, 16, 6, 12),
+            };
+        self.width = u32::max((self.width as f32 * resolution_scale) as u32, 1);
+        self.height = u32::max((self.height as f32 * resolution_scale) as u32, 1);
+        self.samples = samples;
+        self.light_samples = light_samples;
+        self.shadow_jitter_samples = jitter_samples;
+        self.reflection_depth = depth;
+        // Glass objects take two refractions each
+        self.refraction_depth = 2 * depth;
+        self.max_bounces = max_bounces;
+        if self.ao_samples > 0 {
+            self.ao_samples = 4 * samples.max(2);
+        }
+        if self.env_light_samples > 0 {
+            self.env_light_samples = 4 * samples.max(2);
+        }
+    }
+}
+
+/// Summary of a scene and of the acceleration structure built over it, without rendering it.
+#[derive(Debug, Clone)]
+pub struct SceneStats {
+    pub objects: usize,
+    /// Objects without a bounding box, which every ray is tested against
+    pub unbounded_objects: usize,
+    pub bvh_nodes: usize,
+    pub lights: usize,
+    /// Box containing all bounded objects
+    pub bounding_box: Option<Aabb>,
+    /// Approximate number of bytes of memory used by the geometry, the environment map, the BVH
+    /// and the render buffers
+    pub estimated_memory: usize,
+}
+
+/// Build the BVH of a scene and gather statistics about it, e.g. to check a scene loads
+/// correctly before starting a long render.
+pub fn scene_stats(scene: &Scene, settings: &RenderSettings) -> SceneStats {
+    let bvh = Bvh::build(&scene.objs);
+    let bounding_box = scene.bounding_box();
+
+    let geometry: usize = scene.objs.iter().map(|obj| obj.memory_size()).sum();
+    let (bg_width, bg_height) = scene.background.dimensions();
+    let background = bg_width as usize * bg_height as usize * mem::size_of::<Rgba<f32>>();
+    // Sample accumulator, linear framebuffer and output image
+    let pixels = settings.width as usize * settings.height as usize;
+    let render_buffers = pixels * (mem::size_of::<PixelAccum>() + mem::size_of::<Rgb<f32>>() + 4);
+
+    SceneStats {
+        objects: scene.objs.len(),
+        unbounded_objects: bvh.unbounded_count(),
+        bvh_nodes: bvh.node_count(),
+        lights: scene.lights.len(),
+        bounding_box,
+        estimated_memory: geometry + background + bvh.memory_size() + render_buffers,
+    }
+}
+
+/// Problems which would keep a scene from rendering correctly with the given settings, such as
+/// non-finite values or an empty image. Returns one message per problem found.
+pub fn validate_scene(scene: &Scene, settings: &RenderSettings) -> Vec<String> {
+    let is_finite = |point: &Point3| point.iter().all(|coord| coord.is_finite());
+    let mut problems = Vec::new();
+
+    if settings.width == 0 || settings.height == 0 {
+        problems.push(format!(
+            "Image size {}x{} is empty",
+            settings.width, settings.height
+        ));
+    }
+
+    let camera = &scene.camera;
+    if !is_finite(&camera.position) {
+        problems.push("Camera position is not finite".to_string());
+    }
+    if !(camera.fov > 0. && camera.fov.is_finite()) {
+        problems.push(format!(
+            "Camera field of view {} is not positive",
+            camera.fov
+        ));
+    } else if camera.projection == Projection::Perspective && camera.fov >= PI {
+        problems.push(format!(
+            "Perspective camera field of view {} must be below pi radians",
+            camera.fov
+        ));
+    }
+    if camera.projection == Projection::Equisolid && camera.fov > 2. * PI {
+        problems.push(format!(
+            "Equisolid fisheye field of view {} must be at most 2 pi radians",
+            camera.fov
+        ));
+    }
+    if camera.projection == Projection::Orthographic
+        && !(camera.view_height > 0. && camera.view_height.is_finite())
+    {
+        problems.push(format!(
+            "Orthographic camera view height {} is not positive",
+            camera.view_height
+        ));
+    }
+    if !(camera.near >= 0. && camera.near < camera.far) {
+        problems.push(format!(
+            "Camera clipping range [{}, {}] is empty",
+            camera.near, camera.far
+        ));
+    }
+
+    let (bg_width, bg_height) = scene.background.dimensions();
+    if bg_width == 0 || bg_height == 0 {
+        problems.push("Environment map is empty".to_string());
+    }
+
+    for (idx, obj) in scene.objs.iter().enumerate() {
+        if let Some(bbox) = obj.bounding_box() {
+            if !is_finite(&bbox.min) || !is_finite(&bbox.max) {
+                problems.push(format!("Object {} has a non-finite bounding box", idx));
+            }
+        }
+    }
+    for (idx, light) in scene.lights.iter().enumerate() {
+        if !is_finite(&light.position) || !light.rgb_intensity().iter().all(|i| i.is_finite()) {
+            problems.push(format!(
+                "Light {} has a non-finite position or intensity",
+                idx
+            ));
+        }
+    }
+    problems
+}
+
+/// Render a scene into a new image according to the given settings.
+pub fn render_scene(scene: &Scene, settings: &RenderSettings) -> RgbaImage {
+    render_scene_progressive(scene, settings, |_, _| {})
+}
+
+/// Same as `render_scene`, calling `on_progress` every time a tile is completed as
+/// `render_progressive` does. Rendering runs on a thread pool configured by `settings.threads`.
+pub fn render_scene_progressive<F>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    on_progress: F,
+) -> RgbaImage
+where
+    F: FnMut(&RgbaImage, RenderProgress) + Send,
+{
+    render_scene_linear(scene, settings, on_progress).0
+}
+
+/// Same as `render_scene_progressive`, also returning the linear light render (after post
+/// processing) the output image is tone mapped from, e.g. to save it with more than 8 bits per
+/// channel with `tone_map_16`.
+pub fn render_scene_linear<F>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    on_progress: F,
+) -> (RgbaImage, Rgb32FImage)
+where
+    F: FnMut(&RgbaImage, RenderProgress) + Send,
+{
+    let (img, linear, _) = render_scene_timed(scene, settings, on_progress);
+    (img, linear)
+}
+
+/// Same as `render_scene_linear`, also returning the time spent building the top-level BVH of the
+/// scene, rendering it and post processing it. The other stages of the returned timings are left
+/// for the caller to fill in.
+pub fn render_scene_timed<F>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    on_progress: F,
+) -> (RgbaImage, Rgb32FImage, RenderTimings)
+where
+    F: FnMut(&RgbaImage, RenderProgress) + Send,
+{
+    let now = Instant::now();
+    let tlas = Tlas::build(&scene.objs);
+    let bvh_build = now.elapsed();
+    let (img, linear, mut timings) = render_scene_with_tlas(scene, &tlas, settings, on_progress);
+    timings.bvh_build = bvh_build;
+    (img, linear, timings)
+}
+
+/// Same as `render_scene_timed`, with the top-level BVH of the scene already built.
+fn render_scene_with_tlas<F>(
+    scene: &Scene,
+    tlas: &Tlas,
+    settings: &RenderSettings,
+    on_progress: F,
+) -> (RgbaImage, Rgb32FImage, RenderTimings)
+where
+    F: FnMut(&RgbaImage, RenderProgress) + Send,
+{
+    let now = Instant::now();
+    let mut img = RgbaImage::from_pixel(settings.width, settings.height, Rgba([0, 0, 0, 255]));
+    let render_img = |img: &mut RgbaImage| {
+        let bvh = tlas.bvh(&scene.objs).with_section(scene.section.as_ref());
+        render_with_bvh(
+            &bvh,
+            &scene.lights,
+            &scene.camera,
+            &scene.background,
+            settings,
+            img,
+            on_progress,
+        )
+    };
+    let (linear, post) = match build_thread_pool(&settings.threads) {
+        Ok(pool) => pool.install(|| render_img(&mut img)),
+        Err(e) => {
+            eprintln!(
+                "Could not build render thread pool, using the default one: {}",
+                e
+            );
+            render_img(&mut img)
+        }
+    };
+    let timings = RenderTimings {
+        render: now.elapsed() - post,
+        post,
+        ..RenderTimings::default()
+    };
+    (img, linear, timings)
+}
+
+/// Render an animation driven by external code, e.g. a physics simulation moving objects. Before
+/// each frame of `frames`, `update` mutates the scene: objects can be replaced in `scene.objs`, and
+/// lights and the camera moved. The frame is then rendered and handed to `on_frame` along with its
+/// index, e.g. to be saved to a path made with `output::frame_path`. Stops at the first error
+/// returned by either callback.
+///
+/// The top-level BVH of the scene is kept between frames and refitted to objects that moved, while
+/// the BVHs of meshes and point clouds are left untouched, so animating instances placed with
+/// `Transformed` costs little more than rendering a still scene.
+pub fn render_animation<U, F>(
+    scene: &mut Scene,
+    settings: &RenderSettings,
+    frames: Range<u32>,
+    mut update: U,
+    mut on_frame: F,
+) -> Result<(), Box<dyn Error>>
+where
+    U: FnMut(&mut Scene, u32) -> Result<(), Box<dyn Error>>,
+    F: FnMut(RgbaImage, u32) -> Result<(), Box<dyn Error>>,
+{
+    let mut tlas = Tlas::build(&scene.objs);
+    for frame in frames {
+        update(scene, frame)?;
+        tlas.update(&scene.objs);
+        on_frame(
+            render_scene_with_tlas(scene, &tlas, settings, |_, _| {}).0,
+            frame,
+        )?;
+    }
+    Ok(())
+}
+
+/// Rectangular region of the image plane rendered as a single unit of parallel work.
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Split an image of the given dimensions into tiles of at most TILE_SIZE x TILE_SIZE pixels.
+fn make_tiles(width: u32, height: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y in (0..height).step_by(TILE_SIZE as usize) {
+        for x in (0..width).step_by(TILE_SIZE as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: u32::min(TILE_SIZE, width - x),
+                height: u32::min(TILE_SIZE, height - y),
+            });
+        }
+    }
+    tiles
+}
+
+/// Progress of a render, reported every time a tile is completed.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderProgress {
+    /// Index of the current pass, starting at 1
+    pub pass: u32,
+    pub total_passes: u32,
+    /// Number of tiles of the current pass completed so far
+    pub tiles_done: usize,
+    /// Number of tiles rendered in the current pass. Converged tiles are skipped
+    pub tiles_scheduled: usize,
+    /// Checksum of the tile that was just completed, None if the pass had no tile to render
+    pub tile: Option<TileHash>,
+}
+
+/// Checksum of the samples rendered for a tile in a pass. Renders are deterministic, so the same
+/// scene and settings give the same hashes on any machine and with any number of threads: comparing
+/// the hashes reported by different renderers of a tile detects corrupted results and mismatched
+/// versions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileHash {
+    /// Index of the pass the tile was rendered in, starting at 1
+    pub pass: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub hash: u64,
+}
+
+/// 64-bit FNV-1a hash of a tile's samples in a pass, along with the pass and the tile's position.
+/// Unlike the standard library's hasher, its output is stable across platforms and releases.
+fn tile_hash(pass: u32, tile: &Tile, samples: &[Rgb<f32>]) -> TileHash {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut feed = |word: u32| {
+        for byte in word.to_le_bytes().iter() {
+            hash = (hash ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    };
+    for &word in [pass, tile.x, tile.y, tile.width, tile.height].iter() {
+        feed(word);
+    }
+    for sample in samples {
+        for channel in sample.0.iter() {
+            feed(channel.to_bits());
+        }
+    }
+    TileHash {
+        pass,
+        x: tile.x,
+        y: tile.y,
+        width: tile.width,
+        height: tile.height,
+        hash,
+    }
+}
+
+impl RenderProgress {
+    /// Whether every tile of the current pass is complete.
+    pub fn pass_complete(&self) -> bool {
+        self.tiles_done == self.tiles_scheduled
+    }
+}
+
+/// Time spent in each stage of making a render, to tell where the time goes. Displayed as a table
+/// with the share of the total taken by each stage.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderTimings {
+    /// Loading the scene and its assets, including the BVHs of meshes and point clouds
+    pub load: Duration,
+    /// Building the top-level BVH over the objects of the scene
+    pub bvh_build: Duration,
+    /// Tracing rays and tone mapping their results, over all passes
+    pub render: Duration,
+    /// Post processing the passes and drawing overlays
+    pub post: Duration,
+    /// Encoding and writing the output files
+    pub encode: Duration,
+}
+
+impl RenderTimings {
+    pub fn total(&self) -> Duration {
+        self.load + self.bvh_build + self.render + self.post + self.encode
+    }
+}
+
+impl fmt::Display for RenderTimings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        let stages = [
+            ("Load", self.load),
+            ("BVH build", self.bvh_build),
+            ("Render", self.render),
+            ("Post", self.post),
+            ("Encode", self.encode),
+        ];
+        for &(name, time) in stages.iter() {
+            let share = if total > Duration::from_secs(0) {
+                time.as_secs_f64() / total.as_secs_f64() * 100.
+            } else {
+                0.
+            };
+            let time = format!("{:.2?}", time);
+            writeln!(f, "{:<10} {:>10} {:>5.1}%", name, time, share)?;
+        }
+        write!(f, "{:<10} {:>10}", "Total", format!("{:.2?}", total))
+    }
+}
+
+/// Running sum of the samples taken for a pixel across rendering passes.
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelAccum {
+    color_sum: [f32; 3],
+    luma_sq_sum: f32,
+    samples: u32,
+}
+
+impl PixelAccum {
+    fn add_sample(&mut self, color: Rgb<f32>) {
+        for (sum, ch) in self.color_sum.iter_mut().zip(color.0.iter()) {
+            *sum += *ch;
+        }
+        let luma = luma(color);
+        self.luma_sq_sum += luma * luma;
+        self.samples += 1;
+    }
+
+    /// Average of the samples taken so far.
+    fn color(&self) -> Rgb<f32> {
+        let samples = self.samples.max(1) as f32;
+        Rgb([
+            self.color_sum[0] / samples,
+            self.color_sum[1] / samples,
+            self.color_sum[2] / samples,
+        ])
+    }
+
+    /// Estimated variance of the average luminance of the pixel.
+    fn luma_variance(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let samples = self.samples as f32;
+        let mean =
+            (0.2126 * self.color_sum[0] + 0.7152 * self.color_sum[1] + 0.0722 * self.color_sum[2])
+                / samples;
+        let variance = f32::max(0., self.luma_sq_sum / samples - mean * mean);
+        variance / samples
+    }
+}
+
+/// Tone map a linear pixel color of the render into the output image.
+fn output_pixel(color: Rgb<f32>, x: u32, y: u32, settings: &RenderSettings) -> Rgba<u8> {
+    color::tone_map(
+        color,
+        settings.tone_mapping,
+        settings.exposure,
+        settings.color_space,
+        color::dither_offset(settings.dithering, x, y),
+    )
+}
+
+/// Image with 16 bits per channel, e.g. for renders which are graded afterwards.
+pub type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// Tone map the linear light render returned by `render_scene_linear` into an image with 16 bits
+/// per channel, with the same tone mapping, exposure and color space as the 8-bit output.
+pub fn tone_map_16(linear: &Rgb32FImage, settings: &RenderSettings) -> Rgba16Image {
+    Rgba16Image::from_fn(linear.width(), linear.height(), |x, y| {
+        color::tone_map_16(
+            *linear.get_pixel(x, y),
+            settings.tone_mapping,
+            settings.exposure,
+            settings.color_space,
+        )
+    })
+}
+
+fn luma(color: Rgb<f32>) -> f32 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+/// Render state shared by the threads rendering the tiles of a pass.
+struct PassState<'a> {
+    accum: &'a mut [PixelAccum],
+    /// Linear light average of the samples of each pixel, tone mapped into the output image
+    framebuffer: &'a mut Rgb32FImage,
+    converged: &'a mut [bool],
+}
+
+/// Tile completed by a render thread, sent to the thread reporting progress.
+struct CompletedTile<'a> {
+    tile: &'a Tile,
+    hash: TileHash,
+    /// Tone mapped pixels of the tile, row by row
+    pixels: Vec<Rgba<u8>>,
+}
+
+/// Seed for the random number generator of a pixel in a given pass. Every pixel draws its own
+/// random numbers, so renders are bit-identical regardless of the number of threads, how tiles
+/// are scheduled across them and even how the image is split into tiles.
+fn pixel_seed(x: u32, y: u32, pass: u32) -> u64 {
+    ((pass as u64) << 48) | ((y as u64) << 24) | x as u64
+}
+
+/// Position inside a pixel, in [0, 1) x [0, 1), of a sample. Single samples in the first pass are
+/// taken at the pixel center.
+fn sample_offset<R: Rng>(
+    settings: &RenderSettings,
+    pass: u32,
+    sample_idx: u32,
+    rng: &mut R,
+) -> (f32, f32) {
+    if pass == 0 && settings.samples <= 1 {
+        return (0.5, 0.5);
+    }
+    match settings.sampling {
+        SamplingPattern::Jittered => (rng.gen(), rng.gen()),
+        SamplingPattern::Stratified => {
+            let cols = f32::ceil(f32::sqrt(settings.samples as f32)) as u32;
+            let rows = (settings.samples + cols - 1) / cols;
+            let (col, row) = (sample_idx % cols, sample_idx / cols);
+            (
+                (col as f32 + rng.gen::<f32>()) / cols as f32,
+                (row as f32 + rng.gen::<f32>()) / rows as f32,
+            )
+        }
+        SamplingPattern::Halton => {
+            // Skip the first point of the sequence, at the corner of the pixel
+            let index = pass * settings.samples.max(1) + sample_idx + 1;
+            (halton(index, 2), halton(index, 3))
+        }
+    }
+}
+
+/// Normalized image plane coordinates (see `Camera::ray_direction`) of the point (x, y) of an
+/// image of the given size, given in pixels.
+fn image_plane_coords(x: f32, y: f32, width: f32, height: f32) -> (f32, f32) {
+    let u = ((2. * x / width) - 1.) * (width / height);
+    let v = -((2. * y / height) - 1.);
+    (u, v)
+}
+
+/// Cast a camera ray through the point (x, y) of the image plane, given in pixels.
+fn render_sample<R: Rng>(
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    bvh: &Bvh,
+    lights: &Vec<Light>,
+    camera: &Camera,
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> Rgb<f32> {
+    let (u, v) = image_plane_coords(x, y, width, height);
+    let ray = camera.ray(u, v, rng);
+    match settings.integrator {
+        Integrator::Whitted => cast_ray(
+            ray,
+            RayKind::Camera,
+            bvh,
+            lights,
+            background,
+            settings,
+            RayDepth::default(),
+        ),
+        Integrator::Path => PathTracer {
+            max_bounces: settings.max_bounces,
+        }
+        .radiance(ray, bvh, lights, background, settings, rng),
     }
 }
 
@@ -274,60 +1578,230 @@ fn cast_ray(
 /// Casts a series of rays that go from an origin (camera position) to each pixel of an image plane.
 /// Using such rays, as well as rays casted from the different light sources,the visibilty of each
 /// point of each object in the scene is determined,
+/// The image plane is split into tiles which are rendered in parallel.
 pub fn render(
     objs: &Vec<Box<dyn TraceObj>>,
     lights: &Vec<Light>,
     camera: &Camera,
-    background: &RgbaImage,
+    background: &Rgba32FImage,
     img: &mut RgbaImage,
 ) {
+    let settings = RenderSettings {
+        width: img.width(),
+        height: img.height(),
+        ..RenderSettings::default()
+    };
+    render_progressive(
+        objs,
+        lights,
+        camera,
+        background,
+        None,
+        &settings,
+        img,
+        |_, _| {},
+    );
+}
+
+/// Same as `render`, but the image is refined over `settings.passes` passes, each one taking
+/// `settings.samples` samples per pixel distributed according to `settings.sampling`. All samples
+/// of a pixel are averaged, which anti-aliases edges. With a single sample per pixel, the first
+/// pass casts rays through pixel centers. Every time a tile is completed, `on_progress` is called
+/// with the image rendered so far and the progress of the render, e.g. to update a preview or to
+/// write intermediate snapshots once a pass is complete. It is called one tile at a time while the
+/// render threads keep rendering the next tiles, and reports the checksum of the completed tile so
+/// that tiles rendered elsewhere can be verified. With post passes, the last tile of a pass is
+/// reported once they have run.
+///
+/// If `settings.adaptive_threshold` is greater than zero, tiles whose estimated noise falls below
+/// it stop receiving samples, so that passes concentrate on high-variance regions (glass,
+/// edges) instead of already converged flat areas.
+///
+/// Geometry on the removed side of `section`, if given, is not rendered.
+///
+/// Renders are deterministic: random numbers are drawn from per-pixel (and per-hit) seeds and
+/// samples are accumulated in a fixed order, so any number of threads gives bit-identical images.
+///
+/// Return the linear light render, after post processing, which `img` is the tone mapped version
+/// of.
+pub fn render_progressive<F>(
+    objs: &Vec<Box<dyn TraceObj>>,
+    lights: &Vec<Light>,
+    camera: &Camera,
+    background: &Rgba32FImage,
+    section: Option<&SectionPlane>,
+    settings: &RenderSettings,
+    img: &mut RgbaImage,
+    on_progress: F,
+) -> Rgb32FImage
+where
+    F: FnMut(&RgbaImage, RenderProgress) + Send,
+{
+    let bvh = Bvh::build(objs).with_section(section);
+    render_with_bvh(&bvh, lights, camera, background, settings, img, on_progress).0
+}
+
+/// Same as `render_progressive`, tracing rays against an already built BVH. Also return the time
+/// spent in post processing.
+fn render_with_bvh<F>(
+    bvh: &Bvh,
+    lights: &Vec<Light>,
+    camera: &Camera,
+    background: &Rgba32FImage,
+    settings: &RenderSettings,
+    img: &mut RgbaImage,
+    mut on_progress: F,
+) -> (Rgb32FImage, Duration)
+where
+    F: FnMut(&RgbaImage, RenderProgress) + Send,
+{
     let width = img.width() as f32;
     let height = img.height() as f32;
-    let y_fov = f32::tan(camera.fov / 2.);
-    let x_fov = y_fov * (width / height);
-
-    for x in 0..img.width() {
-        for y in 0..img.height() {
-            // i and j components of the direction of the casted ray
-            let i = ((2. * (x as f32 + 0.5) / width) - 1.) * x_fov;
-            let j = -((2. * (y as f32 + 0.5) / height) - 1.) * y_fov;
-
-            let color = cast_ray(
-                Ray {
-                    origin: camera.position,
-                    direction: Vector3::new(i, j, -1.).normalize(),
-                },
-                objs,
-                lights,
-                background,
-                0,
-            );
-            img.put_pixel(x, y, color);
+
+    let tiles = make_tiles(img.width(), img.height());
+    let mut converged = vec![false; tiles.len()];
+    let mut accum = vec![PixelAccum::default(); img.width() as usize * img.height() as usize];
+    // Linear light average of the samples of each pixel, tone mapped into the output image
+    let mut framebuffer = Rgb32FImage::new(img.width(), img.height());
+    let total_passes = settings.passes.max(1);
+    let samples_per_pixel = settings.samples.max(1);
+    let mut post_time = Duration::from_secs(0);
+    let mut processed = None;
+
+    for pass in 0..total_passes {
+        let scheduled: Vec<usize> = (0..tiles.len()).filter(|&idx| !converged[idx]).collect();
+        let progress = RenderProgress {
+            pass: pass + 1,
+            total_passes,
+            tiles_done: 0,
+            tiles_scheduled: scheduled.len(),
+            tile: None,
+        };
+        if scheduled.is_empty() {
+            on_progress(img, progress);
+            continue;
         }
-    }
-}
 
-pub fn push_obj_faces(
-    model: &Obj<Position>,
-    objs_vec: &mut Vec<Box<dyn TraceObj>>,
-    material: Rc<dyn Material>,
-) {
-    let faces_num = model.indices.len();
-    let faces = &model.indices[..faces_num];
-
-    for face in faces.chunks(3) {
-        let [v0x, v0y, v0z] = model.vertices[face[0] as usize].position;
-        let [v1x, v1y, v1z] = model.vertices[face[1] as usize].position;
-        let [v2x, v2y, v2z] = model.vertices[face[2] as usize].position;
-        let point0 = Point3::<f32>::new(v0x, v0y, v0z);
-        let point1 = Point3::<f32>::new(v1x, v1y, v1z);
-        let point2 = Point3::<f32>::new(v2x, v2y, v2z);
-
-        objs_vec.push(Box::new(Triangle {
-            a: point0,
-            b: point1,
-            c: point2,
-            material: material.clone(),
-        }));
+        // Completed tiles are accumulated as soon as they are rendered. Tiles don't overlap and
+        // the samples of a pixel are always added in the same order, so the result doesn't
+        // depend on the order tiles complete in. The render threads send completed tiles to a single
+        // reporter, which copies them into the output and reports progress, so that slow progress
+        // callbacks (previews, snapshots) never hold the render threads back
+        let state = Mutex::new(PassState {
+            accum: &mut accum,
+            framebuffer: &mut framebuffer,
+            converged: &mut converged,
+        });
+        let img_width = img.width();
+        let render_tile = |tile_idx: usize| {
+            let tile = &tiles[tile_idx];
+            let mut samples =
+                Vec::with_capacity((tile.width * tile.height * samples_per_pixel) as usize);
+            for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    let mut rng = SmallRng::seed_from_u64(pixel_seed(x, y, pass));
+                    for sample_idx in 0..samples_per_pixel {
+                        let (dx, dy) = sample_offset(settings, pass, sample_idx, &mut rng);
+                        samples.push(render_sample(
+                            x as f32 + dx,
+                            y as f32 + dy,
+                            width,
+                            height,
+                            bvh,
+                            lights,
+                            camera,
+                            background,
+                            settings,
+                            &mut rng,
+                        ));
+                    }
+                }
+            }
+
+            let hash = tile_hash(pass + 1, tile, &samples);
+
+            let mut colors = Vec::with_capacity((tile.width * tile.height) as usize);
+            {
+                let mut guard = state.lock().unwrap();
+                let state = &mut *guard;
+                let mut variance_sum = 0.;
+                for (idx, pixel_samples) in samples.chunks(samples_per_pixel as usize).enumerate() {
+                    let x = tile.x + idx as u32 % tile.width;
+                    let y = tile.y + idx as u32 / tile.width;
+                    let pixel_accum =
+                        &mut state.accum[y as usize * img_width as usize + x as usize];
+                    for color in pixel_samples {
+                        pixel_accum.add_sample(*color);
+                    }
+                    variance_sum += pixel_accum.luma_variance();
+                    let pixel_color = pixel_accum.color();
+                    state.framebuffer.put_pixel(x, y, pixel_color);
+                    colors.push(pixel_color);
+                }
+
+                if settings.adaptive_threshold > 0. {
+                    // Standard error of the tile's average linear luminance
+                    let tile_error = f32::sqrt(variance_sum / (tile.width * tile.height) as f32);
+                    state.converged[tile_idx] = tile_error < settings.adaptive_threshold;
+                }
+            }
+
+            let pixels = colors
+                .into_iter()
+                .enumerate()
+                .map(|(idx, color)| {
+                    let x = tile.x + idx as u32 % tile.width;
+                    let y = tile.y + idx as u32 / tile.width;
+                    output_pixel(color, x, y, settings)
+                })
+                .collect();
+            CompletedTile { tile, hash, pixels }
+        };
+        let (sender, receiver) = mpsc::channel();
+        let (_, progress) = rayon::join(
+            || {
+                scheduled
+                    .par_iter()
+                    .for_each_with(sender, |sender, &tile_idx| {
+                        // The receiver only goes away once every sender is dropped
+                        sender.send(render_tile(tile_idx)).unwrap()
+                    })
+            },
+            || {
+                let mut progress = progress;
+                for completed in receiver {
+                    let tile = completed.tile;
+                    for (idx, pixel) in completed.pixels.into_iter().enumerate() {
+                        let x = tile.x + idx as u32 % tile.width;
+                        let y = tile.y + idx as u32 / tile.width;
+                        img.put_pixel(x, y, pixel);
+                    }
+                    progress.tiles_done += 1;
+                    progress.tile = Some(completed.hash);
+                    // With post passes, the completed pass is reported once they have run
+                    if !progress.pass_complete() || settings.post.is_empty() {
+                        on_progress(img, progress);
+                    }
+                }
+                progress
+            },
+        );
+        drop(state);
+
+        // Post passes need the whole image, so they run once every tile of the pass is done
+        if !settings.post.is_empty() {
+            let now = Instant::now();
+            let pass_processed = settings.post.apply(&framebuffer);
+            for (x, y, pixel) in pass_processed.enumerate_pixels() {
+                img.put_pixel(x, y, output_pixel(*pixel, x, y, settings));
+            }
+            post_time += now.elapsed();
+            processed = Some(pass_processed);
+            on_progress(img, progress);
+        }
     }
+
+    // The framebuffer only changes in passes which complete, so the last processed one is up to
+    // date
+    (processed.unwrap_or(framebuffer), post_time)
 }
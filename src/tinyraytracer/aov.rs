@@ -0,0 +1,237 @@
+//! Arbitrary output variables (AOVs): images describing the geometry seen through each pixel,
+//! rendered alongside the beauty image for compositing and for debugging the placement of
+//! objects. They are computed from a single camera ray through the center of each pixel.
+use std::collections::HashMap;
+
+use image::{GrayImage, ImageBuffer, Luma, Pixel, Rgb, Rgb32FImage, RgbImage};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+
+use super::bvh::{counting_intersection_tests, intersection_tests, Bvh};
+use super::color;
+use super::compare::heat_color;
+use super::scene::Scene;
+use super::{
+    image_plane_coords, pixel_seed, scene_intersect, Hit, Ray, RayKind, RenderSettings, TraceObj,
+};
+
+/// Single channel image of floating point values.
+pub type FloatImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Image whose pixels are computed by `pixel` from the camera ray through the center of each
+/// pixel and its nearest hit, if any.
+fn render_pixels<P, F>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    pixel: F,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + Send,
+    F: Fn(&Ray, Option<(Hit, &dyn TraceObj)>) -> P + Sync,
+{
+    render_rays(scene, settings, |ray, bvh| {
+        pixel(ray, scene_intersect(ray, RayKind::Camera, bvh))
+    })
+}
+
+/// Image whose pixels are computed by `pixel` from the camera ray through the center of each
+/// pixel and the BVH of the scene.
+fn render_rays<P, F>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    pixel: F,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + Send,
+    F: Fn(&Ray, &Bvh) -> P + Sync,
+{
+    let (width, height) = (settings.width, settings.height);
+    let bvh = Bvh::build(&scene.objs).with_section(scene.section.as_ref());
+    let pixels: Vec<P> = (0..width * height)
+        .into_par_iter()
+        .map(|idx| {
+            let (x, y) = (idx % width, idx / width);
+            let (u, v) =
+                image_plane_coords(x as f32 + 0.5, y as f32 + 0.5, width as f32, height as f32);
+            // Same lens sample as the first pass of the render
+            let mut rng = SmallRng::seed_from_u64(pixel_seed(x, y, 0));
+            let ray = scene.camera.ray(u, v, &mut rng);
+            pixel(&ray, &bvh)
+        })
+        .collect();
+
+    let mut img = ImageBuffer::new(width, height);
+    for (pixel, value) in img.pixels_mut().zip(pixels) {
+        *pixel = value;
+    }
+    img
+}
+
+/// Distance from the camera to the nearest surface seen through each pixel, along the camera ray.
+/// Pixels seeing only the environment are infinite.
+pub fn render_depth(scene: &Scene, settings: &RenderSettings) -> FloatImage {
+    render_pixels(scene, settings, |_, hit| {
+        Luma([hit.map_or(f32::INFINITY, |(hit, _)| hit.t)])
+    })
+}
+
+/// Grayscale picture of a depth image, from white for the nearest surface to black for the
+/// farthest one. Pixels seeing only the environment are black too.
+pub fn depth_to_grayscale(depth: &FloatImage) -> GrayImage {
+    let finite = depth
+        .pixels()
+        .map(|pixel| pixel[0])
+        .filter(|d| d.is_finite());
+    let (near, far) = finite.fold((f32::INFINITY, f32::NEG_INFINITY), |(near, far), d| {
+        (near.min(d), far.max(d))
+    });
+    let range = far - near;
+    GrayImage::from_fn(depth.width(), depth.height(), |x, y| {
+        let d = depth.get_pixel(x, y)[0];
+        if !d.is_finite() {
+            return Luma([0]);
+        }
+        let closeness = if range > 0. {
+            1. - (d - near) / range
+        } else {
+            1.
+        };
+        Luma([(closeness * 255. + 0.5) as u8])
+    })
+}
+
+/// World space unit normal of the nearest surface seen through each pixel, as lit by its material
+/// (with normal and bump maps applied). Pixels seeing only the environment are zero.
+pub fn render_normals(scene: &Scene, settings: &RenderSettings) -> Rgb32FImage {
+    render_pixels(scene, settings, |_, hit| match hit {
+        Some((hit, object)) => {
+            let normal = object.material().shading_normal(&hit);
+            Rgb([normal.x, normal.y, normal.z])
+        }
+        None => Rgb([0., 0., 0.]),
+    })
+}
+
+/// Normal image encoded as colors the usual way, mapping each coordinate from [-1, 1] to
+/// [0, 255]: surfaces facing +x are red, +y green and +z blue.
+pub fn normals_to_rgb(normals: &Rgb32FImage) -> RgbImage {
+    RgbImage::from_fn(normals.width(), normals.height(), |x, y| {
+        let normal = normals.get_pixel(x, y);
+        let encode = |coord: f32| ((coord.max(-1.).min(1.) + 1.) / 2. * 255. + 0.5) as u8;
+        Rgb([encode(normal[0]), encode(normal[1]), encode(normal[2])])
+    })
+}
+
+/// Linear color of the material of the nearest surface seen through each pixel, without any
+/// lighting, as denoisers expect it. Pixels seeing only the environment are black.
+pub fn render_albedo(scene: &Scene, settings: &RenderSettings) -> Rgb32FImage {
+    render_pixels(scene, settings, |_, hit| match hit {
+        Some((hit, object)) => {
+            let albedo = object.material().linear_color(&hit);
+            Rgb([albedo[0], albedo[1], albedo[2]])
+        }
+        None => Rgb([0., 0., 0.]),
+    })
+}
+
+/// Albedo image encoded as 8-bit sRGB colors.
+pub fn albedo_to_srgb(albedo: &Rgb32FImage) -> RgbImage {
+    RgbImage::from_fn(albedo.width(), albedo.height(), |x, y| {
+        let encode = |value: f32| (color::linear_to_srgb(value.max(0.).min(1.)) * 255. + 0.5) as u8;
+        let pixel = albedo.get_pixel(x, y);
+        Rgb([encode(pixel[0]), encode(pixel[1]), encode(pixel[2])])
+    })
+}
+
+/// Index in the scene's object list of the object seen through each pixel. Pixels seeing only the
+/// environment, or surfaces which aren't scene objects such as section caps, are -1.
+pub fn render_object_ids(scene: &Scene, settings: &RenderSettings) -> FloatImage {
+    let indices: HashMap<usize, usize> = scene
+        .objs
+        .iter()
+        .enumerate()
+        .map(|(idx, obj)| (object_address(&**obj), idx))
+        .collect();
+    render_pixels(scene, settings, |_, hit| {
+        let idx = hit.and_then(|(_, object)| indices.get(&object_address(object)));
+        Luma([idx.map_or(-1., |&idx| idx as f32)])
+    })
+}
+
+/// Address of an object, which tells the objects of a scene apart.
+fn object_address(object: &dyn TraceObj) -> usize {
+    object as *const dyn TraceObj as *const u8 as usize
+}
+
+/// Object ID image as colors, one per object. Objects given the same name with `Named`, such as
+/// the instances of a named object, share their color. Colors only depend on the name or the
+/// index of objects, so they don't change between renders of a scene. Pixels without an object
+/// are black.
+pub fn object_ids_to_rgb(ids: &FloatImage, scene: &Scene) -> RgbImage {
+    RgbImage::from_fn(ids.width(), ids.height(), |x, y| {
+        let id = ids.get_pixel(x, y)[0];
+        if id < 0. {
+            return Rgb([0, 0, 0]);
+        }
+        match scene.objs.get(id as usize).and_then(|obj| obj.name()) {
+            Some(name) => id_color(name.as_bytes()),
+            None => id_color(&(id as u32).to_le_bytes()),
+        }
+    })
+}
+
+/// Bright color picked by a 64-bit FNV-1a hash of an ID, so that different IDs get clearly
+/// different colors.
+fn id_color(id: &[u8]) -> Rgb<u8> {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    let hash = id.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+
+    // Any hue, fairly saturated and bright so that no object looks like the black background
+    let hue = (hash & 0xffff) as f32 / 65536. * 6.;
+    let saturation = 0.6 + 0.4 * ((hash >> 16) & 0xff) as f32 / 255.;
+    let value = 0.7 + 0.3 * ((hash >> 24) & 0xff) as f32 / 255.;
+    let chroma = value * saturation;
+    let second = chroma * (1. - (hue % 2. - 1.).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, second, 0.),
+        1 => (second, chroma, 0.),
+        2 => (0., chroma, second),
+        3 => (0., second, chroma),
+        4 => (second, 0., chroma),
+        _ => (chroma, 0., second),
+    };
+    let encode = |channel: f32| ((channel + value - chroma) * 255. + 0.5) as u8;
+    Rgb([encode(r), encode(g), encode(b)])
+}
+
+/// Number of intersection tests performed to find the nearest surface seen through each pixel,
+/// against objects and against the primitives of their own BVHs. Shows where the acceleration
+/// structures help and where rays still test many objects, e.g. near large unbounded objects or
+/// overlapping meshes.
+pub fn render_intersection_tests(scene: &Scene, settings: &RenderSettings) -> FloatImage {
+    counting_intersection_tests(|| {
+        render_rays(scene, settings, |ray, bvh| {
+            let before = intersection_tests();
+            scene_intersect(ray, RayKind::Camera, bvh);
+            Luma([(intersection_tests() - before) as f32])
+        })
+    })
+}
+
+/// Heatmap of intersection test counts, from black for none through red and yellow to white for
+/// the pixel with the most tests.
+pub fn intersection_tests_to_heatmap(tests: &FloatImage) -> RgbImage {
+    let max = tests.pixels().fold(0., |max: f32, pixel| max.max(pixel[0]));
+    RgbImage::from_fn(tests.width(), tests.height(), |x, y| {
+        let heat = if max > 0. {
+            tests.get_pixel(x, y)[0] / max
+        } else {
+            0.
+        };
+        Rgb(heat_color(heat))
+    })
+}
@@ -0,0 +1,381 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::math::max_axis;
+use super::scene_elems::{Aabb, Hit, Ray, RayKind, SectionPlane, TraceObj};
+
+/// Maximum number of primitives stored in a leaf node.
+const LEAF_SIZE: usize = 4;
+
+thread_local! {
+    /// Intersection tests performed by the current thread, see `intersection_tests`
+    static INTERSECTION_TESTS: Cell<u64> = Cell::new(0);
+}
+
+/// Whether intersection tests are counted. Off outside of `counting_intersection_tests`, so that
+/// renders don't pay for the counter.
+static COUNTING_TESTS: AtomicBool = AtomicBool::new(false);
+
+/// Number of intersection tests against objects and against the primitives of their own BVHs
+/// (triangles, points...) performed by the current thread so far, while counting with
+/// `counting_intersection_tests`. The difference between two calls counts the tests in between,
+/// e.g. those needed to trace a ray.
+pub fn intersection_tests() -> u64 {
+    INTERSECTION_TESTS.with(Cell::get)
+}
+
+/// Count the intersection tests performed while `f` runs, on every thread.
+pub fn counting_intersection_tests<T, F: FnOnce() -> T>(f: F) -> T {
+    let previous = COUNTING_TESTS.swap(true, Ordering::Relaxed);
+    let result = f();
+    COUNTING_TESTS.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// Whether intersection tests are being counted. Checked once per traversal.
+fn counting_tests() -> bool {
+    COUNTING_TESTS.load(Ordering::Relaxed)
+}
+
+fn count_intersection_test() {
+    INTERSECTION_TESTS.with(|tests| tests.set(tests.get() + 1));
+}
+
+/// How much larger than right after building it the total area of a refitted top-level
+/// hierarchy's nodes can get before it is rebuilt instead.
+const REBUILD_AREA_RATIO: f32 = 2.;
+
+#[derive(Debug, Clone)]
+enum BvhNode {
+    Leaf {
+        bbox: Aabb,
+        start: usize,
+        count: usize,
+    },
+    Inner {
+        bbox: Aabb,
+        left: usize,
+        right: usize,
+    },
+}
+
+impl BvhNode {
+    fn bbox(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { bbox, .. } | BvhNode::Inner { bbox, .. } => bbox,
+        }
+    }
+
+    fn bbox_mut(&mut self) -> &mut Aabb {
+        match self {
+            BvhNode::Leaf { bbox, .. } | BvhNode::Inner { bbox, .. } => bbox,
+        }
+    }
+}
+
+/// Area of the surface of a box, which the probability of a ray hitting it is proportional to.
+fn surface_area(bbox: &Aabb) -> f32 {
+    let extent = bbox.max - bbox.min;
+    2. * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+}
+
+/// Bounding volume hierarchy over a set of primitives identified by an index. The tree only
+/// stores bounding boxes and indices, so the same structure accelerates both the objects of a
+/// scene and the faces of a triangle mesh.
+#[derive(Debug, Clone, Default)]
+pub struct BvhTree {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+impl BvhTree {
+    /// Build the hierarchy by recursively splitting primitives at the median of their bounding
+    /// box centroids along the longest axis.
+    pub fn build(mut prims: Vec<(usize, Aabb)>) -> Self {
+        let mut tree = BvhTree {
+            nodes: Vec::new(),
+            indices: Vec::with_capacity(prims.len()),
+        };
+        if !prims.is_empty() {
+            tree.build_node(&mut prims);
+        }
+        tree
+    }
+
+    /// Recursively build the subtree for the given primitives. Return the index of its root node.
+    fn build_node(&mut self, prims: &mut [(usize, Aabb)]) -> usize {
+        let bbox = prims[1..]
+            .iter()
+            .fold(prims[0].1, |bbox, (_, prim_bbox)| bbox.union(prim_bbox));
+
+        if prims.len() <= LEAF_SIZE {
+            let start = self.indices.len();
+            self.indices.extend(prims.iter().map(|(idx, _)| *idx));
+            self.nodes.push(BvhNode::Leaf {
+                bbox,
+                start,
+                count: prims.len(),
+            });
+            return self.nodes.len() - 1;
+        }
+
+        // Split along the longest axis of the node
+        let extent = bbox.max - bbox.min;
+        let axis = max_axis(&extent);
+        prims.sort_by(|(_, a), (_, b)| a.centroid()[axis].total_cmp(&b.centroid()[axis]));
+        let (left_prims, right_prims) = prims.split_at_mut(prims.len() / 2);
+
+        // Reserve the node's slot before building its children
+        let node_idx = self.nodes.len();
+        self.nodes.push(BvhNode::Leaf {
+            bbox,
+            start: 0,
+            count: 0,
+        });
+        let left = self.build_node(left_prims);
+        let right = self.build_node(right_prims);
+        self.nodes[node_idx] = BvhNode::Inner { bbox, left, right };
+        node_idx
+    }
+
+    /// Recompute the bounding boxes of the nodes after primitives moved, keeping the structure of
+    /// the hierarchy. `prim_bbox` gives the new bounding box of the primitive of the given index.
+    /// Much cheaper than a rebuild, but the hierarchy gets slower to traverse as primitives move
+    /// away from the ones they were grouped with.
+    pub fn refit<F>(&mut self, prim_bbox: F)
+    where
+        F: Fn(usize) -> Aabb,
+    {
+        // Children are always stored after their parent, so going backwards updates them first
+        for node_idx in (0..self.nodes.len()).rev() {
+            let bbox = match self.nodes[node_idx] {
+                BvhNode::Leaf { start, count, .. } => {
+                    let indices = &self.indices[start..start + count];
+                    indices[1..]
+                        .iter()
+                        .fold(prim_bbox(indices[0]), |bbox, &idx| {
+                            bbox.union(&prim_bbox(idx))
+                        })
+                }
+                BvhNode::Inner { left, right, .. } => {
+                    self.nodes[left].bbox().union(self.nodes[right].bbox())
+                }
+            };
+            *self.nodes[node_idx].bbox_mut() = bbox;
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Sum of the surface areas of all nodes, an estimate of the cost of traversing the hierarchy.
+    fn total_area(&self) -> f32 {
+        self.nodes
+            .iter()
+            .map(|node| surface_area(node.bbox()))
+            .sum()
+    }
+
+    /// Number of bytes of memory used by the hierarchy.
+    pub fn memory_size(&self) -> usize {
+        self.nodes.len() * mem::size_of::<BvhNode>() + self.indices.len() * mem::size_of::<usize>()
+    }
+
+    /// Bounding box of the whole hierarchy, if it is not empty.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| *node.bbox())
+    }
+
+    /// Find the nearest primitive intersected by the ray within its range. `prim_intersect`
+    /// computes the intersection of the ray with the primitive of the given index. Return the
+    /// nearest intersection as well as the primitive index.
+    pub fn intersect<F>(&self, ray: &Ray, mut prim_intersect: F) -> Option<(Hit, usize)>
+    where
+        F: FnMut(usize) -> Option<Hit>,
+    {
+        let mut nearest = None;
+        let mut nearest_dist = ray.t_max;
+        let counting = counting_tests();
+
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if !node.bbox().ray_intersect(ray, nearest_dist) {
+                continue;
+            }
+            match *node {
+                BvhNode::Leaf { start, count, .. } => {
+                    for &idx in self.indices[start..start + count].iter() {
+                        if counting {
+                            count_intersection_test();
+                        }
+                        if let Some(hit) = prim_intersect(idx) {
+                            if hit.t < nearest_dist {
+                                nearest_dist = hit.t;
+                                nearest = Some((hit, idx));
+                            }
+                        }
+                    }
+                }
+                BvhNode::Inner { left, right, .. } => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+
+        nearest
+    }
+}
+
+/// Top-level hierarchy of a scene (TLAS), over its objects referenced by their index in the
+/// scene's object list. Objects made of many primitives keep them in their own bottom-level
+/// hierarchy (e.g. the faces of a `TriangleMesh` or the points of a `PointCloud`), which instances
+/// placed with `Transformed` share. Moving instances around, e.g. between the frames of an
+/// animation, thus only requires updating this small hierarchy. Unbounded objects (e.g. infinite
+/// planes) can't be placed in the hierarchy, so they are kept aside and always tested.
+#[derive(Debug, Clone, Default)]
+pub struct Tlas {
+    tree: BvhTree,
+    /// Indices of the objects placed in the hierarchy
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+    /// Total area of the nodes when the hierarchy was last built
+    built_area: f32,
+}
+
+impl Tlas {
+    pub fn build(objs: &[Box<dyn TraceObj>]) -> Self {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (idx, obj) in objs.iter().enumerate() {
+            match obj.bounding_box() {
+                Some(bbox) => bounded.push((idx, bbox)),
+                None => unbounded.push(idx),
+            }
+        }
+
+        let bounded_indices = bounded.iter().map(|(idx, _)| *idx).collect();
+        let tree = BvhTree::build(bounded);
+        Tlas {
+            built_area: tree.total_area(),
+            tree,
+            bounded: bounded_indices,
+            unbounded,
+        }
+    }
+
+    /// Bring the hierarchy up to date with objects that were moved or replaced since it was built.
+    /// As long as the same objects are bounded, its nodes are refitted around their new bounding
+    /// boxes, without touching the objects' own hierarchies. It is rebuilt if objects were added or
+    /// removed, or once refitting made its nodes too loose. Return whether it was rebuilt.
+    pub fn update(&mut self, objs: &[Box<dyn TraceObj>]) -> bool {
+        let boxes: Vec<Option<Aabb>> = objs.iter().map(|obj| obj.bounding_box()).collect();
+        let same_objects = boxes.len() == self.bounded.len() + self.unbounded.len()
+            && self.bounded.iter().all(|&idx| boxes[idx].is_some())
+            && self.unbounded.iter().all(|&idx| boxes[idx].is_none());
+        if same_objects {
+            self.tree
+                .refit(|idx| boxes[idx].expect("bounded objects have a bounding box"));
+            if self.tree.total_area() <= self.built_area * REBUILD_AREA_RATIO {
+                return false;
+            }
+        }
+        *self = Tlas::build(objs);
+        true
+    }
+
+    /// Hierarchy ready to be traversed over the objects it was built for, which must not have
+    /// changed since it was last built or updated.
+    pub fn bvh<'a>(&'a self, objs: &'a [Box<dyn TraceObj>]) -> Bvh<'a> {
+        Bvh {
+            objs,
+            tlas: Cow::Borrowed(self),
+            section: None,
+        }
+    }
+}
+
+/// Bounding volume hierarchy over the objects of a scene, either built for a single render or
+/// borrowed from a `Tlas` kept between renders. An optional section plane cuts away part of the
+/// scene.
+#[derive(Debug)]
+pub struct Bvh<'a> {
+    objs: &'a [Box<dyn TraceObj>],
+    tlas: Cow<'a, Tlas>,
+    section: Option<&'a SectionPlane>,
+}
+
+impl<'a> Bvh<'a> {
+    pub fn build(objs: &'a [Box<dyn TraceObj>]) -> Self {
+        Bvh {
+            objs,
+            tlas: Cow::Owned(Tlas::build(objs)),
+            section: None,
+        }
+    }
+
+    /// Number of nodes of the hierarchy over the bounded objects.
+    pub fn node_count(&self) -> usize {
+        self.tlas.tree.node_count()
+    }
+
+    /// Number of objects without a bounding box, which every ray is tested against.
+    pub fn unbounded_count(&self) -> usize {
+        self.tlas.unbounded.len()
+    }
+
+    /// Number of bytes of memory used by the hierarchy, not including the objects.
+    pub fn memory_size(&self) -> usize {
+        self.tlas.tree.memory_size()
+            + (self.tlas.bounded.len() + self.tlas.unbounded.len()) * mem::size_of::<usize>()
+    }
+
+    /// Cut away the part of the scene removed by the given section plane.
+    pub fn with_section(self, section: Option<&'a SectionPlane>) -> Self {
+        Bvh { section, ..self }
+    }
+
+    /// Find the nearest object intersected by the ray within its range, skipping objects whose
+    /// material is invisible to the given kind of ray. Return the intersection as well as the
+    /// object.
+    pub fn intersect(&self, ray: &Ray, kind: RayKind) -> Option<(Hit, &'a dyn TraceObj)> {
+        match self.section {
+            Some(section) => section.intersect(ray, |ray| self.intersect_objs(ray, kind)),
+            None => self.intersect_objs(ray, kind),
+        }
+    }
+
+    fn intersect_objs(&self, ray: &Ray, kind: RayKind) -> Option<(Hit, &'a dyn TraceObj)> {
+        let objs: &'a [Box<dyn TraceObj>] = self.objs;
+        let visible_intersect = |idx: usize| {
+            if objs[idx].material().visibility().is_visible(kind) {
+                objs[idx].ray_intersect_kind(ray, kind)
+            } else {
+                None
+            }
+        };
+
+        let mut nearest = self.tlas.tree.intersect(ray, visible_intersect);
+        let counting = counting_tests();
+        for &idx in self.tlas.unbounded.iter() {
+            let nearest_dist = nearest.map_or(ray.t_max, |(hit, _)| hit.t);
+            if counting {
+                count_intersection_test();
+            }
+            if let Some(hit) = visible_intersect(idx) {
+                if hit.t < nearest_dist {
+                    nearest = Some((hit, idx));
+                }
+            }
+        }
+
+        nearest.map(|(hit, idx)| (hit, &*objs[idx]))
+    }
+}
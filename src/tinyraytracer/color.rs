@@ -0,0 +1,369 @@
+use std::path::Path;
+
+use image::{self, DynamicImage, ImageError, Rgb, Rgba, Rgba32FImage};
+
+/// Encoding of the color channels stored in an image file.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorEncoding {
+    /// Colors encoded with the transfer function of their color space, as stored by most 8-bit
+    /// images (photos, painted textures, env maps). sRGB unless specified otherwise
+    Srgb,
+    /// Raw data such as normal maps, whose values must be used as they are
+    Linear,
+}
+
+impl Default for ColorEncoding {
+    fn default() -> Self {
+        ColorEncoding::Srgb
+    }
+}
+
+/// RGB color space, defined by its primaries and transfer function. Renders are computed in
+/// linear light with the primaries of sRGB and Rec. 709, the working space. Other spaces are
+/// converted from and to it at input and output, so that colors look the same on any display.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    /// Standard for most monitors and images on the web
+    Srgb,
+    /// HDTV. Same primaries as sRGB, with a different transfer function
+    Rec709,
+    /// Wide gamut of recent phones and laptops, with the sRGB transfer function
+    DisplayP3,
+    /// Linear space with the wide AP1 primaries, common in film pipelines
+    AcesCg,
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        ColorSpace::Srgb
+    }
+}
+
+// Matrices converting linear colors between the working space and other primaries. ACEScg has a
+// D60 white point, so they include a Bradford chromatic adaptation from and to D65
+const WORKING_TO_DISPLAY_P3: [[f32; 3]; 3] = [
+    [0.822_462, 0.177_538, 0.],
+    [0.033_194, 0.966_806, 0.],
+    [0.017_083, 0.072_397, 0.910_520],
+];
+const DISPLAY_P3_TO_WORKING: [[f32; 3]; 3] = [
+    [1.224_940, -0.224_940, 0.],
+    [-0.042_057, 1.042_057, 0.],
+    [-0.019_638, -0.078_636, 1.098_274],
+];
+const WORKING_TO_ACESCG: [[f32; 3]; 3] = [
+    [0.613_097, 0.339_523, 0.047_379],
+    [0.070_194, 0.916_354, 0.013_452],
+    [0.020_616, 0.109_570, 0.869_815],
+];
+const ACESCG_TO_WORKING: [[f32; 3]; 3] = [
+    [1.704_859, -0.621_715, -0.083_299],
+    [-0.130_078, 1.140_734, -0.010_560],
+    [-0.023_964, -0.128_975, 1.153_013],
+];
+
+// Matrix converting CIE XYZ colors to the working space, with its D65 white point
+const XYZ_TO_WORKING: [[f32; 3]; 3] = [
+    [3.240_454, -1.537_139, -0.498_531],
+    [-0.969_266, 1.876_011, 0.041_556],
+    [0.055_643, -0.204_026, 1.057_225],
+];
+
+/// Wavelengths the visible spectrum is sampled at when integrating emission spectra, in nm.
+const SPECTRUM_START: u32 = 380;
+const SPECTRUM_END: u32 = 780;
+const SPECTRUM_STEP: u32 = 5;
+
+fn mul(matrix: &[[f32; 3]; 3], color: [f32; 3]) -> [f32; 3] {
+    let row = |r: &[f32; 3]| r[0] * color[0] + r[1] * color[1] + r[2] * color[2];
+    [row(&matrix[0]), row(&matrix[1]), row(&matrix[2])]
+}
+
+impl ColorSpace {
+    /// Convert a linear color with this space's primaries to the working space.
+    pub fn to_working(self, color: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Srgb | ColorSpace::Rec709 => color,
+            ColorSpace::DisplayP3 => mul(&DISPLAY_P3_TO_WORKING, color),
+            ColorSpace::AcesCg => mul(&ACESCG_TO_WORKING, color),
+        }
+    }
+
+    /// Convert a linear color from the working space to this space's primaries. Colors outside
+    /// of the gamut of the space get negative channels.
+    pub fn convert_from_working(self, color: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Srgb | ColorSpace::Rec709 => color,
+            ColorSpace::DisplayP3 => mul(&WORKING_TO_DISPLAY_P3, color),
+            ColorSpace::AcesCg => mul(&WORKING_TO_ACESCG, color),
+        }
+    }
+
+    /// Apply the transfer function of the space to a linear channel value in [0, 1].
+    pub fn encode(self, value: f32) -> f32 {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => linear_to_srgb(value),
+            ColorSpace::Rec709 => {
+                if value < 0.018 {
+                    4.5 * value
+                } else {
+                    1.099 * f32::powf(value, 0.45) - 0.099
+                }
+            }
+            ColorSpace::AcesCg => value,
+        }
+    }
+
+    /// Invert the transfer function of the space, giving a linear channel value.
+    pub fn decode(self, value: f32) -> f32 {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => srgb_to_linear(value),
+            ColorSpace::Rec709 => {
+                if value < 0.081 {
+                    value / 4.5
+                } else {
+                    f32::powf((value + 0.099) / 1.099, 1. / 0.45)
+                }
+            }
+            ColorSpace::AcesCg => value,
+        }
+    }
+}
+
+/// Operator compressing the unbounded linear colors of a render into the displayable range.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToneMapping {
+    /// Clip values above 1
+    None,
+    /// x / (1 + x). Keeps every highlight visible, at the cost of some contrast
+    Reinhard,
+    /// Filmic curve approximating the ACES reference rendering transform
+    Aces,
+}
+
+/// Noise added when quantizing colors to 8 bits. Without it, smooth gradients such as skies and
+/// soft shadows show visible bands.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dithering {
+    None,
+    /// Ordered dithering with an 8x8 Bayer matrix
+    Ordered,
+}
+
+/// 8x8 Bayer matrix. Each threshold appears once, and consecutive thresholds are spread apart.
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Offset added to the channels of pixel (x, y) before rounding them to 8 bits, in quantization
+/// steps within [-0.5, 0.5).
+pub fn dither_offset(dithering: Dithering, x: u32, y: u32) -> f32 {
+    match dithering {
+        Dithering::None => 0.,
+        Dithering::Ordered => {
+            (BAYER_8X8[(y % 8) as usize][(x % 8) as usize] as f32 + 0.5) / 64. - 0.5
+        }
+    }
+}
+
+/// Map a linear channel value to [0, 1] with the given operator.
+pub fn tone_map_channel(value: f32, tone_mapping: ToneMapping) -> f32 {
+    let value = value.max(0.);
+    let mapped = match tone_mapping {
+        ToneMapping::None => value,
+        ToneMapping::Reinhard => value / (1. + value),
+        // Krzysztof Narkowicz's fit of the ACES curve
+        ToneMapping::Aces => {
+            (value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14)
+        }
+    };
+    mapped.min(1.)
+}
+
+/// Tone map a linear color of the working space and encode it into a displayable color of the
+/// given color space, with channels in [0, 1].
+fn tone_map_encoded(
+    color: Rgb<f32>,
+    tone_mapping: ToneMapping,
+    exposure: f32,
+    color_space: ColorSpace,
+) -> [f32; 3] {
+    let color = color_space.convert_from_working(color.0);
+    let encode = |value: f32| color_space.encode(tone_map_channel(value * exposure, tone_mapping));
+    [encode(color[0]), encode(color[1]), encode(color[2])]
+}
+
+/// Tone map a linear color of the working space and encode it into an opaque displayable color
+/// of the given color space. `dither` is added before rounding, see `dither_offset`.
+pub fn tone_map(
+    color: Rgb<f32>,
+    tone_mapping: ToneMapping,
+    exposure: f32,
+    color_space: ColorSpace,
+    dither: f32,
+) -> Rgba<u8> {
+    let encoded = tone_map_encoded(color, tone_mapping, exposure, color_space);
+    // Float to integer casts saturate, so dithered values can't wrap around
+    let quantize = |value: f32| (value * 255. + 0.5 + dither) as u8;
+    Rgba([
+        quantize(encoded[0]),
+        quantize(encoded[1]),
+        quantize(encoded[2]),
+        255,
+    ])
+}
+
+/// Same as `tone_map` with 16 bits per channel, whose steps are fine enough to be graded without
+/// banding, so there is no need for dithering.
+pub fn tone_map_16(
+    color: Rgb<f32>,
+    tone_mapping: ToneMapping,
+    exposure: f32,
+    color_space: ColorSpace,
+) -> Rgba<u16> {
+    let encoded = tone_map_encoded(color, tone_mapping, exposure, color_space);
+    let quantize = |value: f32| (value * 65535. + 0.5) as u16;
+    Rgba([
+        quantize(encoded[0]),
+        quantize(encoded[1]),
+        quantize(encoded[2]),
+        u16::MAX,
+    ])
+}
+
+/// Linear working space color of the light emitted by a blackbody at the given temperature, in
+/// kelvin: red for embers around 1000 K, orange for incandescent bulbs around 2700 K, close to
+/// white around 6500 K and blue beyond. Its luminance is 1, so that it can be multiplied with an
+/// intensity. Colors outside of the working space gamut are clamped to it.
+pub fn blackbody(temperature: f32) -> [f32; 3] {
+    let mut xyz = [0.; 3];
+    for wavelength in (SPECTRUM_START..=SPECTRUM_END).step_by(SPECTRUM_STEP as usize) {
+        let wavelength = wavelength as f64;
+        let radiance = planck(wavelength, temperature as f64);
+        let matching = cie_color_matching(wavelength);
+        for (sum, matching) in xyz.iter_mut().zip(matching.iter()) {
+            *sum += radiance * matching;
+        }
+    }
+    // Too cold to emit visible light
+    if !(xyz[1] > 0. && xyz[1].is_finite()) {
+        return [0.; 3];
+    }
+    let xyz = [(xyz[0] / xyz[1]) as f32, 1., (xyz[2] / xyz[1]) as f32];
+    let rgb = mul(&XYZ_TO_WORKING, xyz);
+    [rgb[0].max(0.), rgb[1].max(0.), rgb[2].max(0.)]
+}
+
+/// Spectral radiance of a blackbody given by Planck's law, up to a constant factor, at a
+/// wavelength in nm and a temperature in kelvin.
+fn planck(wavelength: f64, temperature: f64) -> f64 {
+    // Second radiation constant hc/k, in nm K
+    const C2: f64 = 1.438_777e7;
+    1. / (wavelength.powi(5) * f64::exp_m1(C2 / (wavelength * temperature)))
+}
+
+/// CIE 1931 2° color matching functions at a wavelength in nm, from the multi-lobe Gaussian fit
+/// by Wyman, Sloan and Shirley ("Simple Analytic Approximations to the CIE XYZ Color Matching
+/// Functions", 2013).
+fn cie_color_matching(wavelength: f64) -> [f64; 3] {
+    // Gaussian with a different width on each side of its mean
+    let lobe = |mean: f64, width_below: f64, width_above: f64| {
+        let width = if wavelength < mean {
+            width_below
+        } else {
+            width_above
+        };
+        let t = (wavelength - mean) / width;
+        f64::exp(-0.5 * t * t)
+    };
+    [
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    ]
+}
+
+/// Convert an sRGB-encoded channel value in [0, 1] to linear.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        f32::powf((value + 0.055) / 1.055, 2.4)
+    }
+}
+
+/// Convert a linear channel value in [0, 1] to sRGB encoding.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * f32::powf(value, 1. / 2.4) - 0.055
+    }
+}
+
+/// Decode an 8-bit sRGB color into linear values in [0, 1]. Alpha is kept linear.
+pub fn to_linear(color: Rgba<u8>) -> Rgba<f32> {
+    let decode = |value: u8| srgb_to_linear(value as f32 / 255.);
+    Rgba([
+        decode(color[0]),
+        decode(color[1]),
+        decode(color[2]),
+        color[3] as f32 / 255.,
+    ])
+}
+
+/// Encode a linear color as an 8-bit sRGB color. Alpha is kept linear.
+pub fn to_srgb8(color: Rgba<f32>) -> Rgba<u8> {
+    let encode = |value: f32| (linear_to_srgb(value.max(0.).min(1.)) * 255. + 0.5) as u8;
+    Rgba([
+        encode(color[0]),
+        encode(color[1]),
+        encode(color[2]),
+        (color[3].max(0.).min(1.) * 255. + 0.5) as u8,
+    ])
+}
+
+/// Load an image with linear color channels in the working space. Color images are decoded with
+/// the transfer function of their color space and converted to the working space on load, so
+/// lighting computations never operate on gamma-encoded values. Images with linear encoding hold
+/// raw data and are loaded as they are. Alpha is always linear.
+///
+/// High dynamic range images (Radiance HDR, OpenEXR) are stored as linear floating point values,
+/// which may exceed 1, so they are only converted to the working space primaries.
+pub fn load_image(
+    path: &Path,
+    encoding: ColorEncoding,
+    color_space: ColorSpace,
+) -> Result<Rgba32FImage, ImageError> {
+    let img = image::open(path)?;
+    let is_hdr = match img {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => true,
+        _ => false,
+    };
+    let mut img = img.into_rgba32f();
+    if encoding == ColorEncoding::Srgb {
+        for pixel in img.pixels_mut() {
+            let mut color = [pixel[0], pixel[1], pixel[2]];
+            if !is_hdr {
+                for ch in color.iter_mut() {
+                    *ch = color_space.decode(*ch);
+                }
+            }
+            let color = color_space.to_working(color);
+            pixel.0[..3].copy_from_slice(&color);
+        }
+    }
+    Ok(img)
+}
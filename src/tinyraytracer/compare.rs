@@ -0,0 +1,97 @@
+use image::{Rgba, RgbaImage};
+
+/// Side of the square windows SSIM is computed over.
+const SSIM_WINDOW: u32 = 8;
+/// Offset between consecutive SSIM windows.
+const SSIM_STRIDE: u32 = 4;
+// SSIM stabilization constants for 8-bit images, (0.01 * 255)^2 and (0.03 * 255)^2
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+
+fn luma(pixel: &Rgba<u8>) -> f64 {
+    0.2126 * pixel[0] as f64 + 0.7152 * pixel[1] as f64 + 0.0722 * pixel[2] as f64
+}
+
+/// Peak signal-to-noise ratio in dB between two images of the same size, computed over the R, G
+/// and B channels. Identical images yield infinity.
+pub fn psnr(img0: &RgbaImage, img1: &RgbaImage) -> f64 {
+    let mut squared_error = 0.;
+    for (p0, p1) in img0.pixels().zip(img1.pixels()) {
+        for ch in 0..3 {
+            let diff = p0[ch] as f64 - p1[ch] as f64;
+            squared_error += diff * diff;
+        }
+    }
+    let mse = squared_error / (img0.width() * img0.height() * 3) as f64;
+
+    10. * f64::log10(255. * 255. / mse)
+}
+
+/// Mean structural similarity index between the luminance of two images of the same size. 1
+/// means the images are identical.
+pub fn ssim(img0: &RgbaImage, img1: &RgbaImage) -> f64 {
+    let (width, height) = img0.dimensions();
+    let window_size = u32::min(SSIM_WINDOW, u32::min(width, height));
+    let pixels_num = (window_size * window_size) as f64;
+
+    let mut ssim_sum = 0.;
+    let mut windows_num = 0;
+    for y in (0..=height - window_size).step_by(SSIM_STRIDE as usize) {
+        for x in (0..=width - window_size).step_by(SSIM_STRIDE as usize) {
+            // Means, variances and covariance of the window
+            let (mut sum0, mut sum1, mut sum_sq0, mut sum_sq1, mut sum01) = (0., 0., 0., 0., 0.);
+            for j in y..y + window_size {
+                for i in x..x + window_size {
+                    let l0 = luma(img0.get_pixel(i, j));
+                    let l1 = luma(img1.get_pixel(i, j));
+                    sum0 += l0;
+                    sum1 += l1;
+                    sum_sq0 += l0 * l0;
+                    sum_sq1 += l1 * l1;
+                    sum01 += l0 * l1;
+                }
+            }
+            let mean0 = sum0 / pixels_num;
+            let mean1 = sum1 / pixels_num;
+            let var0 = sum_sq0 / pixels_num - mean0 * mean0;
+            let var1 = sum_sq1 / pixels_num - mean1 * mean1;
+            let covar = sum01 / pixels_num - mean0 * mean1;
+
+            ssim_sum += ((2. * mean0 * mean1 + SSIM_C1) * (2. * covar + SSIM_C2))
+                / ((mean0 * mean0 + mean1 * mean1 + SSIM_C1) * (var0 + var1 + SSIM_C2));
+            windows_num += 1;
+        }
+    }
+
+    ssim_sum / windows_num as f64
+}
+
+/// Color of a heatmap for a value in [0, 1], from black (0) through red and yellow to white (1).
+pub fn heat_color(heat: f32) -> [u8; 3] {
+    let scaled = heat * 3.;
+    [
+        (f32::max(0., f32::min(scaled, 1.)) * 255.) as u8,
+        (f32::max(0., f32::min(scaled - 1., 1.)) * 255.) as u8,
+        (f32::max(0., f32::min(scaled - 2., 1.)) * 255.) as u8,
+    ]
+}
+
+/// Heatmap of the per-pixel difference between two images of the same size. Differences go from
+/// black (none) through red and yellow to white (maximum).
+pub fn difference_heatmap(img0: &RgbaImage, img1: &RgbaImage) -> RgbaImage {
+    let mut heatmap = RgbaImage::new(img0.width(), img0.height());
+    for ((p0, p1), heat) in img0.pixels().zip(img1.pixels()).zip(heatmap.pixels_mut()) {
+        let squared_diff: f32 = (0..3)
+            .map(|ch| {
+                let diff = p0[ch] as f32 - p1[ch] as f32;
+                diff * diff
+            })
+            .sum();
+        // Normalized color distance in [0, 1]
+        let diff = f32::sqrt(squared_diff / 3.) / 255.;
+
+        let [r, g, b] = heat_color(diff);
+        *heat = Rgba([r, g, b, 255]);
+    }
+    heatmap
+}
@@ -0,0 +1,219 @@
+use std::f32::consts::PI;
+
+use image::{Rgb, Rgba32FImage};
+use rand::Rng;
+
+use super::bvh::Bvh;
+use super::math::{dot, length, normalize, Point3, Vec3};
+use super::scene_elems::{Hit, Light, Material, Ray, RayKind};
+use super::{
+    fresnel_reflectance, get_background, reflect_dir, refract_dir, scene_intersect, shadow_origin,
+    shadow_transmittance, RenderSettings, ShadingWeights,
+};
+
+/// Bounces after which paths may be terminated by Russian roulette.
+const ROULETTE_MIN_BOUNCES: u32 = 3;
+/// Highest probability of a path surviving Russian roulette, so that paths bouncing between
+/// bright surfaces still end.
+const ROULETTE_MAX_SURVIVAL: f32 = 0.95;
+
+/// Algorithm computing the light arriving at the camera along each camera ray.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Integrator {
+    /// Whitted-style ray tracing: direct lighting plus perfect reflections and refractions
+    Whitted,
+    /// Monte Carlo path tracing. Diffuse and glossy bounces are sampled stochastically, which
+    /// adds global illumination (indirect lighting, color bleeding) at the cost of noise that
+    /// fades as samples accumulate
+    Path,
+}
+
+/// Unidirectional path tracer. At each bounce, lights are sampled directly and a single
+/// continuation direction is chosen at random among the material's mirror reflection,
+/// refraction and BRDF lobes.
+#[derive(Debug, Clone, Copy)]
+pub struct PathTracer {
+    /// Maximum number of bounces along a path
+    pub max_bounces: u32,
+}
+
+impl PathTracer {
+    /// Estimate the light arriving along a camera ray with a single random path.
+    pub fn radiance<R: Rng>(
+        &self,
+        ray: Ray,
+        bvh: &Bvh,
+        lights: &Vec<Light>,
+        background: &Rgba32FImage,
+        settings: &RenderSettings,
+        rng: &mut R,
+    ) -> Rgb<f32> {
+        let mut color = [0.; 3];
+        let mut throughput = [1.; 3];
+        let mut ray = ray;
+        let mut kind = RayKind::Camera;
+        // Absorption of the object the current path segment travels through, if any
+        let mut medium_absorption: Option<[f32; 3]> = None;
+
+        for bounce in 0..=self.max_bounces {
+            let (hit, object) = match scene_intersect(&ray, kind, bvh) {
+                Some(intersection) => intersection,
+                None => {
+                    let env = get_background(background, &ray.direction);
+                    for ch in 0..3 {
+                        color[ch] += throughput[ch] * env[ch];
+                    }
+                    break;
+                }
+            };
+            // Beer-Lambert law: light is absorbed exponentially along the distance traveled
+            if let Some(absorption) = medium_absorption.take() {
+                for ch in 0..3 {
+                    throughput[ch] *= f32::exp(-absorption[ch] * hit.t);
+                }
+            }
+            let material = object.material();
+            let albedo = material.albedo();
+
+            let fresnel = if albedo[3] > 0. {
+                fresnel_reflectance(
+                    ray.direction,
+                    hit.normal,
+                    settings.env_refr_idx,
+                    material.refr_ratio(),
+                )
+            } else {
+                0.
+            };
+            let weights = ShadingWeights::new(
+                albedo[0],
+                albedo[1],
+                albedo[2] + albedo[3] * fresnel,
+                albedo[3] * (1. - fresnel),
+            );
+
+            // Pick the lobe the path continues through with a probability equal to its weight,
+            // so that the choice needs no further compensation
+            let lobe = rng.gen::<f32>();
+            if lobe < weights.reflection {
+                let direction = reflect_dir(ray.direction, hit.normal);
+                ray = offset_ray(hit.point, hit.normal, direction);
+                kind = RayKind::Reflection;
+                // Reflected back inside the object, e.g. by total internal reflection
+                if dot(&direction, &hit.normal) < 0. {
+                    medium_absorption = Some(material.absorption());
+                }
+            } else if lobe < weights.reflection + weights.refraction {
+                let direction = refract_dir(
+                    ray.direction,
+                    hit.normal,
+                    settings.env_refr_idx,
+                    material.refr_ratio(),
+                )
+                .unwrap_or_else(|| reflect_dir(ray.direction, hit.normal));
+                ray = offset_ray(hit.point, hit.normal, direction);
+                kind = RayKind::Refraction;
+                if dot(&direction, &hit.normal) < 0. {
+                    medium_absorption = Some(material.absorption());
+                }
+            } else {
+                let wo = -ray.direction;
+                // Shade the side of the surface the ray comes from
+                let hit = if dot(&hit.normal, &wo) < 0. {
+                    Hit {
+                        normal: -hit.normal,
+                        ..hit
+                    }
+                } else {
+                    hit
+                };
+                // Normal and bump maps tilt the normal the surface is lit with
+                let hit = Hit {
+                    normal: material.shading_normal(&hit),
+                    ..hit
+                };
+
+                let direct = direct_light(&hit, &wo, material, bvh, lights, settings, rng);
+                for ch in 0..3 {
+                    color[ch] += throughput[ch] * direct[ch];
+                }
+
+                let sample = match material.sample(&wo, &hit, rng) {
+                    Some(sample) => sample,
+                    None => break,
+                };
+                for ch in 0..3 {
+                    throughput[ch] *= sample.weight[ch];
+                }
+                ray = offset_ray(hit.point, hit.normal, sample.wi);
+                kind = RayKind::Reflection;
+            }
+
+            // Randomly end paths carrying little light, boosting the survivors to compensate
+            if bounce >= ROULETTE_MIN_BOUNCES {
+                let survival = f32::min(
+                    ROULETTE_MAX_SURVIVAL,
+                    throughput.iter().cloned().fold(0., f32::max),
+                );
+                if rng.gen::<f32>() >= survival {
+                    break;
+                }
+                for ch in throughput.iter_mut() {
+                    *ch /= survival;
+                }
+            }
+        }
+
+        Rgb(color)
+    }
+}
+
+/// Light reflected towards `wo` coming straight from the light sources. A light's intensity is
+/// the brightness of a white, perfectly diffuse surface facing it, as in the Whitted integrator.
+fn direct_light<R: Rng>(
+    hit: &Hit,
+    wo: &Vec3,
+    material: &dyn Material,
+    bvh: &Bvh,
+    lights: &Vec<Light>,
+    settings: &RenderSettings,
+    rng: &mut R,
+) -> Rgb<f32> {
+    let mut light = [0.; 3];
+    let shadow_point = shadow_origin(hit, hit.normal, settings);
+    for source in lights {
+        let positions = source.sample_positions(settings.light_samples, rng);
+        let intensity = source.rgb_intensity();
+        let samples = positions.len() as f32;
+        for position in positions {
+            let transmittance = shadow_transmittance(shadow_point, position, bvh);
+            if transmittance == [0.; 3] {
+                continue;
+            }
+            let attenuation = source.attenuation.factor(length(&(position - hit.point)));
+            let wi = normalize(&(position - hit.point));
+            let cos = dot(&wi, &hit.normal);
+            if cos <= 0. {
+                continue;
+            }
+            let brdf = material.eval_brdf(&wi, wo, hit);
+            for ch in 0..3 {
+                light[ch] +=
+                    brdf[ch] * cos * intensity[ch] * transmittance[ch] * attenuation / samples * PI;
+            }
+        }
+    }
+    Rgb(light)
+}
+
+/// Ray leaving a surface point, offset along the normal to the side it goes towards so that it
+/// doesn't intersect the surface it starts from.
+fn offset_ray(point: Point3, normal: Vec3, direction: Vec3) -> Ray {
+    let offset = if dot(&direction, &normal) > 0. {
+        1e-3
+    } else {
+        -1e-3
+    };
+    Ray::new(point + normal * offset, direction)
+}
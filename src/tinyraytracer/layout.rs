@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use nalgebra::{Point3, Similarity3, Translation3, UnitQuaternion, Vector3};
+
+use super::scene::Scene;
+use super::scene_elems::{TraceObj, Transformed};
+
+/// Element of a scene picked for editing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    /// Index in the scene's lights
+    Light(usize),
+    /// Index in the scene's objects
+    Object(usize),
+}
+
+/// Moves the lights and objects of a scene around, e.g. from the keyboard controls of a viewer.
+/// Objects are moved through a translation, so any object can be moved without knowing its shape:
+/// when the layout is created, the scene's objects are replaced by instances of themselves placed
+/// where they were, and moving an object replaces its instance.
+#[derive(Debug)]
+pub struct Layout {
+    /// Objects at their original position, and their offset from it
+    objects: Vec<(Arc<dyn TraceObj>, Vector3<f32>)>,
+    /// Index of the selected element among the lights, then the objects
+    selected: Option<usize>,
+}
+
+impl Layout {
+    pub fn new(scene: &mut Scene) -> Self {
+        let objects: Vec<(Arc<dyn TraceObj>, Vector3<f32>)> = scene
+            .objs
+            .drain(..)
+            .map(|obj| (Arc::from(obj), Vector3::zeros()))
+            .collect();
+        for (obj, _) in &objects {
+            scene.objs.push(Box::new(Transformed::new(
+                obj.clone(),
+                Similarity3::identity(),
+            )));
+        }
+        Layout {
+            objects,
+            selected: None,
+        }
+    }
+
+    pub fn selection(&self, scene: &Scene) -> Option<Selection> {
+        let lights = scene.lights.len();
+        self.selected.map(|idx| {
+            if idx < lights {
+                Selection::Light(idx)
+            } else {
+                Selection::Object(idx - lights)
+            }
+        })
+    }
+
+    /// Select the next element, going through the lights and then the objects, and wrapping
+    /// around after the last object.
+    pub fn select_next(&mut self, scene: &Scene) -> Option<Selection> {
+        let count = scene.lights.len() + self.objects.len();
+        if count > 0 {
+            self.selected = Some(self.selected.map_or(0, |idx| (idx + 1) % count));
+        }
+        self.selection(scene)
+    }
+
+    /// Select the previous element, in the order of `select_next`.
+    pub fn select_previous(&mut self, scene: &Scene) -> Option<Selection> {
+        let count = scene.lights.len() + self.objects.len();
+        if count > 0 {
+            self.selected = Some(
+                self.selected
+                    .map_or(count - 1, |idx| (idx + count - 1) % count),
+            );
+        }
+        self.selection(scene)
+    }
+
+    /// Move the selected element by `offset`. Return false if nothing is selected.
+    pub fn nudge(&mut self, scene: &mut Scene, offset: Vector3<f32>) -> bool {
+        match self.selection(scene) {
+            Some(Selection::Light(idx)) => scene.lights[idx].position += offset,
+            Some(Selection::Object(idx)) => {
+                let (ref obj, ref mut translation) = self.objects[idx];
+                *translation += offset;
+                let transform = Similarity3::from_parts(
+                    Translation3::from(*translation),
+                    UnitQuaternion::identity(),
+                    1.,
+                );
+                scene.objs[idx] = Box::new(Transformed::new(obj.clone(), transform));
+            }
+            None => return false,
+        }
+        true
+    }
+
+    /// Position of the selected element: the position of a light, or the center of the bounding
+    /// box of an object. None if nothing is selected or if the object is unbounded.
+    pub fn position(&self, scene: &Scene) -> Option<Point3<f32>> {
+        match self.selection(scene)? {
+            Selection::Light(idx) => Some(scene.lights[idx].position),
+            Selection::Object(idx) => scene.objs[idx].bounding_box().map(|bbox| bbox.centroid()),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Thin facade over the linear algebra library. The renderer's primitives, materials and
+//! integrators are written against these aliases and helpers instead of nalgebra directly, so
+//! that switching to another backend (e.g. glam) only requires changing this module.
+use nalgebra;
+
+pub type Vec3 = nalgebra::Vector3<f32>;
+/// Double precision vector, for computations where f32 loses too much, e.g. solving quartics
+pub type DVec3 = nalgebra::Vector3<f64>;
+pub type Point2 = nalgebra::Point2<f32>;
+pub type Point3 = nalgebra::Point3<f32>;
+/// Rotation and translation followed by a uniform scale
+pub type Similarity = nalgebra::Similarity3<f32>;
+/// Rotation and translation, as placed by the physics engine
+pub type Isometry = nalgebra::Isometry3<f32>;
+
+pub fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+    Vec3::new(x, y, z)
+}
+
+pub fn dvec3(x: f64, y: f64, z: f64) -> DVec3 {
+    DVec3::new(x, y, z)
+}
+
+/// Vector with all its coordinates equal to `value`.
+pub fn splat(value: f32) -> Vec3 {
+    Vec3::repeat(value)
+}
+
+/// Vector whose coordinate along each axis is given by `f`.
+pub fn vec3_from_fn<F: FnMut(usize) -> f32>(mut f: F) -> Vec3 {
+    vec3(f(0), f(1), f(2))
+}
+
+pub fn point2(u: f32, v: f32) -> Point2 {
+    Point2::new(u, v)
+}
+
+pub fn point3(x: f32, y: f32, z: f32) -> Point3 {
+    Point3::new(x, y, z)
+}
+
+/// Point at the given offset from the origin.
+pub fn to_point(v: Vec3) -> Point3 {
+    Point3::from(v)
+}
+
+/// Offset of a point from the origin.
+pub fn to_vec(p: &Point3) -> Vec3 {
+    p.coords
+}
+
+pub fn dot(a: &Vec3, b: &Vec3) -> f32 {
+    a.dot(b)
+}
+
+pub fn cross(a: &Vec3, b: &Vec3) -> Vec3 {
+    a.cross(b)
+}
+
+pub fn length(v: &Vec3) -> f32 {
+    v.norm()
+}
+
+pub fn length_squared(v: &Vec3) -> f32 {
+    v.norm_squared()
+}
+
+pub fn normalize(v: &Vec3) -> Vec3 {
+    v.normalize()
+}
+
+/// Unit vector along `v`, or None if `v` is too short to have a reliable direction.
+pub fn try_normalize(v: &Vec3) -> Option<Vec3> {
+    v.try_normalize(1e-6)
+}
+
+/// Point whose coordinates are the smallest of both points' along each axis.
+pub fn min_point(a: &Point3, b: &Point3) -> Point3 {
+    a.inf(b)
+}
+
+/// Point whose coordinates are the largest of both points' along each axis.
+pub fn max_point(a: &Point3, b: &Point3) -> Point3 {
+    a.sup(b)
+}
+
+/// Vector of the absolute values of the coordinates of `v`.
+pub fn abs(v: &Vec3) -> Vec3 {
+    v.abs()
+}
+
+/// Vector whose coordinates are the largest of both vectors' along each axis.
+pub fn max_vec(a: &Vec3, b: &Vec3) -> Vec3 {
+    a.sup(b)
+}
+
+/// Index of the axis along which `v` has its largest coordinate.
+pub fn max_axis(v: &Vec3) -> usize {
+    v.imax()
+}
+
+pub fn midpoint(a: &Point3, b: &Point3) -> Point3 {
+    nalgebra::center(a, b)
+}
+
+/// Transform leaving everything in place.
+pub fn identity() -> Similarity {
+    Similarity::identity()
+}
+
+/// Transform scaling uniformly by `scale` before placing with `isometry`.
+pub fn similarity(isometry: Isometry, scale: f32) -> Similarity {
+    Similarity::from_isometry(isometry, scale)
+}
+
+pub fn transform_point(transform: &Similarity, point: &Point3) -> Point3 {
+    transform.transform_point(point)
+}
+
+/// Apply a transform to a direction. Translations don't affect directions, scaling does.
+pub fn transform_vector(transform: &Similarity, vector: &Vec3) -> Vec3 {
+    transform.transform_vector(vector)
+}
+
+/// Apply only the rotation of a transform, e.g. to a normal.
+pub fn rotate(transform: &Similarity, vector: &Vec3) -> Vec3 {
+    transform.isometry.rotation * vector
+}
+
+pub fn scaling(transform: &Similarity) -> f32 {
+    transform.scaling()
+}
+
+pub fn inverse(transform: &Similarity) -> Similarity {
+    transform.inverse()
+}
@@ -0,0 +1,114 @@
+//! Smooth pseudo-random 3D noise for procedural materials. The same seed always gives the same
+//! noise, so procedural textures don't flicker between frames or passes.
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use super::math::{dot, point3, to_point, to_vec, vec3, Point3, Vec3};
+
+/// Size of the lattice cells' hash table. Noise repeats every this many units along each axis.
+const TABLE_SIZE: usize = 256;
+
+/// Lattice of pseudo-random values and gradients at integer coordinates, interpolated in between.
+#[derive(Debug, Clone)]
+pub struct Noise {
+    /// Permutation of 0..TABLE_SIZE, repeated twice so hashes of neighbour cells don't wrap
+    permutation: Vec<u8>,
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        let mut permutation: Vec<u8> = (0..TABLE_SIZE).map(|i| i as u8).collect();
+        permutation.shuffle(&mut SmallRng::seed_from_u64(seed));
+        let repeated = permutation.clone();
+        permutation.extend(repeated);
+        Noise { permutation }
+    }
+
+    /// Hash of the lattice point at the given integer coordinates.
+    fn hash(&self, x: i32, y: i32, z: i32) -> u8 {
+        let wrap = |coord: i32| (coord & (TABLE_SIZE as i32 - 1)) as usize;
+        let perm = &self.permutation;
+        perm[perm[perm[wrap(x)] as usize + wrap(y)] as usize + wrap(z)]
+    }
+
+    /// Interpolate values computed at the 8 corners of the lattice cell containing a point. The
+    /// corner function gets the hash of the corner and the offset from it to the point.
+    fn interpolate<F>(&self, point: Point3, corner_value: F) -> f32
+    where
+        F: Fn(u8, Vec3) -> f32,
+    {
+        let cell = point3(point.x.floor(), point.y.floor(), point.z.floor());
+        let offset = point - cell;
+        let fade = vec3(fade(offset.x), fade(offset.y), fade(offset.z));
+        let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+        let mut value = 0.;
+        for corner in 0..8 {
+            let (dx, dy, dz) = (corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+            let weight =
+                lerp_weight(fade.x, dx) * lerp_weight(fade.y, dy) * lerp_weight(fade.z, dz);
+            let corner_offset = offset - vec3(dx as f32, dy as f32, dz as f32);
+            value += weight * corner_value(self.hash(x + dx, y + dy, z + dz), corner_offset);
+        }
+        value
+    }
+
+    /// Gradient (Perlin) noise, roughly in [-1, 1]. It is zero at lattice points, which avoids the
+    /// blocky look of value noise.
+    pub fn perlin(&self, point: Point3) -> f32 {
+        self.interpolate(point, |hash, offset| dot(&gradient(hash), &offset))
+    }
+
+    /// Value noise in [0, 1]: random values at lattice points, smoothly interpolated.
+    pub fn value(&self, point: Point3) -> f32 {
+        self.interpolate(point, |hash, _| hash as f32 / (TABLE_SIZE - 1) as f32)
+    }
+
+    /// Sum of the absolute value of `octaves` layers of Perlin noise, each one twice as detailed
+    /// and half as strong as the previous one. Gives the swirly look of marble veins and smoke.
+    pub fn turbulence(&self, point: Point3, octaves: u32) -> f32 {
+        let mut total = 0.;
+        let mut amplitude = 1.;
+        let mut point = point;
+        for _ in 0..octaves {
+            total += amplitude * self.perlin(point).abs();
+            amplitude *= 0.5;
+            point = to_point(to_vec(&point) * 2.);
+        }
+        total
+    }
+}
+
+/// Quintic smoothstep, which has zero first and second derivatives at 0 and 1 so that the noise
+/// has no visible creases along cell boundaries.
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+/// Weight of the low (0) or high (1) corner along an axis.
+fn lerp_weight(t: f32, corner: i32) -> f32 {
+    if corner == 0 {
+        1. - t
+    } else {
+        t
+    }
+}
+
+/// One of the 12 directions towards the edges of a cube, picked by a hash.
+fn gradient(hash: u8) -> Vec3 {
+    match hash % 12 {
+        0 => vec3(1., 1., 0.),
+        1 => vec3(-1., 1., 0.),
+        2 => vec3(1., -1., 0.),
+        3 => vec3(-1., -1., 0.),
+        4 => vec3(1., 0., 1.),
+        5 => vec3(-1., 0., 1.),
+        6 => vec3(1., 0., -1.),
+        7 => vec3(-1., 0., -1.),
+        8 => vec3(0., 1., 1.),
+        9 => vec3(0., -1., 1.),
+        10 => vec3(0., 1., -1.),
+        _ => vec3(0., -1., -1.),
+    }
+}
@@ -0,0 +1,288 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use exr::prelude::{
+    AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, SmallVec,
+    WritableImage,
+};
+use image::{DynamicImage, ImageBuffer, ImageFormat, Pixel, Primitive, RgbaImage};
+use png;
+
+use super::Rgba16Image;
+
+/// Expand a printf-style frame number placeholder in an output path template. Supports `%d` and
+/// zero-padded `%0Nd` (e.g. `render_%04d.png` becomes `render_0012.png` for frame 12). `%%` is
+/// an escaped percent sign. Templates without a placeholder are returned unchanged.
+pub fn frame_path(template: &str, frame: u32) -> PathBuf {
+    let mut path = String::with_capacity(template.len() + 8);
+    let mut chars = template.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            path.push(ch);
+            continue;
+        }
+        if chars.peek() == Some(&'%') {
+            chars.next();
+            path.push('%');
+            continue;
+        }
+
+        // Parse optional zero-padded width, e.g. the "04" in "%04d"
+        let mut width = String::new();
+        while let Some(&digit) = chars.peek() {
+            if !digit.is_ascii_digit() {
+                break;
+            }
+            width.push(digit);
+            chars.next();
+        }
+
+        if chars.peek() == Some(&'d') {
+            chars.next();
+            let width = width.parse::<usize>().unwrap_or(0);
+            path.push_str(&format!("{:0width$}", frame, width = width));
+        } else {
+            // Not a frame placeholder, keep it verbatim
+            path.push('%');
+            path.push_str(&width);
+        }
+    }
+
+    PathBuf::from(path)
+}
+
+/// Write an intermediate render to a PNG file, recording the number of completed passes in a
+/// "Passes" text chunk. The image is first written to a temporary file and then renamed, so an
+/// interrupted write never leaves a corrupted snapshot behind.
+pub fn write_snapshot(
+    img: &RgbaImage,
+    path: &Path,
+    passes: u32,
+    total_passes: u32,
+) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.with_extension("png.tmp");
+    {
+        let file = BufWriter::new(File::create(&tmp_path)?);
+        let mut encoder = png::Encoder::new(file, img.width(), img.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.add_text_chunk("Passes".to_string(), format!("{}/{}", passes, total_passes))?;
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(img.as_raw())?;
+    }
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Write the channels of a floating point image as raw little-endian f32 values, interleaved and
+/// row by row from the top, e.g. for compositing tools and denoisers which read the image size
+/// from elsewhere.
+pub fn write_raw_f32(values: &[f32], path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for value in values {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Save a render to disk. The image format (PNG, JPEG, ...) is inferred from the file extension.
+/// The alpha channel is dropped for formats that don't support it. PPM files are written as plain
+/// PPM, see `write_plain_ppm`.
+pub fn save_image(img: &RgbaImage, path: &Path) -> Result<(), Box<dyn Error>> {
+    if is_ppm(path) {
+        return write_plain_ppm(img, path);
+    }
+    match ImageFormat::from_path(path)? {
+        ImageFormat::Jpeg | ImageFormat::Bmp | ImageFormat::Pnm => {
+            DynamicImage::ImageRgba8(img.clone())
+                .to_rgb8()
+                .save(path)?
+        }
+        _ => img.save(path)?,
+    }
+    Ok(())
+}
+
+/// Check if images with 16 bits per channel can be saved to the given path. Only PNG and PPM files
+/// are supported.
+pub fn supports_16_bit(path: &Path) -> bool {
+    is_ppm(path) || ImageFormat::from_path(path).map_or(false, |format| format == ImageFormat::Png)
+}
+
+/// Save a render with 16 bits per channel, e.g. one made by `tone_map_16`, to a PNG or plain PPM
+/// file.
+pub fn save_image_16(img: &Rgba16Image, path: &Path) -> Result<(), Box<dyn Error>> {
+    if !supports_16_bit(path) {
+        return Err(format!(
+            "Can't save {}: 16-bit output is only supported for PNG and PPM files",
+            path.display()
+        )
+        .into());
+    }
+    if is_ppm(path) {
+        return write_plain_ppm(img, path);
+    }
+    img.save(path)?;
+    Ok(())
+}
+
+/// Check if the given path is a .ppm file.
+fn is_ppm(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("ppm"))
+}
+
+/// Write an image to a plain PPM file, the ASCII variant of the format, which nearly any tool or
+/// script can read without an image library. Images with 16 bits per channel keep them. The alpha
+/// channel is dropped.
+pub fn write_plain_ppm<P>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    path: &Path,
+) -> Result<(), Box<dyn Error>>
+where
+    P: Pixel,
+    P::Subpixel: Into<u32>,
+{
+    let mut file = BufWriter::new(File::create(path)?);
+    let max_value: u32 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+    write!(
+        file,
+        "P3\n{} {}\n{}\n",
+        img.width(),
+        img.height(),
+        max_value
+    )?;
+    // One pixel per line keeps lines below the 70 characters the format allows
+    for pixel in img.pixels() {
+        let channel = |idx: usize| -> u32 { pixel.channels()[idx].into() };
+        writeln!(file, "{} {} {}", channel(0), channel(1), channel(2))?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Check if images saved to the given path are portable float maps (.pfm), which keep floating
+/// point values.
+pub fn is_pfm(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("pfm"))
+}
+
+/// Write a floating point image of the given size to a portable float map (PFM), a simple format
+/// for linear images read by most HDR and denoising tools. PFM files hold either 1 or 3 channels,
+/// whose values are interleaved in `values` row by row from the top.
+pub fn write_pfm(
+    width: u32,
+    height: u32,
+    channels: usize,
+    values: &[f32],
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let magic = match channels {
+        1 => "Pf",
+        3 => "PF",
+        _ => return Err(format!("PFM files hold 1 or 3 channels, not {}", channels).into()),
+    };
+    let row_len = width as usize * channels;
+    if values.len() != row_len * height as usize {
+        return Err(format!(
+            "PFM image holds {} values instead of {}",
+            values.len(),
+            row_len * height as usize
+        )
+        .into());
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    // A negative scale stands for little-endian values
+    write!(file, "{}\n{} {}\n-1.0\n", magic, width, height)?;
+    // Rows are stored from the bottom up
+    for row in values.chunks(row_len.max(1)).rev() {
+        for value in row {
+            file.write_all(&value.to_le_bytes())?;
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Floating point image stored as a layer of an EXR file, e.g. an AOV saved along with the render.
+#[derive(Debug, Clone)]
+pub struct ExrLayer {
+    /// Prefix of the names of the layer's channels, e.g. `depth` for a `depth.Z` channel. The
+    /// channels of the layer with an empty name, the main image, aren't prefixed
+    pub name: String,
+    /// Names of the channels, whose values are interleaved in `values`
+    pub channels: &'static [&'static str],
+    /// Values of the channels, row by row from the top
+    pub values: Vec<f32>,
+}
+
+impl ExrLayer {
+    pub fn new(name: &str, channels: &'static [&'static str], values: &[f32]) -> Self {
+        ExrLayer {
+            name: name.to_string(),
+            channels,
+            values: values.to_vec(),
+        }
+    }
+}
+
+/// Check if images saved to the given path are OpenEXR files, which keep floating point values.
+pub fn is_exr(path: &Path) -> bool {
+    ImageFormat::from_path(path).map_or(false, |format| format == ImageFormat::OpenExr)
+}
+
+/// Write floating point images of the given size as the layers of an OpenEXR file, losslessly
+/// compressed, which keeps their full range and precision for compositing and grading tools.
+pub fn write_exr(
+    width: u32,
+    height: u32,
+    layers: &[ExrLayer],
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let pixels = width as usize * height as usize;
+    let mut channels = SmallVec::new();
+    for layer in layers {
+        let count = layer.channels.len();
+        if layer.values.len() != count * pixels {
+            return Err(format!(
+                "EXR layer \"{}\" holds {} values instead of {}",
+                layer.name,
+                layer.values.len(),
+                count * pixels
+            )
+            .into());
+        }
+        for (channel_idx, channel) in layer.channels.iter().enumerate() {
+            let name = if layer.name.is_empty() {
+                channel.to_string()
+            } else {
+                format!("{}.{}", layer.name, channel)
+            };
+            let samples = layer
+                .values
+                .iter()
+                .skip(channel_idx)
+                .step_by(count)
+                .cloned()
+                .collect();
+            channels.push(AnyChannel::new(name.as_str(), FlatSamples::F32(samples)));
+        }
+    }
+
+    let layer = Layer::new(
+        (width as usize, height as usize),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer).write().to_file(path)?;
+    Ok(())
+}
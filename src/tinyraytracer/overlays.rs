@@ -0,0 +1,166 @@
+use image::{Rgba, RgbaImage};
+
+use super::color;
+use super::math::{length, point3, to_point, to_vec, Point3};
+use super::scene_elems::{text_texture, Camera, Projection};
+
+/// Number of pieces world space segments are split into before being projected, so that they
+/// curve along with projections that don't preserve straight lines.
+const SEGMENT_STEPS: u32 = 64;
+/// Size of the font pixels of overlay labels, in image pixels.
+const LABEL_PIXEL_SIZE: u32 = 2;
+
+const AXIS_COLORS: [Rgba<u8>; 3] = [
+    Rgba([230, 40, 40, 255]),
+    Rgba([40, 200, 40, 255]),
+    Rgba([50, 90, 240, 255]),
+];
+const GRID_COLOR: Rgba<u8> = Rgba([128, 128, 128, 255]);
+const MEASUREMENT_COLOR: Rgba<u8> = Rgba([250, 210, 30, 255]);
+
+/// Square grid lying on a horizontal plane, centered below the world origin.
+#[derive(Debug, Clone)]
+pub struct Grid {
+    /// Height of the plane the grid lies on
+    pub height: f32,
+    /// Distance between grid lines
+    pub spacing: f32,
+    /// Distance from the center of the grid to its edges
+    pub extent: f32,
+}
+
+/// Segment whose length is shown next to it, e.g. to check the size of an imported model.
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub from: Point3,
+    pub to: Point3,
+}
+
+/// Guides drawn on top of the rendered image to help judge the scale and placement of objects.
+/// They are not part of the scene, so they are never shaded, reflected or hidden by objects.
+#[derive(Debug, Clone, Default)]
+pub struct Overlays {
+    /// Length of the world axes drawn from the origin, X in red, Y in green and Z in blue. Zero
+    /// hides them
+    pub axes: f32,
+    pub grid: Option<Grid>,
+    pub measurements: Vec<Measurement>,
+}
+
+/// Draw the overlays into a render of the scene seen from `camera`.
+pub fn draw_overlays(img: &mut RgbaImage, camera: &Camera, overlays: &Overlays) {
+    if let Some(ref grid) = overlays.grid {
+        if grid.spacing > 0. {
+            let lines = (grid.extent / grid.spacing).floor() as i32;
+            let extent = lines as f32 * grid.spacing;
+            for line in -lines..=lines {
+                let offset = line as f32 * grid.spacing;
+                draw_segment(
+                    img,
+                    camera,
+                    point3(offset, grid.height, -extent),
+                    point3(offset, grid.height, extent),
+                    GRID_COLOR,
+                );
+                draw_segment(
+                    img,
+                    camera,
+                    point3(-extent, grid.height, offset),
+                    point3(extent, grid.height, offset),
+                    GRID_COLOR,
+                );
+            }
+        }
+    }
+
+    if overlays.axes > 0. {
+        for (axis, (&color, name)) in AXIS_COLORS.iter().zip(["X", "Y", "Z"].iter()).enumerate() {
+            let mut tip = point3(0., 0., 0.);
+            tip[axis] = overlays.axes;
+            draw_segment(img, camera, point3(0., 0., 0.), tip, color);
+            draw_label(img, camera, tip, name, color);
+        }
+    }
+
+    for measurement in &overlays.measurements {
+        let (from, to) = (measurement.from, measurement.to);
+        draw_segment(img, camera, from, to, MEASUREMENT_COLOR);
+        let length = length(&(to - from));
+        draw_label(
+            img,
+            camera,
+            to_point((to_vec(&from) + to_vec(&to)) / 2.),
+            &format!("{:.2}", length),
+            MEASUREMENT_COLOR,
+        );
+    }
+}
+
+/// Pixel coordinates a point of the scene is seen at.
+fn to_pixel(img: &RgbaImage, camera: &Camera, point: Point3) -> Option<(f32, f32)> {
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    // Inverse of the mapping from pixels to normalized image plane coordinates
+    camera
+        .project(point)
+        .map(|(u, v)| ((u * height + width) / 2., (1. - v) * height / 2.))
+}
+
+fn draw_segment(img: &mut RgbaImage, camera: &Camera, from: Point3, to: Point3, color: Rgba<u8>) {
+    let mut previous = to_pixel(img, camera, from);
+    for step in 1..=SEGMENT_STEPS {
+        let point = from + (to - from) * (step as f32 / SEGMENT_STEPS as f32);
+        let current = to_pixel(img, camera, point);
+        // Pieces the camera only sees one end of are left out
+        if let (Some(start), Some(end)) = (previous, current) {
+            // Pieces crossing the seam of a panorama would wrap around the whole image
+            let wraps = camera.projection == Projection::Equirectangular
+                && (end.0 - start.0).abs() > img.width() as f32 / 2.;
+            if !wraps {
+                draw_line(img, start, end, color);
+            }
+        }
+        previous = current;
+    }
+}
+
+/// Draw a line between two points given in pixels. Parts outside of the image are clipped.
+fn draw_line(img: &mut RgbaImage, start: (f32, f32), end: (f32, f32), color: Rgba<u8>) {
+    let (width, height) = img.dimensions();
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let steps = f32::max(dx.abs(), dy.abs()).ceil();
+    // Points projected close to the edge of the field of view can be very far outside of the
+    // image. Such pieces can't cross it
+    if !steps.is_finite() || steps > 4. * (width + height) as f32 {
+        return;
+    }
+    let steps = u32::max(steps as u32, 1);
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let (x, y) = (start.0 + dx * t, start.1 + dy * t);
+        if x >= 0. && y >= 0. && (x as u32) < width && (y as u32) < height {
+            img.put_pixel(x as u32, y as u32, color);
+        }
+    }
+}
+
+/// Draw text with its lower left corner next to a point of the scene.
+fn draw_label(img: &mut RgbaImage, camera: &Camera, point: Point3, text: &str, color: Rgba<u8>) {
+    let (x, y) = match to_pixel(img, camera, point) {
+        Some(pixel) => pixel,
+        None => return,
+    };
+    let label = text_texture(text, LABEL_PIXEL_SIZE, color, Rgba([0, 0, 0, 0]));
+    let (x, y) = (x as i64 + 2, y as i64 - label.height() as i64 - 2);
+    for (label_x, label_y, &pixel) in label.enumerate_pixels() {
+        let (img_x, img_y) = (x + label_x as i64, y + label_y as i64);
+        // Only the glyphs are drawn, the label's background is transparent
+        if pixel[3] > 0.
+            && img_x >= 0
+            && img_y >= 0
+            && img_x < img.width() as i64
+            && img_y < img.height() as i64
+        {
+            img.put_pixel(img_x as u32, img_y as u32, color::to_srgb8(pixel));
+        }
+    }
+}
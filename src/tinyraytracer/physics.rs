@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use rapier3d::dynamics::{RigidBodyHandle, RigidBodySet};
+
+use super::math::{identity, similarity};
+use super::scene::Scene;
+use super::scene_elems::{TraceObj, Transformed};
+
+/// Object of the scene moved by a rigid body.
+#[derive(Debug)]
+struct Binding {
+    body: RigidBodyHandle,
+    /// Object in the local space of the body
+    object: Arc<dyn TraceObj>,
+    scale: f32,
+    /// Index of the object's instance in the scene's objects
+    index: usize,
+}
+
+/// Keeps scene objects in sync with rigid bodies simulated by rapier. Each bound object is added
+/// to the scene as an instance placed where its body is, and `sync` moves the instances to the
+/// new positions of their bodies. Stepping the simulation then syncing the scene from the update
+/// callback of `render_animation` renders physically animated scenes.
+#[derive(Debug, Default)]
+pub struct PhysicsSync {
+    bindings: Vec<Binding>,
+}
+
+impl PhysicsSync {
+    pub fn new() -> Self {
+        PhysicsSync::default()
+    }
+
+    /// Add an object moved by a rigid body to the scene. The object is given in the local space
+    /// of the body, e.g. a sphere centered at the origin for a ball, and is uniformly scaled by
+    /// `scale` before being placed. Sharing the object between several bodies doesn't duplicate
+    /// its geometry.
+    pub fn bind(
+        &mut self,
+        scene: &mut Scene,
+        bodies: &RigidBodySet,
+        body: RigidBodyHandle,
+        object: Arc<dyn TraceObj>,
+        scale: f32,
+    ) {
+        let binding = Binding {
+            body,
+            object,
+            scale,
+            index: scene.objs.len(),
+        };
+        scene.objs.push(Box::new(Transformed::new(
+            binding.object.clone(),
+            identity(),
+        )));
+        self.bindings.push(binding);
+        self.sync_binding(self.bindings.len() - 1, scene, bodies);
+    }
+
+    /// Move the bound objects to the current positions of their bodies. Objects whose body was
+    /// removed from the set are left where they were.
+    pub fn sync(&self, scene: &mut Scene, bodies: &RigidBodySet) {
+        for binding in 0..self.bindings.len() {
+            self.sync_binding(binding, scene, bodies);
+        }
+    }
+
+    fn sync_binding(&self, binding: usize, scene: &mut Scene, bodies: &RigidBodySet) {
+        let binding = &self.bindings[binding];
+        let body = match bodies.get(binding.body) {
+            Some(body) => body,
+            None => return,
+        };
+        let transform = similarity(*body.position(), binding.scale);
+        scene.objs[binding.index] = Box::new(Transformed::new(binding.object.clone(), transform));
+    }
+}
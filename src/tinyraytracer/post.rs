@@ -0,0 +1,90 @@
+//! Image space effects applied to renders before they are tone mapped into the output image.
+use std::fmt::Debug;
+use std::mem;
+use std::sync::Arc;
+
+use image::{Rgb, Rgb32FImage};
+
+/// Effect applied to a whole linear, high dynamic range render, e.g. sharpening or a custom tone
+/// curve. Passes see the image in the working color space, before exposure and tone mapping.
+pub trait PostPass: Debug + Send + Sync {
+    /// Process `input` into `output`, which has the same dimensions.
+    fn apply(&self, input: &Rgb32FImage, output: &mut Rgb32FImage);
+}
+
+/// Ordered list of post passes, each one processing the output of the previous one. Renders are
+/// post processed every time a pass of `render_progressive` completes, so previews and snapshots
+/// show the effects too.
+#[derive(Debug, Clone, Default)]
+pub struct PostChain {
+    passes: Vec<Arc<dyn PostPass>>,
+}
+
+impl PostChain {
+    pub fn new() -> Self {
+        PostChain::default()
+    }
+
+    /// Add a pass at the end of the chain.
+    pub fn push(&mut self, pass: Arc<dyn PostPass>) {
+        self.passes.push(pass);
+    }
+
+    /// Same as `push`, for building chains in a single expression.
+    pub fn with(mut self, pass: Arc<dyn PostPass>) -> Self {
+        self.push(pass);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    /// Run every pass of the chain in order over an image. Return the result of the last pass.
+    pub fn apply(&self, img: &Rgb32FImage) -> Rgb32FImage {
+        let mut input = img.clone();
+        let mut output = Rgb32FImage::new(img.width(), img.height());
+        for pass in &self.passes {
+            pass.apply(&input, &mut output);
+            // The output of a pass is the input of the next one
+            mem::swap(&mut input, &mut output);
+        }
+        input
+    }
+}
+
+/// Unsharp mask: boost the difference between each pixel and the average of its 3x3
+/// neighbourhood, which makes edges and textures crisper. An `amount` of 0 leaves the image
+/// unchanged, 0.5 is a moderate sharpening.
+#[derive(Debug, Clone, Copy)]
+pub struct Sharpen {
+    pub amount: f32,
+}
+
+impl PostPass for Sharpen {
+    fn apply(&self, input: &Rgb32FImage, output: &mut Rgb32FImage) {
+        let (width, height) = input.dimensions();
+        for (x, y, pixel) in output.enumerate_pixels_mut() {
+            let mut blurred = [0.; 3];
+            let mut count = 0.;
+            // Neighbours beyond the edges of the image are left out
+            for ny in y.saturating_sub(1)..u32::min(y + 2, height) {
+                for nx in x.saturating_sub(1)..u32::min(x + 2, width) {
+                    let neighbour = input.get_pixel(nx, ny);
+                    for ch in 0..3 {
+                        blurred[ch] += neighbour[ch];
+                    }
+                    count += 1.;
+                }
+            }
+            let center = input.get_pixel(x, y);
+            let mut sharpened = [0.; 3];
+            for ch in 0..3 {
+                let detail = center[ch] - blurred[ch] / count;
+                // Ringing must not produce negative light
+                sharpened[ch] = f32::max(0., center[ch] + self.amount * detail);
+            }
+            *pixel = Rgb(sharpened);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Orthonormal bases and warping functions turning uniform random numbers in [0, 1) into samples
+//! distributed over common domains. Taking the random numbers as arguments, rather than a random
+//! number generator, lets callers feed them stratified or low discrepancy sequences.
+use std::f32::consts::PI;
+
+use super::math::{cross, dot, normalize, vec3, Vec3};
+
+/// Two unit vectors perpendicular to the given normal and to each other.
+pub fn orthonormal_basis(normal: Vec3) -> (Vec3, Vec3) {
+    let helper_axis = if normal.x.abs() > 0.9 {
+        vec3(0., 1., 0.)
+    } else {
+        vec3(1., 0., 0.)
+    };
+    let tangent = normalize(&cross(&helper_axis, &normal));
+    (tangent, cross(&normal, &tangent))
+}
+
+/// Element of the Halton low discrepancy sequence along the dimension with the given prime base:
+/// the radical inverse of the index, mirroring its digits in that base around the radix point.
+pub fn halton(index: u32, base: u32) -> f32 {
+    let mut index = index;
+    let mut inverse = 0.;
+    let mut digit_weight = 1. / base as f32;
+    while index > 0 {
+        inverse += (index % base) as f32 * digit_weight;
+        index /= base;
+        digit_weight /= base as f32;
+    }
+    inverse
+}
+
+/// Orthonormal basis around a unit normal. Directions sampled in local space, where the normal is
+/// the z axis, are converted to world space with `to_world`.
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    pub normal: Vec3,
+}
+
+impl Onb {
+    pub fn from_normal(normal: Vec3) -> Self {
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        Onb {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_world(&self, local: Vec3) -> Vec3 {
+        self.tangent * local.x + self.bitangent * local.y + self.normal * local.z
+    }
+
+    pub fn to_local(&self, world: Vec3) -> Vec3 {
+        vec3(
+            dot(&world, &self.tangent),
+            dot(&world, &self.bitangent),
+            dot(&world, &self.normal),
+        )
+    }
+}
+
+/// Point uniformly distributed over the unit disk, in polar coordinates: `u` picks the distance
+/// to the center (through a square root, so that outer rings get as many samples as their area
+/// requires) and `v` the angle.
+pub fn uniform_disk(u: f32, v: f32) -> (f32, f32) {
+    let radius = u.sqrt();
+    let angle = 2. * PI * v;
+    (radius * angle.cos(), radius * angle.sin())
+}
+
+/// Direction uniformly distributed over the unit sphere. Its pdf is `UNIFORM_SPHERE_PDF`.
+pub fn uniform_sphere(u: f32, v: f32) -> Vec3 {
+    let z = 1. - 2. * u;
+    let radius = f32::sqrt(f32::max(0., 1. - z * z));
+    let angle = 2. * PI * v;
+    vec3(radius * angle.cos(), radius * angle.sin(), z)
+}
+
+pub const UNIFORM_SPHERE_PDF: f32 = 1. / (4. * PI);
+
+/// Barycentric weights of a point uniformly distributed over a triangle.
+pub fn uniform_triangle(u: f32, v: f32) -> [f32; 3] {
+    let sqrt_u = u.sqrt();
+    let (weight_b, weight_c) = (sqrt_u * (1. - v), sqrt_u * v);
+    [1. - weight_b - weight_c, weight_b, weight_c]
+}
+
+/// Local direction in the hemisphere around the z axis, distributed proportionally to its cosine
+/// with the axis. Projecting uniform disk samples onto the hemisphere gives that distribution.
+pub fn cosine_hemisphere(u: f32, v: f32) -> Vec3 {
+    let (x, y) = uniform_disk(u, v);
+    vec3(x, y, f32::sqrt(f32::max(0., 1. - u)))
+}
+
+/// Pdf of `cosine_hemisphere` for a direction with the given cosine with the z axis.
+pub fn cosine_hemisphere_pdf(cos: f32) -> f32 {
+    f32::max(cos, 0.) / PI
+}
+
+/// Local direction around the z axis distributed proportionally to its cosine with the axis
+/// raised to `exponent`, like a Phong specular lobe.
+pub fn power_cosine(u: f32, v: f32, exponent: f32) -> Vec3 {
+    let cos = f32::powf(u, 1. / (exponent + 1.));
+    let sin = f32::sqrt(f32::max(0., 1. - cos * cos));
+    let angle = 2. * PI * v;
+    vec3(sin * angle.cos(), sin * angle.sin(), cos)
+}
+
+/// Pdf of `power_cosine` for a direction with the given cosine with the z axis.
+pub fn power_cosine_pdf(cos: f32, exponent: f32) -> f32 {
+    (exponent + 1.) / (2. * PI) * f32::powf(f32::max(0., cos), exponent)
+}
+
+/// GGX (Trowbridge-Reitz) normal distribution of microfacets with the given roughness `alpha`,
+/// for a microfacet normal with the given cosine with the surface normal.
+pub fn ggx_distribution(cos: f32, alpha: f32) -> f32 {
+    if cos <= 0. {
+        return 0.;
+    }
+    let alpha_sq = alpha * alpha;
+    let denom = cos * cos * (alpha_sq - 1.) + 1.;
+    alpha_sq / (PI * denom * denom)
+}
+
+/// Local microfacet normal around the z axis distributed according to the GGX distribution,
+/// weighted by its cosine with the axis. Its pdf is `ggx_normal_pdf`.
+pub fn ggx_normal(u: f32, v: f32, alpha: f32) -> Vec3 {
+    let cos_sq = (1. - u) / (1. + (alpha * alpha - 1.) * u);
+    let cos = cos_sq.sqrt();
+    let sin = f32::sqrt(f32::max(0., 1. - cos_sq));
+    let angle = 2. * PI * v;
+    vec3(sin * angle.cos(), sin * angle.sin(), cos)
+}
+
+/// Pdf of `ggx_normal` for a microfacet normal with the given cosine with the z axis. Reflected
+/// directions have a pdf of this value divided by 4 |wo · h|.
+pub fn ggx_normal_pdf(cos: f32, alpha: f32) -> f32 {
+    ggx_distribution(cos, alpha) * cos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::math::length;
+    use super::*;
+
+    /// Side of the grids of random number pairs the tests sample with.
+    const GRID_SIZE: u32 = 256;
+
+    /// Pairs of numbers at the centers of the cells of a regular grid over [0, 1)², stratifying
+    /// the unit square so that estimates converge quickly and don't depend on a seed.
+    fn grid() -> impl Iterator<Item = (f32, f32)> {
+        (0..GRID_SIZE * GRID_SIZE).map(|idx| {
+            let cell = |coord: u32| (coord as f32 + 0.5) / GRID_SIZE as f32;
+            (cell(idx % GRID_SIZE), cell(idx / GRID_SIZE))
+        })
+    }
+
+    /// Monte Carlo estimate of the integral of a function of directions over the unit sphere,
+    /// from directions uniformly distributed over it.
+    fn integrate_over_sphere<F: Fn(Vec3) -> f32>(f: F) -> f32 {
+        let sum: f64 = grid().map(|(u, v)| f(uniform_sphere(u, v)) as f64).sum();
+        (sum / (GRID_SIZE * GRID_SIZE) as f64) as f32 / UNIFORM_SPHERE_PDF
+    }
+
+    fn assert_close(value: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            value,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn onb_is_orthonormal() {
+        let normals = [
+            vec3(1., 0., 0.),
+            vec3(0., 1., 0.),
+            -vec3(0., 0., 1.),
+            normalize(&vec3(0.95, 0.3, 0.1)),
+            normalize(&vec3(-1., 2., 3.)),
+        ];
+        for &normal in &normals {
+            let onb = Onb::from_normal(normal);
+            for axis in &[onb.tangent, onb.bitangent, onb.normal] {
+                assert_close(length(&axis), 1., 1e-5);
+            }
+            assert_close(dot(&onb.tangent, &onb.bitangent), 0., 1e-5);
+            assert_close(dot(&onb.tangent, &onb.normal), 0., 1e-5);
+            assert_close(dot(&onb.bitangent, &onb.normal), 0., 1e-5);
+            // Right-handed, so that local z is the normal
+            assert!(length(&(cross(&onb.tangent, &onb.bitangent) - normal)) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn onb_round_trip() {
+        let onb = Onb::from_normal(normalize(&vec3(0.2, -0.5, 0.8)));
+        let local = vec3(0.3, -1.2, 2.5);
+        assert!(length(&(onb.to_local(onb.to_world(local)) - local)) < 1e-5);
+        assert!(length(&(onb.to_world(onb.to_local(local)) - local)) < 1e-5);
+        assert!(length(&(onb.to_world(vec3(0., 0., 1.)) - onb.normal)) < 1e-6);
+    }
+
+    #[test]
+    fn samples_stay_in_their_domain() {
+        for (u, v) in grid() {
+            let (x, y) = uniform_disk(u, v);
+            assert!(x * x + y * y <= 1. + 1e-6);
+
+            assert_close(length(&uniform_sphere(u, v)), 1., 1e-5);
+
+            let weights = uniform_triangle(u, v);
+            assert!(weights
+                .iter()
+                .all(|&weight| weight >= -1e-6 && weight <= 1. + 1e-6));
+            assert_close(weights.iter().sum(), 1., 1e-5);
+        }
+    }
+
+    #[test]
+    fn cosine_hemisphere_pdf_integrates_to_one() {
+        let integral = integrate_over_sphere(|dir| cosine_hemisphere_pdf(dir.z));
+        assert_close(integral, 1., 0.01);
+        for (u, v) in grid() {
+            assert!(cosine_hemisphere(u, v).z >= 0.);
+        }
+    }
+
+    #[test]
+    fn power_cosine_pdf_integrates_to_one() {
+        for &exponent in &[1., 10., 20.] {
+            let integral = integrate_over_sphere(|dir| power_cosine_pdf(dir.z, exponent));
+            assert_close(integral, 1., 0.01);
+        }
+    }
+
+    #[test]
+    fn ggx_normal_pdf_integrates_to_one() {
+        for &alpha in &[0.3, 0.5, 1.] {
+            let integral = integrate_over_sphere(|dir| ggx_normal_pdf(dir.z, alpha));
+            assert_close(integral, 1., 0.01);
+        }
+    }
+
+    #[test]
+    fn halton_known_values() {
+        let base_2 = [0., 0.5, 0.25, 0.75, 0.125, 0.625];
+        for (index, &expected) in base_2.iter().enumerate() {
+            assert_close(halton(index as u32, 2), expected, 1e-6);
+        }
+        let base_3 = [0., 1. / 3., 2. / 3., 1. / 9., 4. / 9., 7. / 9.];
+        for (index, &expected) in base_3.iter().enumerate() {
+            assert_close(halton(index as u32, 3), expected, 1e-6);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+pub mod loader;
+
+use image::{Rgba, Rgba32FImage};
+
+use super::overlays::Overlays;
+use super::scene_elems::{Aabb, Camera, Light, SectionPlane, TraceObj};
+
+/// Everything needed to render an image: objects, light sources, camera and environment map.
+pub struct Scene {
+    pub objs: Vec<Box<dyn TraceObj>>,
+    pub lights: Vec<Light>,
+    pub camera: Camera,
+    /// Environment map, with linear color channels
+    pub background: Rgba32FImage,
+    /// Plane cutting away part of the scene, e.g. to look inside a model
+    pub section: Option<SectionPlane>,
+    /// Guides drawn on top of the rendered image
+    pub overlays: Overlays,
+}
+
+/// Number of rows of the environment maps made for gradient backgrounds.
+const GRADIENT_ROWS: u32 = 256;
+
+/// Environment surrounding a scene which has no environment map, or whose environment map can't
+/// be loaded. Colors are linear.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type")]
+pub enum BackgroundFallback {
+    /// The same color in every direction
+    Color { color: [f32; 3] },
+    /// Colors blended from straight down to straight up, e.g. from the ground to the sky
+    Gradient { bottom: [f32; 3], top: [f32; 3] },
+}
+
+impl Default for BackgroundFallback {
+    /// The light blue background of the original Tiny Raytracer.
+    fn default() -> Self {
+        BackgroundFallback::Color {
+            color: [0.2, 0.7, 0.8],
+        }
+    }
+}
+
+impl BackgroundFallback {
+    /// Environment map showing the fallback, to be used as the background of a scene.
+    pub fn to_image(&self) -> Rgba32FImage {
+        match *self {
+            BackgroundFallback::Color { color } => {
+                Rgba32FImage::from_pixel(1, 1, Rgba([color[0], color[1], color[2], 1.]))
+            }
+            BackgroundFallback::Gradient { bottom, top } => {
+                // Environment maps are looked up from their first row straight down to their
+                // last one straight up
+                Rgba32FImage::from_fn(1, GRADIENT_ROWS, |_, y| {
+                    let t = y as f32 / (GRADIENT_ROWS - 1) as f32;
+                    let blend = |channel: usize| bottom[channel] * (1. - t) + top[channel] * t;
+                    Rgba([blend(0), blend(1), blend(2), 1.])
+                })
+            }
+        }
+    }
+}
+
+impl Scene {
+    /// Box containing all bounded objects, None if there are none.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.objs.iter().filter_map(|obj| obj.bounding_box()).fold(
+            None,
+            |bbox: Option<Aabb>, obj_bbox| {
+                Some(bbox.map_or(obj_bbox, |bbox| bbox.union(&obj_bbox)))
+            },
+        )
+    }
+}
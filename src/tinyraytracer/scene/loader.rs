@@ -0,0 +1,1486 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use image::{self, Rgba};
+use nalgebra::{Point3, Similarity3, Vector3};
+use ron;
+use serde_json;
+
+use super::super::color::{self, load_image, ColorEncoding, ColorSpace};
+use super::super::noise::Noise;
+use super::super::overlays::{Grid, Measurement, Overlays};
+use super::super::scene_elems::{
+    AreaLight, Attenuation, Billboard, BoxObj, Camera, CheckerFloorMaterial, Curve, DetailMaterial,
+    Dirt, DirtMaterial, DirtSource, Disk, HairMaterial, ImageTextureMaterial, Light,
+    MarbleMaterial, Material, MeshImportOptions, MicrofacetMaterial, Named, PlainMaterial, Plane,
+    PointCloud, PointShape, Projection, Proxied, RayVisibility, Rectangle, Sdf, SdfObject,
+    SectionPlane, Sphere, SurfaceDetail, Texture, TextureTransform, Torus, TraceObj, Transformed,
+    TransformedTextureMaterial, Triangle, TriangleMesh, TurbulenceMaterial, WoodMaterial,
+};
+use super::super::texture_cache::TextureCache;
+use super::super::RenderSettings;
+use super::{BackgroundFallback, Scene};
+
+/// Number of texture caches created so far, which tells apart the directories of the caches of
+/// scenes loaded by the same process.
+static TEXTURE_CACHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Description of a whole scene as stored in a scene file. Paths to external assets (models and
+/// environment map) are relative to the scene file.
+#[derive(Debug, Deserialize)]
+pub struct SceneDesc {
+    pub camera: CameraDesc,
+    pub materials: HashMap<String, MaterialDesc>,
+    pub objects: Vec<ObjectDesc>,
+    pub lights: Vec<LightDesc>,
+    /// Environment map image, if any
+    #[serde(default)]
+    pub background: Option<String>,
+    /// Color space of the environment map. Defaults to sRGB
+    #[serde(default)]
+    pub background_color_space: ColorSpace,
+    /// Background used without an environment map, or when it can't be loaded
+    #[serde(default)]
+    pub background_fallback: BackgroundFallback,
+    #[serde(default)]
+    pub section: Option<SectionDesc>,
+    #[serde(default)]
+    pub overlays: OverlaysDesc,
+    #[serde(default)]
+    pub render: RenderSettings,
+    /// Memory budget for image textures, in megabytes. Without one (the default) textures are
+    /// kept whole in memory, otherwise they are split into tiles loaded from disk when sampled
+    #[serde(default)]
+    pub texture_budget: Option<usize>,
+}
+
+/// Guides drawn on top of the rendered image.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct OverlaysDesc {
+    /// Length of the world axes. Zero (the default) hides them
+    pub axes: f32,
+    pub grid: Option<GridDesc>,
+    pub measurements: Vec<MeasurementDesc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GridDesc {
+    #[serde(default)]
+    pub height: f32,
+    pub spacing: f32,
+    pub extent: f32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MeasurementDesc {
+    pub from: [f32; 3],
+    pub to: [f32; 3],
+}
+
+/// Plane cutting away the geometry on the side its normal points to.
+#[derive(Debug, Deserialize)]
+pub struct SectionDesc {
+    pub point: [f32; 3],
+    pub normal: [f32; 3],
+    /// Material capping the cut through closed objects. Cut objects are left open without it
+    #[serde(default)]
+    pub cap: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CameraDesc {
+    /// Radians
+    pub fov: f32,
+    pub position: [f32; 3],
+    #[serde(default = "default_projection")]
+    pub projection: Projection,
+    /// Height of the area seen by an orthographic camera
+    #[serde(default = "default_view_height")]
+    pub view_height: f32,
+    /// Lens diameter. Zero (the default) keeps everything in focus
+    #[serde(default)]
+    pub aperture: f32,
+    #[serde(default = "default_focus_dist")]
+    pub focus_dist: f32,
+    /// Distance of the near clipping plane
+    #[serde(default)]
+    pub near: f32,
+    /// Distance of the far clipping plane. Nothing is clipped by default
+    #[serde(default = "default_far")]
+    pub far: f32,
+}
+
+fn default_focus_dist() -> f32 {
+    1.
+}
+
+fn default_far() -> f32 {
+    f32::INFINITY
+}
+
+fn default_view_height() -> f32 {
+    10.
+}
+
+fn default_projection() -> Projection {
+    Projection::Perspective
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum MaterialDesc {
+    Plain {
+        color: [u8; 4],
+        albedo: [f32; 4],
+        spec_exponent: f32,
+        refr_ratio: f32,
+        /// Absorption coefficient per color channel, for tinted glass
+        #[serde(default)]
+        absorption: [f32; 3],
+        /// Dispersion of refractive materials. Zero (the default) disables it
+        #[serde(default)]
+        abbe_number: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    CheckerFloor {
+        color0: [u8; 4],
+        color1: [u8; 4],
+        albedo: [f32; 4],
+        spec_exponent: f32,
+        refr_ratio: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    /// Color looked up in an image through the UV coordinates of the surface
+    ImageTexture {
+        path: String,
+        /// Encoding of the image's colors. Defaults to sRGB
+        #[serde(default)]
+        encoding: ColorEncoding,
+        /// Color space of the image's colors. Defaults to sRGB
+        #[serde(default)]
+        color_space: ColorSpace,
+        albedo: [f32; 4],
+        spec_exponent: f32,
+        refr_ratio: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    /// Physically based material with a GGX microfacet BRDF
+    Microfacet {
+        color: [u8; 4],
+        /// 0 for dielectrics, 1 for metals
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_roughness")]
+        roughness: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    /// Kajiya-Kay strand shading, for `Curve` objects
+    Hair {
+        color: [u8; 4],
+        diffuse: f32,
+        specular: f32,
+        spec_exponent: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    /// Veins of `color1` across `color0`, warped by turbulence
+    Marble {
+        color0: [u8; 4],
+        color1: [u8; 4],
+        #[serde(default = "default_noise_scale")]
+        scale: f32,
+        #[serde(default = "default_marble_turbulence")]
+        turbulence: f32,
+        #[serde(default = "default_noise_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u64,
+        albedo: [f32; 4],
+        spec_exponent: f32,
+        refr_ratio: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    /// Rings around the Y axis, from `color0` to `color1`
+    Wood {
+        color0: [u8; 4],
+        color1: [u8; 4],
+        #[serde(default = "default_noise_scale")]
+        scale: f32,
+        #[serde(default = "default_wood_rings")]
+        rings: f32,
+        #[serde(default = "default_wood_turbulence")]
+        turbulence: f32,
+        #[serde(default)]
+        seed: u64,
+        albedo: [f32; 4],
+        spec_exponent: f32,
+        refr_ratio: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    /// Cloudy blend between `color0` and `color1`
+    Turbulence {
+        color0: [u8; 4],
+        color1: [u8; 4],
+        #[serde(default = "default_noise_scale")]
+        scale: f32,
+        #[serde(default = "default_noise_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u64,
+        albedo: [f32; 4],
+        spec_exponent: f32,
+        refr_ratio: f32,
+        #[serde(default)]
+        visibility: RayVisibility,
+    },
+    /// Another material whose shading normal is perturbed by a tangent-space normal map
+    NormalMap {
+        /// Name of the material the normal map is applied to
+        base: String,
+        /// Image whose channels store normals, read with a linear encoding
+        path: String,
+        #[serde(default = "default_detail_strength")]
+        strength: f32,
+    },
+    /// Another material made bumpy by noise
+    Bump {
+        /// Name of the material the bumps are applied to
+        base: String,
+        /// Number of bumps per unit of distance
+        #[serde(default = "default_noise_scale")]
+        scale: f32,
+        #[serde(default = "default_bump_strength")]
+        strength: f32,
+        #[serde(default)]
+        seed: u64,
+    },
+    /// Another material darkened by dirt in its crevices. The occlusion is read from a baked
+    /// texture if one is given, and estimated at render time otherwise
+    Dirty {
+        /// Name of the material the dirt is applied to, which can't be dirty itself
+        base: String,
+        #[serde(default = "default_dirt_color")]
+        color: [u8; 4],
+        #[serde(default = "default_dirt_strength")]
+        strength: f32,
+        /// Baked occlusion map, white where the surface is clean
+        #[serde(default)]
+        texture: Option<String>,
+        /// Distance within which objects occlude the surface, for estimated occlusion
+        #[serde(default = "default_dirt_distance")]
+        distance: f32,
+        /// Number of occlusion rays per hit, for estimated occlusion
+        #[serde(default = "default_dirt_samples")]
+        samples: u32,
+    },
+    /// Another material whose texture is scaled, rotated (in radians) and moved, across its UV
+    /// coordinates for image textures and across the XZ plane for procedural patterns
+    TextureTransform {
+        /// Name of the material whose texture is transformed
+        base: String,
+        #[serde(default = "default_texture_scale")]
+        scale: [f32; 2],
+        #[serde(default)]
+        rotation: f32,
+        #[serde(default)]
+        offset: [f32; 2],
+    },
+}
+
+impl MaterialDesc {
+    /// Name of the material a wrapper material is based on, None for other materials.
+    fn base(&self) -> Option<&str> {
+        match self {
+            MaterialDesc::Dirty { base, .. }
+            | MaterialDesc::NormalMap { base, .. }
+            | MaterialDesc::Bump { base, .. }
+            | MaterialDesc::TextureTransform { base, .. } => Some(base.as_str()),
+            _ => None,
+        }
+    }
+}
+
+fn default_roughness() -> f32 {
+    0.5
+}
+
+fn default_noise_scale() -> f32 {
+    1.
+}
+
+fn default_noise_octaves() -> u32 {
+    6
+}
+
+fn default_marble_turbulence() -> f32 {
+    5.
+}
+
+fn default_wood_rings() -> f32 {
+    8.
+}
+
+fn default_wood_turbulence() -> f32 {
+    1.
+}
+
+fn default_detail_strength() -> f32 {
+    1.
+}
+
+fn default_bump_strength() -> f32 {
+    0.1
+}
+
+fn default_dirt_color() -> [u8; 4] {
+    [60, 45, 30, 255]
+}
+
+fn default_dirt_strength() -> f32 {
+    1.
+}
+
+fn default_dirt_distance() -> f32 {
+    0.5
+}
+
+fn default_dirt_samples() -> u32 {
+    16
+}
+
+fn default_texture_scale() -> [f32; 2] {
+    [1., 1.]
+}
+
+/// Scene objects. Materials are referenced by their name in the scene's materials table.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum ObjectDesc {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+        material: String,
+    },
+    Plane {
+        p0: [f32; 3],
+        normal: [f32; 3],
+        material: String,
+    },
+    /// Flat disk, visible from the side its normal points to
+    Disk {
+        center: [f32; 3],
+        normal: [f32; 3],
+        radius: f32,
+        material: String,
+    },
+    Rectangle {
+        low_left: [f32; 3],
+        up_right: [f32; 3],
+        material: String,
+    },
+    /// Axis-aligned box spanned by two opposite corners
+    Box {
+        min: [f32; 3],
+        max: [f32; 3],
+        material: String,
+    },
+    Torus {
+        center: [f32; 3],
+        /// Axis of symmetry, perpendicular to the ring
+        axis: [f32; 3],
+        major_radius: f32,
+        minor_radius: f32,
+        material: String,
+    },
+    /// Strand following a cubic Bézier curve from its root (first point) to its tip (last point)
+    Curve {
+        points: [[f32; 3]; 4],
+        root_radius: f32,
+        /// Zero (the default) tapers the strand to a point
+        #[serde(default)]
+        tip_radius: f32,
+        material: String,
+    },
+    Triangle {
+        a: [f32; 3],
+        b: [f32; 3],
+        c: [f32; 3],
+        material: String,
+        /// Make the back face visible too
+        #[serde(default)]
+        two_sided: bool,
+    },
+    /// Shape defined by a signed distance function, e.g. smooth blends and fractals
+    Sdf { shape: SdfDesc, material: String },
+    /// Triangle mesh loaded from an .obj file
+    Mesh {
+        path: String,
+        material: String,
+        /// Axis conversion for models exported with a different orientation, and subdivision of
+        /// low-poly models
+        #[serde(default)]
+        import: MeshImportOptions,
+        /// Placement of the model in the scene, applied after the import conversion
+        #[serde(default)]
+        transform: Option<TransformDesc>,
+        /// Make the back faces visible too
+        #[serde(default)]
+        two_sided: bool,
+        /// Simplified version of the model, imported and placed the same way, tested instead of
+        /// the full model by shadow, reflection and refraction rays
+        #[serde(default)]
+        proxy: Option<String>,
+    },
+    /// Point cloud loaded from an ASCII .xyz file, with optional per-point colors
+    PointCloud {
+        path: String,
+        /// Radius of the spheres or disks drawn at each point
+        radius: f32,
+        /// Draw the points as disks facing the camera instead of spheres
+        #[serde(default)]
+        disks: bool,
+        material: String,
+    },
+    /// Text facing the camera, `height` units tall, e.g. to annotate objects
+    Label {
+        text: String,
+        center: [f32; 3],
+        height: f32,
+        #[serde(default = "default_label_color")]
+        color: [u8; 4],
+        #[serde(default = "default_label_background")]
+        background: [u8; 4],
+    },
+    /// Copies of an object placed with different transforms. The object is only built once, so
+    /// instancing a mesh doesn't duplicate its triangles
+    Instances {
+        object: Box<ObjectDesc>,
+        transforms: Vec<TransformDesc>,
+    },
+    /// Object given a name, shown by the object ID pass. All the objects the description makes
+    /// share the name
+    Named {
+        name: String,
+        object: Box<ObjectDesc>,
+    },
+}
+
+/// Transform placing an instance or a model: scale first, then rotate, then translate.
+#[derive(Debug, Deserialize)]
+pub struct TransformDesc {
+    #[serde(default)]
+    pub translation: [f32; 3],
+    /// Rotation axis scaled by the rotation angle in radians
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    /// Uniform scale
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+}
+
+fn default_label_color() -> [u8; 4] {
+    [0, 0, 0, 255]
+}
+
+fn default_label_background() -> [u8; 4] {
+    [255, 255, 255, 255]
+}
+
+fn default_scale() -> f32 {
+    1.
+}
+
+fn similarity(desc: &TransformDesc) -> Similarity3<f32> {
+    Similarity3::new(
+        Vector3::from(desc.translation),
+        Vector3::from(desc.rotation),
+        desc.scale,
+    )
+}
+
+/// Signed distance function of an `Sdf` object.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum SdfDesc {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+    },
+    RoundBox {
+        center: [f32; 3],
+        half_extents: [f32; 3],
+        radius: f32,
+    },
+    /// Torus lying on the xz plane
+    Torus {
+        center: [f32; 3],
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    SmoothUnion {
+        a: Box<SdfDesc>,
+        b: Box<SdfDesc>,
+        smoothness: f32,
+    },
+    Mandelbulb {
+        center: [f32; 3],
+        scale: f32,
+        #[serde(default = "default_mandelbulb_power")]
+        power: f32,
+        #[serde(default = "default_mandelbulb_iterations")]
+        iterations: u32,
+    },
+}
+
+fn default_mandelbulb_power() -> f32 {
+    8.
+}
+
+fn default_mandelbulb_iterations() -> u32 {
+    12
+}
+
+fn sdf(desc: &SdfDesc) -> Sdf {
+    match *desc {
+        SdfDesc::Sphere { center, radius } => Sdf::Sphere {
+            center: point(center),
+            radius,
+        },
+        SdfDesc::RoundBox {
+            center,
+            half_extents,
+            radius,
+        } => Sdf::RoundBox {
+            center: point(center),
+            half_extents: Vector3::from(half_extents),
+            radius,
+        },
+        SdfDesc::Torus {
+            center,
+            major_radius,
+            minor_radius,
+        } => Sdf::Torus {
+            center: point(center),
+            major_radius,
+            minor_radius,
+        },
+        SdfDesc::SmoothUnion {
+            ref a,
+            ref b,
+            smoothness,
+        } => Sdf::SmoothUnion {
+            a: Box::new(sdf(a)),
+            b: Box::new(sdf(b)),
+            smoothness,
+        },
+        SdfDesc::Mandelbulb {
+            center,
+            scale,
+            power,
+            iterations,
+        } => Sdf::Mandelbulb {
+            center: point(center),
+            scale,
+            power,
+            iterations,
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LightDesc {
+    /// Position of a point light, or center of an area light
+    pub position: [f32; 3],
+    pub intensity: f32,
+    /// Linear RGB color of the light. White by default
+    #[serde(default = "default_light_color")]
+    pub color: [f32; 3],
+    /// Blackbody temperature in kelvin, replacing the color when given
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Falloff with distance. None by default
+    #[serde(default)]
+    pub attenuation: Attenuation,
+    #[serde(default)]
+    pub area: Option<AreaLightDesc>,
+}
+
+fn default_light_color() -> [f32; 3] {
+    [1., 1., 1.]
+}
+
+/// Shape of an area light, centered at the light's position.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum AreaLightDesc {
+    /// Parallelogram spanned by two edge vectors
+    Rect {
+        width: [f32; 3],
+        height: [f32; 3],
+    },
+    Disk {
+        normal: [f32; 3],
+        radius: f32,
+    },
+}
+
+fn point(coords: [f32; 3]) -> Point3<f32> {
+    Point3::from(coords)
+}
+
+/// Build the objects described by `object` and add them to `objs`. Most descriptions make a
+/// single object, but instances make one per transform. Labels are turned towards the camera
+/// position.
+fn add_objects(
+    object: ObjectDesc,
+    base_dir: &Path,
+    camera_position: Point3<f32>,
+    get_material: &dyn Fn(&str) -> Result<Arc<dyn Material>, Box<dyn Error>>,
+    objs: &mut Vec<Box<dyn TraceObj>>,
+) -> Result<(), Box<dyn Error>> {
+    let obj: Box<dyn TraceObj> = match object {
+        ObjectDesc::Instances { object, transforms } => {
+            // Build the instanced objects once and share them between all the instances
+            let mut instanced = Vec::new();
+            add_objects(
+                *object,
+                base_dir,
+                camera_position,
+                get_material,
+                &mut instanced,
+            )?;
+            let instanced: Vec<Arc<dyn TraceObj>> = instanced.into_iter().map(Arc::from).collect();
+            for transform in transforms {
+                for obj in &instanced {
+                    objs.push(Box::new(Transformed::new(
+                        obj.clone(),
+                        similarity(&transform),
+                    )));
+                }
+            }
+            return Ok(());
+        }
+        ObjectDesc::Named { name, object } => {
+            let mut named = Vec::new();
+            add_objects(*object, base_dir, camera_position, get_material, &mut named)?;
+            for obj in named {
+                let obj: Arc<dyn TraceObj> = Arc::from(obj);
+                objs.push(Box::new(Named::new(obj, &name)));
+            }
+            return Ok(());
+        }
+        ObjectDesc::Label {
+            text,
+            center,
+            height,
+            color,
+            background,
+        } => Box::new(Billboard::label(
+            &text,
+            point(center),
+            height,
+            camera_position,
+            Rgba(color),
+            Rgba(background),
+        )),
+        ObjectDesc::Sphere {
+            center,
+            radius,
+            material,
+        } => Box::new(Sphere {
+            center: point(center),
+            radius,
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Plane {
+            p0,
+            normal,
+            material,
+        } => Box::new(Plane {
+            p0: point(p0),
+            normal: Vector3::from(normal).normalize(),
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Disk {
+            center,
+            normal,
+            radius,
+            material,
+        } => Box::new(Disk {
+            center: point(center),
+            normal: Vector3::from(normal).normalize(),
+            radius,
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Rectangle {
+            low_left,
+            up_right,
+            material,
+        } => Box::new(Rectangle {
+            low_left: point(low_left),
+            up_right: point(up_right),
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Box { min, max, material } => Box::new(BoxObj {
+            min: point(min).inf(&point(max)),
+            max: point(min).sup(&point(max)),
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Torus {
+            center,
+            axis,
+            major_radius,
+            minor_radius,
+            material,
+        } => Box::new(Torus {
+            center: point(center),
+            axis: Vector3::from(axis).normalize(),
+            major_radius,
+            minor_radius,
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Curve {
+            points,
+            root_radius,
+            tip_radius,
+            material,
+        } => Box::new(Curve::bezier(
+            [
+                point(points[0]),
+                point(points[1]),
+                point(points[2]),
+                point(points[3]),
+            ],
+            root_radius,
+            tip_radius,
+            get_material(&material)?,
+        )),
+        ObjectDesc::Triangle {
+            a,
+            b,
+            c,
+            material,
+            two_sided,
+        } => Box::new(Triangle {
+            a: point(a),
+            b: point(b),
+            c: point(c),
+            two_sided,
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Sdf { shape, material } => Box::new(SdfObject {
+            sdf: sdf(&shape),
+            material: get_material(&material)?,
+        }),
+        ObjectDesc::Mesh {
+            path: model_path,
+            material,
+            mut import,
+            transform,
+            two_sided,
+            proxy,
+        } => {
+            if let Some(transform) = transform {
+                import.transform = similarity(&transform);
+            }
+            let material = get_material(&material)?;
+            let mesh =
+                TriangleMesh::open_obj(&base_dir.join(model_path), &import, material.clone())?
+                    .with_two_sided(two_sided);
+            match proxy {
+                Some(proxy_path) => {
+                    // Proxies are meant to be cheap, so they aren't subdivided
+                    let proxy_import = MeshImportOptions {
+                        subdivisions: 0,
+                        ..import
+                    };
+                    let proxy = TriangleMesh::open_obj(
+                        &base_dir.join(proxy_path),
+                        &proxy_import,
+                        material,
+                    )?
+                    .with_two_sided(two_sided);
+                    Box::new(Proxied::new(mesh, proxy))
+                }
+                None => Box::new(mesh),
+            }
+        }
+        ObjectDesc::PointCloud {
+            path: cloud_path,
+            radius,
+            disks,
+            material,
+        } => {
+            let shape = if disks {
+                PointShape::Disk { camera_position }
+            } else {
+                PointShape::Sphere
+            };
+            Box::new(PointCloud::open_xyz(
+                &base_dir.join(cloud_path),
+                radius,
+                shape,
+                get_material(&material)?,
+            )?)
+        }
+    };
+    objs.push(obj);
+    Ok(())
+}
+
+/// Parse a scene description file. The format is inferred from the extension: `.ron` files are
+/// parsed as RON, anything else as JSON. Errors point to the file, line and column at fault.
+/// Numbers which aren't finite once stored as `f32` (NaN, infinities, or literals too large) are
+/// rejected: they would silently propagate through the render and give black frames.
+pub fn parse_scene_file(path: &Path) -> Result<SceneDesc, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Could not read scene file {}: {}", path.display(), err))?;
+    if let Some((line, column, token)) = find_non_finite(&contents) {
+        return Err(format!(
+            "{}:{}:{}: number {} is not finite",
+            path.display(),
+            line,
+            column,
+            token
+        )
+        .into());
+    }
+    // Both parsers use the `.` decimal separator regardless of the system's locale
+    let desc = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ron") => {
+            ron::from_str(&contents).map_err(|err| format!("{}:{}", path.display(), err))?
+        }
+        _ => {
+            serde_json::from_str(&contents).map_err(|err| format!("{}: {}", path.display(), err))?
+        }
+    };
+    Ok(desc)
+}
+
+/// Line, column (both starting at 1) and text of the first number in a scene file which isn't
+/// finite as an `f32`. Strings and comments are skipped.
+fn find_non_finite(contents: &str) -> Option<(usize, usize, String)> {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || "._+-".contains(c);
+    let mut chars = contents.chars().peekable();
+    let (mut line, mut column) = (1, 0);
+    let mut in_string = false;
+    let mut in_line_comment = false;
+    let mut in_block_comment = false;
+    let mut token = String::new();
+    let mut token_start = (1, 1);
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line += 1;
+            column = 0;
+        } else {
+            column += 1;
+        }
+
+        if in_string {
+            match c {
+                // Skip the escaped character
+                '\\' => {
+                    chars.next();
+                    column += 1;
+                }
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        if in_line_comment {
+            in_line_comment = c != '\n';
+            continue;
+        }
+        if in_block_comment {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                column += 1;
+                in_block_comment = false;
+            }
+            continue;
+        }
+
+        if is_token_char(c) {
+            if token.is_empty() {
+                token_start = (line, column);
+            }
+            token.push(c);
+            continue;
+        }
+        if is_non_finite(&token) {
+            return Some((token_start.0, token_start.1, token));
+        }
+        token.clear();
+        match c {
+            '"' => in_string = true,
+            '/' if chars.peek() == Some(&'/') => in_line_comment = true,
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                column += 1;
+                in_block_comment = true;
+            }
+            _ => (),
+        }
+    }
+    if is_non_finite(&token) {
+        return Some((token_start.0, token_start.1, token));
+    }
+    None
+}
+
+fn is_non_finite(token: &str) -> bool {
+    let unsigned = token.trim_start_matches(|c| c == '+' || c == '-');
+    match unsigned.to_ascii_lowercase().as_str() {
+        "nan" | "inf" | "infinity" => return true,
+        _ => (),
+    }
+    if !unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return false;
+    }
+    // RON allows underscores between digits
+    match unsigned.replace('_', "").parse::<f64>() {
+        Ok(value) => !(value as f32).is_finite(),
+        // Not a decimal number, e.g. a hexadecimal integer
+        Err(_) => false,
+    }
+}
+
+/// Load a scene file, along with the assets it references, into a scene ready to be rendered and
+/// the render settings it specifies.
+pub fn load_scene(path: &Path) -> Result<(Scene, RenderSettings), Box<dyn Error>> {
+    let desc = parse_scene_file(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let texture_cache = match desc.texture_budget {
+        Some(megabytes) => {
+            let cache_dir = env::temp_dir().join(format!(
+                "tinyraytracer_textures_{}_{}",
+                process::id(),
+                TEXTURE_CACHES.fetch_add(1, Ordering::Relaxed)
+            ));
+            Some(Arc::new(TextureCache::new(&cache_dir, megabytes << 20)?))
+        }
+        None => None,
+    };
+
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    // Materials wrapping another one are built once the materials they are based on are
+    let mut wrapper_materials = Vec::new();
+    for (name, material) in desc.materials {
+        let material: Arc<dyn Material> = match material {
+            MaterialDesc::Plain {
+                color,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                absorption,
+                abbe_number,
+                visibility,
+            } => Arc::new(PlainMaterial {
+                color: Rgba(color),
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                absorption,
+                abbe_number,
+                visibility,
+            }),
+            MaterialDesc::CheckerFloor {
+                color0,
+                color1,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            } => Arc::new(CheckerFloorMaterial {
+                color0: Rgba(color0),
+                color1: Rgba(color1),
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            }),
+            MaterialDesc::ImageTexture {
+                path: texture_path,
+                encoding,
+                color_space,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            } => {
+                let image = load_image(&base_dir.join(texture_path), encoding, color_space)?;
+                let texture = match texture_cache {
+                    Some(ref cache) => Texture::Cached(cache.clone(), cache.register(&image)?),
+                    None => Texture::Image(image),
+                };
+                Arc::new(ImageTextureMaterial {
+                    texture,
+                    albedo,
+                    spec_exponent,
+                    refr_ratio,
+                    visibility,
+                })
+            }
+            MaterialDesc::Microfacet {
+                color,
+                metallic,
+                roughness,
+                visibility,
+            } => Arc::new(MicrofacetMaterial {
+                color: Rgba(color),
+                metallic,
+                roughness,
+                visibility,
+            }),
+            MaterialDesc::Hair {
+                color,
+                diffuse,
+                specular,
+                spec_exponent,
+                visibility,
+            } => Arc::new(HairMaterial {
+                color: Rgba(color),
+                diffuse,
+                specular,
+                spec_exponent,
+                visibility,
+            }),
+            MaterialDesc::Marble {
+                color0,
+                color1,
+                scale,
+                turbulence,
+                octaves,
+                seed,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            } => Arc::new(MarbleMaterial {
+                noise: Noise::new(seed),
+                color0: Rgba(color0),
+                color1: Rgba(color1),
+                scale,
+                turbulence,
+                octaves,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            }),
+            MaterialDesc::Wood {
+                color0,
+                color1,
+                scale,
+                rings,
+                turbulence,
+                seed,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            } => Arc::new(WoodMaterial {
+                noise: Noise::new(seed),
+                color0: Rgba(color0),
+                color1: Rgba(color1),
+                scale,
+                rings,
+                turbulence,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            }),
+            MaterialDesc::Turbulence {
+                color0,
+                color1,
+                scale,
+                octaves,
+                seed,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            } => Arc::new(TurbulenceMaterial {
+                noise: Noise::new(seed),
+                color0: Rgba(color0),
+                color1: Rgba(color1),
+                scale,
+                octaves,
+                albedo,
+                spec_exponent,
+                refr_ratio,
+                visibility,
+            }),
+            wrapper @ MaterialDesc::Dirty { .. }
+            | wrapper @ MaterialDesc::NormalMap { .. }
+            | wrapper @ MaterialDesc::Bump { .. }
+            | wrapper @ MaterialDesc::TextureTransform { .. } => {
+                wrapper_materials.push((name, wrapper));
+                continue;
+            }
+        };
+        materials.insert(name, material);
+    }
+    // Wrappers can be based on other wrappers, so they are built in rounds, each one building the
+    // wrappers whose base material is ready
+    let mut pending = wrapper_materials;
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<_>, Vec<_>) =
+            pending.into_iter().partition(|&(_, ref wrapper)| {
+                wrapper
+                    .base()
+                    .map_or(false, |base| materials.contains_key(base))
+            });
+        if ready.is_empty() {
+            return Err(unresolved_wrappers(waiting).into());
+        }
+        for (name, wrapper) in ready {
+            // The base material of ready wrappers is built
+            let base = materials[wrapper.base().unwrap_or_default()].clone();
+            let material = wrapper_material(wrapper, base, base_dir)?;
+            materials.insert(name, material);
+        }
+        pending = waiting;
+    }
+
+    let get_material = |name: &str| -> Result<Arc<dyn Material>, Box<dyn Error>> {
+        materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Unknown material: {}", name).into())
+    };
+
+    let mut objs: Vec<Box<dyn TraceObj>> = Vec::with_capacity(desc.objects.len());
+    let camera_position = point(desc.camera.position);
+    for object in desc.objects {
+        add_objects(object, base_dir, camera_position, &get_material, &mut objs)?;
+    }
+
+    let section = match desc.section {
+        Some(section) => Some(SectionPlane::new(
+            point(section.point),
+            Vector3::from(section.normal),
+            match section.cap {
+                Some(cap) => Some(get_material(&cap)?),
+                None => None,
+            },
+        )),
+        None => None,
+    };
+
+    let overlays = Overlays {
+        axes: desc.overlays.axes,
+        grid: desc.overlays.grid.map(|grid| Grid {
+            height: grid.height,
+            spacing: grid.spacing,
+            extent: grid.extent,
+        }),
+        measurements: desc
+            .overlays
+            .measurements
+            .iter()
+            .map(|measurement| Measurement {
+                from: point(measurement.from),
+                to: point(measurement.to),
+            })
+            .collect(),
+    };
+
+    let lights = desc
+        .lights
+        .iter()
+        .map(|light| Light {
+            position: point(light.position),
+            intensity: light.intensity,
+            color: light.temperature.map_or(light.color, color::blackbody),
+            attenuation: light.attenuation,
+            area: light.area.as_ref().map(|area| match *area {
+                AreaLightDesc::Rect { width, height } => AreaLight::Rect {
+                    width: Vector3::from(width),
+                    height: Vector3::from(height),
+                },
+                AreaLightDesc::Disk { normal, radius } => AreaLight::Disk {
+                    normal: Vector3::from(normal).normalize(),
+                    radius,
+                },
+            }),
+        })
+        .collect();
+
+    let camera = Camera {
+        fov: desc.camera.fov,
+        position: point(desc.camera.position),
+        projection: desc.camera.projection,
+        view_height: desc.camera.view_height,
+        aperture: desc.camera.aperture,
+        focus_dist: desc.camera.focus_dist,
+        near: desc.camera.near,
+        far: desc.camera.far,
+    };
+
+    let background = match desc.background {
+        Some(ref background) => {
+            let path = base_dir.join(background);
+            match load_image(&path, ColorEncoding::Srgb, desc.background_color_space) {
+                Ok(mut background) => {
+                    image::imageops::flip_vertical_in_place(&mut background);
+                    background
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Could not load environment map {}, using the fallback background: {}",
+                        path.display(),
+                        e
+                    );
+                    desc.background_fallback.to_image()
+                }
+            }
+        }
+        None => desc.background_fallback.to_image(),
+    };
+
+    let scene = Scene {
+        objs,
+        lights,
+        camera,
+        background,
+        section,
+        overlays,
+    };
+    Ok((scene, desc.render))
+}
+
+/// Build a wrapper material from its description, around its already built base material.
+fn wrapper_material(
+    wrapper: MaterialDesc,
+    base: Arc<dyn Material>,
+    base_dir: &Path,
+) -> Result<Arc<dyn Material>, Box<dyn Error>> {
+    let material: Arc<dyn Material> = match wrapper {
+        MaterialDesc::Dirty {
+            base: _,
+            color,
+            strength,
+            texture,
+            distance,
+            samples,
+        } => {
+            let source = match texture {
+                Some(texture) => DirtSource::Baked(load_image(
+                    &base_dir.join(texture),
+                    ColorEncoding::Linear,
+                    ColorSpace::default(),
+                )?),
+                None => DirtSource::Procedural { distance, samples },
+            };
+            Arc::new(DirtMaterial {
+                base,
+                dirt: Dirt {
+                    source,
+                    color: Rgba(color),
+                    strength,
+                },
+            })
+        }
+        MaterialDesc::NormalMap {
+            base: _,
+            path: texture_path,
+            strength,
+        } => Arc::new(DetailMaterial {
+            base,
+            detail: SurfaceDetail::NormalMap {
+                texture: load_image(
+                    &base_dir.join(texture_path),
+                    ColorEncoding::Linear,
+                    ColorSpace::default(),
+                )?,
+                strength,
+            },
+        }),
+        MaterialDesc::Bump {
+            base: _,
+            scale,
+            strength,
+            seed,
+        } => {
+            let noise = Noise::new(seed);
+            Arc::new(DetailMaterial {
+                base,
+                detail: SurfaceDetail::Bump {
+                    height: Arc::new(move |point: Point3<f32>| noise.perlin(point * scale) / scale),
+                    strength,
+                },
+            })
+        }
+        MaterialDesc::TextureTransform {
+            base: _,
+            scale,
+            rotation,
+            offset,
+        } => Arc::new(TransformedTextureMaterial {
+            base,
+            transform: TextureTransform {
+                scale,
+                rotation,
+                offset,
+            },
+        }),
+        _ => unreachable!("Only wrapper materials have a base"),
+    };
+    Ok(material)
+}
+
+/// Error for wrapper materials which couldn't be built, because a base material doesn't exist or
+/// because wrappers are based on each other in a cycle.
+fn unresolved_wrappers(mut waiting: Vec<(String, MaterialDesc)>) -> String {
+    waiting.sort_by(|(name0, _), (name1, _)| name0.cmp(name1));
+    for (name, wrapper) in &waiting {
+        let base = wrapper.base().unwrap_or_default();
+        if !waiting.iter().any(|(other, _)| other == base) {
+            return format!("Unknown base material of {}: {}", name, base);
+        }
+    }
+    let names: Vec<&str> = waiting.iter().map(|(name, _)| name.as_str()).collect();
+    format!(
+        "Wrapper materials based on each other: {}",
+        names.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::error::Error;
+    use std::fs;
+    use std::process;
+
+    use image::{Rgb, RgbImage};
+
+    use super::super::Scene;
+    use super::load_scene;
+
+    const PLAIN: &str = r#""plain": {
+        "type": "Plain",
+        "color": [200, 200, 200, 255],
+        "albedo": [0.6, 0.3, 0.1, 0.0],
+        "spec_exponent": 50.0,
+        "refr_ratio": 1.0
+    }"#;
+
+    /// Load a scene made of a sphere with the material `used`, out of the given materials. The
+    /// scene file is written to the temporary directory under a name made from `name`, along with
+    /// a flat normal map which materials refer to as `flat.png`.
+    fn load_with_materials(name: &str, materials: &str, used: &str) -> Result<Scene, String> {
+        let dir = env::temp_dir();
+        let prefix = format!("tinyraytracer_{}_{}", name, process::id());
+        let normal_map = dir.join(format!("{}_flat.png", prefix));
+        RgbImage::from_pixel(1, 1, Rgb([128, 128, 255]))
+            .save(&normal_map)
+            .unwrap();
+        let scene_path = dir.join(format!("{}.json", prefix));
+        let contents = format!(
+            r#"{{
+                "camera": {{ "fov": 1.0, "position": [0, 0, 0] }},
+                "materials": {{ {} }},
+                "objects": [
+                    {{ "type": "Sphere", "center": [0, 0, -10], "radius": 1, "material": "{}" }}
+                ],
+                "lights": []
+            }}"#,
+            materials.replace("flat.png", &format!("{}_flat.png", prefix)),
+            used
+        );
+        fs::write(&scene_path, contents).unwrap();
+
+        let scene = load_scene(&scene_path).map(|(scene, _)| scene);
+        fs::remove_file(&scene_path).unwrap();
+        fs::remove_file(&normal_map).unwrap();
+        scene.map_err(|e: Box<dyn Error>| e.to_string())
+    }
+
+    #[test]
+    fn stacked_wrapper_materials() {
+        let materials = format!(
+            r#"{},
+            "moved": {{ "type": "TextureTransform", "base": "dirty", "scale": [2, 2] }},
+            "dirty": {{ "type": "Dirty", "base": "bumpy" }},
+            "bumpy": {{ "type": "Bump", "base": "normals" }},
+            "normals": {{ "type": "NormalMap", "base": "plain", "path": "flat.png" }}"#,
+            PLAIN
+        );
+        let scene = load_with_materials("stacked", &materials, "moved").unwrap();
+        let material = format!("{:?}", scene.objs[0].material());
+        for wrapper in &[
+            "TransformedTextureMaterial",
+            "DirtMaterial",
+            "DetailMaterial",
+        ] {
+            assert!(
+                material.contains(wrapper),
+                "{} missing in {}",
+                wrapper,
+                material
+            );
+        }
+        assert!(material.contains("PlainMaterial"));
+    }
+
+    #[test]
+    fn texture_transform_over_wrappers() {
+        let materials = format!(
+            r#"{},
+            "dirty": {{ "type": "Dirty", "base": "plain" }},
+            "bumpy": {{ "type": "Bump", "base": "plain" }},
+            "normals": {{ "type": "NormalMap", "base": "plain", "path": "flat.png" }},
+            "moved_dirty": {{ "type": "TextureTransform", "base": "dirty", "rotation": 0.5 }},
+            "moved_bumpy": {{ "type": "TextureTransform", "base": "bumpy", "rotation": 0.5 }},
+            "moved_normals": {{ "type": "TextureTransform", "base": "normals", "rotation": 0.5 }}"#,
+            PLAIN
+        );
+        for used in &["moved_dirty", "moved_bumpy", "moved_normals"] {
+            let scene = load_with_materials(used, &materials, used).unwrap();
+            let material = format!("{:?}", scene.objs[0].material());
+            assert!(material.starts_with("TransformedTextureMaterial"));
+        }
+    }
+
+    #[test]
+    fn unknown_base_material() {
+        let materials = format!(
+            r#"{},
+            "bumpy": {{ "type": "Bump", "base": "missing" }},
+            "moved": {{ "type": "TextureTransform", "base": "bumpy" }}"#,
+            PLAIN
+        );
+        let error = load_with_materials("unknown", &materials, "plain").unwrap_err();
+        assert_eq!(error, "Unknown base material of bumpy: missing");
+    }
+
+    #[test]
+    fn cyclic_wrapper_materials() {
+        let materials = format!(
+            r#"{},
+            "a": {{ "type": "Bump", "base": "b" }},
+            "b": {{ "type": "TextureTransform", "base": "a" }}"#,
+            PLAIN
+        );
+        let error = load_with_materials("cyclic", &materials, "plain").unwrap_err();
+        assert_eq!(error, "Wrapper materials based on each other: a, b");
+    }
+}
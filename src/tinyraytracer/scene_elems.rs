@@ -1,36 +1,557 @@
+use std::f32::consts::FRAC_PI_2;
 use std::fmt::Debug;
+use std::mem;
+use std::sync::Arc;
 
-use nalgebra::{Point3, Vector3};
+use rand::Rng;
+
+use super::color;
+use super::math::{
+    dot, max_point, midpoint, min_point, normalize, try_normalize, vec3, Point2, Point3, Vec3,
+};
+pub use super::sampling::orthonormal_basis;
+use super::sampling::uniform_disk;
+
+/// Shape of a light emitting from a surface instead of a single point. Area lights cast soft
+/// shadows, with penumbras where only part of the light is occluded.
+#[derive(Debug, Clone, Copy)]
+pub enum AreaLight {
+    /// Parallelogram centered at the light position, spanned by two edge vectors
+    Rect { width: Vec3, height: Vec3 },
+    /// Disk centered at the light position
+    Disk { normal: Vec3, radius: f32 },
+}
+
+/// Falloff of a light's intensity with the distance to it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type")]
+pub enum Attenuation {
+    /// Constant intensity at any distance
+    None,
+    /// Intensity divided by constant + linear * d + quadratic * d², the classic OpenGL falloff
+    Polynomial {
+        constant: f32,
+        linear: f32,
+        quadratic: f32,
+    },
+    /// Intensity divided by the squared distance, as for physical point lights
+    InverseSquare,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Attenuation::None
+    }
+}
+
+impl Attenuation {
+    /// Factor the intensity of a light is multiplied with at the given distance from it.
+    pub fn factor(&self, distance: f32) -> f32 {
+        let divisor = match *self {
+            Attenuation::None => return 1.,
+            Attenuation::Polynomial {
+                constant,
+                linear,
+                quadratic,
+            } => constant + linear * distance + quadratic * distance * distance,
+            Attenuation::InverseSquare => distance * distance,
+        };
+        // Avoid infinite intensities right at the light
+        1. / f32::max(divisor, 1e-6)
+    }
+}
 
 pub struct Light {
-    pub position: Point3<f32>,
+    /// Position of a point light, or center of an area light
+    pub position: Point3,
     pub intensity: f32,
+    /// Linear RGB color the intensity is multiplied with per channel. White lights are [1, 1, 1]
+    pub color: [f32; 3],
+    pub attenuation: Attenuation,
+    /// Point lights have no area
+    pub area: Option<AreaLight>,
+}
+
+impl Light {
+    /// White point light of the given intensity.
+    pub fn new(position: Point3, intensity: f32) -> Self {
+        Light {
+            position,
+            intensity,
+            color: [1., 1., 1.],
+            attenuation: Attenuation::None,
+            area: None,
+        }
+    }
+
+    /// Point light colored like a blackbody at the given temperature in kelvin (see
+    /// `color::blackbody`), e.g. 2700 for an incandescent bulb or 1900 for a candle.
+    pub fn blackbody(position: Point3, temperature: f32, intensity: f32) -> Self {
+        Light {
+            color: color::blackbody(temperature),
+            ..Light::new(position, intensity)
+        }
+    }
+
+    /// Intensity of each color channel of the light.
+    pub fn rgb_intensity(&self) -> [f32; 3] {
+        [
+            self.color[0] * self.intensity,
+            self.color[1] * self.intensity,
+            self.color[2] * self.intensity,
+        ]
+    }
+
+    /// Positions on the light to cast shadow rays towards. Point lights have a single position,
+    /// whereas area lights are sampled `samples` times over their surface, stratifying the
+    /// samples to reduce noise.
+    pub fn sample_positions<R: Rng>(&self, samples: u32, rng: &mut R) -> Vec<Point3> {
+        let area = match self.area {
+            Some(area) if samples > 0 => area,
+            _ => return vec![self.position],
+        };
+
+        let cols = f32::ceil(f32::sqrt(samples as f32)) as u32;
+        let rows = (samples + cols - 1) / cols;
+        (0..samples)
+            .map(|sample_idx| {
+                // Stratified position in [0, 1) x [0, 1)
+                let s = ((sample_idx % cols) as f32 + rng.gen::<f32>()) / cols as f32;
+                let t = ((sample_idx / cols) as f32 + rng.gen::<f32>()) / rows as f32;
+                match area {
+                    AreaLight::Rect { width, height } => {
+                        self.position + width * (s - 0.5) + height * (t - 0.5)
+                    }
+                    AreaLight::Disk { normal, radius } => {
+                        let (tangent, bitangent) = orthonormal_basis(normal);
+                        let (x, y) = uniform_disk(s, t);
+                        self.position + (tangent * x + bitangent * y) * radius
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Mapping from the image plane to the directions of the rays casted from the camera.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    /// Pinhole camera looking towards -z. The fov covers the image height.
+    Perspective,
+    /// "Little planet" stereographic projection. The center of the image looks straight down and
+    /// the fov is the angle covered by the image height, which can go past 180 degrees.
+    Stereographic,
+    /// Parallel rays towards -z, so that sizes don't shrink with distance. The fov is unused, the
+    /// camera's view height sets the size of the area covered by the image height instead.
+    Orthographic,
+    /// 360° panorama mapping the image to longitudes and latitudes, like an environment map.
+    /// The image center looks towards -z and a 2:1 image covers the whole sphere. The fov is
+    /// unused.
+    Equirectangular,
+    /// Equidistant fisheye lens looking towards -z: the angle from the view direction grows
+    /// linearly with the distance to the image center, up to fov / 2 at the top and bottom edges.
+    /// The fov can go past 180 degrees.
+    Equidistant,
+    /// Equisolid angle fisheye lens looking towards -z, which keeps the areas of the scene in
+    /// proportion like most real fisheye lenses. The fov covers the image height, up to 360
+    /// degrees.
+    Equisolid,
 }
 
 pub struct Camera {
     pub fov: f32,
-    pub position: Point3<f32>,
+    pub position: Point3,
+    pub projection: Projection,
+    /// Height of the area seen by an orthographic camera, in scene units
+    pub view_height: f32,
+    /// Diameter of the lens. Zero makes a pinhole camera with everything in focus
+    pub aperture: f32,
+    /// Distance from the camera to the plane in focus
+    pub focus_dist: f32,
+    /// Distance from the camera to the near clipping plane. Geometry closer than it is cut away
+    pub near: f32,
+    /// Distance from the camera to the far clipping plane. Geometry beyond it is not rendered
+    pub far: f32,
+}
+
+impl Camera {
+    /// Ray going through a point of the image plane given in normalized coordinates (see
+    /// `ray_direction`). With a non-zero aperture, the ray starts at a random point of the lens
+    /// and goes through the point in focus, which blurs objects away from the focus distance.
+    /// Intersections outside of the near and far clipping planes are ignored.
+    pub fn ray<R: Rng>(&self, u: f32, v: f32, rng: &mut R) -> Ray {
+        let ray = self.lens_ray(u, v, rng);
+        let depth_to_dist = match self.projection {
+            // Clipping planes face the camera, so the distance to them grows away from the center
+            Projection::Perspective => 1. / -ray.direction.z,
+            // Without a single viewing direction, clip against spheres around the camera instead
+            Projection::Stereographic
+            | Projection::Equirectangular
+            | Projection::Equidistant
+            | Projection::Equisolid => 1.,
+            // Rays go straight towards -z, so distances along them are depths already
+            Projection::Orthographic => 1.,
+        };
+        Ray {
+            t_min: self.near * depth_to_dist,
+            t_max: self.far * depth_to_dist,
+            ..ray
+        }
+    }
+
+    fn lens_ray<R: Rng>(&self, u: f32, v: f32, rng: &mut R) -> Ray {
+        let center = self.ray_origin(u, v);
+        let direction = self.ray_direction(u, v);
+        if self.aperture <= 0. {
+            return Ray::new(center, direction);
+        }
+
+        let focus_point = match self.projection {
+            // Focus on the plane at focus_dist in front of the camera
+            Projection::Perspective | Projection::Orthographic => {
+                center + direction * (self.focus_dist / -direction.z)
+            }
+            // Without a single viewing direction, focus on a sphere around the camera instead
+            Projection::Stereographic
+            | Projection::Equirectangular
+            | Projection::Equidistant
+            | Projection::Equisolid => center + direction * self.focus_dist,
+        };
+        // Uniformly distributed point of the lens disk, which faces -z
+        let (x, y) = uniform_disk(rng.gen(), rng.gen());
+        let radius = 0.5 * self.aperture;
+        let origin = center + vec3(radius * x, radius * y, 0.);
+
+        Ray::new(origin, normalize(&(focus_point - origin)))
+    }
+
+    /// Origin of the ray going through a point of the image plane given in normalized coordinates
+    /// (see `ray_direction`). Only orthographic rays don't all start at the camera position.
+    pub fn ray_origin(&self, u: f32, v: f32) -> Point3 {
+        match self.projection {
+            Projection::Orthographic => {
+                let half_height = self.view_height / 2.;
+                self.position + vec3(u * half_height, v * half_height, 0.)
+            }
+            Projection::Perspective
+            | Projection::Stereographic
+            | Projection::Equirectangular
+            | Projection::Equidistant
+            | Projection::Equisolid => self.position,
+        }
+    }
+
+    /// Direction of the ray going through a point of the image plane given in normalized
+    /// coordinates: v goes from -1 (bottom) to 1 (top), u is scaled by the image aspect ratio.
+    pub fn ray_direction(&self, u: f32, v: f32) -> Vec3 {
+        match self.projection {
+            Projection::Perspective => {
+                let half_fov = f32::tan(self.fov / 2.);
+                normalize(&vec3(u * half_fov, v * half_fov, -1.))
+            }
+            Projection::Stereographic => {
+                // Inverse stereographic projection from the image plane to the unit sphere,
+                // taking the projection pole at the top so the image center maps to the nadir.
+                // The image edge is at fov / 2 from the nadir, at a distance of tan(fov / 4).
+                let scale = f32::tan(self.fov / 4.);
+                let (a, b) = (u * scale, v * scale);
+                let r_sq = a * a + b * b;
+                vec3(2. * a, r_sq - 1., -2. * b) / (1. + r_sq)
+            }
+            Projection::Orthographic => vec3(0., 0., -1.),
+            Projection::Equirectangular => {
+                // u in [-2, 2] spans the longitudes of a 2:1 image, v the latitudes
+                let (longitude, latitude) = (u * FRAC_PI_2, v * FRAC_PI_2);
+                vec3(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    -latitude.cos() * longitude.cos(),
+                )
+            }
+            Projection::Equidistant | Projection::Equisolid => {
+                let radius = f32::sqrt(u * u + v * v);
+                let angle = match self.projection {
+                    Projection::Equidistant => radius * self.fov / 2.,
+                    // Past the edge of the lens' image circle, look straight back
+                    _ => 2. * f32::asin(f32::min(1., radius * f32::sin(self.fov / 4.))),
+                };
+                if radius == 0. {
+                    return vec3(0., 0., -1.);
+                }
+                let scale = angle.sin() / radius;
+                vec3(u * scale, v * scale, -angle.cos())
+            }
+        }
+    }
+
+    /// Normalized image plane coordinates (see `ray_direction`) a point of the scene is seen at,
+    /// ignoring the lens. None for points the camera can't see, like those behind a perspective
+    /// camera.
+    pub fn project(&self, point: Point3) -> Option<(f32, f32)> {
+        let offset = point - self.position;
+        match self.projection {
+            Projection::Perspective => {
+                if offset.z >= 0. {
+                    return None;
+                }
+                let half_fov = f32::tan(self.fov / 2.);
+                Some((
+                    offset.x / (-offset.z * half_fov),
+                    offset.y / (-offset.z * half_fov),
+                ))
+            }
+            Projection::Stereographic => {
+                // Stereographic projection of the direction from the pole at the top
+                let direction = try_normalize(&offset)?;
+                if direction.y >= 1. {
+                    return None;
+                }
+                let scale = f32::tan(self.fov / 4.);
+                Some((
+                    direction.x / ((1. - direction.y) * scale),
+                    -direction.z / ((1. - direction.y) * scale),
+                ))
+            }
+            Projection::Orthographic => {
+                if offset.z >= 0. {
+                    return None;
+                }
+                let half_height = self.view_height / 2.;
+                Some((offset.x / half_height, offset.y / half_height))
+            }
+            Projection::Equirectangular => {
+                let direction = try_normalize(&offset)?;
+                let longitude = direction.x.atan2(-direction.z);
+                let latitude = direction.y.max(-1.).min(1.).asin();
+                Some((longitude / FRAC_PI_2, latitude / FRAC_PI_2))
+            }
+            Projection::Equidistant | Projection::Equisolid => {
+                let direction = try_normalize(&offset)?;
+                let angle = (-direction.z).max(-1.).min(1.).acos();
+                let radius = match self.projection {
+                    Projection::Equidistant => angle / (self.fov / 2.),
+                    _ => f32::sin(angle / 2.) / f32::sin(self.fov / 4.),
+                };
+                let side = f32::sqrt(direction.x * direction.x + direction.y * direction.y);
+                if side == 0. {
+                    // Straight ahead is the image center, straight back is the whole edge
+                    return if direction.z < 0. {
+                        Some((0., 0.))
+                    } else {
+                        None
+                    };
+                }
+                Some((direction.x * radius / side, direction.y * radius / side))
+            }
+        }
+    }
 }
 
 pub struct Ray {
-    pub origin: Point3<f32>,
-    pub direction: Vector3<f32>,
+    pub origin: Point3,
+    pub direction: Vec3,
+    /// Intersections closer than this distance are ignored
+    pub t_min: f32,
+    /// Intersections farther than this distance are ignored
+    pub t_max: f32,
 }
 
-pub trait TraceObj: Debug {
-    fn ray_intersect(&self, ray: &Ray) -> Option<f32>;
-    fn get_normal(&self, intersection_point: Point3<f32>) -> Vector3<f32>;
+impl Ray {
+    /// Ray intersecting anything in front of its origin.
+    pub fn new(origin: Point3, direction: Vec3) -> Self {
+        Ray {
+            origin,
+            direction,
+            t_min: 0.,
+            t_max: f32::INFINITY,
+        }
+    }
+
+    /// Check if an intersection distance lies within the range of the ray.
+    pub fn in_range(&self, t: f32) -> bool {
+        t >= self.t_min && t <= self.t_max
+    }
+}
+
+/// Purpose a ray is casted for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayKind {
+    /// Primary rays going from the camera through the image plane
+    Camera,
+    /// Rays going from a surface point towards a light source
+    Shadow,
+    Reflection,
+    Refraction,
+}
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Point3,
+    pub max: Point3,
+}
+
+impl Aabb {
+    /// Create the bounding box spanned by two opposite corners.
+    pub fn new(corner0: Point3, corner1: Point3) -> Self {
+        Aabb {
+            min: min_point(&corner0, &corner1),
+            max: max_point(&corner0, &corner1),
+        }
+    }
+
+    /// Smallest bounding box containing both boxes.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: min_point(&self.min, &other.min),
+            max: max_point(&self.max, &other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        midpoint(&self.min, &self.max)
+    }
+
+    /// Slab test. Check if the ray enters the box before the given distance.
+    pub fn ray_intersect(&self, ray: &Ray, max_dist: f32) -> bool {
+        self.ray_span(ray, max_dist).is_some()
+    }
+
+    /// Distances along the ray at which it enters and exits the box, clamped to the ray's t_min
+    /// and to the given distance. None if the ray misses the box within that range.
+    #[inline(always)]
+    pub fn ray_span(&self, ray: &Ray, max_dist: f32) -> Option<(f32, f32)> {
+        let mut t_enter = ray.t_min;
+        let mut t_exit = max_dist;
+        for axis in 0..3 {
+            let inv_dir = 1. / ray.direction[axis];
+            let mut t0 = (self.min[axis] - ray.origin[axis]) * inv_dir;
+            let mut t1 = (self.max[axis] - ray.origin[axis]) * inv_dir;
+            if inv_dir < 0. {
+                mem::swap(&mut t0, &mut t1);
+            }
+            // f32::max and f32::min ignore NaNs produced by rays parallel to a flat box
+            t_enter = f32::max(t_enter, t0);
+            t_exit = f32::min(t_exit, t1);
+            if t_exit < t_enter {
+                return None;
+            }
+        }
+        Some((t_enter, t_exit))
+    }
+}
+
+/// Information about the intersection of a ray with an object.
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    /// Distance from the ray origin to the intersection point
+    pub t: f32,
+    pub point: Point3,
+    /// Unit surface normal at the intersection point
+    pub normal: Vec3,
+    /// Texture coordinates of the intersection point on the object's surface
+    pub uv: Point2,
+    /// Unit vector perpendicular to the normal, along which the u texture coordinate increases.
+    /// Orients tangent-space normal maps
+    pub tangent: Vec3,
+    /// Offset from the intersection point to the point shadow rays start from. Smooth shaded
+    /// meshes move it off their flat faces, onto the curved surface their normals describe, so
+    /// that the faces don't shadow themselves along the terminator. Zero for other surfaces
+    pub shadow_offset: Vec3,
+}
+
+/// Unit vector nearest to `direction` which is perpendicular to the unit `normal`, e.g. to build
+/// the tangent of a hit. Falls back to an arbitrary perpendicular vector if `direction` is
+/// parallel to the normal.
+pub fn tangent_towards(direction: Vec3, normal: Vec3) -> Vec3 {
+    try_normalize(&(direction - normal * dot(&normal, &direction)))
+        .unwrap_or_else(|| orthonormal_basis(normal).0)
+}
+
+pub trait TraceObj: Debug + Send + Sync {
+    /// Nearest intersection of the ray with the object within the ray's [t_min, t_max] range.
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit>;
+    /// Nearest intersection with the given kind of ray. Objects may use cheaper geometry for rays
+    /// whose hits are less noticeable than those of camera rays.
+    fn ray_intersect_kind(&self, ray: &Ray, _kind: RayKind) -> Option<Hit> {
+        self.ray_intersect(ray)
+    }
     fn material(&self) -> &dyn Material;
+    /// Bounding box of the object, used to build acceleration structures. Unbounded objects (like
+    /// infinite planes) return None.
+    fn bounding_box(&self) -> Option<Aabb>;
+    /// Approximate number of bytes of memory used by the object's geometry, to estimate the
+    /// memory needed by a scene. Materials are not included.
+    fn memory_size(&self) -> usize {
+        mem::size_of_val(self)
+    }
+    /// Name given to the object with `Named`, if any.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Shared objects, e.g. the same mesh placed several times with `Transformed`.
+impl<T: TraceObj + ?Sized> TraceObj for Arc<T> {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        (**self).ray_intersect(ray)
+    }
+
+    fn ray_intersect_kind(&self, ray: &Ray, kind: RayKind) -> Option<Hit> {
+        (**self).ray_intersect_kind(ray, kind)
+    }
+
+    fn material(&self) -> &dyn Material {
+        (**self).material()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        (**self).bounding_box()
+    }
+
+    /// Shared objects are counted in full by each reference, so instanced scenes are overestimated
+    fn memory_size(&self) -> usize {
+        (**self).memory_size()
+    }
+
+    fn name(&self) -> Option<&str> {
+        (**self).name()
+    }
 }
 
 // Submodules exports
+pub mod billboard;
+pub mod box_obj;
+pub mod curve;
+pub mod disk;
 pub mod materials;
+pub mod named;
 pub mod plane;
+pub mod point_cloud;
+pub mod proxied;
 pub mod rectangle;
+pub mod sdf;
+pub mod section;
 pub mod sphere;
+pub mod torus;
+pub mod transformed;
 pub mod triangle;
+pub mod triangle_mesh;
+pub use self::billboard::*;
+pub use self::box_obj::*;
+pub use self::curve::*;
+pub use self::disk::*;
 pub use self::materials::*;
+pub use self::named::*;
 pub use self::plane::*;
+pub use self::point_cloud::*;
+pub use self::proxied::*;
 pub use self::rectangle::*;
+pub use self::sdf::*;
+pub use self::section::*;
 pub use self::sphere::*;
+pub use self::torus::*;
+pub use self::transformed::*;
 pub use self::triangle::*;
+pub use self::triangle_mesh::*;
@@ -0,0 +1,280 @@
+use std::sync::Arc;
+
+use image::{Rgba, Rgba32FImage};
+
+use super::super::color;
+use super::super::math::{cross, dot, length, normalize, point2, vec3, vec3_from_fn, Point3, Vec3};
+use super::{
+    materials::{ImageTextureMaterial, Material, RayVisibility, Texture},
+    Aabb, Hit, Ray, TraceObj,
+};
+
+/// Size of the glyphs of the built-in font, in font pixels.
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// Flat rectangle turned towards the camera, e.g. to annotate objects with a text label. Its
+/// orientation is fixed when it's created, so it must be created with the final camera position.
+/// Like rectangles, it is only visible from the side facing the camera.
+#[derive(Debug)]
+pub struct Billboard {
+    pub center: Point3,
+    pub width: f32,
+    pub height: f32,
+    pub material: Arc<dyn Material>,
+    /// Unit vector pointing to the camera
+    normal: Vec3,
+    /// Unit vectors along the width and height of the billboard
+    right: Vec3,
+    up: Vec3,
+}
+
+impl Billboard {
+    /// Billboard facing the given camera position. Its height is kept as upright as possible.
+    pub fn new(
+        center: Point3,
+        width: f32,
+        height: f32,
+        camera_position: Point3,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let normal = normalize(&(camera_position - center));
+        let right = cross(&vec3(0., 1., 0.), &normal);
+        // Looking straight up or down, any horizontal direction will do
+        let right = if length(&right) < 1e-6 {
+            vec3(1., 0., 0.)
+        } else {
+            normalize(&right)
+        };
+        let up = cross(&normal, &right);
+        Billboard {
+            center,
+            width,
+            height,
+            material,
+            normal,
+            right,
+            up,
+        }
+    }
+
+    /// Billboard showing a line of text (or several, separated by newlines), `height` units tall
+    /// and as wide as the text requires. Labels don't cast shadows.
+    pub fn label(
+        text: &str,
+        center: Point3,
+        height: f32,
+        camera_position: Point3,
+        foreground: Rgba<u8>,
+        background: Rgba<u8>,
+    ) -> Self {
+        let texture = text_texture(text, 4, foreground, background);
+        let width = height * texture.width() as f32 / texture.height() as f32;
+        let material = ImageTextureMaterial {
+            texture: Texture::Image(texture),
+            albedo: [1., 0., 0., 0.],
+            spec_exponent: 1.,
+            refr_ratio: 1.,
+            visibility: RayVisibility {
+                shadow: false,
+                ..RayVisibility::default()
+            },
+        };
+        Billboard::new(center, width, height, camera_position, Arc::new(material))
+    }
+}
+
+impl TraceObj for Billboard {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let n_dot_raydir = -dot(&self.normal, &ray.direction);
+        if n_dot_raydir <= 0. {
+            return None;
+        }
+
+        let t = dot(&self.normal, &(ray.origin - self.center)) / n_dot_raydir;
+        if !ray.in_range(t) {
+            return None;
+        }
+        let point = ray.origin + t * ray.direction;
+
+        // Project the point on the billboard's axes, with the texture's origin at its lower left
+        let center_vec = point - self.center;
+        let u = dot(&center_vec, &self.right) / self.width + 0.5;
+        let v = dot(&center_vec, &self.up) / self.height + 0.5;
+        if !(0. ..1.).contains(&u) || !(0. ..1.).contains(&v) {
+            return None;
+        }
+
+        Some(Hit {
+            t,
+            point,
+            normal: self.normal,
+            uv: point2(u, v),
+            tangent: self.right,
+            shadow_offset: vec3(0., 0., 0.),
+        })
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = vec3_from_fn(|i| {
+            (self.right[i].abs() * self.width + self.up[i].abs() * self.height) / 2.
+        });
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
+
+/// Render text with a built-in 5x7 pixel font into a texture for image texture materials. Each
+/// font pixel is `pixel_size` texture pixels wide. Newlines start new lines, and characters
+/// outside of printable ASCII are drawn as question marks.
+pub fn text_texture(
+    text: &str,
+    pixel_size: u32,
+    foreground: Rgba<u8>,
+    background: Rgba<u8>,
+) -> Rgba32FImage {
+    let lines: Vec<Vec<char>> = text.lines().map(|line| line.chars().collect()).collect();
+    let columns = lines.iter().map(|line| line.len()).max().unwrap_or(0) as u32;
+    // Glyphs are separated by a font pixel, and the text has a margin of one font pixel
+    let width = (columns.max(1) * (GLYPH_WIDTH + 1) + 1) * pixel_size;
+    let height = (lines.len().max(1) as u32 * (GLYPH_HEIGHT + 1) + 1) * pixel_size;
+
+    let (foreground, background) = (color::to_linear(foreground), color::to_linear(background));
+    let mut texture = Rgba32FImage::from_pixel(width, height, background);
+    for (row, line) in lines.iter().enumerate() {
+        for (column, &character) in line.iter().enumerate() {
+            let glyph = glyph(character);
+            let glyph_x = (column as u32 * (GLYPH_WIDTH + 1) + 1) * pixel_size;
+            let glyph_y = (row as u32 * (GLYPH_HEIGHT + 1) + 1) * pixel_size;
+            for (glyph_column, bits) in glyph.iter().enumerate() {
+                for glyph_row in 0..GLYPH_HEIGHT {
+                    if bits & (1 << glyph_row) == 0 {
+                        continue;
+                    }
+                    for dy in 0..pixel_size {
+                        for dx in 0..pixel_size {
+                            texture.put_pixel(
+                                glyph_x + glyph_column as u32 * pixel_size + dx,
+                                glyph_y + glyph_row * pixel_size + dy,
+                                foreground,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+    texture
+}
+
+/// Columns of a glyph of the built-in font, from left to right. Bit 0 is the top row.
+fn glyph(character: char) -> [u8; 5] {
+    let code = character as u32;
+    if (0x20..0x7f).contains(&code) {
+        FONT_5X7[(code - 0x20) as usize]
+    } else {
+        FONT_5X7[('?' as u32 - 0x20) as usize]
+    }
+}
+
+/// Printable ASCII characters, from space to tilde.
+const FONT_5X7: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x14, 0x08, 0x3e, 0x08, 0x14], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // f
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // g
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // j
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // q
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // t
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // y
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
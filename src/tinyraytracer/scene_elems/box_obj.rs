@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use super::super::math::{point2, vec3, Point3};
+use super::{materials::Material, Aabb, Hit, Ray, TraceObj};
+
+/// Axis-aligned box spanned by two opposite corners.
+#[derive(Debug)]
+pub struct BoxObj {
+    pub min: Point3,
+    pub max: Point3,
+    pub material: Arc<dyn Material>,
+}
+
+impl BoxObj {
+    /// Hit on the face perpendicular to the given axis, at distance t along the ray. The face is
+    /// the one the outward normal `sign` points to.
+    fn face_hit(&self, ray: &Ray, t: f32, axis: usize, sign: f32) -> Hit {
+        let point = ray.origin + ray.direction * t;
+        let mut normal = vec3(0., 0., 0.);
+        normal[axis] = sign;
+
+        // Texture coordinates spanning the face along the two other axes
+        let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+        let extent = self.max - self.min;
+        let mut tangent = vec3(0., 0., 0.);
+        tangent[u_axis] = 1.;
+        Hit {
+            t,
+            point,
+            normal,
+            uv: point2(
+                (point[u_axis] - self.min[u_axis]) / extent[u_axis],
+                (point[v_axis] - self.min[v_axis]) / extent[v_axis],
+            ),
+            tangent,
+            shadow_offset: vec3(0., 0., 0.),
+        }
+    }
+}
+
+impl TraceObj for BoxObj {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Slab test, keeping track of the axes of the faces the ray enters and exits through
+        let (mut t_enter, mut enter_axis) = (f32::NEG_INFINITY, 0);
+        let (mut t_exit, mut exit_axis) = (f32::INFINITY, 0);
+        for axis in 0..3 {
+            let inv_dir = 1. / ray.direction[axis];
+            let t0 = (self.min[axis] - ray.origin[axis]) * inv_dir;
+            let t1 = (self.max[axis] - ray.origin[axis]) * inv_dir;
+            let (t_near, t_far) = if inv_dir < 0. { (t1, t0) } else { (t0, t1) };
+            // Rays parallel to a slab produce NaNs, which these comparisons ignore
+            if t_near > t_enter {
+                t_enter = t_near;
+                enter_axis = axis;
+            }
+            if t_far < t_exit {
+                t_exit = t_far;
+                exit_axis = axis;
+            }
+        }
+        if t_exit < t_enter {
+            return None;
+        }
+
+        // The ray hits the outside of the box where it enters it. If it starts inside the box, it
+        // hits the inside where it exits it instead. Normals point outwards in both cases
+        if ray.in_range(t_enter) {
+            let sign = -ray.direction[enter_axis].signum();
+            Some(self.face_hit(ray, t_enter, enter_axis, sign))
+        } else if ray.in_range(t_exit) {
+            let sign = ray.direction[exit_axis].signum();
+            Some(self.face_hit(ray, t_exit, exit_axis, sign))
+        } else {
+            None
+        }
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.min, self.max))
+    }
+}
@@ -0,0 +1,191 @@
+use std::f32::consts::PI;
+use std::mem;
+use std::sync::Arc;
+
+use super::super::math::{
+    dot, length_squared, normalize, point2, splat, to_point, to_vec, vec3, Point3, Vec3,
+};
+use super::{materials::Material, orthonormal_basis, tangent_towards, Aabb, Hit, Ray, TraceObj};
+
+/// Number of straight segments Bézier curves are split into.
+const BEZIER_SEGMENTS: usize = 16;
+
+/// Piece of a curve: a capsule made by sweeping a sphere along a straight segment.
+#[derive(Debug, Clone, Copy)]
+struct CurveSegment {
+    start: Point3,
+    end: Point3,
+    radius: f32,
+}
+
+impl CurveSegment {
+    /// Distance to the capsule along a unit direction ray, and the position of the nearest point
+    /// of the segment to the hit along it, from 0 at the start to 1 at the end.
+    fn ray_intersect(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let axis = self.end - self.start;
+        let offset = ray.origin - self.start;
+        let axis_sq = length_squared(&axis);
+        let axis_dir = dot(&axis, &ray.direction);
+        let axis_offset = dot(&axis, &offset);
+        let radius_sq = self.radius * self.radius;
+
+        // Infinite cylinder around the segment, in units scaled by the squared axis length
+        let a = axis_sq - axis_dir * axis_dir;
+        let b = axis_sq * dot(&ray.direction, &offset) - axis_offset * axis_dir;
+        let c = axis_sq * length_squared(&offset) - axis_offset * axis_offset - radius_sq * axis_sq;
+        let discriminant = b * b - a * c;
+        if discriminant < 0. {
+            return None;
+        }
+        let (center, along) = if a > 0. {
+            let t = (-b - discriminant.sqrt()) / a;
+            let along = axis_offset + t * axis_dir;
+            if along > 0. && along < axis_sq {
+                return if ray.in_range(t) {
+                    Some((t, along / axis_sq))
+                } else {
+                    None
+                };
+            }
+            // Missed the side of the cylinder, the ray can still hit the sphere capping that end
+            if along <= 0. {
+                (self.start, 0.)
+            } else {
+                (self.end, 1.)
+            }
+        } else if axis_dir > 0. {
+            // Ray parallel to the segment, reaching the cap at its start first
+            (self.start, 0.)
+        } else {
+            (self.end, 1.)
+        };
+
+        let to_center = ray.origin - center;
+        let b = dot(&ray.direction, &to_center);
+        let c = length_squared(&to_center) - radius_sq;
+        let discriminant = b * b - c;
+        if discriminant < 0. {
+            return None;
+        }
+        let t = -b - discriminant.sqrt();
+        if ray.in_range(t) {
+            Some((t, along))
+        } else {
+            None
+        }
+    }
+}
+
+/// Thin tube following a curve, such as a hair strand, a fiber or a blade of grass. It is made of
+/// a chain of capsules whose radius tapers from the root to the tip. Hits are given a tangent
+/// running along the curve, which strand shading models like `HairMaterial` light the curve with.
+#[derive(Debug)]
+pub struct Curve {
+    segments: Vec<CurveSegment>,
+    material: Arc<dyn Material>,
+}
+
+impl Curve {
+    /// Curve going through the given points in order, from the root to the tip, e.g. a strand
+    /// exported by a grooming tool. Its radius goes linearly from `root_radius` to `tip_radius`.
+    pub fn polyline(
+        points: &[Point3],
+        root_radius: f32,
+        tip_radius: f32,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let count = points.len().saturating_sub(1);
+        let segments = points
+            .windows(2)
+            .enumerate()
+            .map(|(i, ends)| {
+                // Each capsule has the radius the taper reaches at its middle
+                let along = (i as f32 + 0.5) / count as f32;
+                CurveSegment {
+                    start: ends[0],
+                    end: ends[1],
+                    radius: root_radius + (tip_radius - root_radius) * along,
+                }
+            })
+            .collect();
+        Curve { segments, material }
+    }
+
+    /// Cubic Bézier curve starting at the root `points[0]`, ending at the tip `points[3]` and
+    /// pulled towards `points[1]` and `points[2]` in between.
+    pub fn bezier(
+        points: [Point3; 4],
+        root_radius: f32,
+        tip_radius: f32,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let samples: Vec<Point3> = (0..=BEZIER_SEGMENTS)
+            .map(|i| {
+                let t = i as f32 / BEZIER_SEGMENTS as f32;
+                let s = 1. - t;
+                let weights = [s * s * s, 3. * s * s * t, 3. * s * t * t, t * t * t];
+                to_point(
+                    points
+                        .iter()
+                        .zip(weights.iter())
+                        .map(|(point, &weight)| to_vec(&point) * weight)
+                        .sum::<Vec3>(),
+                )
+            })
+            .collect();
+        Curve::polyline(&samples, root_radius, tip_radius, material)
+    }
+}
+
+impl TraceObj for Curve {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (index, t, along) = self
+            .segments
+            .iter()
+            .enumerate()
+            .filter_map(|(i, segment)| segment.ray_intersect(ray).map(|(t, along)| (i, t, along)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+        let segment = &self.segments[index];
+
+        // The normal points away from the nearest point of the segment
+        let point = ray.origin + ray.direction * t;
+        let axis = segment.end - segment.start;
+        let axis_point = segment.start + axis * along;
+        let normal = normalize(&(point - axis_point));
+
+        // Position along the curve for u, angle around it for v
+        let (side, up) = orthonormal_basis(normalize(&axis));
+        let u = (index as f32 + along) / self.segments.len() as f32;
+        let v = dot(&normal, &up).atan2(dot(&normal, &side)) / (2. * PI) + 0.5;
+
+        Some(Hit {
+            t,
+            point,
+            normal,
+            uv: point2(u, v),
+            tangent: tangent_towards(axis, normal),
+            shadow_offset: vec3(0., 0., 0.),
+        })
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                let bbox = Aabb::new(segment.start, segment.end);
+                let extent = splat(segment.radius);
+                Aabb::new(bbox.min - extent, bbox.max + extent)
+            })
+            .fold(None, |bbox: Option<Aabb>, segment_box| {
+                Some(bbox.map_or(segment_box, |bbox| bbox.union(&segment_box)))
+            })
+    }
+
+    fn memory_size(&self) -> usize {
+        mem::size_of::<Self>() + self.segments.len() * mem::size_of::<CurveSegment>()
+    }
+}
@@ -0,0 +1,82 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use super::super::math::{cross, dot, length, point2, vec3, vec3_from_fn, Point3, Vec3};
+use super::{
+    materials::Material, orthonormal_basis, tangent_towards, Aabb, AreaLight, Attenuation, Hit,
+    Light, Ray, TraceObj,
+};
+
+/// Flat circular disk. Like planes and rectangles, it is only visible from the side its normal
+/// points to.
+#[derive(Debug)]
+pub struct Disk {
+    pub center: Point3,
+    /// Unit normal
+    pub normal: Vec3,
+    pub radius: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl Disk {
+    /// White area light of the given intensity with the same shape as the disk, e.g. to make a
+    /// visible lamp.
+    pub fn light(&self, intensity: f32) -> Light {
+        Light {
+            position: self.center,
+            intensity,
+            color: [1., 1., 1.],
+            attenuation: Attenuation::None,
+            area: Some(AreaLight::Disk {
+                normal: self.normal,
+                radius: self.radius,
+            }),
+        }
+    }
+}
+
+impl TraceObj for Disk {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Intersect the plane containing the disk, then check the distance to its center
+        let n_dot_raydir = -dot(&self.normal, &ray.direction);
+        if n_dot_raydir <= 0. {
+            return None;
+        }
+
+        let t = dot(&self.normal, &(ray.origin - self.center)) / n_dot_raydir;
+        if !ray.in_range(t) {
+            return None;
+        }
+        let point = ray.origin + t * ray.direction;
+        let center_vec = point - self.center;
+        let center_dist = length(&center_vec);
+        if center_dist > self.radius {
+            return None;
+        }
+
+        // Polar texture coordinates: angle around the center for u, distance to it for v
+        let (tangent, bitangent) = orthonormal_basis(self.normal);
+        let angle = dot(&center_vec, &bitangent).atan2(dot(&center_vec, &tangent));
+
+        Some(Hit {
+            t,
+            point,
+            normal: self.normal,
+            uv: point2(angle / (2. * PI) + 0.5, center_dist / self.radius),
+            tangent: tangent_towards(cross(&self.normal, &center_vec), self.normal),
+            shadow_offset: vec3(0., 0., 0.),
+        })
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Along each axis, the disk spans its radius scaled by how much the axis lies in its plane
+        let extent = vec3_from_fn(|i| {
+            self.radius * f32::sqrt(f32::max(0., 1. - self.normal[i] * self.normal[i]))
+        });
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
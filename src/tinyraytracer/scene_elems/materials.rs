@@ -1,13 +1,211 @@
-use std::fmt::Debug;
+use std::f32::consts::PI;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
 
-use image::Rgba;
-use nalgebra::Point3;
+use image::{Rgb, Rgba, Rgba32FImage};
+use rand::{Rng, RngCore};
 
-pub trait Material: Debug {
-    fn color(&self, intersection_pt: Point3<f32>) -> Rgba<u8>;
+use super::super::color;
+use super::super::math::{
+    cross, dot, normalize, point2, point3, try_normalize, vec3, vec3_from_fn, Point2, Point3, Vec3,
+};
+use super::super::noise::Noise;
+
+use super::super::texture_cache::{TextureCache, TextureId};
+
+use super::super::sampling::{
+    cosine_hemisphere, cosine_hemisphere_pdf, ggx_distribution, ggx_normal, ggx_normal_pdf,
+    power_cosine, power_cosine_pdf, Onb,
+};
+use super::{Hit, RayKind};
+
+/// Direction sampled from a material's BRDF.
+#[derive(Debug, Clone, Copy)]
+pub struct BrdfSample {
+    /// Incoming light direction, pointing away from the surface
+    pub wi: Vec3,
+    /// BRDF times the cosine of the incoming direction, divided by the sampling pdf. This is the
+    /// factor the light coming from `wi` is scaled by in a Monte Carlo estimate
+    pub weight: Rgb<f32>,
+    pub pdf: f32,
+}
+
+/// Kinds of rays objects with a material can be hit by. Hiding an object from some kinds of
+/// secondary rays, e.g. a detailed decoration from refraction rays, saves render time and noise.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RayVisibility {
+    pub camera: bool,
+    /// Whether the object casts shadows
+    pub shadow: bool,
+    pub reflection: bool,
+    pub refraction: bool,
+}
+
+impl Default for RayVisibility {
+    fn default() -> Self {
+        RayVisibility {
+            camera: true,
+            shadow: true,
+            reflection: true,
+            refraction: true,
+        }
+    }
+}
+
+impl RayVisibility {
+    pub fn is_visible(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Reflection => self.reflection,
+            RayKind::Refraction => self.refraction,
+        }
+    }
+}
+
+/// Model the Whitted integrator lights a material with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadingModel {
+    /// Legacy Phong model driven by the albedo terms and the specular exponent
+    Phong,
+    /// Physically based model: lights are shaded through `eval_brdf`, and the surroundings are
+    /// reflected according to the Fresnel term, less so the rougher the surface is
+    Microfacet { metallic: f32, roughness: f32 },
+    /// Kajiya-Kay model of thin strands, lit according to the angle between the lights and the
+    /// hit tangent (running along the strand, see `Curve`) rather than the normal
+    Hair,
+}
+
+pub trait Material: Debug + Send + Sync {
+    fn color(&self, hit: &Hit) -> Rgba<u8>;
+    /// Color of the material in linear light, which lighting is computed with. Materials whose
+    /// colors are more precise than 8-bit sRGB, such as image textures, return them here as is
+    fn linear_color(&self, hit: &Hit) -> Rgba<f32> {
+        color::to_linear(self.color(hit))
+    }
     fn albedo(&self) -> [f32; 4];
     fn spec_exponent(&self) -> f32;
     fn refr_ratio(&self) -> f32;
+    /// Refractive index of the red, green and blue channels. Materials whose indices differ
+    /// disperse white light into colors
+    fn refr_ratios(&self) -> [f32; 3] {
+        let refr_ratio = self.refr_ratio();
+        [refr_ratio, refr_ratio, refr_ratio]
+    }
+    /// Fraction of light absorbed per unit of distance traveled inside the object, per color
+    /// channel. Tints refractive objects more where they are thicker
+    fn absorption(&self) -> [f32; 3] {
+        [0.; 3]
+    }
+    fn visibility(&self) -> RayVisibility {
+        RayVisibility::default()
+    }
+    /// Dirt darkening the material's color in crevices, if any.
+    fn dirt(&self) -> Option<&Dirt> {
+        None
+    }
+    fn shading_model(&self) -> ShadingModel {
+        ShadingModel::Phong
+    }
+    /// Unit normal lighting is computed with at the hit point. Materials with surface detail
+    /// perturb the geometric normal.
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        hit.normal
+    }
+
+    /// Evaluate the BRDF for light coming from `wi` and leaving towards `wo`, both unit vectors
+    /// pointing away from the surface. The default is an energy conserving, reciprocal Phong
+    /// model built from the diffuse and specular albedo.
+    fn eval_brdf(&self, wi: &Vec3, wo: &Vec3, hit: &Hit) -> Rgb<f32> {
+        let normal = hit.normal;
+        if dot(&wi, &normal) <= 0. || dot(&wo, &normal) <= 0. {
+            return Rgb([0., 0., 0.]);
+        }
+        let (kd, ks) = phong_weights(self.albedo());
+        let exponent = self.spec_exponent();
+
+        let reflected = reflect(wi, &normal);
+        let specular = ks * (exponent + 2.) / (2. * PI)
+            * f32::powf(f32::max(0., dot(&reflected, wo)), exponent);
+        let color = self.linear_color(hit);
+        Rgb([
+            color[0] * kd / PI + specular,
+            color[1] * kd / PI + specular,
+            color[2] * kd / PI + specular,
+        ])
+    }
+
+    /// Sample an incoming light direction for light leaving towards `wo`, proportionally to the
+    /// diffuse and specular lobes of `eval_brdf`. Return None if the material absorbs all light.
+    fn sample(&self, wo: &Vec3, hit: &Hit, rng: &mut dyn RngCore) -> Option<BrdfSample> {
+        let normal = hit.normal;
+        let (kd, ks) = phong_weights(self.albedo());
+        if kd + ks <= 0. || dot(&wo, &normal) <= 0. {
+            return None;
+        }
+        let exponent = self.spec_exponent();
+        let diffuse_prob = kd / (kd + ks);
+        let reflected = reflect(wo, &normal);
+
+        let wi = if rng.gen::<f32>() < diffuse_prob {
+            // Cosine-weighted direction around the normal
+            Onb::from_normal(normal).to_world(cosine_hemisphere(rng.gen(), rng.gen()))
+        } else {
+            // Direction around the mirror direction, following the specular lobe
+            Onb::from_normal(reflected).to_world(power_cosine(rng.gen(), rng.gen(), exponent))
+        };
+        let cos_wi = dot(&wi, &normal);
+        if cos_wi <= 0. {
+            return None;
+        }
+
+        // Mixture pdf of both lobes
+        let pdf = diffuse_prob * cosine_hemisphere_pdf(cos_wi)
+            + (1. - diffuse_prob) * power_cosine_pdf(dot(&reflected, &wi), exponent);
+        let brdf = self.eval_brdf(&wi, wo, hit);
+        Some(BrdfSample {
+            wi,
+            weight: Rgb([
+                brdf[0] * cos_wi / pdf,
+                brdf[1] * cos_wi / pdf,
+                brdf[2] * cos_wi / pdf,
+            ]),
+            pdf,
+        })
+    }
+}
+
+/// Mirror a direction pointing away from the surface around the normal.
+fn reflect(dir: &Vec3, normal: &Vec3) -> Vec3 {
+    normal * (2. * dot(&normal, dir)) - dir
+}
+
+/// Diffuse and specular weights of the Phong BRDF, scaled down so that they don't reflect more
+/// light than they receive.
+fn phong_weights(albedo: [f32; 4]) -> (f32, f32) {
+    let (kd, ks) = (albedo[0].max(0.), albedo[1].max(0.));
+    let total = kd + ks;
+    if total > 1. {
+        (kd / total, ks / total)
+    } else {
+        (kd, ks)
+    }
+}
+
+/// Refractive indices of the red, green and blue channels of a material with the given (green)
+/// index and Abbe number. The Abbe number is the index minus one divided by the spread of the
+/// indices across the visible spectrum, red light being refracted the least.
+pub fn dispersed_refr_ratios(refr_ratio: f32, abbe_number: f32) -> [f32; 3] {
+    if abbe_number <= 0. || refr_ratio <= 1. {
+        return [refr_ratio, refr_ratio, refr_ratio];
+    }
+    let spread = (refr_ratio - 1.) / abbe_number;
+    [
+        refr_ratio - spread / 2.,
+        refr_ratio,
+        refr_ratio + spread / 2.,
+    ]
 }
 
 #[derive(Debug)]
@@ -16,10 +214,16 @@ pub struct PlainMaterial {
     pub albedo: [f32; 4],
     pub spec_exponent: f32,
     pub refr_ratio: f32,
+    /// Absorption coefficient of each color channel inside the object. Zero for clear materials
+    pub absorption: [f32; 3],
+    /// Abbe number of refractive materials, lower values dispersing light more (around 60 for
+    /// crown glass, 30 for flint glass). Zero disables dispersion
+    pub abbe_number: f32,
+    pub visibility: RayVisibility,
 }
 
 impl Material for PlainMaterial {
-    fn color(&self, _intersection_pt: Point3<f32>) -> Rgba<u8> {
+    fn color(&self, _hit: &Hit) -> Rgba<u8> {
         self.color
     }
     fn albedo(&self) -> [f32; 4] {
@@ -31,6 +235,15 @@ impl Material for PlainMaterial {
     fn refr_ratio(&self) -> f32 {
         self.refr_ratio
     }
+    fn refr_ratios(&self) -> [f32; 3] {
+        dispersed_refr_ratios(self.refr_ratio, self.abbe_number)
+    }
+    fn absorption(&self) -> [f32; 3] {
+        self.absorption
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
 }
 
 #[derive(Debug)]
@@ -40,10 +253,12 @@ pub struct CheckerFloorMaterial {
     pub albedo: [f32; 4],
     pub spec_exponent: f32,
     pub refr_ratio: f32,
+    pub visibility: RayVisibility,
 }
 
 impl Material for CheckerFloorMaterial {
-    fn color(&self, intersection_pt: Point3<f32>) -> Rgba<u8> {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        let intersection_pt = hit.point;
         if ((0.5 * intersection_pt.x + 1000.) as i32 + (0.5 * intersection_pt.z) as i32) & 1 == 1 {
             self.color0
         } else {
@@ -59,4 +274,861 @@ impl Material for CheckerFloorMaterial {
     fn refr_ratio(&self) -> f32 {
         self.refr_ratio
     }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
+}
+
+/// Material whose color is looked up in an image, using the UV coordinates of the hit point.
+/// Coordinates outside of [0, 1] wrap around, so the texture repeats.
+#[derive(Debug)]
+pub struct ImageTextureMaterial {
+    pub texture: Texture,
+    pub albedo: [f32; 4],
+    pub spec_exponent: f32,
+    pub refr_ratio: f32,
+    pub visibility: RayVisibility,
+}
+
+/// Texel of a texture at the given UV coordinates, which wrap around outside of [0, 1].
+pub fn texture_lookup(texture: &Rgba32FImage, uv: Point2) -> Rgba<f32> {
+    let (width, height) = texture.dimensions();
+    let x = (uv.x.rem_euclid(1.) * width as f32) as u32;
+    // V goes upwards whereas image rows go downwards
+    let y = ((1. - uv.y.rem_euclid(1.)) * height as f32) as u32;
+    *texture.get_pixel(u32::min(x, width - 1), u32::min(y, height - 1))
+}
+
+/// Image with linear color channels which materials look their colors up in.
+#[derive(Debug)]
+pub enum Texture {
+    /// Whole image kept in memory
+    Image(Rgba32FImage),
+    /// Texture registered in a cache, which only keeps its recently sampled tiles in memory
+    Cached(Arc<TextureCache>, TextureId),
+}
+
+impl Texture {
+    /// Texel at the given UV coordinates, which wrap around outside of [0, 1].
+    pub fn lookup(&self, uv: Point2) -> Rgba<f32> {
+        match *self {
+            Texture::Image(ref image) => texture_lookup(image, uv),
+            Texture::Cached(ref cache, id) => cache.sample(id, uv.x, uv.y),
+        }
+    }
+}
+
+impl Material for ImageTextureMaterial {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        color::to_srgb8(self.linear_color(hit))
+    }
+    fn linear_color(&self, hit: &Hit) -> Rgba<f32> {
+        self.texture.lookup(hit.uv)
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.albedo
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.spec_exponent
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.refr_ratio
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
+}
+
+/// Where the amount of dirt covering a surface comes from.
+#[derive(Debug)]
+pub enum DirtSource {
+    /// Occlusion map baked by an external tool, looked up with the UV coordinates of the hit
+    /// point. White texels are clean and black ones fully dirty
+    Baked(Rgba32FImage),
+    /// Occlusion estimated at render time by casting `samples` rays over the hemisphere around
+    /// the hit point, which are blocked by objects within `distance`
+    Procedural { distance: f32, samples: u32 },
+}
+
+/// Dirt gathering in the crevices of a surface, blending its color towards `color`.
+#[derive(Debug)]
+pub struct Dirt {
+    pub source: DirtSource,
+    pub color: Rgba<u8>,
+    /// Factor the occlusion is scaled by before blending, the result being clamped to [0, 1]
+    pub strength: f32,
+}
+
+/// Material darkened by dirt where its surface is occluded, giving cheap detail to otherwise flat
+/// materials. Everything else is taken from the base material. Only the Whitted integrator
+/// applies the dirt.
+#[derive(Debug)]
+pub struct DirtMaterial {
+    pub base: Arc<dyn Material>,
+    pub dirt: Dirt,
+}
+
+impl Material for DirtMaterial {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        self.base.color(hit)
+    }
+    fn linear_color(&self, hit: &Hit) -> Rgba<f32> {
+        self.base.linear_color(hit)
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.base.albedo()
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.base.spec_exponent()
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.base.refr_ratio()
+    }
+    fn refr_ratios(&self) -> [f32; 3] {
+        self.base.refr_ratios()
+    }
+    fn absorption(&self) -> [f32; 3] {
+        self.base.absorption()
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.base.visibility()
+    }
+    fn dirt(&self) -> Option<&Dirt> {
+        Some(&self.dirt)
+    }
+    fn shading_model(&self) -> ShadingModel {
+        self.base.shading_model()
+    }
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        self.base.shading_normal(hit)
+    }
+    fn eval_brdf(&self, wi: &Vec3, wo: &Vec3, hit: &Hit) -> Rgb<f32> {
+        self.base.eval_brdf(wi, wo, hit)
+    }
+    fn sample(&self, wo: &Vec3, hit: &Hit, rng: &mut dyn RngCore) -> Option<BrdfSample> {
+        self.base.sample(wo, hit, rng)
+    }
+}
+
+/// Reflectance of dielectrics (plastics, paint, wood...) at normal incidence.
+pub const DIELECTRIC_F0: f32 = 0.04;
+/// Lowest roughness, keeping the microfacet distribution finite for mirror-like surfaces.
+const MIN_ROUGHNESS: f32 = 0.02;
+
+/// Schlick's approximation of the Fresnel reflectance, for a surface reflecting `f0` at normal
+/// incidence and light making an angle of the given cosine with the (micro)surface normal.
+pub fn schlick_fresnel(f0: f32, cos: f32) -> f32 {
+    f0 + (1. - f0) * f32::powi(1. - f32::max(0., f32::min(1., cos)), 5)
+}
+
+/// Smith's masking term of the GGX distribution for a direction with the given cosine with the
+/// surface normal: fraction of the microfacets facing it which aren't hidden by others.
+fn smith_g1(cos: f32, alpha: f32) -> f32 {
+    let alpha_sq = alpha * alpha;
+    2. * cos / (cos + f32::sqrt(alpha_sq + (1. - alpha_sq) * cos * cos))
+}
+
+/// Physically based material made of a Lambertian diffuse base under a Cook-Torrance specular
+/// lobe: GGX distribution of microfacet normals, Smith masking-shadowing and Schlick Fresnel.
+/// Metals (`metallic` 1) tint their reflections with their color and have no diffuse part,
+/// whereas dielectrics (`metallic` 0) reflect `DIELECTRIC_F0` of the light at normal incidence.
+#[derive(Debug)]
+pub struct MicrofacetMaterial {
+    pub color: Rgba<u8>,
+    pub metallic: f32,
+    /// From 0 (polished) to 1 (matte)
+    pub roughness: f32,
+    pub visibility: RayVisibility,
+}
+
+impl MicrofacetMaterial {
+    /// Width of the GGX distribution. Squaring the roughness makes it perceptually linear
+    fn alpha(&self) -> f32 {
+        let roughness = f32::max(MIN_ROUGHNESS, f32::min(1., self.roughness));
+        roughness * roughness
+    }
+
+    /// Reflectance at normal incidence of each color channel.
+    fn f0(&self, color: Rgba<f32>) -> [f32; 3] {
+        let mut f0 = [0.; 3];
+        for ch in 0..3 {
+            f0[ch] = DIELECTRIC_F0 + (color[ch] - DIELECTRIC_F0) * self.metallic;
+        }
+        f0
+    }
+
+    /// Probability of sampling the specular lobe rather than the diffuse one.
+    fn specular_prob(&self) -> f32 {
+        0.5 + 0.5 * f32::max(0., f32::min(1., self.metallic))
+    }
+}
+
+impl Material for MicrofacetMaterial {
+    fn color(&self, _hit: &Hit) -> Rgba<u8> {
+        self.color
+    }
+    fn albedo(&self) -> [f32; 4] {
+        // Specular reflections are part of the BRDF, not separate mirror reflections
+        [1. - self.metallic, 1., 0., 0.]
+    }
+    fn spec_exponent(&self) -> f32 {
+        // Phong exponent of a lobe about as wide as the GGX one
+        let alpha = self.alpha();
+        2. / (alpha * alpha) - 2.
+    }
+    fn refr_ratio(&self) -> f32 {
+        1.
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
+    fn shading_model(&self) -> ShadingModel {
+        ShadingModel::Microfacet {
+            metallic: self.metallic,
+            roughness: self.roughness,
+        }
+    }
+
+    fn eval_brdf(&self, wi: &Vec3, wo: &Vec3, hit: &Hit) -> Rgb<f32> {
+        let normal = hit.normal;
+        let (cos_i, cos_o) = (dot(&wi, &normal), dot(&wo, &normal));
+        if cos_i <= 0. || cos_o <= 0. {
+            return Rgb([0., 0., 0.]);
+        }
+        let half = normalize(&(wi + wo));
+        let alpha = self.alpha();
+        let microfacets = ggx_distribution(dot(&half, &normal), alpha)
+            * smith_g1(cos_i, alpha)
+            * smith_g1(cos_o, alpha)
+            / (4. * cos_i * cos_o);
+
+        let color = self.linear_color(hit);
+        let f0 = self.f0(color);
+        let diffuse_weight = (1. - self.metallic) / PI;
+        let mut brdf = Rgb([0.; 3]);
+        for ch in 0..3 {
+            let fresnel = schlick_fresnel(f0[ch], dot(&wi, &half));
+            // Only light let through by the specular layer on the way in and on the way out is
+            // scattered by the diffuse base. Weighting both ways keeps the BRDF reciprocal, and
+            // stops grazing light from being reflected by both layers
+            let transmitted =
+                (1. - schlick_fresnel(f0[ch], cos_i)) * (1. - schlick_fresnel(f0[ch], cos_o));
+            brdf[ch] = fresnel * microfacets + transmitted * color[ch] * diffuse_weight;
+        }
+        brdf
+    }
+
+    fn sample(&self, wo: &Vec3, hit: &Hit, rng: &mut dyn RngCore) -> Option<BrdfSample> {
+        let normal = hit.normal;
+        if dot(&wo, &normal) <= 0. {
+            return None;
+        }
+        let alpha = self.alpha();
+        let specular_prob = self.specular_prob();
+        let onb = Onb::from_normal(normal);
+
+        let wi = if rng.gen::<f32>() < specular_prob {
+            // Mirror the outgoing direction around a sampled microfacet normal
+            let half = onb.to_world(ggx_normal(rng.gen(), rng.gen(), alpha));
+            reflect(wo, &half)
+        } else {
+            onb.to_world(cosine_hemisphere(rng.gen(), rng.gen()))
+        };
+        let cos_wi = dot(&wi, &normal);
+        if cos_wi <= 0. {
+            return None;
+        }
+
+        // Mixture pdf of both lobes
+        let half = normalize(&(wi + wo));
+        let specular_pdf =
+            ggx_normal_pdf(dot(&half, &normal), alpha) / (4. * f32::abs(dot(&wo, &half)));
+        let pdf =
+            specular_prob * specular_pdf + (1. - specular_prob) * cosine_hemisphere_pdf(cos_wi);
+        let brdf = self.eval_brdf(&wi, wo, hit);
+        Some(BrdfSample {
+            wi,
+            weight: Rgb([
+                brdf[0] * cos_wi / pdf,
+                brdf[1] * cos_wi / pdf,
+                brdf[2] * cos_wi / pdf,
+            ]),
+            pdf,
+        })
+    }
+}
+
+/// Diffuse and specular terms of the Kajiya-Kay model of a strand with the given unit tangent,
+/// for light coming from `wi` and leaving towards `wo`. A strand is lit from all around, by the
+/// sine of the angle between the light and the strand, and reflects light into a cone around it,
+/// which gives the highlights running across combed hair.
+pub fn kajiya_kay(tangent: &Vec3, wi: &Vec3, wo: &Vec3, exponent: f32) -> (f32, f32) {
+    let sin = |cos: f32| f32::sqrt(f32::max(0., 1. - cos * cos));
+    let diffuse = sin(dot(&tangent, wi));
+    let half = match try_normalize(&(wi + wo)) {
+        Some(half) => half,
+        None => return (diffuse, 0.),
+    };
+    (diffuse, f32::powf(sin(dot(&tangent, &half)), exponent))
+}
+
+/// Factor scaling Kajiya-Kay highlights with the given exponent so that they don't reflect more
+/// light than they receive. Unlike Phong lobes, they spread light over a whole cone around the
+/// strand, so their energy only shrinks with the square root of the exponent. Fitted numerically.
+fn kajiya_kay_normalization(exponent: f32) -> f32 {
+    f32::sqrt((exponent + 12.) / 12.) / PI
+}
+
+/// Material of hair, fur and grass, shaded with the Kajiya-Kay model (see `kajiya_kay`). It is
+/// meant for `Curve` objects, whose hit tangents follow the strands. Its BRDF, used by the path
+/// tracer, lights the diffuse term by both directions' sine with the strand, which keeps it
+/// reciprocal.
+#[derive(Debug)]
+pub struct HairMaterial {
+    pub color: Rgba<u8>,
+    pub diffuse: f32,
+    pub specular: f32,
+    /// Sharpness of the highlights
+    pub spec_exponent: f32,
+    pub visibility: RayVisibility,
+}
+
+impl Material for HairMaterial {
+    fn color(&self, _hit: &Hit) -> Rgba<u8> {
+        self.color
+    }
+    fn albedo(&self) -> [f32; 4] {
+        [self.diffuse, self.specular, 0., 0.]
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.spec_exponent
+    }
+    fn refr_ratio(&self) -> f32 {
+        1.
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
+    fn shading_model(&self) -> ShadingModel {
+        ShadingModel::Hair
+    }
+
+    fn eval_brdf(&self, wi: &Vec3, wo: &Vec3, hit: &Hit) -> Rgb<f32> {
+        let (kd, ks) = phong_weights(self.albedo());
+        let exponent = self.spec_exponent;
+        let (diffuse_in, specular) = kajiya_kay(&hit.tangent, wi, wo, exponent);
+        let (diffuse_out, _) = kajiya_kay(&hit.tangent, wo, wi, exponent);
+        let diffuse = diffuse_in * diffuse_out;
+        let specular = ks * kajiya_kay_normalization(exponent) * specular;
+        let color = self.linear_color(hit);
+        Rgb([
+            color[0] * kd * diffuse / PI + specular,
+            color[1] * kd * diffuse / PI + specular,
+            color[2] * kd * diffuse / PI + specular,
+        ])
+    }
+}
+
+/// Step of the finite differences estimating the gradient of bump functions.
+const BUMP_EPSILON: f32 = 1e-3;
+
+/// Detail perturbing the normal of a surface, making it look rough or engraved without changing
+/// its geometry.
+pub enum SurfaceDetail {
+    /// Tangent-space normal map looked up with the UV coordinates of the hit point. Its red,
+    /// green and blue channels store the components of the normal along the hit's tangent,
+    /// bitangent and geometric normal, mapped from [-1, 1] to [0, 1]. The texture must be loaded
+    /// with a linear encoding. `strength` scales the deviation from the geometric normal
+    NormalMap {
+        texture: Rgba32FImage,
+        strength: f32,
+    },
+    /// Height of the surface above its geometry at each point in world space. The normal tilts
+    /// against the height's gradient, scaled by `strength`
+    Bump {
+        height: Arc<dyn Fn(Point3) -> f32 + Send + Sync>,
+        strength: f32,
+    },
+}
+
+impl fmt::Debug for SurfaceDetail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SurfaceDetail::NormalMap {
+                ref texture,
+                strength,
+            } => f
+                .debug_struct("NormalMap")
+                .field("texture", &texture.dimensions())
+                .field("strength", &strength)
+                .finish(),
+            // Closures can't be printed
+            SurfaceDetail::Bump { strength, .. } => {
+                f.debug_struct("Bump").field("strength", &strength).finish()
+            }
+        }
+    }
+}
+
+impl SurfaceDetail {
+    /// Perturbed unit normal at a hit point.
+    pub fn normal(&self, hit: &Hit) -> Vec3 {
+        let perturbed = match *self {
+            SurfaceDetail::NormalMap {
+                ref texture,
+                strength,
+            } => {
+                let texel = texture_lookup(texture, hit.uv);
+                let bitangent = cross(&hit.normal, &hit.tangent);
+                let deviation =
+                    hit.tangent * (2. * texel[0] - 1.) + bitangent * (2. * texel[1] - 1.);
+                hit.normal * (2. * texel[2] - 1.) + deviation * strength
+            }
+            SurfaceDetail::Bump {
+                ref height,
+                strength,
+            } => {
+                let gradient = vec3_from_fn(|axis| {
+                    let mut offset = vec3(0., 0., 0.);
+                    offset[axis] = BUMP_EPSILON;
+                    (height(hit.point + offset) - height(hit.point - offset)) / (2. * BUMP_EPSILON)
+                });
+                // Only the part of the gradient along the surface tilts the normal
+                let surface_gradient = gradient - hit.normal * dot(&hit.normal, &gradient);
+                hit.normal - surface_gradient * strength
+            }
+        };
+        // Normals turned away from the surface would light it from behind
+        match try_normalize(&perturbed) {
+            Some(normal) if dot(&normal, &hit.normal) > 0. => normal,
+            _ => hit.normal,
+        }
+    }
+}
+
+/// Material whose surface detail perturbs the shading normal of a base material. Everything else
+/// is taken from the base material.
+#[derive(Debug)]
+pub struct DetailMaterial {
+    pub base: Arc<dyn Material>,
+    pub detail: SurfaceDetail,
+}
+
+impl Material for DetailMaterial {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        self.base.color(hit)
+    }
+    fn linear_color(&self, hit: &Hit) -> Rgba<f32> {
+        self.base.linear_color(hit)
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.base.albedo()
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.base.spec_exponent()
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.base.refr_ratio()
+    }
+    fn refr_ratios(&self) -> [f32; 3] {
+        self.base.refr_ratios()
+    }
+    fn absorption(&self) -> [f32; 3] {
+        self.base.absorption()
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.base.visibility()
+    }
+    fn dirt(&self) -> Option<&Dirt> {
+        self.base.dirt()
+    }
+    fn shading_model(&self) -> ShadingModel {
+        self.base.shading_model()
+    }
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        self.detail.normal(hit)
+    }
+    fn eval_brdf(&self, wi: &Vec3, wo: &Vec3, hit: &Hit) -> Rgb<f32> {
+        self.base.eval_brdf(wi, wo, hit)
+    }
+    fn sample(&self, wo: &Vec3, hit: &Hit, rng: &mut dyn RngCore) -> Option<BrdfSample> {
+        self.base.sample(wo, hit, rng)
+    }
+}
+
+/// Scale, rotation and offset of a texture over a surface. Image textures are moved across the UV
+/// coordinates of the surface, and procedural patterns across the XZ plane, which floors and most
+/// patterns are laid out in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureTransform {
+    /// Size of the texture along each axis relative to its original size, e.g. 2 for checker
+    /// squares twice as large
+    pub scale: [f32; 2],
+    /// Rotation of the texture in radians, from the U axis towards the V axis (and from the X axis
+    /// towards the Z axis)
+    pub rotation: f32,
+    /// Displacement of the texture, applied after scaling and rotating it
+    pub offset: [f32; 2],
+}
+
+impl Default for TextureTransform {
+    fn default() -> Self {
+        TextureTransform {
+            scale: [1., 1.],
+            rotation: 0.,
+            offset: [0., 0.],
+        }
+    }
+}
+
+impl TextureTransform {
+    /// Coordinates in the untransformed texture of a point of the transformed one.
+    fn inverse_apply(&self, coords: Point2) -> Point2 {
+        let (x, y) = (coords.x - self.offset[0], coords.y - self.offset[1]);
+        let (sin, cos) = self.rotation.sin_cos();
+        point2(
+            (cos * x + sin * y) / self.scale[0],
+            (cos * y - sin * x) / self.scale[1],
+        )
+    }
+
+    /// Hit whose UV coordinates and position in the XZ plane are moved to where the texture is
+    /// looked up.
+    pub fn texture_hit(&self, hit: &Hit) -> Hit {
+        let xz = self.inverse_apply(point2(hit.point.x, hit.point.z));
+        Hit {
+            point: point3(xz.x, hit.point.y, xz.y),
+            uv: self.inverse_apply(hit.uv),
+            ..*hit
+        }
+    }
+}
+
+/// Material whose texture, image or procedural, is scaled, rotated and moved by a transform, e.g.
+/// to tune the size of the squares of a checker floor per object. Only the color is transformed:
+/// surface detail and dirt are looked up as by the base material, which everything else is taken
+/// from.
+#[derive(Debug)]
+pub struct TransformedTextureMaterial {
+    pub base: Arc<dyn Material>,
+    pub transform: TextureTransform,
+}
+
+impl Material for TransformedTextureMaterial {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        self.base.color(&self.transform.texture_hit(hit))
+    }
+    fn linear_color(&self, hit: &Hit) -> Rgba<f32> {
+        self.base.linear_color(&self.transform.texture_hit(hit))
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.base.albedo()
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.base.spec_exponent()
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.base.refr_ratio()
+    }
+    fn refr_ratios(&self) -> [f32; 3] {
+        self.base.refr_ratios()
+    }
+    fn absorption(&self) -> [f32; 3] {
+        self.base.absorption()
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.base.visibility()
+    }
+    fn dirt(&self) -> Option<&Dirt> {
+        self.base.dirt()
+    }
+    fn shading_model(&self) -> ShadingModel {
+        self.base.shading_model()
+    }
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        self.base.shading_normal(hit)
+    }
+    // The BRDFs of the base material look its color up with the hit
+    fn eval_brdf(&self, wi: &Vec3, wo: &Vec3, hit: &Hit) -> Rgb<f32> {
+        self.base
+            .eval_brdf(wi, wo, &self.transform.texture_hit(hit))
+    }
+    fn sample(&self, wo: &Vec3, hit: &Hit, rng: &mut dyn RngCore) -> Option<BrdfSample> {
+        self.base.sample(wo, &self.transform.texture_hit(hit), rng)
+    }
+}
+
+/// Blend between two colors in linear space, `t` being the weight of the second one.
+fn mix_colors(color0: Rgba<u8>, color1: Rgba<u8>, t: f32) -> Rgba<u8> {
+    let (color0, color1) = (color::to_linear(color0), color::to_linear(color1));
+    let t = f32::max(0., f32::min(1., t));
+    let mut mixed = color0;
+    for ch in 0..4 {
+        mixed[ch] += (color1[ch] - color0[ch]) * t;
+    }
+    color::to_srgb8(mixed)
+}
+
+/// Marble: bands of `color1` veins across `color0` along the X axis, warped by turbulence.
+#[derive(Debug)]
+pub struct MarbleMaterial {
+    pub noise: Noise,
+    pub color0: Rgba<u8>,
+    pub color1: Rgba<u8>,
+    /// Number of noise features per unit of distance
+    pub scale: f32,
+    /// How much the veins are warped by the turbulence
+    pub turbulence: f32,
+    pub octaves: u32,
+    pub albedo: [f32; 4],
+    pub spec_exponent: f32,
+    pub refr_ratio: f32,
+    pub visibility: RayVisibility,
+}
+
+impl Material for MarbleMaterial {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        let point = hit.point * self.scale;
+        let phase = point.x + self.turbulence * self.noise.turbulence(point, self.octaves);
+        // Sharpen the sine so that veins are thinner than the background
+        let vein = f32::powf(1. - (0.5 + 0.5 * phase.sin()), 4.);
+        mix_colors(self.color0, self.color1, vein)
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.albedo
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.spec_exponent
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.refr_ratio
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
+}
+
+/// Wood: concentric rings around the Y axis alternating between `color0` and `color1`, made
+/// irregular by noise.
+#[derive(Debug)]
+pub struct WoodMaterial {
+    pub noise: Noise,
+    pub color0: Rgba<u8>,
+    pub color1: Rgba<u8>,
+    /// Number of noise features per unit of distance
+    pub scale: f32,
+    /// Number of rings per unit of distance
+    pub rings: f32,
+    /// How much the rings are distorted by the noise
+    pub turbulence: f32,
+    pub albedo: [f32; 4],
+    pub spec_exponent: f32,
+    pub refr_ratio: f32,
+    pub visibility: RayVisibility,
+}
+
+impl Material for WoodMaterial {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        let point = hit.point;
+        let distance = f32::sqrt(point.x * point.x + point.z * point.z);
+        let distortion = self.turbulence * self.noise.perlin(point * self.scale);
+        let ring = (distance * self.rings + distortion).rem_euclid(1.);
+        mix_colors(self.color0, self.color1, ring)
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.albedo
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.spec_exponent
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.refr_ratio
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
+}
+
+/// Cloudy blend between `color0` and `color1` following the turbulence of the noise.
+#[derive(Debug)]
+pub struct TurbulenceMaterial {
+    pub noise: Noise,
+    pub color0: Rgba<u8>,
+    pub color1: Rgba<u8>,
+    /// Number of noise features per unit of distance
+    pub scale: f32,
+    pub octaves: u32,
+    pub albedo: [f32; 4],
+    pub spec_exponent: f32,
+    pub refr_ratio: f32,
+    pub visibility: RayVisibility,
+}
+
+impl Material for TurbulenceMaterial {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        let turbulence = self.noise.turbulence(hit.point * self.scale, self.octaves);
+        mix_colors(self.color0, self.color1, turbulence)
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.albedo
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.spec_exponent
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.refr_ratio
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.visibility
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::sampling::{uniform_sphere, UNIFORM_SPHERE_PDF};
+    use super::*;
+
+    /// Side of the grid of directions albedos are estimated with.
+    const GRID_SIZE: u32 = 128;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    /// Hit on a surface facing +z, whose strands (for hair) run along +x.
+    fn hit() -> Hit {
+        Hit {
+            t: 1.,
+            point: point3(0.3, 0., 0.7),
+            normal: vec3(0., 0., 1.),
+            uv: point2(0.25, 0.5),
+            tangent: vec3(1., 0., 0.),
+            shadow_offset: vec3(0., 0., 0.),
+        }
+    }
+
+    /// White materials of each BRDF, reflecting as much light as their parameters allow.
+    fn materials() -> Vec<(&'static str, Arc<dyn Material>)> {
+        let microfacet = |metallic, roughness| -> Arc<dyn Material> {
+            Arc::new(MicrofacetMaterial {
+                color: WHITE,
+                metallic,
+                roughness,
+                visibility: RayVisibility::default(),
+            })
+        };
+        let hair = |diffuse, specular, spec_exponent| -> Arc<dyn Material> {
+            Arc::new(HairMaterial {
+                color: WHITE,
+                diffuse,
+                specular,
+                spec_exponent,
+                visibility: RayVisibility::default(),
+            })
+        };
+        vec![
+            (
+                "plain",
+                Arc::new(PlainMaterial {
+                    color: WHITE,
+                    albedo: [0.6, 0.4, 0., 0.],
+                    spec_exponent: 20.,
+                    refr_ratio: 1.,
+                    absorption: [0.; 3],
+                    abbe_number: 0.,
+                    visibility: RayVisibility::default(),
+                }),
+            ),
+            ("dielectric microfacet", microfacet(0., 0.5)),
+            ("matte microfacet", microfacet(0., 1.)),
+            ("metallic microfacet", microfacet(1., 0.5)),
+            ("hair", hair(0.5, 0.5, 50.)),
+            ("glossy hair", hair(0., 1., 1.)),
+        ]
+    }
+
+    /// Directions above the surface, from near normal to grazing incidence and at several angles
+    /// to the hair strands.
+    fn directions() -> Vec<Vec3> {
+        let mut dirs = Vec::new();
+        for elevation_idx in 0..4 {
+            let elevation = 0.05 + elevation_idx as f32 / 3. * 1.45;
+            for azimuth_idx in 0..3 {
+                let azimuth = 0.3 + azimuth_idx as f32 * PI / 3.;
+                dirs.push(vec3(
+                    elevation.sin() * azimuth.cos(),
+                    elevation.sin() * azimuth.sin(),
+                    elevation.cos(),
+                ));
+            }
+        }
+        dirs
+    }
+
+    /// Monte Carlo estimate of the fraction of the light coming from the whole hemisphere above
+    /// the surface that a material reflects towards `wo`, per channel. The incoming directions
+    /// are stratified over the sphere, so that the estimate converges quickly.
+    fn albedo(material: &dyn Material, wo: &Vec3) -> [f32; 3] {
+        let hit = hit();
+        let mut sum = [0f64; 3];
+        for idx in 0..GRID_SIZE * GRID_SIZE {
+            let cell = |coord: u32| (coord as f32 + 0.5) / GRID_SIZE as f32;
+            let wi = uniform_sphere(cell(idx % GRID_SIZE), cell(idx / GRID_SIZE));
+            if wi.z <= 0. {
+                continue;
+            }
+            let brdf = material.eval_brdf(&wi, wo, &hit);
+            for ch in 0..3 {
+                sum[ch] += (brdf[ch] * wi.z) as f64;
+            }
+        }
+        let weight = 1. / ((GRID_SIZE * GRID_SIZE) as f64 * UNIFORM_SPHERE_PDF as f64);
+        [
+            (sum[0] * weight) as f32,
+            (sum[1] * weight) as f32,
+            (sum[2] * weight) as f32,
+        ]
+    }
+
+    #[test]
+    fn brdfs_are_reciprocal() {
+        let hit = hit();
+        let dirs = directions();
+        for (name, material) in materials() {
+            for wi in &dirs {
+                for wo in &dirs {
+                    let forward = material.eval_brdf(wi, wo, &hit);
+                    let backward = material.eval_brdf(wo, wi, &hit);
+                    for ch in 0..3 {
+                        let tolerance = 1e-4 * f32::max(1., forward[ch]);
+                        assert!(
+                            (forward[ch] - backward[ch]).abs() <= tolerance,
+                            "{} BRDF isn't reciprocal for {:?} and {:?}: {} and {}",
+                            name,
+                            wi,
+                            wo,
+                            forward[ch],
+                            backward[ch]
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn brdfs_conserve_energy() {
+        for (name, material) in materials() {
+            for wo in &directions() {
+                let albedo = albedo(&*material, wo);
+                for &channel in &albedo {
+                    assert!(
+                        channel > 0. && channel <= 1.01,
+                        "{} reflects {} of the light towards {:?}",
+                        name,
+                        channel,
+                        wo
+                    );
+                }
+            }
+        }
+    }
 }
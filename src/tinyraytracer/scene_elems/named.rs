@@ -0,0 +1,45 @@
+use std::mem;
+
+use super::{materials::Material, Aabb, Hit, Ray, RayKind, TraceObj};
+
+/// Object given a name, e.g. to tell which object covers which pixels in the object ID pass.
+#[derive(Debug)]
+pub struct Named<T: TraceObj> {
+    pub object: T,
+    pub name: String,
+}
+
+impl<T: TraceObj> Named<T> {
+    pub fn new(object: T, name: &str) -> Self {
+        Named {
+            object,
+            name: name.to_string(),
+        }
+    }
+}
+
+impl<T: TraceObj> TraceObj for Named<T> {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.object.ray_intersect(ray)
+    }
+
+    fn ray_intersect_kind(&self, ray: &Ray, kind: RayKind) -> Option<Hit> {
+        self.object.ray_intersect_kind(ray, kind)
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.object.material()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.object.bounding_box()
+    }
+
+    fn memory_size(&self) -> usize {
+        mem::size_of::<String>() + self.name.len() + self.object.memory_size()
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
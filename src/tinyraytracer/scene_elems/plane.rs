@@ -1,40 +1,58 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use nalgebra::{Point3, Vector3};
-
-use super::{materials::Material, Ray, TraceObj};
+use super::super::math::{cross, dot, normalize, point2, to_vec, vec3, Point2, Point3, Vec3};
+use super::{materials::Material, Aabb, Hit, Ray, TraceObj};
 
 #[derive(Debug)]
 pub struct Plane {
-    pub p0: Point3<f32>,
-    pub normal: Vector3<f32>,
-    pub material: Rc<dyn Material>,
+    pub p0: Point3,
+    pub normal: Vec3,
+    pub material: Arc<dyn Material>,
 }
 
 impl TraceObj for Plane {
-    fn ray_intersect(&self, ray: &Ray) -> Option<f32> {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
         // Calculate using the equation for the intersection between a line and a plane
-        let d = -self.normal.dot(&self.p0.coords); // Parameter of plane equation
+        let d = -dot(&self.normal, &to_vec(&self.p0)); // Parameter of plane equation
 
-        let n_dot_raydir = -self.normal.dot(&ray.direction);
+        let n_dot_raydir = -dot(&self.normal, &ray.direction);
         // If 0, ray is parallel to plane. If less than zero, plane is behind ray
         if n_dot_raydir <= 0. {
             return None;
         }
 
-        let t = (self.normal.dot(&ray.origin.coords) + d) / n_dot_raydir;
-        if t < 0. {
+        let t = (dot(&self.normal, &to_vec(&ray.origin)) + d) / n_dot_raydir;
+        if !ray.in_range(t) {
             return None;
         }
-
-        Some(t)
-    }
-
-    fn get_normal(&self, _intersect_point: Point3<f32>) -> Vector3<f32> {
-        self.normal
+        let point = ray.origin + t * ray.direction;
+
+        // Planar texture coordinates, in world units along two axes lying on the plane
+        let helper_axis = if self.normal.x.abs() > 0.9 {
+            vec3(0., 1., 0.)
+        } else {
+            vec3(1., 0., 0.)
+        };
+        let tangent = normalize(&cross(&helper_axis, &self.normal));
+        let bitangent = cross(&self.normal, &tangent);
+        let plane_vec = point - self.p0;
+
+        Some(Hit {
+            t,
+            point,
+            normal: self.normal,
+            uv: point2(dot(&plane_vec, &tangent), dot(&plane_vec, &bitangent)),
+            tangent,
+            shadow_offset: vec3(0., 0., 0.),
+        })
     }
 
     fn material(&self) -> &dyn Material {
         &*self.material
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Infinite planes are unbounded
+        None
+    }
 }
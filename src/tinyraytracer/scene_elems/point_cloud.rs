@@ -0,0 +1,235 @@
+use std::error::Error;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::Rgba;
+
+use super::super::bvh::BvhTree;
+use super::super::color;
+use super::super::math::{
+    dot, length_squared, point2, point3, splat, try_normalize, vec3, Point2, Point3, Vec3,
+};
+use super::{
+    materials::{Dirt, Material, RayVisibility},
+    orthonormal_basis, Aabb, Hit, Ray, TraceObj,
+};
+
+/// Shape points of a cloud are drawn as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PointShape {
+    Sphere,
+    /// Flat disk turned towards the given camera position, cheaper to trace than a sphere. Like
+    /// billboards, the disks must be oriented for the final camera position
+    Disk {
+        camera_position: Point3,
+    },
+}
+
+/// Material of a point cloud: the base material, colored per point. Hits on the cloud hold the
+/// index of the point in their u texture coordinate, which is exact for up to 2^24 points. Points
+/// are lit with the Phong model built from the base material's albedo and specular exponent.
+#[derive(Debug)]
+struct PointColors {
+    colors: Vec<Rgba<u8>>,
+    base: Arc<dyn Material>,
+}
+
+impl Material for PointColors {
+    fn color(&self, hit: &Hit) -> Rgba<u8> {
+        match self.colors.get(hit.uv.x as usize) {
+            Some(&color) => color,
+            None => self.base.color(hit),
+        }
+    }
+    fn linear_color(&self, hit: &Hit) -> Rgba<f32> {
+        match self.colors.get(hit.uv.x as usize) {
+            Some(&color) => color::to_linear(color),
+            None => self.base.linear_color(hit),
+        }
+    }
+    fn albedo(&self) -> [f32; 4] {
+        self.base.albedo()
+    }
+    fn spec_exponent(&self) -> f32 {
+        self.base.spec_exponent()
+    }
+    fn refr_ratio(&self) -> f32 {
+        self.base.refr_ratio()
+    }
+    fn refr_ratios(&self) -> [f32; 3] {
+        self.base.refr_ratios()
+    }
+    fn absorption(&self) -> [f32; 3] {
+        self.base.absorption()
+    }
+    fn visibility(&self) -> RayVisibility {
+        self.base.visibility()
+    }
+    fn dirt(&self) -> Option<&Dirt> {
+        self.base.dirt()
+    }
+}
+
+/// Set of points drawn as small spheres or disks of the same radius, such as a LiDAR scan or a
+/// photogrammetry reconstruction. Each point can have its own color. The points are kept in their
+/// own BVH, so clouds of millions of points are a single object of the scene.
+#[derive(Debug)]
+pub struct PointCloud {
+    points: Vec<Point3>,
+    radius: f32,
+    shape: PointShape,
+    material: PointColors,
+    bvh: BvhTree,
+}
+
+impl PointCloud {
+    /// Cloud of the given points. `colors` holds the color of each point, points without one
+    /// take the color of the material.
+    pub fn new(
+        points: Vec<Point3>,
+        colors: Vec<Rgba<u8>>,
+        radius: f32,
+        shape: PointShape,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let extent = splat(radius);
+        let point_boxes = points
+            .iter()
+            .enumerate()
+            .map(|(i, &point)| (i, Aabb::new(point - extent, point + extent)))
+            .collect();
+        PointCloud {
+            points,
+            radius,
+            shape,
+            material: PointColors {
+                colors,
+                base: material,
+            },
+            bvh: BvhTree::build(point_boxes),
+        }
+    }
+
+    /// Load a point cloud from an ASCII .xyz file, as exported by most LiDAR and photogrammetry
+    /// tools: one point per line, given by its coordinates optionally followed by its red, green
+    /// and blue components from 0 to 255. Empty lines and lines starting with # are skipped.
+    pub fn open_xyz(
+        path: &Path,
+        radius: f32,
+        shape: PointShape,
+        material: Arc<dyn Material>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let mut points = Vec::new();
+        let mut colors = Vec::new();
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|value| !value.is_empty())
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|err| format!("{}:{}: {}", path.display(), line_idx + 1, err))?;
+            if values.len() < 3 {
+                return Err(format!(
+                    "{}:{}: expected at least 3 coordinates",
+                    path.display(),
+                    line_idx + 1
+                )
+                .into());
+            }
+            points.push(point3(values[0], values[1], values[2]));
+            if values.len() >= 6 {
+                let channel = |value: f32| value.max(0.).min(255.) as u8;
+                colors.push(Rgba([
+                    channel(values[3]),
+                    channel(values[4]),
+                    channel(values[5]),
+                    255,
+                ]));
+            }
+        }
+        // Colors only apply if every point has one
+        if colors.len() != points.len() {
+            colors.clear();
+        }
+        Ok(PointCloud::new(points, colors, radius, shape, material))
+    }
+
+    pub fn points_num(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Distance to the shape of a point along the ray, and the normal there.
+    fn point_intersect(&self, center: Point3, ray: &Ray) -> Option<(f32, Vec3)> {
+        match self.shape {
+            PointShape::Sphere => {
+                let to_center = ray.origin - center;
+                let b = dot(&ray.direction, &to_center);
+                let c = length_squared(&to_center) - self.radius * self.radius;
+                let discriminant = b * b - c;
+                if discriminant < 0. {
+                    return None;
+                }
+                let t = -b - discriminant.sqrt();
+                if !ray.in_range(t) {
+                    return None;
+                }
+                let normal = (ray.origin + ray.direction * t - center) / self.radius;
+                Some((t, normal))
+            }
+            PointShape::Disk { camera_position } => {
+                let normal = try_normalize(&(camera_position - center))?;
+                let n_dot_raydir = -dot(&normal, &ray.direction);
+                if n_dot_raydir <= 0. {
+                    return None;
+                }
+                let t = dot(&normal, &(ray.origin - center)) / n_dot_raydir;
+                let point = ray.origin + ray.direction * t;
+                if !ray.in_range(t) || length_squared(&(point - center)) > self.radius * self.radius
+                {
+                    return None;
+                }
+                Some((t, normal))
+            }
+        }
+    }
+}
+
+impl TraceObj for PointCloud {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (hit, _) = self.bvh.intersect(ray, |idx| {
+            let (t, normal) = self.point_intersect(self.points[idx], ray)?;
+            Some(Hit {
+                t,
+                point: ray.origin + ray.direction * t,
+                normal,
+                // The material looks the color of the point up with its index
+                uv: point2(idx as f32, 0.),
+                tangent: orthonormal_basis(normal).0,
+                shadow_offset: vec3(0., 0., 0.),
+            })
+        })?;
+        Some(hit)
+    }
+
+    fn material(&self) -> &dyn Material {
+        &self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounding_box()
+    }
+
+    fn memory_size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.points.len() * mem::size_of::<Point3>()
+            + self.material.colors.len() * mem::size_of::<Rgba<u8>>()
+            + self.bvh.memory_size()
+    }
+}
@@ -0,0 +1,50 @@
+use super::{materials::Material, Aabb, Hit, Ray, RayKind, TraceObj};
+
+/// Heavy object paired with a simplified stand-in, e.g. a low-poly version of a detailed mesh.
+/// Camera rays see the full object, while shadow, reflection and refraction rays, whose hits are
+/// blurred by the surfaces they bounce on, only test the much cheaper proxy. Both are shaded with
+/// the full object's material.
+#[derive(Debug)]
+pub struct Proxied<T: TraceObj, P: TraceObj> {
+    pub object: T,
+    pub proxy: P,
+}
+
+impl<T: TraceObj, P: TraceObj> Proxied<T, P> {
+    pub fn new(object: T, proxy: P) -> Self {
+        Proxied { object, proxy }
+    }
+}
+
+impl<T: TraceObj, P: TraceObj> TraceObj for Proxied<T, P> {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.object.ray_intersect(ray)
+    }
+
+    fn ray_intersect_kind(&self, ray: &Ray, kind: RayKind) -> Option<Hit> {
+        match kind {
+            RayKind::Camera => self.object.ray_intersect_kind(ray, kind),
+            _ => self.proxy.ray_intersect_kind(ray, kind),
+        }
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.object.material()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // The proxy may stick out of the full object
+        match (self.object.bounding_box(), self.proxy.bounding_box()) {
+            (Some(object_bbox), Some(proxy_bbox)) => Some(object_bbox.union(&proxy_bbox)),
+            _ => None,
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        self.object.memory_size() + self.proxy.memory_size()
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.object.name()
+    }
+}
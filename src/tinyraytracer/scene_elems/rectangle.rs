@@ -1,74 +1,87 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use nalgebra::{Point3, Vector3};
-
-use super::{materials::Material, Ray, TraceObj};
+use super::super::math::{
+    cross, dot, length, normalize, point2, to_vec, vec3, Point2, Point3, Vec3,
+};
+use super::{materials::Material, Aabb, Hit, Ray, TraceObj};
 
 #[derive(Debug)]
 pub struct Rectangle {
-    pub low_left: Point3<f32>,
-    pub up_right: Point3<f32>,
-    pub material: Rc<dyn Material>,
+    pub low_left: Point3,
+    pub up_right: Point3,
+    pub material: Arc<dyn Material>,
 }
 
 impl Rectangle {
-    fn get_width_height_vectors(&self) -> (Vector3<f32>, Vector3<f32>) {
-        let width_vec = Vector3::new(
+    fn get_width_height_vectors(&self) -> (Vec3, Vec3) {
+        let width_vec = vec3(
             self.up_right.x - self.low_left.x,
             self.up_right.y - self.low_left.y,
             0.0,
         );
-        let height_vec = Vector3::new(0.0, 0.0, self.up_right.z - self.low_left.z);
+        let height_vec = vec3(0.0, 0.0, self.up_right.z - self.low_left.z);
 
         (width_vec, height_vec)
     }
+
+    fn normal(&self) -> Vec3 {
+        let (width_vec, height_vec) = self.get_width_height_vectors();
+
+        normalize(&cross(&width_vec, &height_vec))
+    }
 }
 
 impl TraceObj for Rectangle {
-    fn ray_intersect(&self, ray: &Ray) -> Option<f32> {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
         // First, calculate the intersection point (if any) of the ray with the infinite plane that
         // contains the rectangle
-        let normal = self.get_normal(Point3::origin());
+        let normal = self.normal();
 
-        let d = -normal.dot(&self.low_left.coords); // Parameter of plane equation
+        let d = -dot(&normal, &to_vec(&self.low_left)); // Parameter of plane equation
 
-        let n_dot_raydir = -normal.dot(&ray.direction);
+        let n_dot_raydir = -dot(&normal, &ray.direction);
         if n_dot_raydir <= 0. {
             return None;
         }
 
         // If it exists, calculate the intersection point
-        let t = (normal.dot(&ray.origin.coords) + d) / n_dot_raydir;
-        if t < 0. {
+        let t = (dot(&normal, &to_vec(&ray.origin)) + d) / n_dot_raydir;
+        if !ray.in_range(t) {
             return None;
         }
         let intersection_point = ray.origin + t * ray.direction;
 
         // Then, check if the point is inside of the rectangle
         let (width_vec, height_vec) = self.get_width_height_vectors();
-        let height = height_vec.norm();
-        let width = width_vec.norm();
-        let height_dir = height_vec.normalize();
-        let width_dir = width_vec.normalize();
+        let height = length(&height_vec);
+        let width = length(&width_vec);
+        let height_dir = normalize(&height_vec);
+        let width_dir = normalize(&width_vec);
         // To do so, project the point into the width and height vectors
         let intersection_vec = intersection_point - self.low_left;
-        let height_proj = intersection_vec.dot(&height_dir);
-        let width_proj = intersection_vec.dot(&width_dir);
+        let height_proj = dot(&intersection_vec, &height_dir);
+        let width_proj = dot(&intersection_vec, &width_dir);
         // Then, verify if such projections fit into the dimensions of the rectangle
         if (0. ..height).contains(&height_proj) && (0. ..width).contains(&width_proj) {
-            Some(t)
+            Some(Hit {
+                t,
+                point: intersection_point,
+                normal,
+                uv: point2(width_proj / width, height_proj / height),
+                tangent: width_dir,
+                shadow_offset: vec3(0., 0., 0.),
+            })
         } else {
             None
         }
     }
 
-    fn get_normal(&self, _intersect_point: Point3<f32>) -> Vector3<f32> {
-        let (width_vec, height_vec) = self.get_width_height_vectors();
-
-        width_vec.cross(&height_vec).normalize()
-    }
-
     fn material(&self) -> &dyn Material {
         &*self.material
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Every corner of the rectangle takes its coordinates from either low_left or up_right
+        Some(Aabb::new(self.low_left, self.up_right))
+    }
 }
@@ -0,0 +1,290 @@
+use std::fmt;
+use std::sync::Arc;
+
+use super::super::math::{
+    abs, length, max_vec, normalize, point2, splat, vec3, vec3_from_fn, Point2, Point3, Vec3,
+};
+use super::{materials::Material, tangent_towards, Aabb, Hit, Ray, TraceObj};
+
+/// Maximum number of sphere tracing steps along a ray.
+const MAX_STEPS: u32 = 512;
+/// Distance to the surface under which a ray is considered to hit it.
+const SURFACE_EPSILON: f32 = 1e-4;
+/// Offset used to estimate normals from the gradient of the distance field.
+const NORMAL_EPSILON: f32 = 1e-4;
+/// Distance from the origin beyond which Mandelbulb points are considered to escape.
+const MANDELBULB_BAILOUT: f32 = 2.;
+
+/// Signed distance function: negative inside the shape, positive outside, and never larger than
+/// the actual distance to the surface.
+#[derive(Clone)]
+pub enum Sdf {
+    Sphere {
+        center: Point3,
+        radius: f32,
+    },
+    /// Box whose edges are rounded with the given radius
+    RoundBox {
+        center: Point3,
+        half_extents: Vec3,
+        radius: f32,
+    },
+    /// Torus lying on the xz plane
+    Torus {
+        center: Point3,
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    /// Union of two shapes, blended over a distance of `smoothness`
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        smoothness: f32,
+    },
+    /// Mandelbulb fractal of the given power, spanning about 2.4 times `scale`
+    Mandelbulb {
+        center: Point3,
+        scale: f32,
+        power: f32,
+        iterations: u32,
+    },
+    /// Arbitrary distance function, whose shape must lie within `bounds`
+    Custom {
+        distance: Arc<dyn Fn(Point3) -> f32 + Send + Sync>,
+        bounds: Aabb,
+    },
+}
+
+impl fmt::Debug for Sdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sdf::Sphere { center, radius } => f
+                .debug_struct("Sphere")
+                .field("center", &center)
+                .field("radius", &radius)
+                .finish(),
+            Sdf::RoundBox {
+                center,
+                half_extents,
+                radius,
+            } => f
+                .debug_struct("RoundBox")
+                .field("center", &center)
+                .field("half_extents", &half_extents)
+                .field("radius", &radius)
+                .finish(),
+            Sdf::Torus {
+                center,
+                major_radius,
+                minor_radius,
+            } => f
+                .debug_struct("Torus")
+                .field("center", &center)
+                .field("major_radius", &major_radius)
+                .field("minor_radius", &minor_radius)
+                .finish(),
+            Sdf::SmoothUnion {
+                ref a,
+                ref b,
+                smoothness,
+            } => f
+                .debug_struct("SmoothUnion")
+                .field("a", a)
+                .field("b", b)
+                .field("smoothness", &smoothness)
+                .finish(),
+            Sdf::Mandelbulb {
+                center,
+                scale,
+                power,
+                iterations,
+            } => f
+                .debug_struct("Mandelbulb")
+                .field("center", &center)
+                .field("scale", &scale)
+                .field("power", &power)
+                .field("iterations", &iterations)
+                .finish(),
+            // Closures can't be printed
+            Sdf::Custom { bounds, .. } => {
+                f.debug_struct("Custom").field("bounds", &bounds).finish()
+            }
+        }
+    }
+}
+
+impl Sdf {
+    /// Signed distance from a point to the surface of the shape.
+    pub fn distance(&self, point: Point3) -> f32 {
+        match *self {
+            Sdf::Sphere { center, radius } => length(&(point - center)) - radius,
+            Sdf::RoundBox {
+                center,
+                half_extents,
+                radius,
+            } => {
+                let q = abs(&(point - center)) - half_extents;
+                let outside = length(&max_vec(&q, &vec3(0., 0., 0.)));
+                let inside = f32::min(q.max(), 0.);
+                outside + inside - radius
+            }
+            Sdf::Torus {
+                center,
+                major_radius,
+                minor_radius,
+            } => {
+                let p = point - center;
+                let ring_dist = f32::sqrt(p.x * p.x + p.z * p.z) - major_radius;
+                f32::sqrt(ring_dist * ring_dist + p.y * p.y) - minor_radius
+            }
+            Sdf::SmoothUnion {
+                ref a,
+                ref b,
+                smoothness,
+            } => {
+                let (da, db) = (a.distance(point), b.distance(point));
+                if smoothness <= 0. {
+                    return f32::min(da, db);
+                }
+                // Polynomial smooth minimum
+                let h = f32::max(0., f32::min(1., 0.5 + 0.5 * (db - da) / smoothness));
+                db + (da - db) * h - smoothness * h * (1. - h)
+            }
+            Sdf::Mandelbulb {
+                center,
+                scale,
+                power,
+                iterations,
+            } => scale * mandelbulb_distance((point - center) / scale, power, iterations),
+            Sdf::Custom { ref distance, .. } => distance(point),
+        }
+    }
+
+    /// Box containing the whole shape.
+    pub fn bounding_box(&self) -> Aabb {
+        match *self {
+            Sdf::Sphere { center, radius } => {
+                let extent = splat(radius);
+                Aabb::new(center - extent, center + extent)
+            }
+            Sdf::RoundBox {
+                center,
+                half_extents,
+                radius,
+            } => {
+                let extent = half_extents + splat(radius);
+                Aabb::new(center - extent, center + extent)
+            }
+            Sdf::Torus {
+                center,
+                major_radius,
+                minor_radius,
+            } => {
+                let ring = major_radius + minor_radius;
+                let extent = vec3(ring, minor_radius, ring);
+                Aabb::new(center - extent, center + extent)
+            }
+            Sdf::SmoothUnion {
+                ref a,
+                ref b,
+                smoothness,
+            } => {
+                // Blending only grows the shapes by a fraction of the smoothness
+                let bbox = a.bounding_box().union(&b.bounding_box());
+                let margin = splat(f32::max(smoothness, 0.));
+                Aabb::new(bbox.min - margin, bbox.max + margin)
+            }
+            Sdf::Mandelbulb { center, scale, .. } => {
+                let extent = splat(1.2 * scale);
+                Aabb::new(center - extent, center + extent)
+            }
+            Sdf::Custom { bounds, .. } => bounds,
+        }
+    }
+
+    /// Unit normal at a point of the surface, estimated from the gradient of the distance field.
+    fn normal(&self, point: Point3) -> Vec3 {
+        let gradient = vec3_from_fn(|axis| {
+            let mut offset = vec3(0., 0., 0.);
+            offset[axis] = NORMAL_EPSILON;
+            self.distance(point + offset) - self.distance(point - offset)
+        });
+        normalize(&gradient)
+    }
+}
+
+/// Distance estimate to a Mandelbulb of the given power centered at the origin.
+fn mandelbulb_distance(point: Vec3, power: f32, iterations: u32) -> f32 {
+    let mut z = point;
+    let mut derivative = 1.;
+    let mut radius = length(&z);
+    for _ in 0..iterations {
+        if radius > MANDELBULB_BAILOUT || radius == 0. {
+            break;
+        }
+        // Raise z to the given power in spherical coordinates, then add the starting point
+        let theta = (z.z / radius).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        derivative = radius.powf(power - 1.) * power * derivative + 1.;
+        z = vec3(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        ) * radius.powf(power)
+            + point;
+        radius = length(&z);
+    }
+    if radius == 0. {
+        return 0.;
+    }
+    0.5 * radius.ln() * radius / derivative
+}
+
+/// Object defined by a signed distance function, rendered by sphere tracing: rays advance by the
+/// distance to the nearest surface, which can never overshoot it, until they get close enough.
+#[derive(Debug)]
+pub struct SdfObject {
+    pub sdf: Sdf,
+    pub material: Arc<dyn Material>,
+}
+
+impl TraceObj for SdfObject {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Only march through the part of the ray within the shape's bounds
+        let (t_start, t_end) = self.sdf.bounding_box().ray_span(ray, ray.t_max)?;
+
+        // Rays starting inside the shape (e.g. refracted rays) march towards its exit instead
+        let inside = self.sdf.distance(ray.origin + ray.direction * t_start) < 0.;
+        let mut t = t_start;
+        for _ in 0..MAX_STEPS {
+            let point = ray.origin + ray.direction * t;
+            let distance = self.sdf.distance(point);
+            let distance = if inside { -distance } else { distance };
+            if distance < SURFACE_EPSILON {
+                let normal = self.sdf.normal(point);
+                return Some(Hit {
+                    t,
+                    point,
+                    normal,
+                    // Planar texture coordinates along the x and z axes
+                    uv: point2(point.x, point.z),
+                    tangent: tangent_towards(vec3(1., 0., 0.), normal),
+                    shadow_offset: vec3(0., 0., 0.),
+                });
+            }
+            t += distance;
+            if t > t_end {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.sdf.bounding_box())
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use super::super::math::{dot, normalize, Point3, Vec3};
+use super::{materials::Material, Hit, Plane, Ray, TraceObj};
+
+/// Plane slicing the scene for cutaway views. Geometry on the side its normal points to is
+/// removed, revealing the inside of objects. Where the cut goes through a closed object, the
+/// opening can be capped with a solid surface of a highlight material.
+#[derive(Debug)]
+pub struct SectionPlane {
+    pub point: Point3,
+    /// Unit normal pointing towards the removed half-space
+    pub normal: Vec3,
+    /// Surface capping cut objects, lying on the section plane and facing the removed side
+    cap: Option<Plane>,
+}
+
+impl SectionPlane {
+    /// Section plane going through `point`, removing what lies on the side `normal` points to.
+    /// Cut objects are capped with `cap_material` if given, and left open otherwise.
+    pub fn new(point: Point3, normal: Vec3, cap_material: Option<Arc<dyn Material>>) -> Self {
+        let normal = normalize(&normal);
+        SectionPlane {
+            point,
+            normal,
+            cap: cap_material.map(|material| Plane {
+                p0: point,
+                normal,
+                material,
+            }),
+        }
+    }
+
+    /// Find the nearest intersection of a ray with the part of the scene that is kept, using
+    /// `intersect` to intersect the scene with a ray. If the ray enters the kept half-space
+    /// inside a closed object, the cap is returned instead.
+    pub fn intersect<'a, F>(&'a self, ray: &Ray, intersect: F) -> Option<(Hit, &'a dyn TraceObj)>
+    where
+        F: FnOnce(&Ray) -> Option<(Hit, &'a dyn TraceObj)>,
+    {
+        // Signed distance from the ray origin to the plane, positive on the removed side
+        let origin_dist = dot(&(ray.origin - self.point), &self.normal);
+        let dir_dot_n = dot(&ray.direction, &self.normal);
+        // Distance along the ray at which it crosses the plane
+        let t_cross = -origin_dist / dir_dot_n;
+
+        if origin_dist <= 0. {
+            // The ray starts in the kept half-space, so only the part before it crosses the
+            // plane (if it ever does) is kept
+            let t_max = if dir_dot_n > 0. {
+                f32::min(ray.t_max, t_cross)
+            } else {
+                ray.t_max
+            };
+            return intersect(&Ray { t_max, ..*ray });
+        }
+
+        // The ray starts in the removed half-space. It must head towards the plane to see
+        // anything
+        if dir_dot_n >= 0. || t_cross > ray.t_max {
+            return None;
+        }
+        let clipped = Ray {
+            t_min: f32::max(ray.t_min, t_cross),
+            ..*ray
+        };
+        let (hit, obj) = intersect(&clipped)?;
+
+        // Hitting the inside of a surface first means the ray entered the kept half-space within
+        // a closed object
+        match self.cap {
+            Some(ref cap) if t_cross >= ray.t_min && dot(&hit.normal, &ray.direction) > 0. => cap
+                .ray_intersect(ray)
+                .map(|cap_hit| (cap_hit, cap as &dyn TraceObj)),
+            _ => Some((hit, obj)),
+        }
+    }
+}
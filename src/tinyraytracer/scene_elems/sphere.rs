@@ -1,25 +1,25 @@
-use std::rc::Rc;
+use std::f32::consts::PI;
+use std::sync::Arc;
 
-use nalgebra::{Point3, Vector3};
-
-use super::{materials::Material, Ray, TraceObj};
+use super::super::math::{dot, length_squared, normalize, point2, vec3, Point2, Point3};
+use super::{materials::Material, tangent_towards, Aabb, Hit, Ray, TraceObj};
 
 #[derive(Debug)]
 pub struct Sphere {
-    pub center: Point3<f32>,
+    pub center: Point3,
     pub radius: f32,
-    pub material: Rc<dyn Material>,
+    pub material: Arc<dyn Material>,
 }
 
 impl TraceObj for Sphere {
-    fn ray_intersect(&self, ray: &Ray) -> Option<f32> {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
         // Vector from ray origin to sphere center
         let orig_to_center = self.center - ray.origin;
         // Length of the vector that goes from the ray origin to the vertical line that passes
         // through the sphere's center
-        let proj_on_ray = orig_to_center.dot(&ray.direction);
+        let proj_on_ray = dot(&orig_to_center, &ray.direction);
         // Squared distance between sphere center and casted ray
-        let sphere_center_to_ray_sq = orig_to_center.norm_squared() - proj_on_ray * proj_on_ray;
+        let sphere_center_to_ray_sq = length_squared(&orig_to_center) - proj_on_ray * proj_on_ray;
 
         // If line from sphere center to ray is longer than radius, there is no intersection point
         if sphere_center_to_ray_sq > self.radius * self.radius {
@@ -34,22 +34,41 @@ impl TraceObj for Sphere {
         let intersection0 = proj_on_ray - centerline_to_intersection;
         let intersection1 = proj_on_ray + centerline_to_intersection;
 
-        match (intersection0, intersection1) {
-            // If first intersection is positive, it is in front of the ray's origin so return that
-            _ if intersection0 > 0. => Some(intersection0),
-            // If first intersection is negative, it is behind the ray, so if the second one is
-            // positive, return that
-            _ if intersection1 > 0. => Some(intersection1),
-            // If both are negative, both are behind the ray, so there is no intersection
-            _ => None,
-        }
-    }
+        let t = match (intersection0, intersection1) {
+            // If first intersection is within the ray's range, it is the nearest one so return that
+            _ if ray.in_range(intersection0) => intersection0,
+            // Otherwise it is behind the ray (or clipped), so if the second one is within range,
+            // return that
+            _ if ray.in_range(intersection1) => intersection1,
+            // If both are out of range, there is no intersection
+            _ => return None,
+        };
 
-    fn get_normal(&self, intersect_point: Point3<f32>) -> Vector3<f32> {
-        (intersect_point - self.center).normalize()
+        let point = ray.origin + ray.direction * t;
+        let normal = normalize(&(point - self.center));
+        // Spherical coordinates of the normal: longitude for u, latitude for v
+        let uv = point2(
+            0.5 + f32::atan2(normal.z, normal.x) / (2. * PI),
+            0.5 + f32::asin(normal.y.max(-1.).min(1.)) / PI,
+        );
+
+        Some(Hit {
+            t,
+            point,
+            normal,
+            uv,
+            // Direction of increasing longitude
+            tangent: tangent_towards(vec3(-normal.z, 0., normal.x), normal),
+            shadow_offset: vec3(0., 0., 0.),
+        })
     }
 
     fn material(&self) -> &dyn Material {
         &*self.material
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = vec3(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.center - radius, self.center + radius))
+    }
 }
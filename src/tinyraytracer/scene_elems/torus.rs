@@ -0,0 +1,220 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use super::super::math::{
+    cross, dot, dvec3, length, normalize, point2, vec3, vec3_from_fn, Point2, Point3, Vec3,
+};
+use super::{materials::Material, orthonormal_basis, tangent_towards, Aabb, Hit, Ray, TraceObj};
+
+/// Newton iterations refining each root of the quartic, which the closed-form solution only
+/// gives approximately.
+const NEWTON_ITERATIONS: usize = 4;
+
+/// Torus made by sweeping a circle of radius `minor_radius` around a circle of radius
+/// `major_radius`, centered at `center` and perpendicular to `axis`.
+#[derive(Debug)]
+pub struct Torus {
+    pub center: Point3,
+    /// Unit vector along the torus' axis of symmetry
+    pub axis: Vec3,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl TraceObj for Torus {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (tangent, bitangent) = orthonormal_basis(self.axis);
+        let to_local = |v: Vec3| {
+            dvec3(
+                dot(&v, &tangent) as f64,
+                dot(&v, &bitangent) as f64,
+                dot(&v, &self.axis) as f64,
+            )
+        };
+
+        // Start the ray on the torus' bounding sphere, so that the quartic's coefficients stay
+        // small even for distant rays
+        let bounding_radius = self.major_radius + self.minor_radius;
+        let origin_dist = length(&(ray.origin - self.center));
+        let t_offset = f32::max(0., origin_dist - bounding_radius);
+        let origin = to_local(ray.origin + ray.direction * t_offset - self.center);
+        let dir = to_local(ray.direction);
+
+        // Substituting the ray in the implicit equation of a torus around the z axis,
+        // (x² + y² + z² + R² - r²)² = 4R²(x² + y²), gives a quartic in t
+        let (major_sq, minor_sq) = (
+            (self.major_radius as f64).powi(2),
+            (self.minor_radius as f64).powi(2),
+        );
+        let n = origin.x * dir.x + origin.y * dir.y + origin.z * dir.z;
+        let k =
+            origin.x * origin.x + origin.y * origin.y + origin.z * origin.z + major_sq - minor_sq;
+        let coeffs = [
+            1.,
+            4. * n,
+            4. * n * n + 2. * k - 4. * major_sq * (dir.x * dir.x + dir.y * dir.y),
+            4. * n * k - 8. * major_sq * (origin.x * dir.x + origin.y * dir.y),
+            k * k - 4. * major_sq * (origin.x * origin.x + origin.y * origin.y),
+        ];
+
+        let mut roots = solve_quartic(coeffs);
+        roots.sort_by(|a, b| a.total_cmp(b));
+        let t_local = roots
+            .into_iter()
+            .find(|&t| ray.in_range(t as f32 + t_offset))?;
+        let t = t_local as f32 + t_offset;
+
+        // The normal points away from the nearest point of the circle the tube sweeps around
+        let local = origin + dir * t_local;
+        let ring_dist = f64::sqrt(local.x * local.x + local.y * local.y);
+        let local_normal = if ring_dist > 0. {
+            let ring_scale = self.major_radius as f64 / ring_dist;
+            dvec3(
+                local.x * (1. - ring_scale),
+                local.y * (1. - ring_scale),
+                local.z,
+            )
+        } else {
+            local
+        };
+        let normal = normalize(
+            &(tangent * local_normal.x as f32
+                + bitangent * local_normal.y as f32
+                + self.axis * local_normal.z as f32),
+        );
+
+        // Angle around the axis for u, angle around the tube for v
+        let u = local.y.atan2(local.x) as f32 / (2. * PI) + 0.5;
+        let v = local.z.atan2(ring_dist - self.major_radius as f64) as f32 / (2. * PI) + 0.5;
+
+        let point = ray.origin + ray.direction * t;
+        Some(Hit {
+            t,
+            point,
+            normal,
+            uv: point2(u, v),
+            tangent: tangent_towards(cross(&self.axis, &(point - self.center)), normal),
+            shadow_offset: vec3(0., 0., 0.),
+        })
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Along each world axis, the ring spans the major radius scaled by how much that axis
+        // lies in the ring's plane, and the tube adds its radius on both sides
+        let extent = vec3_from_fn(|i| {
+            self.major_radius * f32::sqrt(f32::max(0., 1. - self.axis[i] * self.axis[i]))
+                + self.minor_radius
+        });
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
+
+/// Evaluate a polynomial and its derivative, coefficients going from the highest degree down.
+fn eval_poly(coeffs: &[f64], x: f64) -> (f64, f64) {
+    coeffs.iter().fold((0., 0.), |(value, derivative), &coeff| {
+        (value * x + coeff, derivative * x + value)
+    })
+}
+
+/// Real roots of a x² + b x + c with a != 0.
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    let discriminant = b * b - 4. * a * c;
+    if discriminant < 0. {
+        return Vec::new();
+    }
+    // Avoid the cancellation between b and the square root of the discriminant
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    if q == 0. {
+        return vec![0.];
+    }
+    vec![q / a, c / q]
+}
+
+/// Largest real root of x³ + a x² + b x + c.
+fn cubic_max_root(a: f64, b: f64, c: f64) -> f64 {
+    // Depressed cubic y³ + p y + q with x = y - a / 3
+    let p = b - a * a / 3.;
+    let q = 2. * a * a * a / 27. - a * b / 3. + c;
+    let discriminant = q * q / 4. + p * p * p / 27.;
+
+    let y = if discriminant > 0. {
+        // Single real root, from Cardano's formula
+        let sqrt_disc = discriminant.sqrt();
+        (-q / 2. + sqrt_disc).cbrt() + (-q / 2. - sqrt_disc).cbrt()
+    } else {
+        // Three real roots. The trigonometric form gives the largest one with k = 0
+        let radius = f64::sqrt(-p / 3.);
+        let cos = if radius > 0. {
+            f64::max(-1., f64::min(1., -q / (2. * radius * radius * radius)))
+        } else {
+            0.
+        };
+        2. * radius * f64::cos(cos.acos() / 3.)
+    };
+    y - a / 3.
+}
+
+/// Real roots of a quartic polynomial given by its coefficients from x⁴ down to the constant term.
+/// Ferrari's method gives approximate roots, which are then refined with Newton's method.
+fn solve_quartic(coeffs: [f64; 5]) -> Vec<f64> {
+    let (a, b, c, d) = (
+        coeffs[1] / coeffs[0],
+        coeffs[2] / coeffs[0],
+        coeffs[3] / coeffs[0],
+        coeffs[4] / coeffs[0],
+    );
+
+    // Depressed quartic y⁴ + p y² + q y + r with x = y - a / 4
+    let shift = a / 4.;
+    let p = b - 6. * shift * shift;
+    let q = c - 2. * b * shift + 8. * shift * shift * shift;
+    let r = d - c * shift + b * shift * shift - 3. * shift * shift * shift * shift;
+
+    let mut roots = Vec::with_capacity(4);
+    if q.abs() < 1e-12 {
+        // Biquadratic: solve for y²
+        for y_sq in solve_quadratic(1., p, r) {
+            if y_sq >= 0. {
+                roots.push(y_sq.sqrt());
+                roots.push(-y_sq.sqrt());
+            }
+        }
+    } else {
+        // Split the quartic into two quadratics with a positive root m of the resolvent cubic
+        let m = cubic_max_root(p, p * p / 4. - r, -q * q / 8.);
+        if m <= 0. {
+            return Vec::new();
+        }
+        let sqrt_2m = f64::sqrt(2. * m);
+        roots.extend(solve_quadratic(
+            1.,
+            sqrt_2m,
+            p / 2. + m - q / (2. * sqrt_2m),
+        ));
+        roots.extend(solve_quadratic(
+            1.,
+            -sqrt_2m,
+            p / 2. + m + q / (2. * sqrt_2m),
+        ));
+    }
+
+    roots
+        .into_iter()
+        .map(|y| {
+            let mut x = y - shift;
+            for _ in 0..NEWTON_ITERATIONS {
+                let (value, derivative) = eval_poly(&coeffs, x);
+                if derivative == 0. {
+                    break;
+                }
+                x -= value / derivative;
+            }
+            x
+        })
+        .collect()
+}
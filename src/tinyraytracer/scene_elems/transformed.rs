@@ -0,0 +1,94 @@
+use std::mem;
+
+use super::super::math::{
+    inverse, normalize, point3, rotate, scaling, transform_point, transform_vector, Similarity,
+};
+
+use super::{materials::Material, Aabb, Hit, Ray, RayKind, TraceObj};
+
+/// Object placed in the scene through a similarity transform (rotation, translation and uniform
+/// scale). Rays are transformed into the object's space and hits back into world space. Wrapping
+/// an `Arc` of an object instances it several times without duplicating its geometry.
+#[derive(Debug)]
+pub struct Transformed<T: TraceObj> {
+    pub object: T,
+    /// Transform from the object's space to world space
+    pub transform: Similarity,
+}
+
+impl<T: TraceObj> Transformed<T> {
+    pub fn new(object: T, transform: Similarity) -> Self {
+        Transformed { object, transform }
+    }
+}
+
+impl<T: TraceObj> Transformed<T> {
+    /// Intersect the object with the ray brought into its space by `intersect`, and bring the hit
+    /// back into world space.
+    fn intersect_local<F>(&self, ray: &Ray, intersect: F) -> Option<Hit>
+    where
+        F: FnOnce(&Ray) -> Option<Hit>,
+    {
+        // Object space distances are world space distances divided by the scale
+        let scale = scaling(&self.transform);
+        let to_local = inverse(&self.transform);
+        let local_ray = Ray {
+            origin: transform_point(&to_local, &ray.origin),
+            direction: normalize(&transform_vector(&to_local, &ray.direction)),
+            t_min: ray.t_min / scale,
+            t_max: ray.t_max / scale,
+        };
+
+        let hit = intersect(&local_ray)?;
+        // A uniform scale doesn't change the direction of normals, only rotations do
+        Some(Hit {
+            t: hit.t * scale,
+            point: transform_point(&self.transform, &hit.point),
+            normal: rotate(&self.transform, &hit.normal),
+            uv: hit.uv,
+            tangent: rotate(&self.transform, &hit.tangent),
+            shadow_offset: transform_vector(&self.transform, &hit.shadow_offset),
+        })
+    }
+}
+
+impl<T: TraceObj> TraceObj for Transformed<T> {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.intersect_local(ray, |local_ray| self.object.ray_intersect(local_ray))
+    }
+
+    fn ray_intersect_kind(&self, ray: &Ray, kind: RayKind) -> Option<Hit> {
+        self.intersect_local(ray, |local_ray| {
+            self.object.ray_intersect_kind(local_ray, kind)
+        })
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.object.material()
+    }
+
+    fn memory_size(&self) -> usize {
+        mem::size_of::<Similarity>() + self.object.memory_size()
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.object.name()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Box containing the transformed corners of the object's box
+        let bbox = self.object.bounding_box()?;
+        let corner = |i: usize| {
+            point3(
+                if i & 1 == 0 { bbox.min.x } else { bbox.max.x },
+                if i & 2 == 0 { bbox.min.y } else { bbox.max.y },
+                if i & 4 == 0 { bbox.min.z } else { bbox.max.z },
+            )
+        };
+        let first = transform_point(&self.transform, &corner(0));
+        Some((1..8).fold(Aabb::new(first, first), |world_bbox, i| {
+            let point = transform_point(&self.transform, &corner(i));
+            world_bbox.union(&Aabb::new(point, point))
+        }))
+    }
+}
@@ -1,74 +1,102 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use nalgebra::{Point3, Vector3};
+use super::super::math::{cross, dot, normalize, point2, vec3, Point2, Point3, Vec3};
+use super::{materials::Material, Aabb, Hit, Ray, TraceObj};
 
-use super::{materials::Material, Ray, TraceObj};
+/// Determinants below this mean the ray is parallel to the triangle's plane.
+const PARALLEL_EPSILON: f32 = 1e-12;
 
 #[derive(Debug)]
 pub struct Triangle {
-    pub a: Point3<f32>,
-    pub b: Point3<f32>,
-    pub c: Point3<f32>,
-    pub material: Rc<dyn Material>,
+    pub a: Point3,
+    pub b: Point3,
+    pub c: Point3,
+    /// Whether rays can hit the back face too. Otherwise back faces are culled
+    pub two_sided: bool,
+    pub material: Arc<dyn Material>,
 }
 
-impl TraceObj for Triangle {
-    fn ray_intersect(&self, ray: &Ray) -> Option<f32> {
-        // First, calculate the intersection point (if any) of the ray with the infinite plane that
-        // contains the triangle
-        let normal = self.get_normal(Point3::origin());
-
-        let d = -normal.dot(&self.a.coords); // Parameter of plane equation
-
-        let n_dot_raydir = -normal.dot(&ray.direction);
-        if n_dot_raydir <= 0. {
-            return None;
-        }
+/// Normal of the triangle defined by the given vertices. Vertices are in counter-clockwise order
+/// when looking at the front face.
+pub fn triangle_normal(a: Point3, b: Point3, c: Point3) -> Vec3 {
+    let vec0 = b - a;
+    let vec1 = c - a;
+    normalize(&cross(&vec0, &vec1))
+}
 
-        // If it exists, calculate the intersection point
-        let t = (normal.dot(&ray.origin.coords) + d) / n_dot_raydir;
-        if t < 0. {
-            return None;
-        }
-        let intersection_point = ray.origin + t * ray.direction;
+/// Intersection between a ray and the triangle defined by the given vertices, if any. Back faces
+/// are only hit in two-sided mode, and the normal always points out of the front face. The UV
+/// coordinates of the hit are the barycentric weights of vertices b and c.
+pub fn triangle_intersect(
+    a: Point3,
+    b: Point3,
+    c: Point3,
+    ray: &Ray,
+    two_sided: bool,
+) -> Option<Hit> {
+    let (t, [_, weight_b, weight_c]) = triangle_ray_barycentric(a, b, c, ray, two_sided)?;
+    Some(Hit {
+        t,
+        point: ray.origin + t * ray.direction,
+        normal: triangle_normal(a, b, c),
+        uv: point2(weight_b, weight_c),
+        // U is the weight of vertex b
+        tangent: normalize(&(b - a)),
+        shadow_offset: vec3(0., 0., 0.),
+    })
+}
 
-        // Through barycentric coordinates, calculate if point is inside the triangle
-        // edge 0
-        let vec_ab = self.b - self.a;
-        let vec_ap = intersection_point - self.a;
-        let c = vec_ab.cross(&vec_ap);
-        let w = normal.dot(&c);
-        if w < 0. {
-            return None;
-        }
+/// Distance along the ray to its intersection with the triangle, along with the barycentric
+/// weights of vertices a, b and c at the intersection point, if the ray hits the triangle within
+/// its range.
+///
+/// Möller–Trumbore intersection: solve origin + t * direction = a + u * (b - a) + v * (c - a)
+/// for t, u and v with Cramer's rule, sharing the cross products between the determinants.
+pub fn triangle_ray_barycentric(
+    a: Point3,
+    b: Point3,
+    c: Point3,
+    ray: &Ray,
+    two_sided: bool,
+) -> Option<(f32, [f32; 3])> {
+    let edge_ab = b - a;
+    let edge_ac = c - a;
+    let p = cross(&ray.direction, &edge_ac);
+    // Positive when the ray hits the front face, negative for the back face
+    let det = dot(&edge_ab, &p);
+    if det < PARALLEL_EPSILON && (!two_sided || det > -PARALLEL_EPSILON) {
+        return None;
+    }
+    let inv_det = 1. / det;
 
-        // edge 1
-        let vec_bc = self.c - self.b;
-        let vec_bp = intersection_point - self.b;
-        let c = vec_bc.cross(&vec_bp);
-        let u = normal.dot(&c);
-        if u < 0. {
-            return None;
-        }
+    let a_to_origin = ray.origin - a;
+    let u = dot(&a_to_origin, &p) * inv_det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
+    }
+    let q = cross(&a_to_origin, &edge_ab);
+    let v = dot(&ray.direction, &q) * inv_det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
 
-        // edge 2
-        let vec_ca = self.a - self.c;
-        let vec_cp = intersection_point - self.c;
-        let c = vec_ca.cross(&vec_cp);
-        let v = normal.dot(&c);
-        if v < 0. {
-            return None;
-        }
-        Some(t)
+    let t = dot(&edge_ac, &q) * inv_det;
+    if !ray.in_range(t) {
+        return None;
     }
+    Some((t, [1. - u - v, u, v]))
+}
 
-    fn get_normal(&self, _intersect_point: Point3<f32>) -> Vector3<f32> {
-        let vec0 = self.b - self.a;
-        let vec1 = self.c - self.a;
-        vec0.cross(&vec1).normalize()
+impl TraceObj for Triangle {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        triangle_intersect(self.a, self.b, self.c, ray, self.two_sided)
     }
 
     fn material(&self) -> &dyn Material {
         &*self.material
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.a, self.b).union(&Aabb::new(self.c, self.c)))
+    }
 }
@@ -0,0 +1,549 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use obj::raw::parse_obj;
+use obj::{Obj, Position, Vertex};
+
+use super::super::bvh::BvhTree;
+use super::super::math::{
+    cross, dot, identity, length, normalize, point3, rotate, to_point, to_vec, transform_point,
+    try_normalize, vec3, Point3, Similarity, Vec3,
+};
+use super::triangle::triangle_intersect;
+use super::{materials::Material, tangent_towards, Aabb, Hit, Ray, TraceObj};
+
+/// Faces whose doubled area is below this are considered degenerate.
+const MIN_FACE_AREA: f32 = 1e-12;
+
+/// Most Loop subdivision levels applied to imported models. Each level multiplies the number of
+/// faces by four.
+const MAX_SUBDIVISIONS: u32 = 3;
+
+/// Triangle mesh stored as flat vertex and index arrays. Faces are accelerated with their own
+/// bounding volume hierarchy, so the whole mesh is a single scene object.
+#[derive(Debug)]
+pub struct TriangleMesh {
+    vertices: Vec<Point3>,
+    /// Per-vertex normals, interpolated across faces for smooth shading. Faces are shaded flat
+    /// if absent
+    normals: Option<Vec<Vec3>>,
+    /// Three vertex indices per face
+    indices: Vec<u32>,
+    /// Whether rays can hit the back faces too. Otherwise back faces are culled
+    two_sided: bool,
+    material: Arc<dyn Material>,
+    bvh: BvhTree,
+}
+
+/// Vertical axis of a model file. Some modeling tools export models with the Z axis pointing up,
+/// whereas the renderer's camera uses Y as the up axis.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+/// Axis conversion and placement applied to the vertices of imported models.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MeshImportOptions {
+    pub up_axis: UpAxis,
+    /// Uniform scale converting model units into scene units
+    pub scale: f32,
+    /// Mirror the model along the X, Y and Z axes respectively, after the up axis conversion
+    pub mirror: [bool; 3],
+    /// Loop subdivision levels applied to the model, up to 3, to smooth low-poly models.
+    /// Subdivided models are smooth shaded with recomputed normals
+    pub subdivisions: u32,
+    /// Placement of the converted model in the scene. Scene files set it through the mesh's
+    /// `transform`
+    #[serde(skip, default = "identity")]
+    pub transform: Similarity,
+}
+
+impl Default for MeshImportOptions {
+    fn default() -> Self {
+        MeshImportOptions {
+            up_axis: UpAxis::Y,
+            scale: 1.,
+            mirror: [false; 3],
+            subdivisions: 0,
+            transform: identity(),
+        }
+    }
+}
+
+impl MeshImportOptions {
+    /// Convert a model vertex into scene coordinates and move it into place.
+    pub fn apply(&self, vertex: Point3) -> Point3 {
+        let mut converted = match self.up_axis {
+            UpAxis::Y => vertex,
+            // Rotate -90 degrees around X so that +Z maps to +Y
+            UpAxis::Z => point3(vertex.x, vertex.z, -vertex.y),
+        } * self.scale;
+        for axis in 0..3 {
+            if self.mirror[axis] {
+                converted[axis] = -converted[axis];
+            }
+        }
+        transform_point(&self.transform, &converted)
+    }
+
+    /// Convert a model normal into scene coordinates.
+    pub fn apply_normal(&self, normal: Vec3) -> Vec3 {
+        let mut converted = match self.up_axis {
+            UpAxis::Y => normal,
+            UpAxis::Z => vec3(normal.x, normal.z, -normal.y),
+        };
+        for axis in 0..3 {
+            if self.mirror[axis] {
+                converted[axis] = -converted[axis];
+            }
+        }
+        // A negative scale mirrors the model through its origin. The transform's uniform scale
+        // doesn't change the direction of normals, only its rotation does
+        rotate(&self.transform, &converted) * self.scale.signum()
+    }
+
+    /// Check if the conversion turns the model inside out, which reverses the winding order of
+    /// its faces.
+    fn flips_winding(&self) -> bool {
+        let mirrored_axes = self.mirror.iter().filter(|&&mirror| mirror).count();
+        (mirrored_axes % 2 == 1) != (self.scale < 0.)
+    }
+}
+
+/// Check if a face would produce a NaN normal: either it has a non-finite vertex or it has no
+/// area.
+fn is_degenerate(a: Point3, b: Point3, c: Point3) -> bool {
+    let finite = [a, b, c]
+        .iter()
+        .all(|vertex| vertex.iter().all(|coord| coord.is_finite()));
+    !finite || length(&cross(&(b - a), &(c - a))) < MIN_FACE_AREA
+}
+
+impl TriangleMesh {
+    /// Create a flat shaded mesh from its vertices and the vertex indices of its faces.
+    /// Degenerate faces (zero area, NaN vertices or out of range indices) are skipped with a
+    /// warning.
+    pub fn new(vertices: Vec<Point3>, indices: Vec<u32>, material: Arc<dyn Material>) -> Self {
+        TriangleMesh::build(vertices, None, indices, material)
+    }
+
+    /// Create a smooth shaded mesh, with one normal per vertex.
+    pub fn with_normals(
+        vertices: Vec<Point3>,
+        normals: Vec<Vec3>,
+        indices: Vec<u32>,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        assert_eq!(
+            vertices.len(),
+            normals.len(),
+            "Expected one normal per vertex"
+        );
+        TriangleMesh::build(vertices, Some(normals), indices, material)
+    }
+
+    /// Create a smooth shaded mesh by applying `levels` steps of Loop subdivision to the given
+    /// faces, up to 3, and computing vertex normals from the subdivided faces. Vertices at the same
+    /// position are merged first, so that models split along their normal seams stay closed.
+    pub fn subdivided(
+        vertices: Vec<Point3>,
+        indices: Vec<u32>,
+        levels: u32,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let (mut vertices, mut indices) = weld_vertices(&vertices, &indices);
+        for _ in 0..levels.min(MAX_SUBDIVISIONS) {
+            let (subdivided_vertices, subdivided_indices) = loop_subdivide(&vertices, &indices);
+            vertices = subdivided_vertices;
+            indices = subdivided_indices;
+        }
+        let normals = vertex_normals(&vertices, &indices);
+        TriangleMesh::with_normals(vertices, normals, indices, material)
+    }
+
+    fn build(
+        vertices: Vec<Point3>,
+        normals: Option<Vec<Vec3>>,
+        indices: Vec<u32>,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let valid_indices: Vec<u32> = indices
+            .chunks_exact(3)
+            .filter(|face| {
+                let face_vertices: Option<Vec<Point3>> = face
+                    .iter()
+                    .map(|&idx| vertices.get(idx as usize).cloned())
+                    .collect();
+                match face_vertices {
+                    Some(face_vertices) => {
+                        !is_degenerate(face_vertices[0], face_vertices[1], face_vertices[2])
+                    }
+                    None => false,
+                }
+            })
+            .flatten()
+            .cloned()
+            .collect();
+        let skipped_faces = (indices.len() - valid_indices.len()) / 3;
+        if skipped_faces > 0 {
+            eprintln!("Warning: skipped {} degenerate mesh faces", skipped_faces);
+        }
+
+        let mut mesh = TriangleMesh {
+            vertices,
+            normals,
+            indices: valid_indices,
+            two_sided: false,
+            material,
+            bvh: BvhTree::default(),
+        };
+        let face_boxes = (0..mesh.faces_num())
+            .map(|face| {
+                let [a, b, c] = mesh.face_vertices(face);
+                (face, Aabb::new(a, b).union(&Aabb::new(c, c)))
+            })
+            .collect();
+        mesh.bvh = BvhTree::build(face_boxes);
+        mesh
+    }
+
+    /// Load an .obj file into a mesh, converting its axes with the given options. Models with
+    /// normals for every face are smooth shaded, the rest are shaded flat unless subdivided.
+    pub fn open_obj(
+        path: &Path,
+        options: &MeshImportOptions,
+        material: Arc<dyn Material>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let raw = parse_obj(BufReader::new(File::open(path)?))?;
+        if !raw.normals.is_empty() {
+            if let Ok(model) = Obj::<Vertex, u32>::new(raw.clone()) {
+                return Ok(TriangleMesh::from_obj_smooth(&model, options, material));
+            }
+        }
+        let model = Obj::<Position, u32>::new(raw)?;
+        Ok(TriangleMesh::from_obj(&model, options, material))
+    }
+
+    /// Create a flat shaded mesh from the faces of an .obj model, converting its axes with the
+    /// given options.
+    pub fn from_obj<I: Copy + Into<u32>>(
+        model: &Obj<Position, I>,
+        options: &MeshImportOptions,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let vertices = model
+            .vertices
+            .iter()
+            .map(|vertex| {
+                options.apply(point3(
+                    vertex.position[0],
+                    vertex.position[1],
+                    vertex.position[2],
+                ))
+            })
+            .collect();
+        let indices = obj_indices(&model.indices, options);
+        if options.subdivisions > 0 {
+            return TriangleMesh::subdivided(vertices, indices, options.subdivisions, material);
+        }
+
+        TriangleMesh::new(vertices, indices, material)
+    }
+
+    /// Create a smooth shaded mesh from the faces of an .obj model with vertex normals,
+    /// converting its axes with the given options. Subdivided models get new normals instead.
+    pub fn from_obj_smooth<I: Copy + Into<u32>>(
+        model: &Obj<Vertex, I>,
+        options: &MeshImportOptions,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let vertices = model
+            .vertices
+            .iter()
+            .map(|vertex| {
+                options.apply(point3(
+                    vertex.position[0],
+                    vertex.position[1],
+                    vertex.position[2],
+                ))
+            })
+            .collect();
+        let indices = obj_indices(&model.indices, options);
+        if options.subdivisions > 0 {
+            return TriangleMesh::subdivided(vertices, indices, options.subdivisions, material);
+        }
+        let normals = model
+            .vertices
+            .iter()
+            .map(|vertex| {
+                options.apply_normal(vec3(vertex.normal[0], vertex.normal[1], vertex.normal[2]))
+            })
+            .collect();
+
+        TriangleMesh::with_normals(vertices, normals, indices, material)
+    }
+
+    /// Let rays hit the back faces of the mesh, e.g. for open surfaces seen from both sides or
+    /// models whose faces aren't consistently oriented.
+    pub fn with_two_sided(mut self, two_sided: bool) -> Self {
+        self.two_sided = two_sided;
+        self
+    }
+
+    pub fn faces_num(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn face_vertices(&self, face: usize) -> [Point3; 3] {
+        let face_indices = &self.indices[face * 3..face * 3 + 3];
+        [
+            self.vertices[face_indices[0] as usize],
+            self.vertices[face_indices[1] as usize],
+            self.vertices[face_indices[2] as usize],
+        ]
+    }
+}
+
+/// Offset moving a point of a smooth shaded face onto the curved surface described by the normals
+/// of its vertices, as in "Hacking the Shadow Terminator" (Hanika, 2021). The point is projected
+/// onto the tangent plane of each vertex it lies below, and the projections are blended with its
+/// barycentric coordinates `weights`. Points above every tangent plane, on concave parts of the
+/// surface, stay in place.
+fn terminator_offset(
+    point: Point3,
+    vertices: &[Point3; 3],
+    normals: &[Vec3; 3],
+    weights: &[f32; 3],
+) -> Vec3 {
+    (0..3)
+        .map(|i| {
+            let below = f32::min(0., dot(&(point - vertices[i]), &normals[i]));
+            -normals[i] * below * weights[i]
+        })
+        .sum()
+}
+
+/// Merge the vertices sharing the same position. Faces with out of range indices, or left with
+/// fewer than three distinct vertices, are dropped.
+fn weld_vertices(vertices: &[Point3], indices: &[u32]) -> (Vec<Point3>, Vec<u32>) {
+    let mut welded = Vec::new();
+    let mut welded_indices = HashMap::new();
+    let remap: Vec<u32> = vertices
+        .iter()
+        .map(|vertex| {
+            let key = [vertex.x.to_bits(), vertex.y.to_bits(), vertex.z.to_bits()];
+            *welded_indices.entry(key).or_insert_with(|| {
+                welded.push(*vertex);
+                welded.len() as u32 - 1
+            })
+        })
+        .collect();
+
+    let mut welded_faces = Vec::with_capacity(indices.len());
+    for face in indices.chunks_exact(3) {
+        let face: Option<Vec<u32>> = face
+            .iter()
+            .map(|&idx| remap.get(idx as usize).cloned())
+            .collect();
+        if let Some(face) = face {
+            if face[0] != face[1] && face[1] != face[2] && face[2] != face[0] {
+                welded_faces.extend_from_slice(&face);
+            }
+        }
+    }
+    (welded, welded_faces)
+}
+
+/// Edges are identified by their vertex indices, smallest first.
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+/// One step of Loop subdivision: every face is split in four at new vertices on its edges, and
+/// every vertex is moved towards the smooth limit surface by weighting it with its neighbors.
+/// Boundary edges, and edges shared by more than two faces, are kept as creases which only
+/// smooth along themselves.
+fn loop_subdivide(vertices: &[Point3], indices: &[u32]) -> (Vec<Point3>, Vec<u32>) {
+    // Vertices facing each edge in the faces sharing it
+    let mut opposites: HashMap<(u32, u32), Vec<u32>> = HashMap::new();
+    for face in indices.chunks_exact(3) {
+        for i in 0..3 {
+            opposites
+                .entry(edge_key(face[i], face[(i + 1) % 3]))
+                .or_insert_with(Vec::new)
+                .push(face[(i + 2) % 3]);
+        }
+    }
+
+    // New vertex of each edge, numbered after the original vertices in the order faces are met
+    let mut edge_vertices = HashMap::new();
+    let mut edges = Vec::new();
+    let mut neighbors = vec![Vec::new(); vertices.len()];
+    let mut crease_neighbors = vec![Vec::new(); vertices.len()];
+    for face in indices.chunks_exact(3) {
+        for i in 0..3 {
+            let (a, b) = edge_key(face[i], face[(i + 1) % 3]);
+            if edge_vertices.contains_key(&(a, b)) {
+                continue;
+            }
+            edge_vertices.insert((a, b), (vertices.len() + edges.len()) as u32);
+            edges.push((a, b));
+            neighbors[a as usize].push(b);
+            neighbors[b as usize].push(a);
+            if opposites[&(a, b)].len() != 2 {
+                crease_neighbors[a as usize].push(b);
+                crease_neighbors[b as usize].push(a);
+            }
+        }
+    }
+
+    let coords = |idx: u32| to_vec(&vertices[idx as usize]);
+    let mut subdivided: Vec<Point3> = vertices
+        .iter()
+        .enumerate()
+        .map(|(idx, vertex)| {
+            let creases = &crease_neighbors[idx];
+            let ring = &neighbors[idx];
+            if creases.len() == 2 {
+                return to_point(
+                    to_vec(&vertex) * 0.75 + (coords(creases[0]) + coords(creases[1])) * 0.125,
+                );
+            }
+            // Corners where several creases meet stay in place
+            if !creases.is_empty() || ring.is_empty() {
+                return *vertex;
+            }
+            let valence = ring.len() as f32;
+            let beta = if ring.len() == 3 {
+                3. / 16.
+            } else {
+                3. / (8. * valence)
+            };
+            let ring_sum: Vec3 = ring.iter().map(|&neighbor| coords(neighbor)).sum();
+            to_point(to_vec(&vertex) * (1. - valence * beta) + ring_sum * beta)
+        })
+        .collect();
+    subdivided.extend(edges.iter().map(|&(a, b)| {
+        let ends = coords(a) + coords(b);
+        match opposites[&(a, b)][..] {
+            [c, d] => to_point(ends * 0.375 + (coords(c) + coords(d)) * 0.125),
+            _ => to_point(ends * 0.5),
+        }
+    }));
+
+    let mut subdivided_indices = Vec::with_capacity(indices.len() * 4);
+    for face in indices.chunks_exact(3) {
+        let (a, b, c) = (face[0], face[1], face[2]);
+        let ab = edge_vertices[&edge_key(a, b)];
+        let bc = edge_vertices[&edge_key(b, c)];
+        let ca = edge_vertices[&edge_key(c, a)];
+        subdivided_indices.extend_from_slice(&[a, ab, ca, ab, b, bc, ca, bc, c, ab, bc, ca]);
+    }
+    (subdivided, subdivided_indices)
+}
+
+/// Smooth normals of the vertices of a mesh: the average of the normals of the faces around each
+/// vertex, weighted by their area.
+fn vertex_normals(vertices: &[Point3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![vec3(0., 0., 0.); vertices.len()];
+    for face in indices.chunks_exact(3) {
+        let (a, b, c) = (
+            vertices[face[0] as usize],
+            vertices[face[1] as usize],
+            vertices[face[2] as usize],
+        );
+        // The cross product is as long as twice the area of the face
+        let face_normal = cross(&(b - a), &(c - a));
+        for &idx in face {
+            normals[idx as usize] += face_normal;
+        }
+    }
+    normals
+        .into_iter()
+        .map(|normal| {
+            if length(&normal) < MIN_FACE_AREA {
+                normal
+            } else {
+                normalize(&normal)
+            }
+        })
+        .collect()
+}
+
+/// Face indices of an .obj model. Keep faces pointing outwards when the model is mirrored.
+fn obj_indices<I: Copy + Into<u32>>(model_indices: &[I], options: &MeshImportOptions) -> Vec<u32> {
+    let faces_num = model_indices.len() / 3;
+    let mut indices: Vec<u32> = model_indices[..faces_num * 3]
+        .iter()
+        .map(|&idx| idx.into())
+        .collect();
+    if options.flips_winding() {
+        for face in indices.chunks_exact_mut(3) {
+            face.swap(1, 2);
+        }
+    }
+    indices
+}
+
+impl TraceObj for TriangleMesh {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (mut hit, face) = self.bvh.intersect(ray, |face| {
+            let [a, b, c] = self.face_vertices(face);
+            triangle_intersect(a, b, c, ray, self.two_sided)
+        })?;
+
+        if let Some(ref normals) = self.normals {
+            // Interpolate vertex normals with the barycentric coordinates of the hit
+            let face_indices = &self.indices[face * 3..face * 3 + 3];
+            let face_normals = [
+                normals[face_indices[0] as usize],
+                normals[face_indices[1] as usize],
+                normals[face_indices[2] as usize],
+            ];
+            let (weight_b, weight_c) = (hit.uv.x, hit.uv.y);
+            let weights = [1. - weight_b - weight_c, weight_b, weight_c];
+            let interpolated = face_normals[0] * weights[0]
+                + face_normals[1] * weights[1]
+                + face_normals[2] * weights[2];
+            // Keep the face normal if vertex normals are broken
+            if let Some(normal) = try_normalize(&interpolated) {
+                hit.normal = normal;
+                hit.tangent = tangent_towards(hit.tangent, normal);
+                hit.shadow_offset = terminator_offset(
+                    hit.point,
+                    &self.face_vertices(face),
+                    &face_normals,
+                    &weights,
+                );
+            }
+        }
+
+        Some(hit)
+    }
+
+    fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounding_box()
+    }
+
+    fn memory_size(&self) -> usize {
+        let normals = self.normals.as_ref().map_or(0, Vec::len);
+        mem::size_of::<Self>()
+            + self.vertices.len() * mem::size_of::<Point3>()
+            + normals * mem::size_of::<Vec3>()
+            + self.indices.len() * mem::size_of::<u32>()
+            + self.bvh.memory_size()
+    }
+}
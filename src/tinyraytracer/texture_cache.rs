@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use image::imageops;
+use image::{Rgba, Rgba32FImage};
+
+/// Side in pixels of the square tiles textures are split into.
+const TEXTURE_TILE_SIZE: u32 = 64;
+const CHANNELS: usize = 4;
+
+/// Handle to a texture registered in a `TextureCache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TileKey {
+    texture: TextureId,
+    tile_x: u32,
+    tile_y: u32,
+}
+
+#[derive(Debug)]
+struct TextureInfo {
+    /// Directory holding the texture's tiles on disk
+    dir: PathBuf,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug)]
+struct CachedTile {
+    /// Linear RGBA pixels of the tile
+    data: Arc<Vec<f32>>,
+    width: u32,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    tiles: HashMap<TileKey, CachedTile>,
+    used_bytes: usize,
+    /// Incremented on every access, used to find the least recently used tile
+    clock: u64,
+}
+
+impl CacheState {
+    /// Pixels and width of a tile if it's in memory, marking it as just used.
+    fn touch(&mut self, key: TileKey) -> Option<(Arc<Vec<f32>>, u32)> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.tiles.get_mut(&key).map(|tile| {
+            tile.last_used = clock;
+            (tile.data.clone(), tile.width)
+        })
+    }
+}
+
+/// Texture cache with a memory budget. Registering a texture writes it to disk as tiles. Tiles
+/// are then loaded on demand when sampled, and the least recently used ones are evicted whenever
+/// the budget is exceeded, so scenes with many large textures don't have to keep all of them in
+/// memory.
+#[derive(Debug)]
+pub struct TextureCache {
+    cache_dir: PathBuf,
+    budget_bytes: usize,
+    textures: RwLock<Vec<Arc<TextureInfo>>>,
+    state: Mutex<CacheState>,
+}
+
+impl TextureCache {
+    /// Create a cache storing texture tiles in `cache_dir` and keeping at most `budget_bytes` of
+    /// tiles in memory. The directory belongs to the cache, which removes it when dropped.
+    pub fn new(cache_dir: &Path, budget_bytes: usize) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(cache_dir)?;
+        Ok(TextureCache {
+            cache_dir: cache_dir.to_path_buf(),
+            budget_bytes,
+            textures: RwLock::new(Vec::new()),
+            state: Mutex::new(CacheState::default()),
+        })
+    }
+
+    /// Write a texture with linear color channels to the cache directory as tiles.
+    pub fn register(&self, texture: &Rgba32FImage) -> Result<TextureId, Box<dyn Error>> {
+        let mut textures = self.textures.write().unwrap();
+        let id = TextureId(textures.len());
+        let dir = self.cache_dir.join(format!("texture_{}", id.0));
+        fs::create_dir_all(&dir)?;
+
+        let (width, height) = texture.dimensions();
+        for tile_y in 0..tiles_num(height) {
+            for tile_x in 0..tiles_num(width) {
+                let (x, y) = (tile_x * TEXTURE_TILE_SIZE, tile_y * TEXTURE_TILE_SIZE);
+                let tile = imageops::crop_imm(
+                    texture,
+                    x,
+                    y,
+                    u32::min(TEXTURE_TILE_SIZE, width - x),
+                    u32::min(TEXTURE_TILE_SIZE, height - y),
+                )
+                .to_image();
+                let bytes: Vec<u8> = tile
+                    .into_raw()
+                    .into_iter()
+                    .flat_map(|channel| channel.to_le_bytes().to_vec())
+                    .collect();
+                fs::write(tile_path(&dir, tile_x, tile_y), bytes)?;
+            }
+        }
+
+        textures.push(Arc::new(TextureInfo { dir, width, height }));
+        Ok(id)
+    }
+
+    /// Sample a texture at UV coordinates in [0, 1] x [0, 1], wrapping outside of that range. V
+    /// goes upwards, from the bottom row of the texture to its top row.
+    pub fn sample(&self, texture: TextureId, u: f32, v: f32) -> Rgba<f32> {
+        let info = self.textures.read().unwrap()[texture.0].clone();
+        let (width, height) = (info.width, info.height);
+
+        let x = u32::min((u.rem_euclid(1.) * width as f32) as u32, width - 1);
+        let y = u32::min(((1. - v.rem_euclid(1.)) * height as f32) as u32, height - 1);
+        let key = TileKey {
+            texture,
+            tile_x: x / TEXTURE_TILE_SIZE,
+            tile_y: y / TEXTURE_TILE_SIZE,
+        };
+
+        match self.tile(key, &info) {
+            Some((data, tile_width)) => {
+                let offset = (((y % TEXTURE_TILE_SIZE) * tile_width + x % TEXTURE_TILE_SIZE)
+                    as usize)
+                    * CHANNELS;
+                Rgba([
+                    data[offset],
+                    data[offset + 1],
+                    data[offset + 2],
+                    data[offset + 3],
+                ])
+            }
+            // Missing tile files shouldn't bring the render down. Show them in magenta instead
+            None => Rgba([1., 0., 1., 1.]),
+        }
+    }
+
+    /// Get a tile from memory, loading it from disk and evicting least recently used tiles if
+    /// needed. Return its pixels and its width.
+    fn tile(&self, key: TileKey, info: &TextureInfo) -> Option<(Arc<Vec<f32>>, u32)> {
+        if let Some(tile) = self.state.lock().unwrap().touch(key) {
+            return Some(tile);
+        }
+
+        // Read the tile without holding the lock, so that other threads keep sampling meanwhile
+        let bytes = fs::read(tile_path(&info.dir, key.tile_x, key.tile_y)).ok()?;
+        let data: Arc<Vec<f32>> = Arc::new(
+            bytes
+                .chunks_exact(mem::size_of::<f32>())
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        );
+        let width = u32::min(
+            TEXTURE_TILE_SIZE,
+            info.width - key.tile_x * TEXTURE_TILE_SIZE,
+        );
+
+        let mut state = self.state.lock().unwrap();
+        // Another thread may have loaded the same tile in the meantime
+        if let Some(tile) = state.touch(key) {
+            return Some(tile);
+        }
+        let clock = state.clock;
+        state.used_bytes += tile_bytes(&data);
+        while state.used_bytes > self.budget_bytes && !state.tiles.is_empty() {
+            let lru_key = *state
+                .tiles
+                .iter()
+                .min_by_key(|(_, tile)| tile.last_used)
+                .map(|(key, _)| key)
+                .unwrap();
+            let evicted = state.tiles.remove(&lru_key).unwrap();
+            state.used_bytes -= tile_bytes(&evicted.data);
+        }
+        state.tiles.insert(
+            key,
+            CachedTile {
+                data: data.clone(),
+                width,
+                last_used: clock,
+            },
+        );
+
+        Some((data, width))
+    }
+}
+
+impl Drop for TextureCache {
+    fn drop(&mut self) {
+        // Leftover tiles only waste disk space, so failing to remove them isn't worth reporting
+        let _ = fs::remove_dir_all(&self.cache_dir);
+    }
+}
+
+fn tiles_num(size: u32) -> u32 {
+    (size + TEXTURE_TILE_SIZE - 1) / TEXTURE_TILE_SIZE
+}
+
+fn tile_path(dir: &Path, tile_x: u32, tile_y: u32) -> PathBuf {
+    dir.join(format!("{}_{}.rgba", tile_x, tile_y))
+}
+
+fn tile_bytes(data: &[f32]) -> usize {
+    data.len() * mem::size_of::<f32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use image::{Rgba, Rgba32FImage};
+
+    use super::super::math::point2;
+    use super::super::scene_elems::materials::texture_lookup;
+    use super::{TextureCache, TEXTURE_TILE_SIZE};
+
+    #[test]
+    fn cached_texture_matches_image() {
+        let (width, height) = (3 * TEXTURE_TILE_SIZE + 5, 2 * TEXTURE_TILE_SIZE + 7);
+        let image = Rgba32FImage::from_fn(width, height, |x, y| {
+            Rgba([x as f32 / width as f32, y as f32 / height as f32, 0.5, 1.])
+        });
+        let tile_bytes = (TEXTURE_TILE_SIZE * TEXTURE_TILE_SIZE) as usize * 4 * 4;
+        let dir = env::temp_dir().join(format!("tinyraytracer_texture_cache_{}", process::id()));
+        let cache = TextureCache::new(&dir, 2 * tile_bytes).unwrap();
+        let texture = cache.register(&image).unwrap();
+
+        // Sweep the texture twice so that tiles get evicted and loaded back
+        for _ in 0..2 {
+            for y in 0..height {
+                for x in 0..width {
+                    let uv = point2(
+                        (x as f32 + 0.5) / width as f32,
+                        (y as f32 + 0.5) / height as f32,
+                    );
+                    assert_eq!(
+                        cache.sample(texture, uv.x, uv.y),
+                        texture_lookup(&image, uv)
+                    );
+                }
+            }
+        }
+        assert!(cache.state.lock().unwrap().used_bytes <= 2 * tile_bytes);
+
+        drop(cache);
+        assert!(!dir.exists());
+    }
+}
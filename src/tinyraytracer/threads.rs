@@ -0,0 +1,119 @@
+use std::fs;
+
+use core_affinity::{self, CoreId};
+#[cfg(unix)]
+use libc;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+/// Niceness given to render threads in low-priority mode (the lowest priority on Unix).
+#[cfg(unix)]
+const LOW_PRIORITY_NICENESS: i32 = 19;
+
+/// Which CPU cores render threads may run on. On big.LITTLE / hybrid CPUs, performance and
+/// efficiency cores are told apart by their maximum frequency.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoreSelection {
+    All,
+    /// Only the fastest cores
+    Performance,
+    /// Only the slower cores, leaving the fast ones free for interactive use
+    Efficiency,
+}
+
+/// Configuration of the thread pool used for rendering.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThreadSettings {
+    /// Number of render threads. Zero uses one thread per selected core
+    pub threads: usize,
+    /// Lower the scheduling priority of render threads so long renders don't make the desktop
+    /// unresponsive
+    pub low_priority: bool,
+    pub cores: CoreSelection,
+}
+
+impl Default for ThreadSettings {
+    fn default() -> Self {
+        ThreadSettings {
+            threads: 0,
+            low_priority: false,
+            cores: CoreSelection::All,
+        }
+    }
+}
+
+/// Maximum frequency of a core as reported by the kernel, if available.
+fn core_max_freq(core: CoreId) -> Option<u64> {
+    let path = format!(
+        "/sys/devices/system/cpu/cpu{}/cpufreq/cpuinfo_max_freq",
+        core.id
+    );
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Cores matching the given selection. If cores can't be told apart (homogeneous CPU or no
+/// frequency information), every core is selected.
+fn select_cores(selection: CoreSelection) -> Vec<CoreId> {
+    let cores = core_affinity::get_core_ids().unwrap_or_default();
+    if selection == CoreSelection::All {
+        return cores;
+    }
+
+    let freqs: Vec<Option<u64>> = cores.iter().map(|&core| core_max_freq(core)).collect();
+    let max_freq = match freqs.iter().flatten().max() {
+        Some(&freq) => freq,
+        None => return cores,
+    };
+    let selected: Vec<CoreId> = cores
+        .iter()
+        .zip(freqs.iter())
+        .filter(|(_, freq)| match selection {
+            CoreSelection::Performance => **freq == Some(max_freq),
+            _ => freq.map_or(false, |freq| freq < max_freq),
+        })
+        .map(|(&core, _)| core)
+        .collect();
+
+    if selected.is_empty() {
+        cores
+    } else {
+        selected
+    }
+}
+
+#[cfg(unix)]
+fn lower_thread_priority() {
+    // On Linux, a `who` of zero refers to the calling thread only
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICENESS);
+    }
+}
+
+#[cfg(not(unix))]
+fn lower_thread_priority() {}
+
+/// Build the thread pool render work is dispatched to. When a subset of cores is selected,
+/// threads are pinned to those cores in a round-robin fashion.
+pub fn build_thread_pool(settings: &ThreadSettings) -> Result<ThreadPool, ThreadPoolBuildError> {
+    let cores = select_cores(settings.cores);
+    let threads = if settings.threads > 0 {
+        settings.threads
+    } else {
+        usize::max(cores.len(), 1)
+    };
+    let pin_threads = settings.cores != CoreSelection::All && !cores.is_empty();
+    let low_priority = settings.low_priority;
+
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .start_handler(move |thread_idx| {
+            if pin_threads {
+                core_affinity::set_for_current(cores[thread_idx % cores.len()]);
+            }
+            if low_priority {
+                lower_thread_priority();
+            }
+        })
+        .build()
+}
@@ -0,0 +1,73 @@
+//! Small, quickly rendered previews of scene files, e.g. to build a gallery of saved scenes. Every
+//! scene is seen from the same angle, framed to fit, so that thumbnails are easy to compare.
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::RgbaImage;
+
+use super::math::{length, vec3};
+use super::scene::loader::load_scene;
+use super::scene::Scene;
+use super::scene_elems::Projection;
+use super::{render_scene, Preset, RenderSettings};
+
+/// Vertical field of view of the camera thumbnails are rendered with.
+const THUMBNAIL_FOV: f32 = PI / 3.;
+
+/// Place a pinhole perspective camera looking towards -z in front of the bounded objects of a
+/// scene, far enough for all of them to be in view. Scenes without bounded objects keep their
+/// camera.
+pub fn frame_scene(scene: &mut Scene) {
+    let bbox = match scene.bounding_box() {
+        Some(bbox) => bbox,
+        None => return,
+    };
+    // Fit the sphere around the box in the field of view
+    let radius = f32::max(length(&(bbox.max - bbox.min)) / 2., 1e-3);
+    let distance = radius / f32::sin(THUMBNAIL_FOV / 2.);
+
+    let camera = &mut scene.camera;
+    camera.projection = Projection::Perspective;
+    camera.fov = THUMBNAIL_FOV;
+    camera.position = bbox.centroid() + vec3(0., 0., distance);
+    camera.aperture = 0.;
+    camera.near = 0.;
+    camera.far = f32::INFINITY;
+}
+
+/// Settings for a fast render of a square thumbnail `size` pixels wide: the draft preset, with a
+/// single pass. Other settings, such as the integrator and the tone mapping, are kept.
+pub fn thumbnail_settings(settings: &RenderSettings, size: u32) -> RenderSettings {
+    let mut settings = settings.clone();
+    settings.apply_preset(Preset::Draft);
+    settings.width = size;
+    settings.height = size;
+    settings.passes = 1;
+    settings
+}
+
+/// Load a scene file and render its thumbnail, `size` pixels wide.
+pub fn render_thumbnail(path: &Path, size: u32) -> Result<RgbaImage, Box<dyn Error>> {
+    let (mut scene, settings) = load_scene(path)?;
+    frame_scene(&mut scene);
+    Ok(render_scene(&scene, &thumbnail_settings(&settings, size)))
+}
+
+/// Scene files (.json and .ron) of a directory, sorted by name.
+pub fn scene_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_scene = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map_or(false, |ext| ext == "json" || ext == "ron");
+        if is_scene && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}